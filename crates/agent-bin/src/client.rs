@@ -1,11 +1,15 @@
 // ESNODE | Source Available BUSL-1.1 | Copyright (c) 2024 Estimatedstocks AB
 use agent_core::state::StatusSnapshot;
-use anyhow::{anyhow, Context, Result};
+use agent_core::tsdb::Sample;
+use anyhow::{anyhow, bail, Context, Result};
 use std::io::{Read, Write};
 use std::net::{SocketAddr, TcpStream};
 use std::time::Duration;
 use url::Url;
 
+/// Samples per `/tsdb/export` page.
+const TSDB_PAGE_SIZE: usize = 50_000;
+
 /// Lightweight HTTP client for talking to the local agent without external deps.
 pub struct AgentClient {
     base_url: String,
@@ -62,8 +66,49 @@ impl AgentClient {
     }
 
 
+    /// Samples of `metrics` in `[from_ms, to_ms]` from the agent's local
+    /// TSDB, read page by page from `/tsdb/export`.
+    pub fn fetch_tsdb_samples(
+        &self,
+        from_ms: i64,
+        to_ms: i64,
+        metrics: &[&str],
+    ) -> Result<Vec<Sample>> {
+        let mut samples = Vec::new();
+        let mut next: Option<String> = None;
+        loop {
+            let mut path = format!(
+                "/tsdb/export?format=jsonl&limit={TSDB_PAGE_SIZE}&from={from_ms}&to={to_ms}&metrics={}",
+                metrics.join(",")
+            );
+            if let Some(token) = &next {
+                path.push_str("&next=");
+                path.push_str(token);
+            }
+            let response = self.request(&path, Duration::from_secs(30))?;
+            match response.status {
+                200 => {}
+                404 => bail!("the agent's local TSDB is disabled (enable_local_tsdb)"),
+                status => bail!("requesting /tsdb/export: {status} {}", response.body.trim()),
+            }
+            for line in response.body.lines().filter(|l| !l.trim().is_empty()) {
+                if let Some(sample) = parse_export_line(line)? {
+                    samples.push(sample);
+                }
+            }
+            next = response.header("x-next-token").map(str::to_string);
+            if next.is_none() {
+                return Ok(samples);
+            }
+        }
+    }
 
     fn http_get(&self, path: &str) -> Result<(u16, String)> {
+        let response = self.request(path, Duration::from_secs(2))?;
+        Ok((response.status, response.body))
+    }
+
+    fn request(&self, path: &str, timeout: Duration) -> Result<Response> {
         let url = Url::parse(&format!("{}{}", self.base_url, path)).context("parsing URL")?;
         let host = url
             .host_str()
@@ -74,11 +119,15 @@ impl AgentClient {
             .context("resolving address")?;
         let mut stream = TcpStream::connect_timeout(&addr, Duration::from_secs(2))
             .context("connecting to agent")?;
-        stream.set_read_timeout(Some(Duration::from_secs(2))).ok();
+        stream.set_read_timeout(Some(timeout)).ok();
         stream.set_write_timeout(Some(Duration::from_secs(2))).ok();
+        let target = match url.query() {
+            Some(query) => format!("{}?{query}", url.path()),
+            None => url.path().to_string(),
+        };
         let req = format!(
             "GET {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n\r\n",
-            url.path(),
+            target,
             host
         );
         stream
@@ -102,17 +151,69 @@ impl AgentClient {
         }
         let resp = String::from_utf8_lossy(&resp_bytes).to_string();
         let mut parts = resp.splitn(2, "\r\n\r\n");
-        let status_line = parts.next().unwrap_or("");
+        let head = parts.next().unwrap_or("");
         let body = parts.next().unwrap_or("").to_string();
-        let status = status_line
+        let mut lines = head.lines();
+        let status = lines
+            .next()
+            .unwrap_or("")
             .split_whitespace()
             .nth(1)
             .and_then(|s| s.parse::<u16>().ok())
             .unwrap_or(0);
-        Ok((status, body))
+        let headers = lines
+            .filter_map(|line| line.split_once(':'))
+            .map(|(name, value)| (name.trim().to_ascii_lowercase(), value.trim().to_string()))
+            .collect();
+        Ok(Response {
+            status,
+            headers,
+            body,
+        })
     }
 }
 
+struct Response {
+    status: u16,
+    /// Lower-cased names.
+    headers: Vec<(String, String)>,
+    body: String,
+}
+
+impl Response {
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, v)| v.as_str())
+    }
+}
+
+/// Parses a `jsonl` export line; samples whose value is not a number
+/// (`"NaN"`, `"+Inf"`) are skipped.
+fn parse_export_line(line: &str) -> Result<Option<Sample>> {
+    #[derive(serde::Deserialize)]
+    struct Line {
+        metric: std::collections::HashMap<String, String>,
+        value: serde_json::Value,
+        timestamp: i64,
+    }
+    let mut line: Line = serde_json::from_str(line).context("parsing TSDB export")?;
+    let Some(value) = line.value.as_f64() else {
+        return Ok(None);
+    };
+    let metric = line
+        .metric
+        .remove("__name__")
+        .ok_or_else(|| anyhow!("TSDB export sample without __name__"))?;
+    Ok(Some(Sample {
+        metric,
+        labels: line.metric,
+        ts_ms: line.timestamp,
+        value,
+    }))
+}
+
 #[cfg(test)]
 mod tests {
     use super::AgentClient;
@@ -191,4 +292,62 @@ mod tests {
         assert_eq!(snapshot.cpu_cores, Some(4));
         assert_eq!(snapshot.cpu_util_percent, Some(10.0));
     }
+
+    #[test]
+    fn fetch_tsdb_samples_follows_next_tokens() {
+        let listener = match TcpListener::bind("127.0.0.1:0") {
+            Ok(l) => l,
+            Err(_) => return,
+        };
+        let addr = listener.local_addr().unwrap();
+
+        let server = thread::spawn(move || {
+            let pages = [
+                (
+                    "x-next-token: 00000000000003e80000000000000001\r\n",
+                    concat!(
+                        r#"{"metric":{"__name__":"esnode_gpu_power_watts","uuid":"abc"},"value":400,"timestamp":1000}"#,
+                        "\n",
+                        r#"{"metric":{"__name__":"esnode_gpu_power_watts","uuid":"abc"},"value":"NaN","timestamp":1500}"#,
+                        "\n"
+                    ),
+                ),
+                (
+                    "",
+                    concat!(
+                        r#"{"metric":{"__name__":"esnode_node_power_watts"},"value":900.5,"timestamp":2000}"#,
+                        "\n"
+                    ),
+                ),
+            ];
+            let mut requests = Vec::new();
+            for (header, body) in pages {
+                let (mut stream, _) = listener.accept().unwrap();
+                let mut buf = [0u8; 1024];
+                let n = stream.read(&mut buf).unwrap();
+                requests.push(String::from_utf8_lossy(&buf[..n]).to_string());
+                let resp = format!(
+                    "HTTP/1.1 200 OK\r\nContent-Length: {}\r\n{header}\r\n{body}",
+                    body.len()
+                );
+                let _ = stream.write_all(resp.as_bytes());
+            }
+            requests
+        });
+
+        let client = AgentClient::new(&format!("{addr}"));
+        let samples = client
+            .fetch_tsdb_samples(0, 5000, &["esnode_gpu_power_watts", "esnode_node_power_watts"])
+            .expect("samples");
+        assert_eq!(samples.len(), 2);
+        assert_eq!(samples[0].metric, "esnode_gpu_power_watts");
+        assert_eq!(samples[0].labels.get("uuid").map(String::as_str), Some("abc"));
+        assert_eq!(samples[1].value, 900.5);
+
+        let requests = server.join().unwrap();
+        assert!(requests[0].starts_with(
+            "GET /tsdb/export?format=jsonl&limit=50000&from=0&to=5000&metrics=esnode_gpu_power_watts,esnode_node_power_watts HTTP/1.1"
+        ));
+        assert!(requests[1].contains("&next=00000000000003e80000000000000001 HTTP/1.1"));
+    }
 }
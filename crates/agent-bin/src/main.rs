@@ -3,6 +3,7 @@ mod client;
 mod console;
 
 use std::{
+    collections::HashMap,
     fs,
     path::{Path, PathBuf},
     time::Duration,
@@ -91,7 +92,7 @@ struct Cli {
     #[arg(long, env = "ESNODE_NODE_POWER_ENVELOPE_WATTS")]
     node_power_envelope_watts: Option<f64>,
 
-    /// Enable lightweight on-agent TSDB buffer (JSONL-backed).
+    /// Enable lightweight on-agent TSDB buffer (compressed 2h blocks).
     #[arg(long, env = "ESNODE_ENABLE_LOCAL_TSDB")]
     enable_local_tsdb: Option<bool>,
 
@@ -107,6 +108,30 @@ struct Cli {
     #[arg(long, env = "ESNODE_LOCAL_TSDB_MAX_DISK_MB")]
     local_tsdb_max_disk_mb: Option<u64>,
 
+    /// fsync cadence for the on-agent TSDB write-ahead log (e.g. 1s; 0s syncs every write).
+    #[arg(long, env = "ESNODE_LOCAL_TSDB_WAL_FSYNC_INTERVAL")]
+    local_tsdb_wal_fsync_interval: Option<String>,
+
+    /// Default interval between on-agent TSDB writes (e.g. 30s); per-metric policies go in the config file.
+    #[arg(long, env = "ESNODE_LOCAL_TSDB_WRITE_INTERVAL")]
+    local_tsdb_write_interval: Option<String>,
+
+    /// Maximum active series in the on-agent TSDB.
+    #[arg(long, env = "ESNODE_LOCAL_TSDB_MAX_SERIES")]
+    local_tsdb_max_series: Option<usize>,
+
+    /// Maximum active series per metric in the on-agent TSDB.
+    #[arg(long, env = "ESNODE_LOCAL_TSDB_MAX_SERIES_PER_METRIC")]
+    local_tsdb_max_series_per_metric: Option<usize>,
+
+    /// Bearer token for the admin endpoints (TSDB admin API, /v1/policy/*, /v1/audit).
+    #[arg(long, env = "ESNODE_ADMIN_TOKEN")]
+    admin_token: Option<String>,
+
+    /// Deprecated alias of --admin-token.
+    #[arg(long, env = "ESNODE_LOCAL_TSDB_ADMIN_TOKEN", hide = true)]
+    local_tsdb_admin_token: Option<String>,
+
     /// Enable ESNODE-Orchestrator (Autonomous features)
     #[arg(long, env = "ESNODE_ENABLE_ORCHESTRATOR")]
     pub enable_orchestrator: Option<bool>,
@@ -163,17 +188,55 @@ enum Command {
     },
     /// Plan an efficiency profile against the current node status.
     Plan {
-        /// Path to the efficiency profile (YAML).
+        /// Path to the efficiency profile (YAML) or a directory of profiles.
         file: PathBuf,
+        /// Evaluate recorded history instead: `tsdb` for the agent's local TSDB, or a file of
+        /// status snapshots (JSON lines saved from /status or /events, or a JSON array).
+        #[arg(long, value_name = "tsdb|FILE")]
+        replay: Option<String>,
+        /// Start of the replay: RFC 3339, Unix seconds or a duration ago such as 6h
+        /// (default: 24h for the TSDB, the whole file otherwise).
+        #[arg(long, requires = "replay")]
+        from: Option<String>,
+        /// End of the replay, in the same formats (default: now).
+        #[arg(long, requires = "replay")]
+        to: Option<String>,
+        /// Interval between evaluations when replaying the TSDB.
+        #[arg(long, default_value = "30s", requires = "replay")]
+        step: String,
     },
     /// Enforce an efficiency profile (Apply actions).
     Apply {
-        /// Path to the efficiency profile (YAML).
+        /// Path to the efficiency profile (YAML) or a directory of profiles.
         file: PathBuf,
         /// Skip interactive confirmation.
         #[arg(long, short = 'y')]
         yes: bool,
     },
+    /// Check efficiency profiles without contacting the agent.
+    Validate {
+        /// Profile files or directories of profiles.
+        #[arg(required_unless_present = "schema")]
+        paths: Vec<PathBuf>,
+        /// Print the profile JSON Schema instead.
+        #[arg(long)]
+        schema: bool,
+    },
+    /// Inspect the enforcement audit log.
+    Audit {
+        #[command(subcommand)]
+        action: AuditCommand,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+enum AuditCommand {
+    /// Check that no record was edited or deleted, across rotated files.
+    Verify {
+        /// Audit log to check (default: audit.path from the config).
+        #[arg(long)]
+        path: Option<PathBuf>,
+    },
 }
 
 #[derive(Debug, Subcommand)]
@@ -262,13 +325,22 @@ async fn main() -> Result<()> {
             ConfigCommand::Show => command_config_show(&config_path, &config),
             ConfigCommand::Set { key_value } => command_config_set(&config_path, key_value),
         },
-        Command::Plan { file } => {
+        Command::Plan { file, replay: Some(source), from, to, step } => {
+            command_plan_replay(&config, file, source, from.as_deref(), to.as_deref(), step)
+        },
+        Command::Plan { file, .. } => {
             let client = AgentClient::new(&config.listen_address);
-            command_plan(&client, file)
+            command_plan(&client, file, &config.tags)
         },
         Command::Apply { file, yes } => {
             let client = AgentClient::new(&config.listen_address);
-            command_apply(&client, file, *yes)
+            command_apply(&client, file, *yes, &config.tags, &config.kill_process)
+        },
+        Command::Validate { paths, schema } => command_validate(paths, *schema),
+        Command::Audit { action } => match action {
+            AuditCommand::Verify { path } => {
+                command_audit_verify(path.as_deref().unwrap_or(&config.audit.path))
+            }
         },
     }
 }
@@ -324,9 +396,20 @@ fn cli_to_overrides(cli: &Cli) -> Result<ConfigOverrides> {
         local_tsdb_path: cli.local_tsdb_path.clone(),
         local_tsdb_retention_hours: cli.local_tsdb_retention_hours,
         local_tsdb_max_disk_mb: cli.local_tsdb_max_disk_mb,
+        local_tsdb_wal_fsync_interval: parse_duration(cli.local_tsdb_wal_fsync_interval.as_deref())?,
+        local_tsdb_rollups: None,
+        local_tsdb_write_interval: parse_duration(cli.local_tsdb_write_interval.as_deref())?,
+        local_tsdb_write_policies: None,
+        local_tsdb_max_series: cli.local_tsdb_max_series,
+        local_tsdb_max_series_per_metric: cli.local_tsdb_max_series_per_metric,
+        local_tsdb_series_overflow: None,
+        admin_token: cli.admin_token.clone().map(Into::into),
+        local_tsdb_admin_token: cli.local_tsdb_admin_token.clone().map(Into::into),
+        remote_write: None,
         log_level: parse_log_level(cli.log_level.as_deref())?,
         orchestrator,
         efficiency_profile_path: None,
+        efficiency_profiles_dir: None,
         enforcement_mode: None,
         enforcement_interval: None,
         dampening_interval: None,
@@ -772,12 +855,112 @@ fn apply_config_kv(config: &mut AgentConfig, key: &str, val: &str) -> Result<()>
     Ok(())
 }
 
-fn command_plan(client: &AgentClient, profile_path: &Path) -> Result<()> {
-    let contents = fs::read_to_string(profile_path)
-        .with_context(|| format!("failed to read profile {}", profile_path.display()))?;
-    
-    let profile: agent_core::policy::EfficiencyProfile = serde_yaml::from_str(&contents)
-        .with_context(|| "failed to parse efficiency profile YAML")?;
+fn command_validate(paths: &[PathBuf], schema: bool) -> Result<()> {
+    if schema {
+        println!("{}", serde_json::to_string_pretty(&agent_core::policy::profile_schema())?);
+        return Ok(());
+    }
+
+    let mut files = Vec::new();
+    for path in paths {
+        files.extend(
+            agent_core::policy::profile_files(path)
+                .with_context(|| format!("failed to read {}", path.display()))?,
+        );
+    }
+
+    let mut errors = 0;
+    for file in &files {
+        let contents = fs::read_to_string(file)
+            .with_context(|| format!("failed to read profile {}", file.display()))?;
+        let diagnostics = agent_core::policy::validate_yaml(&contents);
+        for diagnostic in &diagnostics {
+            println!("{}:{}", file.display(), diagnostic);
+        }
+        errors += diagnostics.len();
+    }
+    if errors == 0 {
+        // Checks across files, e.g. the same profile name twice.
+        let mut profiles = agent_core::policy::ProfileSet::new(paths.iter().cloned());
+        for (file, e) in profiles.reload().failed {
+            println!("{}: {}", file.display(), e);
+            errors += 1;
+        }
+    }
+
+    if errors > 0 {
+        bail!("{} problem(s) found in {} profile(s)", errors, files.len());
+    }
+    println!("✅ {} profile(s) valid.", files.len());
+    Ok(())
+}
+
+fn command_audit_verify(path: &Path) -> Result<()> {
+    let verification = agent_core::audit::verify(path)?;
+    for problem in &verification.problems {
+        println!("{}", problem);
+    }
+    if !verification.is_intact() {
+        bail!(
+            "{} problem(s) found in audit log {}",
+            verification.problems.len(),
+            path.display()
+        );
+    }
+    match (verification.first_seq, verification.last_seq) {
+        (Some(first), Some(last)) => {
+            println!(
+                "✅ Audit log intact: {} record(s), {} to {}, in {} file(s).",
+                verification.records,
+                first,
+                last,
+                verification.files.len()
+            );
+            if first > 1 {
+                println!("Records before {} were rotated away.", first);
+            }
+        }
+        _ => println!("✅ Audit log is empty."),
+    }
+    Ok(())
+}
+
+/// Loads a profile file or a directory of profiles, failing on any
+/// invalid one.
+fn load_profiles(
+    profile_path: &Path,
+    tags: &HashMap<String, String>,
+) -> Result<agent_core::policy::ProfileSet> {
+    let mut profiles = agent_core::policy::ProfileSet::new([profile_path.to_path_buf()]);
+    let reload = profiles.reload();
+    if !reload.failed.is_empty() {
+        let errors: Vec<String> = reload
+            .failed
+            .iter()
+            .map(|(path, e)| format!("{}: {}", path.display(), e))
+            .collect();
+        bail!("failed to load efficiency profiles:\n  {}", errors.join("\n  "));
+    }
+    if profiles.profiles().is_empty() {
+        bail!("no efficiency profiles found at {}", profile_path.display());
+    }
+    for profile in profiles.profiles() {
+        if !profile.applies_to(tags) {
+            println!(
+                "Profile '{}' does not apply to this agent: selectors.match_tags {:?} do not match tags {:?}.",
+                profile.metadata.name, profile.selectors.match_tags, tags
+            );
+        }
+    }
+    Ok(profiles)
+}
+
+fn command_plan(
+    client: &AgentClient,
+    profile_path: &Path,
+    tags: &HashMap<String, String>,
+) -> Result<()> {
+    let profiles = load_profiles(profile_path, tags)?;
 
     println!("Refreshing state from agent at {}...", client.base_url());
     let status = client.fetch_status()
@@ -785,27 +968,51 @@ fn command_plan(client: &AgentClient, profile_path: &Path) -> Result<()> {
 
     println!("Analyzed {} GPUs.", status.gpus.len());
     
-    let result = profile.plan(&status);
-    
-    println!("\nPlan: {} policies to check for profile '{}'.\n", result.matched_policies.len(), result.profile_name);
+    let results = profiles.plan(
+        &mut agent_core::policy::PolicyEvaluator::default(),
+        &status,
+        tags,
+        status.last_scrape_unix_ms as i64,
+    );
     
     let mut violations = 0;
-    
-    for plan in result.matched_policies {
-        let symbol = match plan.status {
-            agent_core::policy::PlanStatus::Satisfied => "✅",
-            agent_core::policy::PlanStatus::Violated => "❌",
-            agent_core::policy::PlanStatus::Skipped => "⏭️",
-        };
-        
-        println!("{} Policy \"{}\" on {}:", symbol, plan.policy_name, plan.target_resource);
-        println!("    Current: {} | Limit: {}", plan.current_value, plan.threshold);
-        
-        if let Some(action) = plan.computed_action.clone() {
-            println!("    -> PLAN ACTION: {}", action);
-            violations += 1;
+    let mut conflicts = 0;
+
+    for result in results {
+        println!("\nPlan: {} policies to check for profile '{}'.\n", result.matched_policies.len(), result.profile_name);
+
+        for plan in result.matched_policies {
+            let symbol = match plan.status {
+                agent_core::policy::PlanStatus::Satisfied => "✅",
+                agent_core::policy::PlanStatus::Pending => "⏳",
+                agent_core::policy::PlanStatus::Violated => "❌",
+                agent_core::policy::PlanStatus::Skipped => "⏭️",
+                agent_core::policy::PlanStatus::Conflict => "⚠️",
+                agent_core::policy::PlanStatus::Inactive => "💤",
+                agent_core::policy::PlanStatus::Maintenance => "🔧",
+            };
+            
+            println!("{} Policy \"{}\" on {}:", symbol, plan.policy_name, plan.target_resource);
+            println!("    Current: {} | Limit: {}", plan.current_value, plan.threshold);
+            if plan.status == agent_core::policy::PlanStatus::Inactive {
+                println!("    -> OUTSIDE SCHEDULE");
+            }
+            if let Some(window) = &plan.maintenance_window {
+                println!("    -> MAINTENANCE WINDOW: {}", window);
+            }
+            
+            if let Some(action) = plan.computed_action.clone() {
+                println!("    -> PLAN ACTION: {}", action);
+                violations += 1;
+            }
+            if !plan.conflicts_with.is_empty() {
+                println!("    -> CONFLICTS WITH: {}", plan.conflicts_with.join(", "));
+                if plan.status == agent_core::policy::PlanStatus::Conflict {
+                    conflicts += 1;
+                }
+            }
+            println!();
         }
-        println!();
     }
     
     if violations > 0 {
@@ -813,18 +1020,167 @@ fn command_plan(client: &AgentClient, profile_path: &Path) -> Result<()> {
     } else {
         println!("✨ No violations found. Cluster is efficient.");
     }
+    if conflicts > 0 {
+        println!("⚠️  {} conflicting actions would not be applied; set metadata.priority to pick one.", conflicts);
+    }
 
     Ok(())
 }
 
-fn command_apply(client: &AgentClient, profile_path: &Path, yes: bool) -> Result<()> {
-    let contents = fs::read_to_string(profile_path)
-        .with_context(|| format!("failed to read profile {}", profile_path.display()))?;
-    
-    // We need to import the EfficiencyProfile struct. Since agent-core exposes it in policy
-    // but agent-bin depends on agent-core, we can access it.
-    let profile: agent_core::policy::EfficiencyProfile = serde_yaml::from_str(&contents)
-        .with_context(|| "failed to parse efficiency profile YAML")?;
+/// Default replay range for the TSDB.
+const DEFAULT_REPLAY_RANGE: Duration = Duration::from_secs(24 * 60 * 60);
+
+fn command_plan_replay(
+    config: &AgentConfig,
+    profile_path: &Path,
+    source: &str,
+    from: Option<&str>,
+    to: Option<&str>,
+    step: &str,
+) -> Result<()> {
+    use agent_core::policy::{read_snapshots, replay, snapshots_from_samples, ActionType, REPLAY_METRICS};
+
+    let profiles = load_profiles(profile_path, &config.tags)?;
+
+    let now_ms = chrono::Utc::now().timestamp_millis();
+    let from_ms = from.map(|t| parse_replay_time(t, now_ms)).transpose()?;
+    let to_ms = to.map(|t| parse_replay_time(t, now_ms)).transpose()?.unwrap_or(now_ms);
+
+    let snapshots = if source == "tsdb" {
+        let from_ms = from_ms.unwrap_or(now_ms - DEFAULT_REPLAY_RANGE.as_millis() as i64);
+        let step_ms = agent_core::tsdb::query::parse_duration_ms(step)
+            .filter(|ms| *ms > 0)
+            .ok_or_else(|| anyhow!("invalid --step {:?}", step))?;
+        let client = AgentClient::new(&config.listen_address);
+        println!("Reading history from the local TSDB of the agent at {}...", client.base_url());
+        let samples = client
+            .fetch_tsdb_samples(from_ms, to_ms, REPLAY_METRICS)
+            .with_context(|| "failed to read history from agent")?;
+        let mut snapshots = snapshots_from_samples(&samples, from_ms, to_ms, step_ms);
+        // The power envelope is configuration, not a recorded metric.
+        for snapshot in &mut snapshots {
+            snapshot.node_power_envelope_watts = config.node_power_envelope_watts;
+        }
+        snapshots
+    } else {
+        let contents = fs::read_to_string(source)
+            .with_context(|| format!("failed to read snapshots from {}", source))?;
+        let mut snapshots = read_snapshots(&contents)
+            .with_context(|| format!("failed to parse snapshots in {}", source))?;
+        snapshots.retain(|s| {
+            let ts = s.last_scrape_unix_ms as i64;
+            from_ms.is_none_or(|from| ts >= from) && ts <= to_ms
+        });
+        snapshots
+    };
+    if snapshots.is_empty() {
+        bail!("no recorded status found in the replayed range");
+    }
+
+    let report = replay(&profiles, &snapshots, &config.tags);
+    println!(
+        "Replayed {} snapshots from {} to {} ({} covered).",
+        report.snapshots,
+        format_unix_ms(report.from_ms),
+        format_unix_ms(report.to_ms),
+        format_ms(report.covered_ms)
+    );
+
+    let mut fired_policies = 0;
+    let mut fired = 0;
+    let mut saved_wh = 0.0;
+    let mut profile = None;
+    for policy in &report.policies {
+        if profile != Some(&policy.profile_name) {
+            println!("\nProfile '{}':\n", policy.profile_name);
+            profile = Some(&policy.profile_name);
+        }
+        if policy.fired == 0 {
+            println!("✅ Policy \"{}\" ({}): never fired", policy.policy_name, policy.action.name());
+            continue;
+        }
+        fired_policies += 1;
+        fired += policy.fired;
+        print!(
+            "❌ Policy \"{}\" ({}): fired {} time(s), violated for {}",
+            policy.policy_name,
+            policy.action.name(),
+            policy.fired,
+            format_ms(policy.violated_ms)
+        );
+        if let Some(wh) = policy.energy_saved_wh {
+            saved_wh += wh;
+            print!(", est. {} saved", format_wh(wh));
+        }
+        println!();
+        for resource in &policy.resources {
+            print!(
+                "    {}: fired {} time(s), violated for {}",
+                resource.resource,
+                resource.fired,
+                format_ms(resource.violated_ms)
+            );
+            if resource.conflict_ms > 0 {
+                print!(" ({} in conflict, not applied)", format_ms(resource.conflict_ms));
+            }
+            if let Some(wh) = resource.energy_saved_wh {
+                print!(", est. {}", format_wh(wh));
+            }
+            println!();
+        }
+    }
+
+    println!(
+        "\n{} of {} policies would have fired {} time(s).",
+        fired_policies,
+        report.policies.len(),
+        fired
+    );
+    if report.policies.iter().any(|p| p.action == ActionType::ThrottlePower) {
+        println!(
+            "Estimated throttle_power saving: {} (power above the cap while violated).",
+            format_wh(saved_wh)
+        );
+    }
+    Ok(())
+}
+
+/// RFC 3339, Unix seconds, or a duration before `now_ms` such as `6h`.
+fn parse_replay_time(input: &str, now_ms: i64) -> Result<i64> {
+    agent_core::tsdb::query::parse_time_ms(input)
+        .or_else(|| agent_core::tsdb::query::parse_duration_ms(input).map(|ago| now_ms - ago))
+        .ok_or_else(|| anyhow!("invalid time {:?}: expected RFC 3339, Unix seconds or a duration like 6h", input))
+}
+
+fn format_unix_ms(ms: i64) -> String {
+    chrono::DateTime::from_timestamp_millis(ms)
+        .map(|t| t.format("%Y-%m-%d %H:%M:%S UTC").to_string())
+        .unwrap_or_else(|| ms.to_string())
+}
+
+fn format_ms(ms: i64) -> String {
+    if ms < 1000 {
+        return "0s".to_string();
+    }
+    humantime::format_duration(Duration::from_secs(ms as u64 / 1000)).to_string()
+}
+
+fn format_wh(wh: f64) -> String {
+    if wh >= 1000.0 {
+        format!("{:.2} kWh", wh / 1000.0)
+    } else {
+        format!("{:.1} Wh", wh)
+    }
+}
+
+fn command_apply(
+    client: &AgentClient,
+    profile_path: &Path,
+    yes: bool,
+    tags: &HashMap<String, String>,
+    kill_guards: &agent_core::config::KillProcessConfig,
+) -> Result<()> {
+    let profiles = load_profiles(profile_path, tags)?;
 
     println!("Refreshing state from agent at {}...", client.base_url());
     let status = client.fetch_status()
@@ -832,21 +1188,34 @@ fn command_apply(client: &AgentClient, profile_path: &Path, yes: bool) -> Result
 
     println!("Analyzed {} GPUs.", status.gpus.len());
     
-    let result = profile.plan(&status);
+    let results = profiles.plan(
+        &mut agent_core::policy::PolicyEvaluator::default(),
+        &status,
+        tags,
+        status.last_scrape_unix_ms as i64,
+    );
     
     // Filter for violations. Note: plan.status is an Enum so we need to match carefully.
-    let violations: Vec<_> = result.matched_policies.iter().filter(|p| {
-        matches!(p.status, agent_core::policy::PlanStatus::Violated)
-    }).collect();
+    let violations: Vec<_> = results.iter()
+        .flat_map(|result| result.matched_policies.iter().map(move |p| (result, p)))
+        .filter(|(_, p)| matches!(p.status, agent_core::policy::PlanStatus::Violated))
+        .collect();
+
+    for result in &results {
+        for plan in result.matched_policies.iter().filter(|p| p.status == agent_core::policy::PlanStatus::Conflict) {
+            println!("⚠️  Skipping policy \"{}\" of '{}' on {}: conflicts with {}",
+                plan.policy_name, result.profile_name, plan.target_resource, plan.conflicts_with.join(", "));
+        }
+    }
 
     if violations.is_empty() {
-        println!("✨ No violations found in profile '{}'. Nothing to apply.", profile.metadata.name);
+        println!("✨ No violations found. Nothing to apply.");
         return Ok(());
     }
 
     println!("\n⚠️  Found {} violations that require action:", violations.len());
-    for plan in &violations {
-        println!("❌ Policy \"{}\" on {}:", plan.policy_name, plan.target_resource);
+    for (result, plan) in &violations {
+        println!("❌ Policy \"{}\" of '{}' on {}:", plan.policy_name, result.profile_name, plan.target_resource);
         println!("    Current: {} | Limit: {}", plan.current_value, plan.threshold);
         if let Some(action) = &plan.computed_action {
              println!("    -> PROPOSED ACTION: {}", action);
@@ -866,16 +1235,18 @@ fn command_apply(client: &AgentClient, profile_path: &Path, yes: bool) -> Result
         }
     }
 
-    println!("Applying efficiency profile '{}'...", profile.metadata.name);
+    println!("Applying efficiency profiles...");
     
     // Instantiate Enforcer
-    let enforcer = agent_core::control::Enforcer::new();
+    let enforcer = agent_core::control::Enforcer::new().with_kill_guards(kill_guards.clone());
     let mut applied_count = 0;
     
-    for plan in violations {
+    for (result, plan) in violations {
         // Find defining policy
-        if let Some(policy) = profile.policies.iter().find(|p| p.name == plan.policy_name) {
-             match enforcer.apply_action(&plan.target_resource, &policy.action) {
+        let policy = profiles.get(&result.profile_name)
+            .and_then(|profile| profile.policies.iter().find(|p| p.name == plan.policy_name));
+        if let Some(policy) = policy {
+             match enforcer.apply_action(&plan.target_resource, policy.action_at(plan.escalation_step.unwrap_or(0))) {
                 Ok(msg) => {
                     println!("✅ Applied on {}: {}", plan.target_resource, msg);
                     applied_count += 1;
@@ -889,6 +1260,16 @@ fn command_apply(client: &AgentClient, profile_path: &Path, yes: bool) -> Result
         }
     }
 
+    if enforcer.pending_kills() > 0 {
+        println!("Waiting for {} terminated processes to exit...", enforcer.pending_kills());
+    }
+    while enforcer.pending_kills() > 0 {
+        std::thread::sleep(Duration::from_secs(1));
+        for msg in enforcer.reap() {
+            println!("✅ {}", msg);
+        }
+    }
+
     println!("\nSummary: {} actions applied successfully.", applied_count);
     Ok(())
 }
@@ -0,0 +1,638 @@
+// ESNODE | Source Available BUSL-1.1 | Copyright (c) 2025 Estimatedstocks AB
+//! The enforcement audit log.
+//!
+//! Every action the control loop applies, fails to apply or rolls back is
+//! appended to a JSON lines file as an [`AuditRecord`]. Each record holds
+//! the SHA-256 hash of the record before it and its own hash covers that,
+//! so editing or deleting a record breaks the chain for every later one;
+//! [`verify`] walks it. The file is rotated to `<path>.1`, `<path>.2`, ...
+//! once it reaches `max_bytes`, and the chain continues across files. When
+//! rotation drops the oldest file, a `rotate` record naming the last record
+//! dropped is chained into the new file, so deleting the oldest files by
+//! hand is detected too. Deleting the newest records is not: that needs an
+//! anchor kept outside the log, such as the last hash shipped elsewhere.
+
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use anyhow::{bail, Context, Result};
+use parking_lot::Mutex;
+use ring::digest;
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+
+use crate::config::AuditConfig;
+use crate::control::PriorState;
+
+/// The `prev_hash` of the first record.
+pub const GENESIS_HASH: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AuditOutcome {
+    Success,
+    Failure,
+}
+
+/// What was done, as reported by the control loop.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AuditEntry {
+    pub profile_name: String,
+    pub policy_name: String,
+    pub target_resource: String,
+    /// The action type, or `restore` for a rollback.
+    pub action: String,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub parameters: BTreeMap<String, serde_json::Value>,
+    /// The settings the action changes, before and after it.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub before: Vec<PriorState>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub after: Vec<PriorState>,
+    /// Whether the action only reported what it would do.
+    pub dry_run: bool,
+    pub outcome: AuditOutcome,
+    /// The enforcer's message or error.
+    pub message: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AuditRecord {
+    /// Increases by one per record, across rotations.
+    pub seq: u64,
+    pub unix_ms: i64,
+    /// Who acted: `esnode-core@<host>`.
+    pub actor: String,
+    #[serde(flatten)]
+    pub entry: AuditEntry,
+    /// Set on the `rotate` record written when rotation drops records.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dropped: Option<DroppedRecords>,
+    pub prev_hash: String,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub hash: String,
+}
+
+/// The end of the records a rotation dropped, where the remaining chain
+/// must pick up.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DroppedRecords {
+    pub last_seq: u64,
+    pub last_hash: String,
+}
+
+impl AuditRecord {
+    /// The hex SHA-256 of the record's JSON without `hash`.
+    fn compute_hash(&self) -> Result<String> {
+        let unhashed = AuditRecord {
+            hash: String::new(),
+            ..self.clone()
+        };
+        let digest = digest::digest(&digest::SHA256, &serde_json::to_vec(&unhashed)?);
+        let mut hex = String::with_capacity(64);
+        for byte in digest.as_ref() {
+            let _ = write!(hex, "{:02x}", byte);
+        }
+        Ok(hex)
+    }
+}
+
+/// Appends to the audit log; clones share the file.
+#[derive(Clone)]
+pub struct AuditLog {
+    inner: Arc<Mutex<Writer>>,
+}
+
+struct Writer {
+    path: PathBuf,
+    max_bytes: u64,
+    max_files: usize,
+    actor: String,
+    file: File,
+    size: u64,
+    seq: u64,
+    last_hash: String,
+}
+
+impl AuditLog {
+    /// Opens the log at `config.path`, continuing the chain of the records
+    /// already there. A torn last line, left by a crash, is cut off.
+    pub fn open(config: &AuditConfig) -> Result<Self> {
+        let path = config.path.clone();
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir).with_context(|| format!("creating {}", dir.display()))?;
+        }
+        truncate_torn_line(&path)?;
+        let last = match last_record(&path)? {
+            Some(record) => Some(record),
+            None => last_record(&rotated(&path, 1))?,
+        };
+        let (seq, last_hash) = last.map_or((0, GENESIS_HASH.to_string()), |r| (r.seq, r.hash));
+        let file = open_append(&path)?;
+        let size = file.metadata()?.len();
+        Ok(Self {
+            inner: Arc::new(Mutex::new(Writer {
+                path,
+                max_bytes: config.max_bytes,
+                max_files: config.max_files,
+                actor: format!("esnode-core@{}", hostname()),
+                file,
+                size,
+                seq,
+                last_hash,
+            })),
+        })
+    }
+
+    /// Appends `entry` as the next record and syncs it to disk.
+    pub fn record(&self, entry: AuditEntry, now_ms: i64) -> Result<AuditRecord> {
+        let mut writer = self.inner.lock();
+        let mut record = writer.next_record(entry, None, now_ms)?;
+        let len = line(&record)?.len() as u64;
+        if writer.size > 0 && writer.size + len > writer.max_bytes {
+            if let Some(dropped) = writer.rotate()? {
+                let rotation =
+                    writer.next_record(rotation_entry(&dropped), Some(dropped), now_ms)?;
+                writer.append(&rotation)?;
+                record = writer.next_record(record.entry, None, now_ms)?;
+            }
+        }
+        writer.append(&record)?;
+        Ok(record)
+    }
+
+    /// The newest `limit` records with a `seq` greater than `since`,
+    /// oldest first. Lines that do not parse are skipped.
+    pub fn records(&self, since: Option<u64>, limit: usize) -> Result<Vec<AuditRecord>> {
+        let writer = self.inner.lock();
+        let since = since.unwrap_or(0);
+        let mut records = Vec::new();
+        // Newest file first, until enough records are found.
+        for file in log_files(&writer.path).iter().rev() {
+            let mut older: Vec<AuditRecord> = read_lines(file)?
+                .iter()
+                .filter_map(|line| serde_json::from_str(line).ok())
+                .collect();
+            let done = older.first().is_some_and(|r| r.seq <= since);
+            older.retain(|r| r.seq > since);
+            older.append(&mut records);
+            records = older;
+            if done || records.len() >= limit {
+                break;
+            }
+        }
+        let skip = records.len().saturating_sub(limit);
+        records.drain(..skip);
+        Ok(records)
+    }
+}
+
+impl Writer {
+    /// The record following the last one written.
+    fn next_record(
+        &self,
+        entry: AuditEntry,
+        dropped: Option<DroppedRecords>,
+        now_ms: i64,
+    ) -> Result<AuditRecord> {
+        let mut record = AuditRecord {
+            seq: self.seq + 1,
+            unix_ms: now_ms,
+            actor: self.actor.clone(),
+            entry,
+            dropped,
+            prev_hash: self.last_hash.clone(),
+            hash: String::new(),
+        };
+        record.hash = record.compute_hash()?;
+        Ok(record)
+    }
+
+    fn append(&mut self, record: &AuditRecord) -> Result<()> {
+        let line = line(record)?;
+        self.file.write_all(&line)?;
+        self.file.sync_data()?;
+        self.size += line.len() as u64;
+        self.seq = record.seq;
+        self.last_hash = record.hash.clone();
+        Ok(())
+    }
+
+    /// Shifts `<path>.N` to `<path>.N+1`, dropping the oldest, and starts a
+    /// new file. Returns the last record dropped, if any.
+    fn rotate(&mut self) -> Result<Option<DroppedRecords>> {
+        let oldest = if self.max_files > 0 {
+            rotated(&self.path, self.max_files)
+        } else {
+            self.path.clone()
+        };
+        let dropped = match last_record(&oldest) {
+            Ok(last) => last.map(|r| DroppedRecords {
+                last_seq: r.seq,
+                last_hash: r.hash,
+            }),
+            Err(e) => {
+                warn!("{:#}", e);
+                None
+            }
+        };
+        for i in (1..self.max_files).rev() {
+            let from = rotated(&self.path, i);
+            if from.exists() {
+                fs::rename(&from, rotated(&self.path, i + 1))?;
+            }
+        }
+        if self.max_files > 0 {
+            fs::rename(&self.path, rotated(&self.path, 1))?;
+        } else {
+            fs::remove_file(&self.path)?;
+        }
+        self.file = open_append(&self.path)?;
+        self.size = 0;
+        Ok(dropped)
+    }
+}
+
+/// The entry of the `rotate` record.
+fn rotation_entry(dropped: &DroppedRecords) -> AuditEntry {
+    AuditEntry {
+        profile_name: String::new(),
+        policy_name: String::new(),
+        target_resource: String::new(),
+        action: "rotate".to_string(),
+        parameters: BTreeMap::new(),
+        before: Vec::new(),
+        after: Vec::new(),
+        dry_run: false,
+        outcome: AuditOutcome::Success,
+        message: format!("records up to {} rotated away", dropped.last_seq),
+    }
+}
+
+/// `record` as a JSON line.
+fn line(record: &AuditRecord) -> Result<Vec<u8>> {
+    let mut line = serde_json::to_vec(record)?;
+    line.push(b'\n');
+    Ok(line)
+}
+
+/// The outcome of [`verify`].
+#[derive(Debug, Default)]
+pub struct Verification {
+    /// Oldest first.
+    pub files: Vec<PathBuf>,
+    pub records: u64,
+    pub first_seq: Option<u64>,
+    pub last_seq: Option<u64>,
+    /// Edited, missing or unreadable records.
+    pub problems: Vec<String>,
+}
+
+impl Verification {
+    pub fn is_intact(&self) -> bool {
+        self.problems.is_empty()
+    }
+}
+
+/// Checks the hash chain of the log at `path` and its rotated files.
+/// A chain starting at `seq` 1 must start from [`GENESIS_HASH`]; a later
+/// one must pick up where a `rotate` record says rotation dropped records.
+pub fn verify(path: &Path) -> Result<Verification> {
+    let mut verification = Verification {
+        files: log_files(path),
+        ..Default::default()
+    };
+    if verification.files.is_empty() {
+        bail!("no audit log at {}", path.display());
+    }
+    let mut previous: Option<AuditRecord> = None;
+    // Where the oldest record is, its `seq` and `prev_hash`.
+    let mut head: Option<(String, u64, String)> = None;
+    let mut dropped = Vec::new();
+    for file in &verification.files {
+        for (i, line) in read_lines(file)?.iter().enumerate() {
+            let at = format!("{}:{}", file.display(), i + 1);
+            let record: AuditRecord = match serde_json::from_str(line) {
+                Ok(record) => record,
+                Err(e) => {
+                    verification
+                        .problems
+                        .push(format!("{at}: not an audit record: {e}"));
+                    previous = None;
+                    continue;
+                }
+            };
+            if record.compute_hash()? != record.hash {
+                verification.problems.push(format!(
+                    "{at}: record {} was modified (hash mismatch)",
+                    record.seq
+                ));
+            }
+            match &previous {
+                Some(p) if record.seq != p.seq + 1 => verification.problems.push(format!(
+                    "{at}: records {} to {} are missing",
+                    p.seq + 1,
+                    record.seq.saturating_sub(1)
+                )),
+                Some(p) if record.prev_hash != p.hash => verification.problems.push(format!(
+                    "{at}: record {} does not follow record {} (chain broken)",
+                    record.seq, p.seq
+                )),
+                None if verification.first_seq.is_none()
+                    && record.seq == 1
+                    && record.prev_hash != GENESIS_HASH =>
+                {
+                    verification
+                        .problems
+                        .push(format!("{at}: record 1 does not start the chain"))
+                }
+                _ => {}
+            }
+            if head.is_none() {
+                head = Some((at, record.seq, record.prev_hash.clone()));
+            }
+            dropped.extend(record.dropped.clone());
+            verification.first_seq.get_or_insert(record.seq);
+            verification.last_seq = Some(record.seq);
+            verification.records += 1;
+            previous = Some(record);
+        }
+    }
+    if let Some((at, seq, prev_hash)) = head {
+        let rotated_away = dropped
+            .iter()
+            .any(|d| d.last_seq + 1 == seq && d.last_hash == prev_hash);
+        if seq > 1 && !rotated_away {
+            verification.problems.push(format!(
+                "{at}: records before {seq} are missing and were not rotated away"
+            ));
+        }
+    }
+    Ok(verification)
+}
+
+/// `<path>.<n>`
+fn rotated(path: &Path, n: usize) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(format!(".{n}"));
+    PathBuf::from(name)
+}
+
+/// The log's files that exist, oldest first.
+fn log_files(path: &Path) -> Vec<PathBuf> {
+    let mut files: Vec<_> = (1..)
+        .map(|n| rotated(path, n))
+        .take_while(|file| file.exists())
+        .collect();
+    files.reverse();
+    if path.exists() {
+        files.push(path.to_path_buf());
+    }
+    files
+}
+
+fn read_lines(path: &Path) -> Result<Vec<String>> {
+    let contents = match fs::read_to_string(path) {
+        Ok(contents) => contents,
+        // Rotated away meanwhile.
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e).with_context(|| format!("reading {}", path.display())),
+    };
+    Ok(contents
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(str::to_string)
+        .collect())
+}
+
+fn last_record(path: &Path) -> Result<Option<AuditRecord>> {
+    let Some(line) = read_lines(path)?.pop() else {
+        return Ok(None);
+    };
+    match serde_json::from_str(&line) {
+        Ok(record) => Ok(Some(record)),
+        Err(e) => bail!(
+            "cannot continue the audit chain: the last record of {} is invalid: {}",
+            path.display(),
+            e
+        ),
+    }
+}
+
+/// Cuts off a last line without a newline: a record the agent did not
+/// finish writing.
+fn truncate_torn_line(path: &Path) -> Result<()> {
+    let contents = match fs::read(path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e).with_context(|| format!("reading {}", path.display())),
+    };
+    if contents.is_empty() || contents.ends_with(b"\n") {
+        return Ok(());
+    }
+    let keep = contents
+        .iter()
+        .rposition(|&b| b == b'\n')
+        .map_or(0, |i| i + 1);
+    warn!(
+        "Truncating torn record at the end of audit log {}",
+        path.display()
+    );
+    OpenOptions::new()
+        .write(true)
+        .open(path)?
+        .set_len(keep as u64)?;
+    Ok(())
+}
+
+fn open_append(path: &Path) -> Result<File> {
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .with_context(|| format!("opening audit log {}", path.display()))
+}
+
+fn hostname() -> String {
+    let mut buf = [0u8; 256];
+    // SAFETY: the buffer outlives the call and its length is passed along.
+    let rc = unsafe { libc::gethostname(buf.as_mut_ptr().cast(), buf.len()) };
+    if rc != 0 {
+        return "unknown".to_string();
+    }
+    let len = buf.iter().position(|&b| b == 0).unwrap_or(buf.len());
+    String::from_utf8_lossy(&buf[..len]).into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(dir: &Path, max_bytes: u64) -> AuditConfig {
+        AuditConfig {
+            enabled: true,
+            path: dir.join("audit.jsonl"),
+            max_bytes,
+            max_files: 2,
+        }
+    }
+
+    fn entry(message: &str) -> AuditEntry {
+        AuditEntry {
+            profile_name: "power".to_string(),
+            policy_name: "cap".to_string(),
+            target_resource: "GPU-0".to_string(),
+            action: "throttle_power".to_string(),
+            parameters: BTreeMap::from([("limit_watts".to_string(), 400.into())]),
+            before: vec![PriorState::PowerLimit { watts: 700.0 }],
+            after: vec![PriorState::PowerLimit { watts: 400.0 }],
+            dry_run: false,
+            outcome: AuditOutcome::Success,
+            message: message.to_string(),
+        }
+    }
+
+    #[test]
+    fn chains_records_across_restarts() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = config(dir.path(), 1 << 20);
+        let log = AuditLog::open(&config).unwrap();
+        let first = log.record(entry("one"), 1).unwrap();
+        assert_eq!(first.seq, 1);
+        assert_eq!(first.prev_hash, GENESIS_HASH);
+        drop(log);
+
+        let log = AuditLog::open(&config).unwrap();
+        let second = log.record(entry("two"), 2).unwrap();
+        assert_eq!(second.seq, 2);
+        assert_eq!(second.prev_hash, first.hash);
+
+        let verification = verify(&config.path).unwrap();
+        assert!(verification.is_intact(), "{:?}", verification.problems);
+        assert_eq!(verification.records, 2);
+        assert_eq!(log.records(Some(1), 10).unwrap(), [second]);
+    }
+
+    #[test]
+    fn detects_edits_and_deletions() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = config(dir.path(), 1 << 20);
+        let log = AuditLog::open(&config).unwrap();
+        for message in ["one", "two", "three", "four"] {
+            log.record(entry(message), 0).unwrap();
+        }
+        let contents = fs::read_to_string(&config.path).unwrap();
+        let mut lines: Vec<_> = contents.lines().map(str::to_string).collect();
+        lines[1] = lines[1].replace("\"two\"", "\"2\"");
+        lines.remove(2);
+        fs::write(&config.path, lines.join("\n") + "\n").unwrap();
+
+        let verification = verify(&config.path).unwrap();
+        assert_eq!(
+            problems(&verification),
+            [
+                "record 2 was modified (hash mismatch)",
+                "records 3 to 3 are missing"
+            ]
+        );
+    }
+
+    /// A log rotated twice with `max_files` 2: records 1 and 2 were
+    /// dropped, 3-4 and 5-6 are in the rotated files and the `rotate`
+    /// record 7 and record 8 in the current one.
+    fn rotated_log(dir: &Path) -> (AuditConfig, AuditLog) {
+        // About two records per file.
+        let line = serde_json::to_vec(&AuditRecord {
+            seq: 1,
+            unix_ms: 0,
+            actor: format!("esnode-core@{}", hostname()),
+            entry: entry("x"),
+            dropped: None,
+            prev_hash: GENESIS_HASH.to_string(),
+            hash: GENESIS_HASH.to_string(),
+        })
+        .unwrap()
+        .len() as u64;
+        let config = config(dir, 2 * line + 10);
+        let log = AuditLog::open(&config).unwrap();
+        for _ in 0..7 {
+            log.record(entry("x"), 0).unwrap();
+        }
+        (config, log)
+    }
+
+    fn problems(verification: &Verification) -> Vec<&str> {
+        verification
+            .problems
+            .iter()
+            .map(|p| p.rsplit_once(": ").unwrap().1)
+            .collect()
+    }
+
+    #[test]
+    fn rotates_and_keeps_the_chain() {
+        let dir = tempfile::tempdir().unwrap();
+        let (config, log) = rotated_log(dir.path());
+        assert!(rotated(&config.path, 2).exists());
+        assert!(!rotated(&config.path, 3).exists());
+
+        let verification = verify(&config.path).unwrap();
+        assert!(verification.is_intact(), "{:?}", verification.problems);
+        assert_eq!(verification.files.len(), 3);
+        assert_eq!(verification.first_seq, Some(3));
+        assert_eq!(verification.last_seq, Some(8));
+
+        let records = log.records(None, 4).unwrap();
+        let seqs: Vec<_> = records.iter().map(|r| r.seq).collect();
+        assert_eq!(seqs, [5, 6, 7, 8]);
+        let rotation = &records[2];
+        assert_eq!(rotation.entry.action, "rotate");
+        assert_eq!(rotation.dropped.as_ref().map(|d| d.last_seq), Some(2));
+    }
+
+    #[test]
+    fn detects_a_deleted_head() {
+        let dir = tempfile::tempdir().unwrap();
+        let (config, _log) = rotated_log(dir.path());
+        fs::remove_file(rotated(&config.path, 2)).unwrap();
+
+        let verification = verify(&config.path).unwrap();
+        assert_eq!(
+            problems(&verification),
+            ["records before 5 are missing and were not rotated away"]
+        );
+    }
+
+    #[test]
+    fn detects_a_deleted_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let (config, _log) = rotated_log(dir.path());
+        // `<path>.2` is no longer found either.
+        fs::remove_file(rotated(&config.path, 1)).unwrap();
+
+        let verification = verify(&config.path).unwrap();
+        assert_eq!(
+            problems(&verification),
+            ["records before 7 are missing and were not rotated away"]
+        );
+    }
+
+    #[test]
+    fn cuts_off_a_torn_record() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = config(dir.path(), 1 << 20);
+        let log = AuditLog::open(&config).unwrap();
+        log.record(entry("one"), 0).unwrap();
+        drop(log);
+        let mut file = OpenOptions::new().append(true).open(&config.path).unwrap();
+        file.write_all(b"{\"seq\":2,\"unix_").unwrap();
+
+        let log = AuditLog::open(&config).unwrap();
+        assert_eq!(log.record(entry("two"), 0).unwrap().seq, 2);
+        assert!(verify(&config.path).unwrap().is_intact());
+    }
+}
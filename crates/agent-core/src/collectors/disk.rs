@@ -73,6 +73,7 @@ impl Collector for DiskCollector {
         }
 
         let mut root_io_ms_delta: Option<u64> = None;
+        let mut worst_latency_ms: Option<f64> = None;
 
         if let Some(map) = read_diskstats() {
             for (dev, io) in &map {
@@ -95,6 +96,9 @@ impl Collector for DiskCollector {
                     0.0
                 };
                 let latency_degraded = avg_latency_ms > 50.0;
+                if total_ops > 0 {
+                    worst_latency_ms = Some(worst_latency_ms.map_or(avg_latency_ms, |w| w.max(avg_latency_ms)));
+                }
                 let busy_pct = if dt > 0.0 {
                     (io_ms_delta as f64 / (dt * 1000.0)).min(1.0)
                 } else {
@@ -150,6 +154,7 @@ impl Collector for DiskCollector {
 
         self.status
             .set_disk_summary(root_total, root_used, root_io_ms_delta);
+        self.status.set_disk_latency(worst_latency_ms);
         // Set coarse degradation flag if any device was busy >80% this interval.
         let any_busy = self.previous.keys().any(|dev| {
             metrics
@@ -16,8 +16,6 @@ use nvml_wrapper::{
 use prometheus::GaugeVec;
 use std::collections::HashMap;
 use std::collections::HashSet;
-use crate::collectors::pue::PowerAggregator;
-use crate::state::StatusState;
 #[cfg(feature = "gpu")]
 use std::time::Instant;
 #[cfg(all(feature = "gpu", target_os = "linux"))]
@@ -245,9 +243,8 @@ impl Collector for GpuCollector {
             let event_set: Option<()> = None;
             #[cfg(not(target_os = "linux"))]
             let _ = &event_set;
-            let events_enabled = self.enable_events;
             #[cfg(not(target_os = "linux"))]
-            if events_enabled {
+            if self.enable_events {
                 tracing::debug!(
                     "GPU event polling requested but not supported on this platform; skipping"
                 );
@@ -301,7 +298,9 @@ impl Collector for GpuCollector {
                     let cuda_driver_version = nvml.sys_cuda_driver_version().ok();
                     let pci_id = pci.as_ref().map(|p| p.pci_device_id);
                     let pci_sub = pci.as_ref().map(|p| p.pci_sub_system_id);
+                    let numa_node = pci.as_ref().and_then(|p| pci_numa_node(&p.bus_id));
                     Some(GpuIdentity {
+                        model: device.name().ok(),
                         pci_bus_id: pci.as_ref().map(|p| p.bus_id.clone()),
                         pci_domain: pci.as_ref().map(|p| p.domain),
                         pci_bus: pci.as_ref().map(|p| p.bus),
@@ -315,7 +314,7 @@ impl Collector for GpuCollector {
                         device_id: pci_id,
                         subsystem_id: pci_sub.flatten(),
                         board_id: None,
-                        numa_node: None,
+                        numa_node,
                     })
                 };
                 let topo = {
@@ -420,6 +419,7 @@ impl Collector for GpuCollector {
                         .gpu_power_limit_watts
                         .with_label_values(&[uuid_label, gpu_label.as_str()])
                         .set(f64::from(limit) / 1000.0);
+                    status.power_limit_watts = Some(f64::from(limit) / 1000.0);
                 }
 
                 if let Ok(fan) = device.fan_speed(0) {
@@ -1225,6 +1225,18 @@ fn pcie_lane_bytes_per_sec(_gen: u32, speed_mt_s: u32) -> f64 {
 }
 
 #[cfg(feature = "gpu")]
+/// NUMA node of a PCI device from sysfs. NVML reports an 8-digit domain
+/// ("00000000:17:00.0") where sysfs uses 4 ("0000:17:00.0").
+#[cfg(feature = "gpu")]
+fn pci_numa_node(bus_id: &str) -> Option<i32> {
+    let (domain, rest) = bus_id.split_once(':')?;
+    let domain = &domain[domain.len().saturating_sub(4)..];
+    let path = format!("/sys/bus/pci/devices/{}:{}/numa_node", domain, rest).to_lowercase();
+    let node: i32 = std::fs::read_to_string(path).ok()?.trim().parse().ok()?;
+    // -1 when the platform has no NUMA affinity for the device.
+    (node >= 0).then_some(node)
+}
+
 fn build_filter(raw: Option<&str>) -> Option<HashSet<String>> {
     raw.filter(|s| !s.is_empty() && *s != "all").map(|s| {
         s.split(',')
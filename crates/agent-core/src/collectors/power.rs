@@ -45,7 +45,7 @@ pub struct PowerCollector {
     envelope_watts: Option<f64>,
     last_node_power_watts: Option<f64>,
     last_node_ts: Option<Instant>,
-    aggregator: Option<PowerAggregator>,
+    _aggregator: Option<PowerAggregator>,
 }
 
 impl PowerCollector {
@@ -62,7 +62,7 @@ impl PowerCollector {
             envelope_watts,
             last_node_power_watts: None,
             last_node_ts: None,
-            aggregator,
+            _aggregator: aggregator,
         }
     }
 }
@@ -1,7 +1,7 @@
 use crate::collectors::Collector;
 use crate::drivers::Driver;
 use crate::metrics::MetricsRegistry;
-use crate::state::StatusState;
+use crate::state::{IotSensorReading, StatusState};
 use async_trait::async_trait;
 use std::sync::Arc;
 use tokio::sync::Mutex;
@@ -36,6 +36,7 @@ impl Collector for ProtocolRunner {
         for driver in drivers.iter_mut() {
             match driver.read_all().await {
                 Ok(readings) => {
+                    let mut latest = Vec::with_capacity(readings.len());
                     for reading in readings {
                         // Export reading to Prometheus
                         // We need a generic metric in MetricsRegistry for this.
@@ -62,7 +63,15 @@ impl Collector for ProtocolRunner {
                                 param
                             ])
                             .set(reading.value);
+                        latest.push(IotSensorReading {
+                            driver_id: driver.id().to_string(),
+                            sensor_type: sensor_type_str,
+                            unit: reading.unit.clone(),
+                            param: param.to_string(),
+                            value: reading.value,
+                        });
                     }
+                    self.status.set_iot_readings(driver.id(), latest);
                 }
                 Err(e) => {
                     error!("Driver {} failed: {:?}", driver.id(), e);
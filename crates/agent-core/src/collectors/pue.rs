@@ -15,7 +15,7 @@ use tracing::{debug, warn};
 /// Ideal PUE = 1.0 (all power goes to IT)
 /// Typical PUE = 1.5-2.0 (facilities, cooling, lighting)
 pub struct PueCalculator {
-    _status: StatusState,
+    status: StatusState,
     /// Cached IT equipment power by source (watts)
     it_power_sources: Arc<RwLock<HashMap<String, f64>>>,
     /// Cached total facility power by source (watts)
@@ -25,7 +25,7 @@ pub struct PueCalculator {
 impl PueCalculator {
     pub fn new(status: StatusState) -> Self {
         Self {
-            _status: status,
+            status,
             it_power_sources: Arc::new(RwLock::new(HashMap::new())),
             facility_power_sources: Arc::new(RwLock::new(HashMap::new())),
         }
@@ -123,6 +123,7 @@ impl PueCalculator {
 
         // Export PUE metric
         metrics.pue_ratio.set(pue);
+        self.status.set_pue_ratio((it_power > 0.0).then_some(pue));
         
         // Export supporting metrics
         metrics.pue_it_power_watts.set(it_power);
@@ -168,12 +169,14 @@ impl PowerAggregator {
 
     /// Update IT power from node telemetry
     /// Call this from GPU/CPU collectors
+    #[allow(dead_code)]
     pub fn report_it_power(&self, source: &str, watts: f64) {
         self.pue_calculator.update_it_power(source, watts);
     }
 
     /// Update facility power from IoT sensors
     /// Call this from MQTT/SNMP collectors
+    #[allow(dead_code)]
     pub fn report_facility_power(&self, source: &str, watts: f64) {
         self.pue_calculator.update_facility_power(source, watts);
     }
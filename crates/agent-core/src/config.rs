@@ -3,7 +3,7 @@ use std::collections::HashMap;
 use std::path::PathBuf;
 use std::time::Duration;
 
-#[derive(Debug, Deserialize, Serialize, Clone)]
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
 pub struct OrchestratorConfig {
     pub enabled: bool,
     pub token: Option<String>,
@@ -19,12 +19,206 @@ pub struct DriverConfig {
     pub params: HashMap<String, String>,
 }
 
-impl Default for OrchestratorConfig {
+/// A downsampled TSDB tier kept after raw samples expire.
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+pub struct LocalTsdbRollupTier {
+    /// Width of each rollup window (must divide the 2h block duration).
+    #[serde(with = "humantime_serde")]
+    pub resolution: Duration,
+    /// How long rollup blocks of this tier are kept.
+    #[serde(with = "humantime_serde")]
+    pub retention: Duration,
+}
+
+/// How often samples of matching metrics are written to the local TSDB.
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+pub struct LocalTsdbWritePolicy {
+    /// Metric name, or a `prefix*` pattern as in `/tsdb/export?metrics=`.
+    pub metric: String,
+    #[serde(with = "humantime_serde")]
+    pub interval: Duration,
+}
+
+/// What the local TSDB does with new series beyond its cardinality limits.
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SeriesOverflow {
+    /// Discard samples of series over the limit.
+    #[default]
+    Drop,
+    /// Sum them into one overflow series per metric.
+    Aggregate,
+}
+
+/// A Prometheus remote-write endpoint fed from the local TSDB.
+#[derive(Deserialize, Serialize, Clone, PartialEq)]
+pub struct RemoteWriteConfig {
+    pub url: String,
+    /// Label for metrics and the watermark file; defaults to the URL.
+    #[serde(default)]
+    pub name: Option<String>,
+    #[serde(default)]
+    pub bearer_token: Option<String>,
+    #[serde(default = "default_remote_write_max_samples")]
+    pub max_samples_per_send: usize,
+    /// Pause between replay passes while the endpoint is healthy.
+    #[serde(default = "default_remote_write_interval", with = "humantime_serde")]
+    pub interval: Duration,
+    #[serde(default = "default_remote_write_timeout", with = "humantime_serde")]
+    pub timeout: Duration,
+    #[serde(default = "default_remote_write_min_backoff", with = "humantime_serde")]
+    pub min_backoff: Duration,
+    #[serde(default = "default_remote_write_max_backoff", with = "humantime_serde")]
+    pub max_backoff: Duration,
+}
+
+impl RemoteWriteConfig {
+    pub fn new(url: impl Into<String>) -> Self {
+        Self {
+            url: url.into(),
+            name: None,
+            bearer_token: None,
+            max_samples_per_send: default_remote_write_max_samples(),
+            interval: default_remote_write_interval(),
+            timeout: default_remote_write_timeout(),
+            min_backoff: default_remote_write_min_backoff(),
+            max_backoff: default_remote_write_max_backoff(),
+        }
+    }
+}
+
+/// A token from the config, printed as `<redacted>` so the config can be
+/// logged at startup.
+#[derive(Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(transparent)]
+pub struct Secret(String);
+
+impl Secret {
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+}
+
+impl From<String> for Secret {
+    fn from(secret: String) -> Self {
+        Self(secret)
+    }
+}
+
+impl std::fmt::Debug for Secret {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("<redacted>")
+    }
+}
+
+// Keeps the bearer token out of the config logged at startup.
+impl std::fmt::Debug for RemoteWriteConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("RemoteWriteConfig")
+            .field("url", &self.url)
+            .field("name", &self.name)
+            .field(
+                "bearer_token",
+                &self.bearer_token.as_ref().map(|_| "<redacted>"),
+            )
+            .field("max_samples_per_send", &self.max_samples_per_send)
+            .field("interval", &self.interval)
+            .field("timeout", &self.timeout)
+            .field("min_backoff", &self.min_backoff)
+            .field("max_backoff", &self.max_backoff)
+            .finish()
+    }
+}
+
+fn default_remote_write_max_samples() -> usize {
+    2000
+}
+
+fn default_remote_write_interval() -> Duration {
+    Duration::from_secs(30)
+}
+
+fn default_remote_write_timeout() -> Duration {
+    Duration::from_secs(10)
+}
+
+fn default_remote_write_min_backoff() -> Duration {
+    Duration::from_secs(1)
+}
+
+fn default_remote_write_max_backoff() -> Duration {
+    Duration::from_secs(5 * 60)
+}
+
+fn default_action_ledger_path() -> PathBuf {
+    PathBuf::from("/var/lib/esnode/action-ledger.json")
+}
+
+/// Guards for the `kill_process` policy action. A process on the GPU is only
+/// terminated when it passes every guard; list entries may use `*` globs.
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+#[serde(default)]
+pub struct KillProcessConfig {
+    /// Only report what would be killed. On by default.
+    pub dry_run: bool,
+    /// User names (or uids) whose processes may be killed; empty allows any.
+    pub allow_users: Vec<String>,
+    pub deny_users: Vec<String>,
+    /// cgroup paths, e.g. `/kubepods/*`; empty allows any.
+    pub allow_cgroups: Vec<String>,
+    pub deny_cgroups: Vec<String>,
+    /// Executable paths, or file names for entries without a `/`; empty
+    /// allows any.
+    pub allow_executables: Vec<String>,
+    pub deny_executables: Vec<String>,
+    /// Processes younger than this are left alone.
+    #[serde(with = "humantime_serde")]
+    pub min_runtime: Duration,
+    /// How long a process must have held GPU memory without using the GPU.
+    #[serde(with = "humantime_serde")]
+    pub min_idle_time: Duration,
+    /// Between SIGTERM and SIGKILL, unless the action sets
+    /// `grace_period_seconds`.
+    #[serde(with = "humantime_serde")]
+    pub grace_period: Duration,
+}
+
+impl Default for KillProcessConfig {
+    fn default() -> Self {
+        Self {
+            dry_run: true,
+            allow_users: Vec::new(),
+            deny_users: vec!["root".to_string()],
+            allow_cgroups: Vec::new(),
+            deny_cgroups: Vec::new(),
+            allow_executables: Vec::new(),
+            deny_executables: Vec::new(),
+            min_runtime: Duration::from_secs(5 * 60),
+            min_idle_time: Duration::ZERO,
+            grace_period: Duration::from_secs(30),
+        }
+    }
+}
+
+/// The enforcement audit log (see [`crate::audit`]).
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+#[serde(default)]
+pub struct AuditConfig {
+    pub enabled: bool,
+    /// JSON lines file; rotated files get a `.1`, `.2`, ... suffix.
+    pub path: PathBuf,
+    /// Size at which the file is rotated.
+    pub max_bytes: u64,
+    /// Rotated files to keep; older ones are deleted.
+    pub max_files: usize,
+}
+
+impl Default for AuditConfig {
     fn default() -> Self {
         Self {
-            enabled: false,
-            token: None,
-            allow_public: false,
+            enabled: true,
+            path: PathBuf::from("/var/lib/esnode/audit.jsonl"),
+            max_bytes: 16 * 1024 * 1024,
+            max_files: 8,
         }
     }
 }
@@ -103,17 +297,67 @@ pub struct AgentConfig {
     pub local_tsdb_path: String,
     pub local_tsdb_retention_hours: u64,
     pub local_tsdb_max_disk_mb: u64,
+    /// fsync cadence for the TSDB write-ahead log (0s = every write batch).
+    #[serde(with = "humantime_serde")]
+    pub local_tsdb_wal_fsync_interval: Duration,
+    /// Rollup tiers (min/max/avg/count), finest first; empty keeps raw only.
+    #[serde(default)]
+    pub local_tsdb_rollups: Vec<LocalTsdbRollupTier>,
+    /// Default interval between writes of registry samples to the TSDB.
+    #[serde(with = "humantime_serde")]
+    pub local_tsdb_write_interval: Duration,
+    /// Per-metric write intervals; the first matching policy wins.
+    #[serde(default)]
+    pub local_tsdb_write_policies: Vec<LocalTsdbWritePolicy>,
+    /// Maximum active series in the TSDB (unlimited when unset).
+    #[serde(default)]
+    pub local_tsdb_max_series: Option<usize>,
+    /// Maximum active series per metric name (unlimited when unset).
+    #[serde(default)]
+    pub local_tsdb_max_series_per_metric: Option<usize>,
+    /// Handling of new series beyond either limit.
+    #[serde(default)]
+    pub local_tsdb_series_overflow: SeriesOverflow,
+    /// Bearer token required by the admin endpoints: the `/tsdb/*` admin
+    /// API, `/v1/policy/*`, `/v1/audit` and the policy events on `/events`.
+    /// Without it they are only served on a loopback listener.
+    #[serde(default)]
+    pub admin_token: Option<Secret>,
+    /// Deprecated alias of `admin_token`, used when that is unset.
+    #[serde(default)]
+    pub local_tsdb_admin_token: Option<Secret>,
+    /// Endpoints that receive the local TSDB via Prometheus remote write.
+    #[serde(default)]
+    pub remote_write: Vec<RemoteWriteConfig>,
 
     // Control Plane
     pub orchestrator: Option<OrchestratorConfig>,
     
     // Policy / Enforcement
     pub efficiency_profile_path: Option<PathBuf>,
+    /// Directory of efficiency profiles (`*.yaml`, `*.yml`, `*.es`), loaded
+    /// alongside `efficiency_profile_path` and reloaded when modified.
+    #[serde(default)]
+    pub efficiency_profiles_dir: Option<PathBuf>,
     pub enforcement_mode: EnforcementMode,
     #[serde(with = "humantime_serde")]
     pub enforcement_interval: Duration,
     #[serde(with = "humantime_serde")]
     pub dampening_interval: Duration,
+    /// Guards for the `kill_process` action.
+    #[serde(default)]
+    pub kill_process: KillProcessConfig,
+    /// How long a policy must stay clear before the settings its actions
+    /// changed are rolled back. Default: right away.
+    #[serde(default, with = "humantime_serde")]
+    pub rollback_after: Duration,
+    /// Where the settings to roll back are persisted, so a restarted agent
+    /// can undo the changes of one that crashed.
+    #[serde(default = "default_action_ledger_path")]
+    pub action_ledger_path: PathBuf,
+    /// Where enforcement actions are recorded.
+    #[serde(default)]
+    pub audit: AuditConfig,
 
     // Drivers
     #[serde(default)]
@@ -151,9 +395,22 @@ pub struct ConfigOverrides {
     pub local_tsdb_path: Option<String>,
     pub local_tsdb_retention_hours: Option<u64>,
     pub local_tsdb_max_disk_mb: Option<u64>,
+    #[serde(default, with = "humantime_serde")]
+    pub local_tsdb_wal_fsync_interval: Option<Duration>,
+    pub local_tsdb_rollups: Option<Vec<LocalTsdbRollupTier>>,
+    #[serde(default, with = "humantime_serde")]
+    pub local_tsdb_write_interval: Option<Duration>,
+    pub local_tsdb_write_policies: Option<Vec<LocalTsdbWritePolicy>>,
+    pub local_tsdb_max_series: Option<usize>,
+    pub local_tsdb_max_series_per_metric: Option<usize>,
+    pub local_tsdb_series_overflow: Option<SeriesOverflow>,
+    pub admin_token: Option<Secret>,
+    pub local_tsdb_admin_token: Option<Secret>,
+    pub remote_write: Option<Vec<RemoteWriteConfig>>,
     pub log_level: Option<LogLevel>,
     pub orchestrator: Option<OrchestratorConfig>,
     pub efficiency_profile_path: Option<PathBuf>,
+    pub efficiency_profiles_dir: Option<PathBuf>,
     pub enforcement_mode: Option<EnforcementMode>,
     #[serde(default, with = "humantime_serde")]
     pub enforcement_interval: Option<Duration>,
@@ -199,13 +456,37 @@ impl Default for AgentConfig {
             local_tsdb_path: "/tmp/esnode_tsdb".to_string(),
             local_tsdb_retention_hours: 24,
             local_tsdb_max_disk_mb: 512,
+            local_tsdb_wal_fsync_interval: Duration::from_secs(1),
+            local_tsdb_rollups: vec![
+                LocalTsdbRollupTier {
+                    resolution: Duration::from_secs(60),
+                    retention: Duration::from_secs(7 * 24 * 60 * 60),
+                },
+                LocalTsdbRollupTier {
+                    resolution: Duration::from_secs(15 * 60),
+                    retention: Duration::from_secs(90 * 24 * 60 * 60),
+                },
+            ],
+            local_tsdb_write_interval: Duration::from_secs(30),
+            local_tsdb_write_policies: Vec::new(),
+            local_tsdb_max_series: None,
+            local_tsdb_max_series_per_metric: None,
+            local_tsdb_series_overflow: SeriesOverflow::Drop,
+            admin_token: None,
+            local_tsdb_admin_token: None,
+            remote_write: Vec::new(),
             
             orchestrator: None,
             
             efficiency_profile_path: None,
+            efficiency_profiles_dir: None,
             enforcement_mode: EnforcementMode::Monitor,
             enforcement_interval: Duration::from_secs(5),
             dampening_interval: Duration::from_secs(60),
+            kill_process: KillProcessConfig::default(),
+            rollback_after: Duration::ZERO,
+            action_ledger_path: default_action_ledger_path(),
+            audit: AuditConfig::default(),
             
             drivers: Vec::new(),
 
@@ -240,13 +521,33 @@ impl AgentConfig {
         if let Some(v) = overrides.local_tsdb_path { self.local_tsdb_path = v; }
         if let Some(v) = overrides.local_tsdb_retention_hours { self.local_tsdb_retention_hours = v; }
         if let Some(v) = overrides.local_tsdb_max_disk_mb { self.local_tsdb_max_disk_mb = v; }
+        if let Some(v) = overrides.local_tsdb_wal_fsync_interval { self.local_tsdb_wal_fsync_interval = v; }
+        if let Some(v) = overrides.local_tsdb_rollups { self.local_tsdb_rollups = v; }
+        if let Some(v) = overrides.local_tsdb_write_interval { self.local_tsdb_write_interval = v; }
+        if let Some(v) = overrides.local_tsdb_write_policies { self.local_tsdb_write_policies = v; }
+        if let Some(v) = overrides.local_tsdb_max_series { self.local_tsdb_max_series = Some(v); }
+        if let Some(v) = overrides.local_tsdb_max_series_per_metric { self.local_tsdb_max_series_per_metric = Some(v); }
+        if let Some(v) = overrides.local_tsdb_series_overflow { self.local_tsdb_series_overflow = v; }
+        if let Some(v) = overrides.admin_token { self.admin_token = Some(v); }
+        if let Some(v) = overrides.local_tsdb_admin_token { self.local_tsdb_admin_token = Some(v); }
+        if let Some(v) = overrides.remote_write { self.remote_write = v; }
         if let Some(v) = overrides.log_level { self.log_level = v; }
         if let Some(v) = overrides.orchestrator { self.orchestrator = Some(v); }
         if let Some(v) = overrides.efficiency_profile_path { self.efficiency_profile_path = Some(v); }
+        if let Some(v) = overrides.efficiency_profiles_dir { self.efficiency_profiles_dir = Some(v); }
         if let Some(v) = overrides.enforcement_mode { self.enforcement_mode = v; }
         if let Some(v) = overrides.enforcement_interval { self.enforcement_interval = v; }
         if let Some(v) = overrides.dampening_interval { self.dampening_interval = v; }
     }
+
+    /// The token guarding the admin endpoints, falling back to the
+    /// deprecated `local_tsdb_admin_token`.
+    pub fn admin_token(&self) -> Option<&str> {
+        self.admin_token
+            .as_ref()
+            .or(self.local_tsdb_admin_token.as_ref())
+            .map(Secret::expose)
+    }
 }
 
 pub fn load_config(path: Option<PathBuf>) -> Result<AgentConfig, config::ConfigError> {
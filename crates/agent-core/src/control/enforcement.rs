@@ -0,0 +1,438 @@
+// ESNODE | Source Available BUSL-1.1 | Copyright (c) 2025 Estimatedstocks AB
+//! The enforcement control loop, one tick at a time.
+//!
+//! Each tick evaluates the loaded profiles against a status snapshot,
+//! applies the actions of violated policies (in `enforce` mode), steps
+//! escalation ladders, unwinds the ladders of policies that cleared and
+//! rolls back the settings they changed, as recorded in the
+//! [`ActionLedger`]. The agent calls [`EnforcementLoop::recover`] when it
+//! starts and [`EnforcementLoop::shutdown`] when it stops; tests drive the
+//! loop with a [`SimulatedGpus`] enforcer and chosen timestamps. Applied and
+//! rolled back actions are also written to the [`AuditLog`], if any.
+//!
+//! [`SimulatedGpus`]: super::SimulatedGpus
+
+use std::collections::{HashMap, HashSet};
+use std::time::Duration;
+
+use tracing::{debug, info, warn};
+
+use super::{
+    ActionLedger, Enforcer, EscalationTracker, FlapDampener, LedgerSlot, PriorState, Setting,
+};
+use crate::audit::{AuditEntry, AuditLog, AuditOutcome};
+use crate::config::{AgentConfig, EnforcementMode};
+use crate::metrics::MetricsRegistry;
+use crate::policy::{
+    ActionType, PlanResult, PlanStatus, PolicyEvaluator, PolicyHistory, PolicyPlan, ProfileSet,
+};
+use crate::state::StatusSnapshot;
+
+pub struct EnforcementLoop {
+    profiles: ProfileSet,
+    enforcer: Enforcer,
+    mode: EnforcementMode,
+    tags: HashMap<String, String>,
+    /// Tracks sustained violations across ticks (and profile reloads).
+    evaluator: PolicyEvaluator,
+    dampener: FlapDampener,
+    /// Escalation steps applied per policy and target, unwound when they clear.
+    escalations: EscalationTracker,
+    /// The settings to put back per target, and the policies holding them.
+    ledger: ActionLedger,
+    /// How long a policy stays clear before its changes are rolled back.
+    rollback_after: Duration,
+    history: PolicyHistory,
+    metrics: MetricsRegistry,
+    audit: Option<AuditLog>,
+}
+
+impl EnforcementLoop {
+    pub fn new(
+        profiles: ProfileSet,
+        enforcer: Enforcer,
+        config: &AgentConfig,
+        ledger: ActionLedger,
+        history: PolicyHistory,
+        metrics: MetricsRegistry,
+    ) -> Self {
+        Self {
+            profiles,
+            enforcer,
+            mode: config.enforcement_mode.clone(),
+            tags: config.tags.clone(),
+            evaluator: PolicyEvaluator::default(),
+            dampener: FlapDampener::new(config.dampening_interval),
+            escalations: EscalationTracker::default(),
+            ledger,
+            rollback_after: config.rollback_after,
+            history,
+            metrics,
+            audit: None,
+        }
+    }
+
+    /// Records applied and rolled back actions in `audit`.
+    pub fn with_audit_log(self, audit: AuditLog) -> Self {
+        Self {
+            audit: Some(audit),
+            ..self
+        }
+    }
+
+    /// Picks up new, modified and removed profile files. Only files whose
+    /// modification time changed are re-parsed.
+    pub fn reload(&mut self) {
+        let reload = self.profiles.reload();
+        for path in &reload.loaded {
+            info!("Loaded efficiency profile {}", path.display());
+        }
+        for path in &reload.removed {
+            info!("Removed efficiency profile {}", path.display());
+        }
+        for (path, e) in &reload.failed {
+            warn!(
+                "Failed to load efficiency profile {}: {}",
+                path.display(),
+                e
+            );
+        }
+        if !reload.is_empty() {
+            for profile in self.profiles.profiles() {
+                if !profile.applies_to(&self.tags) {
+                    debug!(
+                        "Efficiency profile '{}' does not match this agent's tags; skipping",
+                        profile.metadata.name
+                    );
+                }
+            }
+        }
+    }
+
+    /// Evaluates `snapshot` at `now_ms` (unix ms) and acts on the result.
+    pub fn tick(&mut self, snapshot: &StatusSnapshot, now_ms: i64) -> Vec<PlanResult> {
+        for msg in self.enforcer.reap() {
+            info!("ENFORCED: {}", msg);
+        }
+        let plans = self
+            .profiles
+            .plan(&mut self.evaluator, snapshot, &self.tags, now_ms);
+        self.history.update(&plans, now_ms);
+
+        for plan in &plans {
+            for c in plan
+                .matched_policies
+                .iter()
+                .filter(|p| p.status == PlanStatus::Conflict)
+            {
+                warn!(
+                    "Conflict: {}/{} on {} not applied, conflicts with {}",
+                    plan.profile_name,
+                    c.policy_name,
+                    c.target_resource,
+                    c.conflicts_with.join(", ")
+                );
+            }
+        }
+
+        // Profiles are ordered by priority, so higher priority actions run first.
+        let violations: Vec<_> = plans
+            .iter()
+            .flat_map(|plan| plan.matched_policies.iter().map(move |p| (plan, p)))
+            .filter(|(_, p)| p.status == PlanStatus::Violated)
+            .collect();
+
+        if !violations.is_empty() {
+            info!("Efficiency Audit: Found {} violations", violations.len());
+        }
+        for (plan, v) in &violations {
+            info!(
+                "Violation: {} on {} (Current: {}, Limit: {})",
+                v.policy_name, v.target_resource, v.current_value, v.threshold
+            );
+
+            self.metrics
+                .policy_violations_total
+                .with_label_values(&[&v.policy_name, &v.target_resource, "violation"])
+                .inc();
+
+            if self.mode != EnforcementMode::Enforce {
+                continue;
+            }
+            let policy_key = format!("{}/{}", plan.profile_name, v.policy_name);
+            // A new escalation step is applied right away.
+            let escalated = v.escalation_step.is_some_and(|step| {
+                self.escalations.advance(
+                    &plan.profile_name,
+                    &v.policy_name,
+                    &v.target_resource,
+                    step,
+                )
+            });
+            if !escalated && !self.dampener.can_apply(&policy_key, &v.target_resource) {
+                info!(
+                    "Dampened enforcement of {} on {}",
+                    policy_key, v.target_resource
+                );
+                continue;
+            }
+            // Re-find policy definition to get the action details
+            let Some(policy) = self
+                .profiles
+                .get(&plan.profile_name)
+                .and_then(|profile| profile.policies.iter().find(|p| p.name == v.policy_name))
+            else {
+                continue;
+            };
+            let action = policy.action_at(v.escalation_step.unwrap_or(0)).clone();
+            let settings = match action.action_type {
+                ActionType::ThrottlePower => vec![Setting::PowerLimit],
+                // An unknown clock fails when the action is applied.
+                _ => Enforcer::clock_domains(&action)
+                    .unwrap_or_default()
+                    .into_iter()
+                    .map(Setting::Clock)
+                    .collect(),
+            };
+            let (before, result) =
+                match self.record_prior_state(&plan.profile_name, v, &settings, now_ms) {
+                    Ok(before) => {
+                        let result = self.enforcer.apply_action(&v.target_resource, &action);
+                        (before, result.map_err(|e| e.to_string()))
+                    }
+                    // Never apply a change that could not be rolled back.
+                    Err(e) => (Vec::new(), Err(e)),
+                };
+            match &result {
+                Ok(msg) => {
+                    info!("ENFORCED: {}", msg);
+                    self.history
+                        .record_enforcement(&plan.profile_name, v, Ok(msg), now_ms);
+                    self.dampener.record_action(&policy_key, &v.target_resource);
+                    self.metrics
+                        .policy_enforced_total
+                        .with_label_values(&[&v.policy_name, &v.target_resource, "success"])
+                        .inc();
+                }
+                Err(e) => {
+                    warn!("ENFORCEMENT FAILED: {}", e);
+                    self.history
+                        .record_enforcement(&plan.profile_name, v, Err(e.as_str()), now_ms);
+                    self.metrics
+                        .policy_enforced_total
+                        .with_label_values(&[&v.policy_name, &v.target_resource, "failure"])
+                        .inc();
+                }
+            }
+            let (outcome, message) = match result {
+                Ok(msg) => (AuditOutcome::Success, msg),
+                Err(e) => (AuditOutcome::Failure, e),
+            };
+            self.audit(
+                AuditEntry {
+                    profile_name: plan.profile_name.clone(),
+                    policy_name: v.policy_name.clone(),
+                    target_resource: v.target_resource.clone(),
+                    action: action.action_type.name().to_string(),
+                    parameters: action.parameters.clone().into_iter().collect(),
+                    before,
+                    after: self.read_settings(&v.target_resource, &settings),
+                    dry_run: self.enforcer.is_dry_run(&action),
+                    outcome,
+                    message,
+                },
+                now_ms,
+            );
+        }
+
+        self.unwind(&plans, now_ms);
+        plans
+    }
+
+    /// Rolls back the changes a previous agent recorded in the ledger but
+    /// did not undo, e.g. because it crashed.
+    pub fn recover(&mut self, now_ms: i64) {
+        if !self.ledger.is_empty() {
+            info!(
+                "Rolling back {} policy changes left by a previous agent",
+                self.ledger.slots().len()
+            );
+        }
+        let slots = self.ledger.all();
+        self.roll_back(slots, now_ms);
+    }
+
+    /// Rolls back every change the loop made, e.g. when the agent stops.
+    pub fn shutdown(&mut self, now_ms: i64) {
+        let slots = self.ledger.all();
+        self.roll_back(slots, now_ms);
+    }
+
+    /// Reads the settings about to be changed on the target of `v` and
+    /// records them in the ledger, unless a policy already changed them.
+    /// Fails if a setting could not be restored later, in which case the
+    /// action must not be applied.
+    fn record_prior_state(
+        &mut self,
+        profile_name: &str,
+        v: &PolicyPlan,
+        settings: &[Setting],
+        now_ms: i64,
+    ) -> Result<Vec<PriorState>, String> {
+        let target = &v.target_resource;
+        let mut before = Vec::new();
+        for &setting in settings {
+            match self.enforcer.prior_state(target, setting) {
+                Ok(prior) => {
+                    self.ledger
+                        .record(profile_name, &v.policy_name, target, prior, now_ms)
+                        .map_err(|e| format!("{:#}", e))?;
+                    before.push(prior);
+                }
+                // The ledger already holds the state to restore.
+                Err(e) if self.ledger.slot(target, setting).is_some() => {
+                    warn!("Cannot read the {} of {}: {}", setting, target, e)
+                }
+                Err(e) => {
+                    return Err(format!(
+                        "cannot read the {} of {} to restore later: {}",
+                        setting, target, e
+                    ))
+                }
+            }
+        }
+        Ok(before)
+    }
+
+    /// The current state of `settings` on `target`, leaving out those that
+    /// cannot be read.
+    fn read_settings(&self, target: &str, settings: &[Setting]) -> Vec<PriorState> {
+        settings
+            .iter()
+            .filter_map(|&setting| self.enforcer.prior_state(target, setting).ok())
+            .collect()
+    }
+
+    /// Appends `entry` to the audit log, if there is one.
+    fn audit(&self, entry: AuditEntry, now_ms: i64) {
+        let Some(audit) = &self.audit else {
+            return;
+        };
+        match audit.record(entry, now_ms) {
+            // Outside the log, so cutting records off its end is noticed.
+            Ok(record) => info!(
+                target: "audit",
+                seq = record.seq,
+                hash = %record.hash,
+                "Audit record {} written",
+                record.seq
+            ),
+            Err(e) => warn!("Failed to write the audit log: {:#}", e),
+        }
+    }
+
+    /// Unwinds the escalation ladders of policies that cleared (or were
+    /// removed) and rolls back their changes once they stayed clear for
+    /// `rollback_after`, or right away when they were removed. Unknown
+    /// readings keep them.
+    fn unwind(&mut self, plans: &[PlanResult], now_ms: i64) {
+        let active: HashSet<_> = plans
+            .iter()
+            .flat_map(|plan| plan.matched_policies.iter().map(move |p| (plan, p)))
+            .filter(|(_, p)| {
+                matches!(
+                    p.status,
+                    PlanStatus::Violated | PlanStatus::Conflict | PlanStatus::Skipped
+                )
+            })
+            .map(|(plan, p)| {
+                (
+                    plan.profile_name.as_str(),
+                    p.policy_name.as_str(),
+                    p.target_resource.as_str(),
+                )
+            })
+            .collect();
+        let is_active =
+            |profile: &str, policy: &str, target: &str| active.contains(&(profile, policy, target));
+
+        for ((profile_name, policy_name, target), step) in self.escalations.clear(is_active) {
+            let msg = format!(
+                "De-escalated {}/{} on {} from step {}",
+                profile_name, policy_name, target, step
+            );
+            info!("DE-ESCALATED: {}", msg);
+            self.history.record_deescalation(
+                &profile_name,
+                &policy_name,
+                &target,
+                Ok(&msg),
+                now_ms,
+            );
+        }
+
+        self.ledger.update(is_active, now_ms);
+        let profiles = &self.profiles;
+        let due = self
+            .ledger
+            .due(now_ms, self.rollback_after, |profile, policy| {
+                profiles
+                    .get(profile)
+                    .is_some_and(|p| p.policies.iter().any(|p| p.name == policy))
+            });
+        self.roll_back(due, now_ms);
+    }
+
+    /// Puts back the settings of `slots`, latest change first, and drops
+    /// them from the ledger. Those that fail stay and are retried on the
+    /// next tick. The restore is recorded in the history of every holder,
+    /// and audited as undoing the change of the last one.
+    fn roll_back(&mut self, slots: Vec<LedgerSlot>, now_ms: i64) {
+        for slot in slots {
+            let target = &slot.target_resource;
+            let before = self.read_settings(target, &[slot.setting()]);
+            let result = self
+                .enforcer
+                .restore(target, &slot.prior)
+                .map_err(|e| e.to_string());
+            match &result {
+                Ok(msg) => {
+                    info!("RESTORED: {}", msg);
+                    self.ledger.remove(target, slot.setting());
+                }
+                Err(e) => warn!("RESTORE FAILED: {}", e),
+            }
+            for holder in &slot.holders {
+                self.history.record_restore(
+                    &holder.profile_name,
+                    &holder.policy_name,
+                    target,
+                    result.as_deref().map_err(String::as_str),
+                    now_ms,
+                );
+            }
+            let Some(last) = slot.holders.last() else {
+                continue;
+            };
+            let (outcome, message) = match result {
+                Ok(msg) => (AuditOutcome::Success, msg),
+                Err(e) => (AuditOutcome::Failure, e),
+            };
+            self.audit(
+                AuditEntry {
+                    profile_name: last.profile_name.clone(),
+                    policy_name: last.policy_name.clone(),
+                    target_resource: target.clone(),
+                    action: "restore".to_string(),
+                    parameters: Default::default(),
+                    before,
+                    after: self.read_settings(target, &[slot.setting()]),
+                    dry_run: false,
+                    outcome,
+                    message,
+                },
+                now_ms,
+            );
+        }
+    }
+}
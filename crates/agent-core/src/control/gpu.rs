@@ -0,0 +1,562 @@
+// ESNODE | Source Available BUSL-1.1 | Copyright (c) 2025 Estimatedstocks AB
+//! GPU control backends for the [`Enforcer`](super::Enforcer).
+//!
+//! [`GpuControl`] is what enforcement needs from a GPU: power limits, locked
+//! clocks and the processes running on it. [`NvmlControl`] drives real
+//! NVIDIA GPUs; [`SimulatedGpus`] keeps the same state in memory, applies the
+//! same constraints and can be told to fail, so the enforcement loop,
+//! escalation and rollback can be exercised without hardware.
+//!
+//! GPUs are addressed by the resource names policies report: their UUID
+//! (`GPU-<uuid>`), or `GPU-<index>`.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::sync::Arc;
+
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+#[derive(Debug, Error, Clone, PartialEq)]
+pub enum ControlError {
+    #[error("GPU {0} not found")]
+    NotFound(String),
+    #[error("process {0} not found")]
+    ProcessNotFound(u32),
+    #[error("requested {what} {requested} is out of range ({min} - {max})")]
+    OutOfRange {
+        what: &'static str,
+        requested: String,
+        min: String,
+        max: String,
+    },
+    #[error("{0} is not supported")]
+    Unsupported(String),
+    #[error("{0}")]
+    Backend(String),
+}
+
+/// A clock that can be locked.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ClockDomain {
+    /// The graphics (SM) clock.
+    Graphics,
+    Memory,
+}
+
+impl ClockDomain {
+    pub const ALL: [ClockDomain; 2] = [ClockDomain::Graphics, ClockDomain::Memory];
+
+    /// The `clock` action parameter.
+    pub fn parse(name: &str) -> Option<Self> {
+        match name {
+            "graphics" | "sm" => Some(ClockDomain::Graphics),
+            "memory" => Some(ClockDomain::Memory),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for ClockDomain {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            ClockDomain::Graphics => "graphics",
+            ClockDomain::Memory => "memory",
+        })
+    }
+}
+
+/// What a GPU accepts.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct GpuConstraints {
+    pub min_power_limit_watts: f64,
+    pub max_power_limit_watts: f64,
+    /// The power limit the GPU boots with.
+    pub default_power_limit_watts: f64,
+    /// The lowest and highest supported graphics clock.
+    pub graphics_clocks_mhz: (u32, u32),
+    /// The lowest and highest supported memory clock.
+    pub memory_clocks_mhz: (u32, u32),
+}
+
+impl GpuConstraints {
+    pub fn clocks_mhz(&self, domain: ClockDomain) -> (u32, u32) {
+        match domain {
+            ClockDomain::Graphics => self.graphics_clocks_mhz,
+            ClockDomain::Memory => self.memory_clocks_mhz,
+        }
+    }
+}
+
+/// A process with a compute context on a GPU.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct GpuProcess {
+    pub pid: u32,
+    pub used_memory_bytes: Option<u64>,
+    /// Recent SM utilization of the process; `None` when unknown.
+    pub sm_utilization_percent: Option<u32>,
+}
+
+pub trait GpuControl: Send + Sync {
+    fn constraints(&self, gpu: &str) -> Result<GpuConstraints, ControlError>;
+    fn power_limit_watts(&self, gpu: &str) -> Result<f64, ControlError>;
+    fn set_power_limit(&self, gpu: &str, watts: f64) -> Result<(), ControlError>;
+    /// Locks a clock between `min_mhz` and `max_mhz`.
+    fn lock_clocks(
+        &self,
+        gpu: &str,
+        domain: ClockDomain,
+        min_mhz: u32,
+        max_mhz: u32,
+    ) -> Result<(), ControlError>;
+    /// Lets a clock float again.
+    fn reset_clocks(&self, gpu: &str, domain: ClockDomain) -> Result<(), ControlError>;
+    /// The `(min, max)` MHz a clock is locked to, `None` when it floats.
+    /// Fails with [`ControlError::Unsupported`] when the backend cannot
+    /// tell.
+    fn locked_clocks(
+        &self,
+        gpu: &str,
+        domain: ClockDomain,
+    ) -> Result<Option<(u32, u32)>, ControlError>;
+    fn compute_processes(&self, gpu: &str) -> Result<Vec<GpuProcess>, ControlError>;
+}
+
+/// Checks a power limit against `constraints`, as NVML would.
+pub fn check_power_limit(constraints: &GpuConstraints, watts: f64) -> Result<(), ControlError> {
+    if watts < constraints.min_power_limit_watts || watts > constraints.max_power_limit_watts {
+        return Err(ControlError::OutOfRange {
+            what: "power limit",
+            requested: format!("{watts:.1}W"),
+            min: format!("{:.1}W", constraints.min_power_limit_watts),
+            max: format!("{:.1}W", constraints.max_power_limit_watts),
+        });
+    }
+    Ok(())
+}
+
+/// Checks a clock lock against the supported clocks in `constraints`.
+pub fn check_clocks(
+    constraints: &GpuConstraints,
+    domain: ClockDomain,
+    min_mhz: u32,
+    max_mhz: u32,
+) -> Result<(), ControlError> {
+    let (lowest, highest) = constraints.clocks_mhz(domain);
+    if min_mhz > max_mhz || min_mhz < lowest || max_mhz > highest {
+        return Err(ControlError::OutOfRange {
+            what: match domain {
+                ClockDomain::Graphics => "graphics clock lock",
+                ClockDomain::Memory => "memory clock lock",
+            },
+            requested: format!("{min_mhz}-{max_mhz}MHz"),
+            min: format!("{lowest}MHz"),
+            max: format!("{highest}MHz"),
+        });
+    }
+    Ok(())
+}
+
+#[cfg(feature = "gpu")]
+pub use nvml::NvmlControl;
+
+#[cfg(feature = "gpu")]
+mod nvml {
+    use std::collections::HashMap;
+
+    use nvml_wrapper::enum_wrappers::device::Clock;
+    use nvml_wrapper::enums::device::{GpuLockedClocksSetting, UsedGpuMemory};
+    use nvml_wrapper::error::NvmlError;
+    use nvml_wrapper::{Device, Nvml};
+
+    use super::*;
+
+    /// A `(min, max)` MHz clock lock, `None` when the clock floats.
+    type ClockLock = Option<(u32, u32)>;
+
+    pub struct NvmlControl {
+        nvml: Nvml,
+        /// NVML cannot read locked clocks back, so the clocks locked or
+        /// reset through this backend are remembered per (GPU, clock). The
+        /// others are unknown: another tool may have locked them.
+        locks: Mutex<HashMap<(String, ClockDomain), ClockLock>>,
+    }
+
+    fn backend(what: &str) -> impl Fn(NvmlError) -> ControlError + '_ {
+        move |e| match e {
+            NvmlError::NotSupported => ControlError::Unsupported(what.to_string()),
+            e => ControlError::Backend(format!("failed to {what}: {e}")),
+        }
+    }
+
+    impl NvmlControl {
+        pub fn init() -> Result<Self, NvmlError> {
+            Ok(Self {
+                nvml: Nvml::init()?,
+                locks: Mutex::default(),
+            })
+        }
+
+        fn device(&self, gpu: &str) -> Result<Device<'_>, ControlError> {
+            // Resources are named `GPU-<index>` or `GPU-<uuid>`, and NVML
+            // UUIDs carry their own `GPU-` prefix.
+            let id = gpu.strip_prefix("GPU-").unwrap_or(gpu);
+            let device = match id.parse::<u32>() {
+                Ok(index) => self.nvml.device_by_index(index),
+                Err(_) => self.nvml.device_by_uuid(id),
+            };
+            device.map_err(|e| match e {
+                NvmlError::NotFound | NvmlError::InvalidArg => {
+                    ControlError::NotFound(gpu.to_string())
+                }
+                e => ControlError::Backend(format!("failed to find device {gpu}: {e}")),
+            })
+        }
+    }
+
+    impl GpuControl for NvmlControl {
+        fn constraints(&self, gpu: &str) -> Result<GpuConstraints, ControlError> {
+            let device = self.device(gpu)?;
+            let power = device
+                .power_management_limit_constraints()
+                .map_err(backend("get power constraints"))?;
+            let default = device
+                .power_management_limit_default()
+                .map_err(backend("get default power limit"))?;
+            // The supported clocks, or up to the max clock where the GPU
+            // does not list them.
+            let range = |supported: Result<Vec<u32>, NvmlError>, clock: Clock| {
+                let supported = supported.unwrap_or_default();
+                match (supported.iter().min(), supported.iter().max()) {
+                    (Some(&min), Some(&max)) => Ok((min, max)),
+                    _ => Ok((0, device.max_clock_info(clock)?)),
+                }
+            };
+            let memory_clocks_mhz = range(device.supported_memory_clocks(), Clock::Memory)
+                .map_err(backend("get supported memory clocks"))?;
+            let graphics_clocks_mhz = range(
+                device.supported_graphics_clocks(memory_clocks_mhz.1),
+                Clock::Graphics,
+            )
+            .map_err(backend("get supported graphics clocks"))?;
+            Ok(GpuConstraints {
+                min_power_limit_watts: power.min_limit as f64 / 1000.0,
+                max_power_limit_watts: power.max_limit as f64 / 1000.0,
+                default_power_limit_watts: default as f64 / 1000.0,
+                graphics_clocks_mhz,
+                memory_clocks_mhz,
+            })
+        }
+
+        fn power_limit_watts(&self, gpu: &str) -> Result<f64, ControlError> {
+            let limit = self
+                .device(gpu)?
+                .power_management_limit()
+                .map_err(backend("get power limit"))?;
+            Ok(limit as f64 / 1000.0)
+        }
+
+        fn set_power_limit(&self, gpu: &str, watts: f64) -> Result<(), ControlError> {
+            // NVML takes milliwatts.
+            self.device(gpu)?
+                .set_power_management_limit((watts * 1000.0) as u32)
+                .map_err(backend("set power limit"))
+        }
+
+        fn lock_clocks(
+            &self,
+            gpu: &str,
+            domain: ClockDomain,
+            min_mhz: u32,
+            max_mhz: u32,
+        ) -> Result<(), ControlError> {
+            let mut device = self.device(gpu)?;
+            match domain {
+                ClockDomain::Graphics => device
+                    .set_gpu_locked_clocks(GpuLockedClocksSetting::Numeric {
+                        min_clock_mhz: min_mhz,
+                        max_clock_mhz: max_mhz,
+                    })
+                    .map_err(backend("lock graphics clocks"))?,
+                ClockDomain::Memory => device
+                    .set_mem_locked_clocks(min_mhz, max_mhz)
+                    .map_err(backend("lock memory clocks"))?,
+            }
+            self.locks
+                .lock()
+                .insert((gpu.to_string(), domain), Some((min_mhz, max_mhz)));
+            Ok(())
+        }
+
+        fn reset_clocks(&self, gpu: &str, domain: ClockDomain) -> Result<(), ControlError> {
+            let mut device = self.device(gpu)?;
+            match domain {
+                ClockDomain::Graphics => device
+                    .reset_gpu_locked_clocks()
+                    .map_err(backend("reset graphics clocks"))?,
+                ClockDomain::Memory => device
+                    .reset_mem_locked_clocks()
+                    .map_err(backend("reset memory clocks"))?,
+            }
+            self.locks.lock().insert((gpu.to_string(), domain), None);
+            Ok(())
+        }
+
+        fn locked_clocks(
+            &self,
+            gpu: &str,
+            domain: ClockDomain,
+        ) -> Result<Option<(u32, u32)>, ControlError> {
+            self.device(gpu)?;
+            self.locks
+                .lock()
+                .get(&(gpu.to_string(), domain))
+                .copied()
+                .ok_or_else(|| {
+                    ControlError::Unsupported(format!(
+                        "reading a {domain} clock lock not set by the agent"
+                    ))
+                })
+        }
+
+        fn compute_processes(&self, gpu: &str) -> Result<Vec<GpuProcess>, ControlError> {
+            let device = self.device(gpu)?;
+            let processes = device
+                .running_compute_processes()
+                .map_err(backend("list compute processes"))?;
+            // The highest SM utilization among the samples NVML buffered;
+            // processes without samples did not use the GPU meanwhile.
+            let utilization = device.process_utilization_stats(None).ok().map(|samples| {
+                let mut by_pid = HashMap::new();
+                for sample in samples {
+                    let util = by_pid.entry(sample.pid).or_insert(0);
+                    *util = sample.sm_util.max(*util);
+                }
+                by_pid
+            });
+            Ok(processes
+                .into_iter()
+                .map(|p| GpuProcess {
+                    pid: p.pid,
+                    used_memory_bytes: match p.used_gpu_memory {
+                        UsedGpuMemory::Used(bytes) => Some(bytes),
+                        UsedGpuMemory::Unavailable => None,
+                    },
+                    sm_utilization_percent: utilization
+                        .as_ref()
+                        .map(|by_pid| by_pid.get(&p.pid).copied().unwrap_or(0)),
+                })
+                .collect())
+        }
+    }
+}
+
+/// The state of one [`SimulatedGpus`] device.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SimulatedGpu {
+    pub constraints: GpuConstraints,
+    pub power_limit_watts: f64,
+    /// `(min, max)` MHz while locked.
+    pub locked_graphics_clocks_mhz: Option<(u32, u32)>,
+    pub locked_memory_clocks_mhz: Option<(u32, u32)>,
+    /// Makes the clock locks unreadable, as they are through NVML for locks
+    /// the agent did not set.
+    pub unknown_clock_locks: bool,
+    pub processes: Vec<GpuProcess>,
+    /// When set, every call on this GPU fails with it.
+    pub failure: Option<ControlError>,
+}
+
+impl SimulatedGpu {
+    /// A GPU at its default power limit, e.g. `new(700.0, 200.0, 700.0)`.
+    pub fn new(
+        default_power_limit_watts: f64,
+        min_power_limit_watts: f64,
+        max_power_limit_watts: f64,
+    ) -> Self {
+        Self {
+            constraints: GpuConstraints {
+                min_power_limit_watts,
+                max_power_limit_watts,
+                default_power_limit_watts,
+                graphics_clocks_mhz: (210, 1980),
+                memory_clocks_mhz: (1593, 2619),
+            },
+            power_limit_watts: default_power_limit_watts,
+            locked_graphics_clocks_mhz: None,
+            locked_memory_clocks_mhz: None,
+            unknown_clock_locks: false,
+            processes: Vec::new(),
+            failure: None,
+        }
+    }
+
+    fn locked_clocks_mut(&mut self, domain: ClockDomain) -> &mut Option<(u32, u32)> {
+        match domain {
+            ClockDomain::Graphics => &mut self.locked_graphics_clocks_mhz,
+            ClockDomain::Memory => &mut self.locked_memory_clocks_mhz,
+        }
+    }
+}
+
+/// In-memory GPUs. Clones share the same devices, so a test can hand one to
+/// an [`Enforcer`](super::Enforcer) and inspect or change the state through
+/// another.
+#[derive(Clone, Default)]
+pub struct SimulatedGpus {
+    gpus: Arc<Mutex<BTreeMap<String, SimulatedGpu>>>,
+}
+
+impl SimulatedGpus {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_gpu(self, name: &str, gpu: SimulatedGpu) -> Self {
+        self.gpus.lock().insert(name.to_string(), gpu);
+        self
+    }
+
+    pub fn gpu(&self, name: &str) -> Option<SimulatedGpu> {
+        self.gpus.lock().get(name).cloned()
+    }
+
+    /// Changes a device, e.g. to inject a failure or a process.
+    pub fn update(&self, name: &str, f: impl FnOnce(&mut SimulatedGpu)) {
+        if let Some(gpu) = self.gpus.lock().get_mut(name) {
+            f(gpu);
+        }
+    }
+
+    fn with<T>(
+        &self,
+        name: &str,
+        f: impl FnOnce(&mut SimulatedGpu) -> Result<T, ControlError>,
+    ) -> Result<T, ControlError> {
+        let mut gpus = self.gpus.lock();
+        let gpu = gpus
+            .get_mut(name)
+            .ok_or_else(|| ControlError::NotFound(name.to_string()))?;
+        if let Some(failure) = &gpu.failure {
+            return Err(failure.clone());
+        }
+        f(gpu)
+    }
+}
+
+impl GpuControl for SimulatedGpus {
+    fn constraints(&self, gpu: &str) -> Result<GpuConstraints, ControlError> {
+        self.with(gpu, |gpu| Ok(gpu.constraints))
+    }
+
+    fn power_limit_watts(&self, gpu: &str) -> Result<f64, ControlError> {
+        self.with(gpu, |gpu| Ok(gpu.power_limit_watts))
+    }
+
+    fn set_power_limit(&self, gpu: &str, watts: f64) -> Result<(), ControlError> {
+        self.with(gpu, |gpu| {
+            check_power_limit(&gpu.constraints, watts)?;
+            gpu.power_limit_watts = watts;
+            Ok(())
+        })
+    }
+
+    fn lock_clocks(
+        &self,
+        gpu: &str,
+        domain: ClockDomain,
+        min_mhz: u32,
+        max_mhz: u32,
+    ) -> Result<(), ControlError> {
+        self.with(gpu, |gpu| {
+            check_clocks(&gpu.constraints, domain, min_mhz, max_mhz)?;
+            *gpu.locked_clocks_mut(domain) = Some((min_mhz, max_mhz));
+            Ok(())
+        })
+    }
+
+    fn reset_clocks(&self, gpu: &str, domain: ClockDomain) -> Result<(), ControlError> {
+        self.with(gpu, |gpu| {
+            *gpu.locked_clocks_mut(domain) = None;
+            Ok(())
+        })
+    }
+
+    fn locked_clocks(
+        &self,
+        gpu: &str,
+        domain: ClockDomain,
+    ) -> Result<Option<(u32, u32)>, ControlError> {
+        self.with(gpu, |gpu| {
+            if gpu.unknown_clock_locks {
+                return Err(ControlError::Unsupported(format!(
+                    "reading a {domain} clock lock not set by the agent"
+                )));
+            }
+            Ok(*gpu.locked_clocks_mut(domain))
+        })
+    }
+
+    fn compute_processes(&self, gpu: &str) -> Result<Vec<GpuProcess>, ControlError> {
+        self.with(gpu, |gpu| Ok(gpu.processes.clone()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn simulated_gpus_apply_constraints_and_failures() {
+        let gpus = SimulatedGpus::new().with_gpu("GPU-0", SimulatedGpu::new(700.0, 200.0, 700.0));
+        let control: &dyn GpuControl = &gpus;
+
+        control.set_power_limit("GPU-0", 350.0).unwrap();
+        assert_eq!(control.power_limit_watts("GPU-0"), Ok(350.0));
+        assert_eq!(
+            control
+                .set_power_limit("GPU-0", 100.0)
+                .unwrap_err()
+                .to_string(),
+            "requested power limit 100.0W is out of range (200.0W - 700.0W)"
+        );
+        assert_eq!(
+            control
+                .lock_clocks("GPU-0", ClockDomain::Graphics, 210, 3000)
+                .unwrap_err()
+                .to_string(),
+            "requested graphics clock lock 210-3000MHz is out of range (210MHz - 1980MHz)"
+        );
+        assert!(control
+            .lock_clocks("GPU-0", ClockDomain::Memory, 1000, 2619)
+            .is_err());
+        control
+            .lock_clocks("GPU-0", ClockDomain::Graphics, 210, 1410)
+            .unwrap();
+        control
+            .lock_clocks("GPU-0", ClockDomain::Memory, 2619, 2619)
+            .unwrap();
+        assert_eq!(
+            control.locked_clocks("GPU-0", ClockDomain::Graphics),
+            Ok(Some((210, 1410)))
+        );
+        control
+            .reset_clocks("GPU-0", ClockDomain::Graphics)
+            .unwrap();
+        let gpu = gpus.gpu("GPU-0").unwrap();
+        assert_eq!(gpu.locked_graphics_clocks_mhz, None);
+        assert_eq!(gpu.locked_memory_clocks_mhz, Some((2619, 2619)));
+
+        assert_eq!(
+            control.power_limit_watts("GPU-1"),
+            Err(ControlError::NotFound("GPU-1".to_string()))
+        );
+        gpus.update("GPU-0", |gpu| {
+            gpu.failure = Some(ControlError::Backend("XID 79".to_string()))
+        });
+        assert!(control.set_power_limit("GPU-0", 300.0).is_err());
+        assert_eq!(gpus.gpu("GPU-0").unwrap().power_limit_watts, 350.0);
+    }
+}
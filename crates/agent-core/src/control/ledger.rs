@@ -0,0 +1,450 @@
+// ESNODE | Source Available BUSL-1.1 | Copyright (c) 2025 Estimatedstocks AB
+//! The action ledger: the settings policies changed, as they were before
+//! the first change, so they can be rolled back.
+//!
+//! There is one slot per target and setting. Policies that change a setting
+//! another policy already changed join its slot as holders instead of
+//! recording the changed value, and the setting is only put back once every
+//! holder is released.
+//!
+//! The ledger is written to disk before an action is applied, so an agent
+//! restarted after a crash can undo the changes its predecessor left behind
+//! (see [`EnforcementLoop::recover`](super::EnforcementLoop::recover)).
+
+use std::fmt;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+
+use super::ClockDomain;
+
+/// A setting an action changes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Setting {
+    PowerLimit,
+    Clock(ClockDomain),
+}
+
+impl fmt::Display for Setting {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Setting::PowerLimit => f.write_str("power limit"),
+            Setting::Clock(domain) => write!(f, "{} clock", domain),
+        }
+    }
+}
+
+/// The value of a [`Setting`] before a policy changed it.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "setting", rename_all = "snake_case")]
+pub enum PriorState {
+    PowerLimit {
+        watts: f64,
+    },
+    /// The `(min, max)` MHz lock of a clock, `None` when it floated.
+    Clock {
+        domain: ClockDomain,
+        locked: Option<(u32, u32)>,
+    },
+    /// A clock whose lock the backend cannot read (see
+    /// [`GpuControl::locked_clocks`](super::GpuControl::locked_clocks)). It
+    /// is left as is on rollback rather than guessed.
+    UnknownClock {
+        domain: ClockDomain,
+    },
+}
+
+impl PriorState {
+    pub fn setting(&self) -> Setting {
+        match self {
+            PriorState::PowerLimit { .. } => Setting::PowerLimit,
+            PriorState::Clock { domain, .. } | PriorState::UnknownClock { domain } => {
+                Setting::Clock(*domain)
+            }
+        }
+    }
+}
+
+/// A policy holding a changed setting.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Holder {
+    pub profile_name: String,
+    pub policy_name: String,
+    /// Since when the policy has been clear (unix ms); `None` while active.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cleared_ms: Option<i64>,
+}
+
+impl Holder {
+    fn is(&self, profile: &str, policy: &str) -> bool {
+        self.profile_name == profile && self.policy_name == policy
+    }
+}
+
+/// A setting of a target that policies changed.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LedgerSlot {
+    pub target_resource: String,
+    /// When the first change was recorded (unix ms).
+    pub recorded_ms: i64,
+    /// The setting before the first change.
+    pub prior: PriorState,
+    /// The policies that changed it, in the order they took hold.
+    pub holders: Vec<Holder>,
+}
+
+impl LedgerSlot {
+    pub fn setting(&self) -> Setting {
+        self.prior.setting()
+    }
+
+    fn is(&self, target: &str, setting: Setting) -> bool {
+        self.target_resource == target && self.setting() == setting
+    }
+}
+
+/// The [`LedgerSlot`]s of the changes not rolled back yet, oldest first.
+#[derive(Debug, Default)]
+pub struct ActionLedger {
+    slots: Vec<LedgerSlot>,
+    /// Where the ledger is persisted; `None` keeps it in memory.
+    path: Option<PathBuf>,
+}
+
+impl ActionLedger {
+    /// Opens the ledger persisted at `path`, with the slots a previous
+    /// agent left behind. A corrupt or unreadable ledger is moved aside to
+    /// `<path>.corrupt-<ms>` and fails: the changes it recorded can no
+    /// longer be rolled back, so the caller must not enforce.
+    pub fn open(path: impl Into<PathBuf>) -> Result<Self> {
+        let path = path.into();
+        let read = match std::fs::read(&path) {
+            Ok(bytes) => serde_json::from_slice(&bytes).map_err(anyhow::Error::from),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Vec::new()),
+            Err(e) => Err(e.into()),
+        };
+        let slots = match read {
+            Ok(slots) => slots,
+            Err(e) => {
+                let mut aside = path.clone().into_os_string();
+                aside.push(format!(
+                    ".corrupt-{}",
+                    chrono::Utc::now().timestamp_millis()
+                ));
+                let aside = PathBuf::from(aside);
+                std::fs::rename(&path, &aside).with_context(|| {
+                    format!("moving aside unreadable action ledger {}", path.display())
+                })?;
+                return Err(e.context(format!(
+                    "unreadable action ledger {}, moved aside to {}",
+                    path.display(),
+                    aside.display()
+                )));
+            }
+        };
+        Ok(Self {
+            slots,
+            path: Some(path),
+        })
+    }
+
+    pub fn slots(&self) -> &[LedgerSlot] {
+        &self.slots
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.slots.is_empty()
+    }
+
+    /// The slot of `setting` on `target`, if a policy changed it.
+    pub fn slot(&self, target: &str, setting: Setting) -> Option<&LedgerSlot> {
+        self.slots.iter().find(|s| s.is(target, setting))
+    }
+
+    /// Records that the policy is about to change the setting of `prior`.
+    /// `prior` is only kept when no other policy holds the setting changed;
+    /// otherwise the policy joins the holders of the original state. Fails,
+    /// leaving the ledger as it was, when it cannot be saved: the change
+    /// must then not be made.
+    pub fn record(
+        &mut self,
+        profile: &str,
+        policy: &str,
+        target: &str,
+        prior: PriorState,
+        now_ms: i64,
+    ) -> Result<()> {
+        let previous = self.slots.clone();
+        match self
+            .slots
+            .iter_mut()
+            .find(|s| s.is(target, prior.setting()))
+        {
+            Some(slot) if slot.holders.iter().any(|h| h.is(profile, policy)) => return Ok(()),
+            Some(slot) => slot.holders.push(Holder {
+                profile_name: profile.to_string(),
+                policy_name: policy.to_string(),
+                cleared_ms: None,
+            }),
+            None => self.slots.push(LedgerSlot {
+                target_resource: target.to_string(),
+                recorded_ms: now_ms,
+                prior,
+                holders: vec![Holder {
+                    profile_name: profile.to_string(),
+                    policy_name: policy.to_string(),
+                    cleared_ms: None,
+                }],
+            }),
+        }
+        self.try_save().inspect_err(|_| self.slots = previous)
+    }
+
+    /// Marks the holders for which `active(profile, policy, target)` is
+    /// false as cleared since `now_ms`, and those active again as not
+    /// cleared.
+    pub fn update(&mut self, active: impl Fn(&str, &str, &str) -> bool, now_ms: i64) {
+        let mut changed = false;
+        for slot in &mut self.slots {
+            for holder in &mut slot.holders {
+                let cleared_ms = match active(
+                    &holder.profile_name,
+                    &holder.policy_name,
+                    &slot.target_resource,
+                ) {
+                    true => None,
+                    false => Some(holder.cleared_ms.unwrap_or(now_ms)),
+                };
+                changed |= holder.cleared_ms != cleared_ms;
+                holder.cleared_ms = cleared_ms;
+            }
+        }
+        if changed {
+            self.save();
+        }
+    }
+
+    /// The slots to roll back, latest first: those whose every holder is
+    /// released, i.e. cleared for at least `delay` or no longer
+    /// `loaded(profile, policy)`. They stay in the ledger until
+    /// [`Self::remove`]d, so a failed restore is retried.
+    pub fn due(
+        &self,
+        now_ms: i64,
+        delay: Duration,
+        loaded: impl Fn(&str, &str) -> bool,
+    ) -> Vec<LedgerSlot> {
+        let delay_ms = i64::try_from(delay.as_millis()).unwrap_or(i64::MAX);
+        let released = |h: &Holder| {
+            h.cleared_ms
+                .is_some_and(|cleared| now_ms.saturating_sub(cleared) >= delay_ms)
+                || !loaded(&h.profile_name, &h.policy_name)
+        };
+        self.slots
+            .iter()
+            .rev()
+            .filter(|s| s.holders.iter().all(released))
+            .cloned()
+            .collect()
+    }
+
+    /// Every slot, latest first.
+    pub fn all(&self) -> Vec<LedgerSlot> {
+        self.slots.iter().rev().cloned().collect()
+    }
+
+    /// Drops the slot of `setting` on `target` once it is restored.
+    pub fn remove(&mut self, target: &str, setting: Setting) {
+        let len = self.slots.len();
+        self.slots.retain(|s| !s.is(target, setting));
+        if self.slots.len() != len {
+            self.save();
+        }
+    }
+
+    /// Saves the ledger, logging failures: the change was already made, and
+    /// an outdated ledger at worst restores a setting again.
+    fn save(&self) {
+        if let Err(e) = self.try_save() {
+            warn!("{:#}", e);
+        }
+    }
+
+    fn try_save(&self) -> Result<()> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        write_slots(path, &self.slots)
+            .with_context(|| format!("failed to save action ledger {}", path.display()))
+    }
+}
+
+/// Replaces the file at `path` in one step, so a crash leaves either the
+/// old or the new ledger.
+fn write_slots(path: &Path, slots: &[LedgerSlot]) -> std::io::Result<()> {
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    let tmp = path.with_extension("tmp");
+    let mut file = std::fs::File::create(&tmp)?;
+    file.write_all(&serde_json::to_vec_pretty(slots)?)?;
+    file.sync_all()?;
+    std::fs::rename(&tmp, path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const POWER: PriorState = PriorState::PowerLimit { watts: 350.0 };
+    const GRAPHICS: PriorState = PriorState::Clock {
+        domain: ClockDomain::Graphics,
+        locked: None,
+    };
+
+    fn holders(slot: &LedgerSlot) -> Vec<&str> {
+        slot.holders
+            .iter()
+            .map(|h| h.policy_name.as_str())
+            .collect()
+    }
+
+    #[test]
+    fn keeps_the_first_state_per_target_and_setting() {
+        let mut ledger = ActionLedger::default();
+        ledger.record("p", "hot", "GPU-0", POWER, 0).unwrap();
+        ledger
+            .record(
+                "p",
+                "hot",
+                "GPU-0",
+                PriorState::PowerLimit { watts: 280.0 },
+                1,
+            )
+            .unwrap();
+        ledger.record("p", "hot", "GPU-0", GRAPHICS, 2).unwrap();
+        ledger.record("p", "hot", "GPU-1", POWER, 3).unwrap();
+
+        assert_eq!(ledger.slots().len(), 3);
+        let power = ledger.slot("GPU-0", Setting::PowerLimit).unwrap();
+        assert_eq!((power.prior, power.recorded_ms), (POWER, 0));
+        assert_eq!(holders(power), ["hot"]);
+        assert!(ledger
+            .slot("GPU-0", Setting::Clock(ClockDomain::Memory))
+            .is_none());
+    }
+
+    #[test]
+    fn overlapping_policies_share_the_original_state() {
+        let mut ledger = ActionLedger::default();
+        // `a` lowers 350W to 300W, then `b` finds 300W and lowers it to 250W.
+        ledger.record("p", "a", "GPU-0", POWER, 0).unwrap();
+        ledger
+            .record(
+                "q",
+                "b",
+                "GPU-0",
+                PriorState::PowerLimit { watts: 300.0 },
+                1,
+            )
+            .unwrap();
+        let slot = ledger.slot("GPU-0", Setting::PowerLimit).unwrap();
+        assert_eq!(slot.prior, POWER);
+        assert_eq!(holders(slot), ["a", "b"]);
+
+        // Released only once the last holder is.
+        ledger.update(|_, policy, _| policy == "b", 2);
+        assert!(ledger.due(2, Duration::ZERO, |_, _| true).is_empty());
+        ledger.update(|_, _, _| false, 3);
+        let due = ledger.due(3, Duration::ZERO, |_, _| true);
+        assert_eq!(due.len(), 1);
+        assert_eq!(due[0].prior, POWER);
+    }
+
+    #[test]
+    fn rolls_back_after_the_delay() {
+        let mut ledger = ActionLedger::default();
+        ledger.record("p", "hot", "GPU-0", POWER, 0).unwrap();
+        ledger.record("p", "hot", "GPU-1", POWER, 0).unwrap();
+        let delay = Duration::from_secs(60);
+
+        ledger.update(|_, _, _| false, 1_000);
+        assert!(ledger.due(60_999, delay, |_, _| true).is_empty());
+        // Active again: the delay starts over.
+        ledger.update(|_, _, target| target == "GPU-1", 30_000);
+        ledger.update(|_, _, _| false, 40_000);
+        let due = ledger.due(61_000, delay, |_, _| true);
+        assert_eq!(due.len(), 1);
+        assert_eq!(due[0].target_resource, "GPU-0");
+        // Due until removed.
+        assert_eq!(ledger.due(61_000, delay, |_, _| true), due);
+        ledger.remove("GPU-0", Setting::PowerLimit);
+
+        // A removed policy is rolled back right away.
+        ledger.update(|_, _, _| true, 62_000);
+        assert_eq!(ledger.due(62_000, delay, |_, _| false).len(), 1);
+    }
+
+    #[test]
+    fn takes_the_latest_first() {
+        let mut ledger = ActionLedger::default();
+        ledger.record("p", "a", "GPU-0", POWER, 0).unwrap();
+        ledger.record("p", "b", "GPU-0", GRAPHICS, 1).unwrap();
+        let settings: Vec<_> = ledger.all().into_iter().map(|s| s.setting()).collect();
+        assert_eq!(
+            settings,
+            [Setting::Clock(ClockDomain::Graphics), Setting::PowerLimit]
+        );
+    }
+
+    #[test]
+    fn persists_slots() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("state").join("ledger.json");
+        let mut ledger = ActionLedger::open(&path).unwrap();
+        assert!(ledger.is_empty());
+        ledger.record("p", "hot", "GPU-0", POWER, 0).unwrap();
+        ledger.record("p", "hot", "GPU-0", GRAPHICS, 0).unwrap();
+        ledger.update(|_, _, _| false, 5);
+
+        let reopened = ActionLedger::open(&path).unwrap();
+        assert_eq!(reopened.slots(), ledger.slots());
+        assert_eq!(reopened.slots()[0].holders[0].cleared_ms, Some(5));
+
+        ledger.remove("GPU-0", Setting::PowerLimit);
+        ledger.remove("GPU-0", Setting::Clock(ClockDomain::Graphics));
+        assert!(ActionLedger::open(&path).unwrap().is_empty());
+    }
+
+    #[test]
+    fn moves_a_corrupt_ledger_aside() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("ledger.json");
+        std::fs::write(&path, "{").unwrap();
+        assert!(ActionLedger::open(&path).is_err());
+        assert!(!path.exists());
+        let aside: Vec<_> = std::fs::read_dir(dir.path())
+            .unwrap()
+            .flatten()
+            .map(|e| e.file_name().to_string_lossy().into_owned())
+            .collect();
+        assert_eq!(aside.len(), 1);
+        assert!(aside[0].starts_with("ledger.json.corrupt-"));
+        // The next agent starts over.
+        assert!(ActionLedger::open(&path).unwrap().is_empty());
+    }
+
+    #[test]
+    fn a_change_that_cannot_be_saved_is_not_recorded() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut ledger = ActionLedger::open(dir.path().join("state").join("ledger.json")).unwrap();
+        // A file where the ledger's directory should be.
+        std::fs::write(dir.path().join("state"), "").unwrap();
+        assert!(ledger.record("p", "hot", "GPU-0", POWER, 0).is_err());
+        assert!(ledger.is_empty());
+    }
+}
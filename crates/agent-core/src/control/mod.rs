@@ -0,0 +1,438 @@
+// ESNODE | Source Available BUSL-1.1 | Copyright (c) 2024 Estimatedstocks AB
+
+mod enforcement;
+mod gpu;
+mod ledger;
+mod process;
+
+use crate::config::KillProcessConfig;
+use crate::policy::{ActionType, PolicyAction};
+use anyhow::{anyhow, Result};
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+use tracing::{info, warn};
+
+pub use enforcement::EnforcementLoop;
+#[cfg(feature = "gpu")]
+pub use gpu::NvmlControl;
+pub use gpu::{
+    check_clocks, check_power_limit, ClockDomain, ControlError, GpuConstraints, GpuControl,
+    GpuProcess, SimulatedGpu, SimulatedGpus,
+};
+pub use ledger::{ActionLedger, Holder, LedgerSlot, PriorState, Setting};
+pub use process::{
+    ProcFs, ProcessControl, ProcessInfo, ProcessKiller, Signal, SimulatedProcess,
+    SimulatedProcesses,
+};
+
+pub struct Enforcer {
+    /// `None` when no GPU backend could be initialized.
+    gpu: Option<Box<dyn GpuControl>>,
+    killer: ProcessKiller,
+}
+
+impl Default for Enforcer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Enforcer {
+    /// Controls GPUs through NVML when it is available.
+    pub fn new() -> Self {
+        #[cfg(feature = "gpu")]
+        let gpu = match NvmlControl::init() {
+            Ok(n) => Some(Box::new(n) as Box<dyn GpuControl>),
+            Err(e) => {
+                warn!("Failed to initialize NVML for enforcement: {}", e);
+                None
+            }
+        };
+        #[cfg(not(feature = "gpu"))]
+        let gpu = None;
+
+        Self {
+            gpu,
+            killer: ProcessKiller::new(ProcFs, KillProcessConfig::default()),
+        }
+    }
+
+    /// Controls GPUs through `gpu`, e.g. [`SimulatedGpus`] in tests.
+    pub fn with_gpu_control(gpu: impl GpuControl + 'static) -> Self {
+        Self {
+            gpu: Some(Box::new(gpu)),
+            killer: ProcessKiller::new(ProcFs, KillProcessConfig::default()),
+        }
+    }
+
+    /// Guards `kill_process` with `config` instead of the (dry-run) defaults.
+    pub fn with_kill_guards(self, config: KillProcessConfig) -> Self {
+        Self {
+            killer: self.killer.with_config(config),
+            ..self
+        }
+    }
+
+    /// Signals processes through `processes`, e.g. [`SimulatedProcesses`]
+    /// in tests, instead of `/proc` and `kill(2)`.
+    pub fn with_process_control(self, processes: impl ProcessControl + 'static) -> Self {
+        Self {
+            killer: self.killer.with_process_control(processes),
+            ..self
+        }
+    }
+
+    fn gpu(&self) -> Result<&dyn GpuControl> {
+        self.gpu
+            .as_deref()
+            .ok_or_else(|| anyhow!("NVML not available, cannot control GPUs"))
+    }
+
+    pub fn apply_action(&self, target_resource: &str, action: &PolicyAction) -> Result<String> {
+        match action.action_type {
+            ActionType::ThrottlePower => self.apply_throttle_power(target_resource, action),
+            ActionType::LockClock => self.apply_lock_clock(target_resource, action),
+            ActionType::ResetClocks => self.apply_reset_clocks(target_resource, action),
+            ActionType::Alert => self.apply_alert(target_resource, action),
+            ActionType::KillProcess => self.apply_kill_process(target_resource, action),
+            ActionType::MigratePod => self.apply_migrate_pod(target_resource, action),
+        }
+    }
+
+    fn apply_throttle_power(&self, target: &str, action: &PolicyAction) -> Result<String> {
+        let gpu = self.gpu()?;
+        let constraints = gpu.constraints(target)?;
+        // Parameters: "limit_watts" or "limit", or "limit_percent" of the default limit
+        let limit_watts = match action.number("limit_watts") {
+            Some(watts) => watts,
+            None => {
+                let percent = action
+                    .parameters
+                    .get("limit_percent")
+                    .and_then(|v| v.as_f64())
+                    .ok_or_else(|| {
+                        anyhow!(
+                            "Missing 'limit_watts' or 'limit_percent' parameter for throttle_power"
+                        )
+                    })?;
+                constraints.default_power_limit_watts * percent / 100.0
+            }
+        };
+
+        check_power_limit(&constraints, limit_watts)?;
+        gpu.set_power_limit(target, limit_watts)?;
+        let msg = format!("Throttled {} to {:.1}W", target, limit_watts);
+        info!("{}", msg);
+        Ok(msg)
+    }
+
+    /// Sets the power limit of `target` back to `limit_watts`, e.g. when the
+    /// policy that throttled it clears.
+    pub fn restore_power_limit(&self, target: &str, limit_watts: f64) -> Result<String> {
+        self.gpu()?.set_power_limit(target, limit_watts)?;
+        let msg = format!("Restored {} power limit to {:.1}W", target, limit_watts);
+        info!("{}", msg);
+        Ok(msg)
+    }
+
+    /// The power limit currently set on `target`.
+    pub fn power_limit_watts(&self, target: &str) -> Result<f64> {
+        Ok(self.gpu()?.power_limit_watts(target)?)
+    }
+
+    /// The clocks `action` locks or resets: the `clock` parameter, by
+    /// default the graphics clock for `lock_clock` and both for
+    /// `reset_clocks`.
+    pub fn clock_domains(action: &PolicyAction) -> Result<Vec<ClockDomain>> {
+        let clock =
+            match action.parameters.get("clock") {
+                None => None,
+                Some(v) => Some(v.as_str().and_then(ClockDomain::parse).ok_or_else(|| {
+                    anyhow!("Unknown clock {} for {}", v, action.action_type.name())
+                })?),
+            };
+        Ok(match (&action.action_type, clock) {
+            (ActionType::LockClock | ActionType::ResetClocks, Some(domain)) => vec![domain],
+            (ActionType::LockClock, None) => vec![ClockDomain::Graphics],
+            (ActionType::ResetClocks, None) => ClockDomain::ALL.to_vec(),
+            _ => Vec::new(),
+        })
+    }
+
+    fn apply_lock_clock(&self, target: &str, action: &PolicyAction) -> Result<String> {
+        let gpu = self.gpu()?;
+        let domain = Self::clock_domains(action)?[0];
+        let constraints = gpu.constraints(target)?;
+        // Parameters: "min_mhz" (default: the lowest supported) and "max_mhz",
+        // or "frequency_mhz" for both
+        let (min_mhz, max_mhz) = match (action.number("max_mhz"), action.number("frequency_mhz")) {
+            (Some(max), _) => (
+                action
+                    .number("min_mhz")
+                    .unwrap_or(constraints.clocks_mhz(domain).0 as f64),
+                max,
+            ),
+            (None, Some(frequency)) => (frequency, frequency),
+            (None, None) => {
+                return Err(anyhow!(
+                    "Missing 'max_mhz' or 'frequency_mhz' parameter for lock_clock"
+                ))
+            }
+        };
+        let (min_mhz, max_mhz) = (min_mhz.round() as u32, max_mhz.round() as u32);
+
+        check_clocks(&constraints, domain, min_mhz, max_mhz)?;
+        gpu.lock_clocks(target, domain, min_mhz, max_mhz)?;
+        let msg = format!(
+            "Locked {} {} clock to {}-{}MHz",
+            target, domain, min_mhz, max_mhz
+        );
+        info!("{}", msg);
+        Ok(msg)
+    }
+
+    fn apply_reset_clocks(&self, target: &str, action: &PolicyAction) -> Result<String> {
+        let gpu = self.gpu()?;
+        let domains = Self::clock_domains(action)?;
+        for domain in &domains {
+            gpu.reset_clocks(target, *domain)?;
+        }
+        let names: Vec<_> = domains.iter().map(|d| d.to_string()).collect();
+        let msg = format!("Reset {} {} clocks", target, names.join(" and "));
+        info!("{}", msg);
+        Ok(msg)
+    }
+
+    /// The `(min, max)` MHz a clock of `target` is locked to, `None` when
+    /// it floats.
+    pub fn locked_clocks(&self, target: &str, domain: ClockDomain) -> Result<Option<(u32, u32)>> {
+        Ok(self.gpu()?.locked_clocks(target, domain)?)
+    }
+
+    /// Puts a clock of `target` back to `locked` (see [`Self::locked_clocks`]),
+    /// e.g. when the policy that changed it clears.
+    pub fn restore_clocks(
+        &self,
+        target: &str,
+        domain: ClockDomain,
+        locked: Option<(u32, u32)>,
+    ) -> Result<String> {
+        let gpu = self.gpu()?;
+        let msg = match locked {
+            Some((min_mhz, max_mhz)) => {
+                gpu.lock_clocks(target, domain, min_mhz, max_mhz)?;
+                format!(
+                    "Restored {} {} clock lock to {}-{}MHz",
+                    target, domain, min_mhz, max_mhz
+                )
+            }
+            None => {
+                gpu.reset_clocks(target, domain)?;
+                format!("Restored {} {} clock to unlocked", target, domain)
+            }
+        };
+        info!("{}", msg);
+        Ok(msg)
+    }
+
+    /// The current state of `setting` on `target`, to restore later.
+    pub fn prior_state(&self, target: &str, setting: Setting) -> Result<PriorState> {
+        Ok(match setting {
+            Setting::PowerLimit => PriorState::PowerLimit {
+                watts: self.power_limit_watts(target)?,
+            },
+            Setting::Clock(domain) => match self.gpu()?.locked_clocks(target, domain) {
+                Ok(locked) => PriorState::Clock { domain, locked },
+                Err(ControlError::Unsupported(_)) => PriorState::UnknownClock { domain },
+                Err(e) => return Err(e.into()),
+            },
+        })
+    }
+
+    /// Puts a setting of `target` back to `prior`.
+    pub fn restore(&self, target: &str, prior: &PriorState) -> Result<String> {
+        match *prior {
+            PriorState::PowerLimit { watts } => self.restore_power_limit(target, watts),
+            PriorState::Clock { domain, locked } => self.restore_clocks(target, domain, locked),
+            PriorState::UnknownClock { domain } => {
+                let msg = format!(
+                    "Left {} {} clock as is: its lock before the policy is unknown",
+                    target, domain
+                );
+                warn!("{}", msg);
+                Ok(msg)
+            }
+        }
+    }
+
+    fn apply_alert(&self, target: &str, action: &PolicyAction) -> Result<String> {
+        let msg = action
+            .parameters
+            .get("message")
+            .and_then(|v| v.as_str())
+            .unwrap_or("Policy violation detected");
+
+        // In a real system, this might send a webhook, Slack message, etc.
+        // For now, we just log it as an "applied action".
+        let out = format!("ALERT on {}: {}", target, msg);
+        warn!("{}", out);
+        Ok(out)
+    }
+
+    fn apply_kill_process(&self, target: &str, action: &PolicyAction) -> Result<String> {
+        let grace_period = match action.number("grace_period_seconds") {
+            Some(seconds) => Some(
+                Duration::try_from_secs_f64(seconds)
+                    .map_err(|_| anyhow!("Invalid 'grace_period_seconds' {}", seconds))?,
+            ),
+            None => None,
+        };
+        self.killer.kill(self.gpu()?, target, grace_period)
+    }
+
+    /// Whether applying `action` would only report what it would do.
+    pub fn is_dry_run(&self, action: &PolicyAction) -> bool {
+        action.action_type == ActionType::KillProcess && self.killer.dry_run()
+    }
+
+    /// Sends SIGKILL to the processes `kill_process` terminated that are
+    /// still running after their grace period; one message per process.
+    pub fn reap(&self) -> Vec<String> {
+        self.killer.reap()
+    }
+
+    /// Processes sent SIGTERM that may still need SIGKILL.
+    pub fn pending_kills(&self) -> usize {
+        self.killer.pending()
+    }
+
+    fn apply_migrate_pod(&self, _target: &str, _action: &PolicyAction) -> Result<String> {
+        // Would interface with K8s API to drain/cordon node or delete pod.
+        Ok("Pod migration simulated (K8s integration pending)".to_string())
+    }
+}
+
+pub struct FlapDampener {
+    last_actions: HashMap<(String, String), Instant>, // (policy, target) -> timestamp
+    dampening_interval: Duration,
+}
+
+impl FlapDampener {
+    pub fn new(dampening_interval: Duration) -> Self {
+        Self {
+            last_actions: HashMap::new(),
+            dampening_interval,
+        }
+    }
+
+    pub fn can_apply(&self, policy: &str, target: &str) -> bool {
+        if let Some(last) = self
+            .last_actions
+            .get(&(policy.to_string(), target.to_string()))
+        {
+            if last.elapsed() < self.dampening_interval {
+                return false;
+            }
+        }
+        true
+    }
+
+    pub fn record_action(&mut self, policy: &str, target: &str) {
+        self.last_actions
+            .insert((policy.to_string(), target.to_string()), Instant::now());
+    }
+}
+
+/// The escalation ladders in progress, per (profile, policy, target).
+///
+/// Each rung is applied once, as soon as it is reached. The settings the
+/// rungs change are rolled back through the [`ActionLedger`].
+#[derive(Default)]
+pub struct EscalationTracker {
+    /// The highest step applied.
+    ladders: HashMap<LadderKey, usize>,
+}
+
+/// (profile, policy, target).
+pub type LadderKey = (String, String, String);
+
+fn ladder_key(profile: &str, policy: &str, target: &str) -> LadderKey {
+    (profile.to_string(), policy.to_string(), target.to_string())
+}
+
+impl EscalationTracker {
+    /// Records that `step` is due; returns whether it was not reached
+    /// before, i.e. its action should be applied now.
+    pub fn advance(&mut self, profile: &str, policy: &str, target: &str, step: usize) -> bool {
+        let ladder = self.ladders.entry(ladder_key(profile, policy, target));
+        match ladder {
+            std::collections::hash_map::Entry::Occupied(mut entry) => {
+                let highest = entry.get_mut();
+                let advanced = step > *highest;
+                *highest = (*highest).max(step);
+                advanced
+            }
+            std::collections::hash_map::Entry::Vacant(entry) => {
+                entry.insert(step);
+                true
+            }
+        }
+    }
+
+    /// Removes and returns the ladders, with their highest step, for which
+    /// `active(profile, policy, target)` is false.
+    pub fn clear(&mut self, active: impl Fn(&str, &str, &str) -> bool) -> Vec<(LadderKey, usize)> {
+        let mut cleared: Vec<_> = self
+            .ladders
+            .extract_if(|(profile, policy, target), _| !active(profile, policy, target))
+            .collect();
+        cleared.sort();
+        cleared
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_escalation_tracker() {
+        let mut tracker = EscalationTracker::default();
+        assert!(tracker.advance("p", "hot", "GPU-0", 0));
+        assert!(!tracker.advance("p", "hot", "GPU-0", 0));
+        assert!(tracker.advance("p", "hot", "GPU-0", 2));
+        assert!(!tracker.advance("p", "hot", "GPU-0", 1));
+        assert!(tracker.advance("p", "hot", "GPU-1", 0));
+
+        let cleared = tracker.clear(|_, _, target| target == "GPU-1");
+        assert_eq!(cleared, [(ladder_key("p", "hot", "GPU-0"), 2)]);
+        assert!(tracker.clear(|_, _, _| true).is_empty());
+        // Starts over once cleared.
+        assert!(tracker.advance("p", "hot", "GPU-0", 0));
+    }
+
+    #[test]
+    fn test_flap_dampener() {
+        let mut dampener = FlapDampener::new(Duration::from_millis(100));
+        let policy = "test_policy";
+        let target = "test_target";
+
+        // First check should pass (no record)
+        assert!(dampener.can_apply(policy, target));
+
+        // Record action
+        dampener.record_action(policy, target);
+
+        // Immediate check should fail (dampened)
+        assert!(!dampener.can_apply(policy, target));
+
+        // Different target should pass
+        assert!(dampener.can_apply(policy, "other_target"));
+
+        // Wait for interval
+        std::thread::sleep(Duration::from_millis(150));
+
+        // Should pass again
+        assert!(dampener.can_apply(policy, target));
+    }
+}
@@ -0,0 +1,572 @@
+// ESNODE | Source Available BUSL-1.1 | Copyright (c) 2025 Estimatedstocks AB
+//! Guarded termination of GPU processes for the `kill_process` action.
+//!
+//! The candidates are the compute processes the GPU backend reports on the
+//! target. [`ProcessKiller`] only signals a process that passes every guard
+//! of [`KillProcessConfig`]: never PID 1, the agent or its descendants; the
+//! user, cgroup and executable allow/deny lists; a minimum runtime; and a
+//! minimum time it has held GPU memory without using the GPU, as observed by
+//! the agent. Victims get SIGTERM, and SIGKILL from [`ProcessKiller::reap`]
+//! once the grace period has passed and they are still running.
+//!
+//! Every victim, and every process spared, is logged to the `audit` tracing
+//! target. In `dry_run` mode (the default) nothing is signalled and the
+//! result says what would have been.
+
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::fs;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, Result};
+use humantime_serde::re::humantime::format_duration;
+use parking_lot::Mutex;
+use serde::Serialize;
+use tracing::{info, warn};
+
+use super::{ControlError, GpuControl, GpuProcess};
+use crate::config::KillProcessConfig;
+use crate::policy::glob_match;
+
+/// How far up the parent chain to look for the agent.
+const MAX_ANCESTORS: usize = 64;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ProcessInfo {
+    pub pid: u32,
+    pub ppid: u32,
+    /// User name, or the uid when it has none.
+    pub user: String,
+    /// The cgroup v2 path (or that of the first v1 hierarchy).
+    pub cgroup: String,
+    /// Path of the executable; empty when it cannot be read.
+    pub executable: String,
+    /// Start time in clock ticks since boot. Tells a process apart from a
+    /// later one reusing its PID.
+    pub start_ticks: u64,
+    pub runtime: Duration,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Signal {
+    Term,
+    Kill,
+}
+
+impl fmt::Display for Signal {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Signal::Term => "SIGTERM",
+            Signal::Kill => "SIGKILL",
+        })
+    }
+}
+
+/// What the killer needs from the operating system.
+pub trait ProcessControl: Send + Sync {
+    fn info(&self, pid: u32) -> Result<ProcessInfo, ControlError>;
+    fn signal(&self, pid: u32, signal: Signal) -> Result<(), ControlError>;
+}
+
+/// The processes of this host, read from `/proc` and signalled with
+/// `kill(2)`.
+#[derive(Debug, Default)]
+pub struct ProcFs;
+
+impl ProcessControl for ProcFs {
+    fn info(&self, pid: u32) -> Result<ProcessInfo, ControlError> {
+        let dir = Path::new("/proc").join(pid.to_string());
+        let malformed = || ControlError::Backend(format!("cannot parse /proc/{pid}"));
+        let stat =
+            fs::read_to_string(dir.join("stat")).map_err(|_| ControlError::ProcessNotFound(pid))?;
+        // The command name may contain spaces and parentheses.
+        let fields: Vec<&str> = stat
+            .rsplit_once(')')
+            .map(|(_, rest)| rest.split_whitespace().collect())
+            .unwrap_or_default();
+        let field = |i: usize| fields.get(i).and_then(|f| f.parse::<u64>().ok());
+        let ppid = field(1).ok_or_else(malformed)? as u32;
+        let start_ticks = field(19).ok_or_else(malformed)?;
+
+        let status = fs::read_to_string(dir.join("status"))
+            .map_err(|_| ControlError::ProcessNotFound(pid))?;
+        let uid = status
+            .lines()
+            .find_map(|line| line.strip_prefix("Uid:"))
+            .and_then(|ids| ids.split_whitespace().next())
+            .and_then(|uid| uid.parse::<u32>().ok())
+            .ok_or_else(malformed)?;
+        let cgroup = fs::read_to_string(dir.join("cgroup"))
+            .map(|contents| parse_cgroup(&contents))
+            .unwrap_or_default();
+        let executable = fs::read_link(dir.join("exe"))
+            .map(|path| path.display().to_string())
+            .unwrap_or_default();
+
+        // SAFETY: sysconf has no memory safety requirements.
+        let ticks_per_second = unsafe { libc::sysconf(libc::_SC_CLK_TCK) }.max(1) as f64;
+        let uptime = fs::read_to_string("/proc/uptime")
+            .ok()
+            .and_then(|s| s.split_whitespace().next()?.parse::<f64>().ok())
+            .ok_or_else(malformed)?;
+        let runtime = (uptime - start_ticks as f64 / ticks_per_second).max(0.0);
+
+        Ok(ProcessInfo {
+            pid,
+            ppid,
+            user: user_name(uid).unwrap_or_else(|| uid.to_string()),
+            cgroup,
+            executable,
+            start_ticks,
+            runtime: Duration::from_secs_f64(runtime),
+        })
+    }
+
+    fn signal(&self, pid: u32, signal: Signal) -> Result<(), ControlError> {
+        // kill(2) treats 0 and negative PIDs as process groups.
+        let target = libc::pid_t::try_from(pid)
+            .ok()
+            .filter(|&pid| pid > 0)
+            .ok_or(ControlError::ProcessNotFound(pid))?;
+        let sig = match signal {
+            Signal::Term => libc::SIGTERM,
+            Signal::Kill => libc::SIGKILL,
+        };
+        // SAFETY: kill has no memory safety requirements.
+        if unsafe { libc::kill(target, sig) } == 0 {
+            return Ok(());
+        }
+        let err = std::io::Error::last_os_error();
+        match err.raw_os_error() {
+            Some(libc::ESRCH) => Err(ControlError::ProcessNotFound(pid)),
+            _ => Err(ControlError::Backend(format!(
+                "failed to send {signal} to {pid}: {err}"
+            ))),
+        }
+    }
+}
+
+/// Deny entries win over allow entries; an empty allow list allows any.
+fn check_list(
+    what: &str,
+    allow: &[String],
+    deny: &[String],
+    matches: impl Fn(&str) -> bool,
+) -> Result<(), String> {
+    if let Some(pattern) = deny.iter().find(|p| matches(p)) {
+        return Err(format!("{what} denied by {pattern:?}"));
+    }
+    if !allow.is_empty() && !allow.iter().any(|p| matches(p)) {
+        return Err(format!("{what} not allowed"));
+    }
+    Ok(())
+}
+
+/// The `0::` (cgroup v2) path, or that of the first hierarchy listed.
+fn parse_cgroup(contents: &str) -> String {
+    let path = |line: &str| line.splitn(3, ':').nth(2).map(str::to_string);
+    contents
+        .lines()
+        .find(|line| line.starts_with("0::"))
+        .or_else(|| contents.lines().next())
+        .and_then(path)
+        .unwrap_or_default()
+}
+
+fn user_name(uid: u32) -> Option<String> {
+    let passwd = fs::read_to_string("/etc/passwd").ok()?;
+    passwd.lines().find_map(|line| {
+        let mut fields = line.split(':');
+        let name = fields.next()?;
+        let id = fields.nth(1)?.parse::<u32>().ok()?;
+        (id == uid).then(|| name.to_string())
+    })
+}
+
+/// A process sent SIGTERM, to be sent SIGKILL at `deadline`.
+struct Pending {
+    gpu: String,
+    info: ProcessInfo,
+    deadline: Instant,
+}
+
+pub struct ProcessKiller {
+    processes: Box<dyn ProcessControl>,
+    config: KillProcessConfig,
+    agent_pid: u32,
+    /// Since when each (GPU, PID) has been seen holding memory while idle.
+    idle_since: Mutex<HashMap<(String, u32), Instant>>,
+    pending: Mutex<Vec<Pending>>,
+}
+
+impl ProcessKiller {
+    pub fn new(processes: impl ProcessControl + 'static, config: KillProcessConfig) -> Self {
+        Self {
+            processes: Box::new(processes),
+            config,
+            agent_pid: std::process::id(),
+            idle_since: Mutex::default(),
+            pending: Mutex::default(),
+        }
+    }
+
+    pub fn with_config(self, config: KillProcessConfig) -> Self {
+        Self { config, ..self }
+    }
+
+    /// Whether `kill` only reports what it would do.
+    pub fn dry_run(&self) -> bool {
+        self.config.dry_run
+    }
+
+    pub fn with_process_control(self, processes: impl ProcessControl + 'static) -> Self {
+        Self {
+            processes: Box::new(processes),
+            ..self
+        }
+    }
+
+    /// Sends SIGTERM to the processes on `target` that pass the guards, or
+    /// reports them in dry-run mode. `grace_period` overrides the
+    /// configured one.
+    pub fn kill(
+        &self,
+        gpu: &dyn GpuControl,
+        target: &str,
+        grace_period: Option<Duration>,
+    ) -> Result<String> {
+        let processes = gpu.compute_processes(target)?;
+        self.track_idle(target, &processes);
+        let grace_period = grace_period.unwrap_or(self.config.grace_period);
+        let dry_run = self.config.dry_run;
+
+        let mut victims = Vec::new();
+        let mut spared = Vec::new();
+        let mut failed = Vec::new();
+        for process in &processes {
+            let info = match self.processes.info(process.pid) {
+                Ok(info) => info,
+                // Exited since the GPU listed it.
+                Err(ControlError::ProcessNotFound(_)) => continue,
+                Err(e) => {
+                    spared.push(format!("{} ({})", process.pid, e));
+                    continue;
+                }
+            };
+            if let Err(reason) = self.check(target, &info) {
+                info!(
+                    target: "audit",
+                    gpu = target,
+                    pid = info.pid,
+                    user = %info.user,
+                    executable = %info.executable,
+                    cgroup = %info.cgroup,
+                    reason = %reason,
+                    "kill_process spared"
+                );
+                spared.push(format!("{} ({})", info.pid, reason));
+                continue;
+            }
+            if !dry_run {
+                if let Err(e) = self.processes.signal(info.pid, Signal::Term) {
+                    failed.push(format!("{} ({})", info.pid, e));
+                    continue;
+                }
+            }
+            warn!(
+                target: "audit",
+                gpu = target,
+                pid = info.pid,
+                user = %info.user,
+                executable = %info.executable,
+                cgroup = %info.cgroup,
+                signal = %Signal::Term,
+                dry_run,
+                "kill_process"
+            );
+            victims.push(describe(&info));
+            if !dry_run {
+                self.pending.lock().push(Pending {
+                    gpu: target.to_string(),
+                    info,
+                    deadline: Instant::now() + grace_period,
+                });
+            }
+        }
+
+        let mut msg = match (dry_run, victims.is_empty()) {
+            (_, true) => format!("No process to kill on {}", target),
+            (true, false) => format!(
+                "Dry run: would send SIGTERM to {} on {}",
+                victims.join(", "),
+                target
+            ),
+            (false, false) => format!(
+                "Sent SIGTERM to {} on {}, SIGKILL after {}",
+                victims.join(", "),
+                target,
+                format_duration(grace_period)
+            ),
+        };
+        if !spared.is_empty() {
+            msg.push_str(&format!("; spared {}", spared.join(", ")));
+        }
+        if !failed.is_empty() {
+            msg.push_str(&format!("; failed {}", failed.join(", ")));
+            if victims.is_empty() {
+                return Err(anyhow!(msg));
+            }
+        }
+        info!("{}", msg);
+        Ok(msg)
+    }
+
+    /// Sends SIGKILL to the victims still running after their grace period.
+    pub fn reap(&self) -> Vec<String> {
+        let mut messages = Vec::new();
+        let now = Instant::now();
+        self.pending.lock().retain(|pending| {
+            let running = self
+                .processes
+                .info(pending.info.pid)
+                .is_ok_and(|info| info.start_ticks == pending.info.start_ticks);
+            if !running {
+                return false;
+            }
+            if pending.deadline > now {
+                return true;
+            }
+            let outcome = self.processes.signal(pending.info.pid, Signal::Kill);
+            warn!(
+                target: "audit",
+                gpu = %pending.gpu,
+                pid = pending.info.pid,
+                user = %pending.info.user,
+                executable = %pending.info.executable,
+                cgroup = %pending.info.cgroup,
+                signal = %Signal::Kill,
+                error = outcome.as_ref().err().map(|e| e.to_string()),
+                "kill_process"
+            );
+            messages.push(match outcome {
+                Ok(()) => format!(
+                    "Sent SIGKILL to {} on {} after the grace period",
+                    describe(&pending.info),
+                    pending.gpu
+                ),
+                Err(e) => format!(
+                    "Failed to send SIGKILL to {} on {}: {}",
+                    describe(&pending.info),
+                    pending.gpu,
+                    e
+                ),
+            });
+            false
+        });
+        messages
+    }
+
+    /// Victims sent SIGTERM that may still need SIGKILL.
+    pub fn pending(&self) -> usize {
+        self.pending.lock().len()
+    }
+
+    fn track_idle(&self, target: &str, processes: &[GpuProcess]) {
+        let mut idle_since = self.idle_since.lock();
+        idle_since.retain(|(gpu, pid), _| gpu != target || processes.iter().any(|p| p.pid == *pid));
+        for process in processes {
+            let key = (target.to_string(), process.pid);
+            // Unknown utilization never counts as idle.
+            if process.sm_utilization_percent == Some(0) {
+                idle_since.entry(key).or_insert_with(Instant::now);
+            } else {
+                idle_since.remove(&key);
+            }
+        }
+    }
+
+    /// Why `info` must not be killed, if it must not.
+    fn check(&self, target: &str, info: &ProcessInfo) -> Result<(), String> {
+        let config = &self.config;
+        if info.pid <= 1 {
+            return Err("PID 1 is never killed".to_string());
+        }
+        if self.is_agent(info) {
+            return Err("belongs to the agent".to_string());
+        }
+        if self
+            .pending
+            .lock()
+            .iter()
+            .any(|p| p.info.pid == info.pid && p.info.start_ticks == info.start_ticks)
+        {
+            return Err("already sent SIGTERM".to_string());
+        }
+        let executable_name = info.executable.rsplit('/').next().unwrap_or_default();
+        let executable_matches = |pattern: &str| {
+            let value = if pattern.contains('/') {
+                info.executable.as_str()
+            } else {
+                executable_name
+            };
+            glob_match(pattern, value)
+        };
+        check_list("user", &config.allow_users, &config.deny_users, |p| {
+            glob_match(p, &info.user)
+        })?;
+        check_list("cgroup", &config.allow_cgroups, &config.deny_cgroups, |p| {
+            glob_match(p, &info.cgroup)
+        })?;
+        check_list(
+            "executable",
+            &config.allow_executables,
+            &config.deny_executables,
+            executable_matches,
+        )?;
+        if info.runtime < config.min_runtime {
+            return Err(format!(
+                "running for less than {}",
+                format_duration(config.min_runtime)
+            ));
+        }
+        if !config.min_idle_time.is_zero() {
+            let idle = self
+                .idle_since
+                .lock()
+                .get(&(target.to_string(), info.pid))
+                .map(Instant::elapsed);
+            if idle.is_none_or(|idle| idle < config.min_idle_time) {
+                return Err(format!(
+                    "not idle for {}",
+                    format_duration(config.min_idle_time)
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether `info` is the agent or one of its descendants.
+    fn is_agent(&self, info: &ProcessInfo) -> bool {
+        let mut pid = info.pid;
+        let mut ppid = info.ppid;
+        for _ in 0..MAX_ANCESTORS {
+            if pid == self.agent_pid || ppid == self.agent_pid {
+                return true;
+            }
+            if ppid <= 1 {
+                return false;
+            }
+            pid = ppid;
+            ppid = match self.processes.info(pid) {
+                Ok(parent) => parent.ppid,
+                Err(_) => return false,
+            };
+        }
+        false
+    }
+}
+
+fn describe(info: &ProcessInfo) -> String {
+    let name = info.executable.rsplit('/').next().unwrap_or_default();
+    format!("{} ({}, {})", info.pid, name, info.user)
+}
+
+/// A process of [`SimulatedProcesses`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct SimulatedProcess {
+    pub info: ProcessInfo,
+    /// Keeps running after SIGTERM, until SIGKILL.
+    pub ignores_sigterm: bool,
+}
+
+#[derive(Default)]
+struct SimulatedInner {
+    processes: BTreeMap<u32, SimulatedProcess>,
+    signals: Vec<(u32, Signal)>,
+}
+
+/// In-memory processes that record the signals they get and exit on them.
+/// Clones share the same processes.
+#[derive(Clone, Default)]
+pub struct SimulatedProcesses {
+    inner: Arc<Mutex<SimulatedInner>>,
+}
+
+impl SimulatedProcesses {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_process(self, info: ProcessInfo, ignores_sigterm: bool) -> Self {
+        self.inner.lock().processes.insert(
+            info.pid,
+            SimulatedProcess {
+                info,
+                ignores_sigterm,
+            },
+        );
+        self
+    }
+
+    /// The signals sent so far, in order.
+    pub fn signals(&self) -> Vec<(u32, Signal)> {
+        self.inner.lock().signals.clone()
+    }
+
+    pub fn is_running(&self, pid: u32) -> bool {
+        self.inner.lock().processes.contains_key(&pid)
+    }
+}
+
+impl ProcessControl for SimulatedProcesses {
+    fn info(&self, pid: u32) -> Result<ProcessInfo, ControlError> {
+        self.inner
+            .lock()
+            .processes
+            .get(&pid)
+            .map(|p| p.info.clone())
+            .ok_or(ControlError::ProcessNotFound(pid))
+    }
+
+    fn signal(&self, pid: u32, signal: Signal) -> Result<(), ControlError> {
+        let mut inner = self.inner.lock();
+        let process = inner
+            .processes
+            .get(&pid)
+            .ok_or(ControlError::ProcessNotFound(pid))?;
+        if signal == Signal::Kill || !process.ignores_sigterm {
+            inner.processes.remove(&pid);
+        }
+        inner.signals.push((pid, signal));
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_cgroups() {
+        assert_eq!(
+            parse_cgroup("12:cpu,cpuacct:/slurm/job42\n0::/kubepods/pod1/abc\n"),
+            "/kubepods/pod1/abc"
+        );
+        assert_eq!(parse_cgroup("4:memory:/slurm/job42\n"), "/slurm/job42");
+        assert_eq!(parse_cgroup(""), "");
+    }
+
+    #[test]
+    fn procfs_reads_this_process() {
+        let info = ProcFs.info(std::process::id()).unwrap();
+        assert_eq!(info.pid, std::process::id());
+        assert!(info.start_ticks > 0);
+        assert!(!info.user.is_empty());
+        assert_eq!(
+            ProcFs.signal(0, Signal::Term),
+            Err(ControlError::ProcessNotFound(0))
+        );
+    }
+}
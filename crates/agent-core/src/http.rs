@@ -2,31 +2,36 @@
 use std::sync::Arc;
 
 use axum::{
-    extract::{Query, State},
+    extract::{Form, Query, State},
     http::StatusCode,
     response::{
         sse::{Event, Sse},
         IntoResponse, Response,
     },
-    routing::get,
+    routing::{delete, get},
     Json, Router,
 };
 use tokio::task::JoinHandle;
 use tracing::info;
 
 use crate::metrics::MetricsRegistry;
+use crate::policy::PolicyHistory;
 use crate::state::StatusState;
+use crate::tsdb::{ExportCursor, ExportFormat};
 
 #[derive(Clone)]
 pub struct HttpState {
     pub metrics: MetricsRegistry,
     pub healthy: Arc<std::sync::atomic::AtomicBool>,
     pub status: StatusState,
+    pub policy: PolicyHistory,
     pub tsdb: Option<std::sync::Arc<crate::tsdb::LocalTsdb>>,
     pub orchestrator: Option<esnode_orchestrator::AppState>,
     pub orchestrator_allow_public: bool,
     pub listen_is_loopback: bool,
     pub orchestrator_token: Option<String>,
+    pub admin_token: Option<String>,
+    pub audit: Option<crate::audit::AuditLog>,
 }
 
 pub fn build_router(state: HttpState) -> Router {
@@ -36,7 +41,22 @@ pub fn build_router(state: HttpState) -> Router {
         .route("/status", get(status_handler))
         .route("/v1/status", get(status_handler))
         .route("/events", get(events_handler))
-        .route("/tsdb/export", get(tsdb_export_handler));
+        .route("/v1/policy/plan", get(policy_plan_handler))
+        .route("/v1/policy/history", get(policy_history_handler))
+        .route("/v1/audit", get(audit_handler))
+        .route("/tsdb/export", get(tsdb_export_handler))
+        .route("/tsdb/stats", get(tsdb_stats_handler))
+        .route("/tsdb/blocks", get(tsdb_blocks_handler))
+        .route("/tsdb/series", delete(tsdb_delete_series_handler))
+        .route("/tsdb/snapshot", get(tsdb_snapshot_handler))
+        .route(
+            "/api/v1/query",
+            get(prom_query_handler).post(prom_query_handler),
+        )
+        .route(
+            "/api/v1/query_range",
+            get(prom_query_range_handler).post(prom_query_range_handler),
+        );
 
     if let Some(orch_state) = &state.orchestrator {
         if state.orchestrator_allow_public || state.listen_is_loopback {
@@ -94,22 +114,107 @@ async fn status_handler(State(state): State<HttpState>) -> impl IntoResponse {
     Json(snapshot)
 }
 
+/// Status snapshots every 5s as unnamed events, interleaved with `policy`
+/// events (see [`PolicyHistory`]) as they happen. Policy events are only
+/// sent to clients that may read `/v1/policy/history`.
 async fn events_handler(
     State(state): State<HttpState>,
+    headers: axum::http::HeaderMap,
 ) -> Sse<impl futures::Stream<Item = Result<Event, std::convert::Infallible>>> {
     use futures::StreamExt;
+    use tokio::sync::broadcast::error::RecvError;
     use tokio_stream::wrappers::IntervalStream;
 
+    let feed = is_admin(&state, &headers).then(|| state.policy.subscribe());
     let interval = tokio::time::interval(std::time::Duration::from_secs(5));
     let state_clone = state.status;
 
-    let stream = IntervalStream::new(interval).map(move |_| {
+    let status = IntervalStream::new(interval).map(move |_| {
         let snap = state_clone.snapshot();
         let payload = serde_json::to_string(&snap).unwrap_or_else(|_| "{}".to_string());
         Ok(Event::default().data(payload))
     });
+    let policy = futures::stream::unfold(feed, |feed| async move {
+        let mut feed = feed?;
+        loop {
+            match feed.recv().await {
+                Ok(event) => {
+                    let payload =
+                        serde_json::to_string(&event).unwrap_or_else(|_| "{}".to_string());
+                    let sse = Event::default()
+                        .event("policy")
+                        .id(event.seq.to_string())
+                        .data(payload);
+                    return Some((Ok(sse), Some(feed)));
+                }
+                // Missed events remain in /v1/policy/history.
+                Err(RecvError::Lagged(_)) => continue,
+                Err(RecvError::Closed) => return None,
+            }
+        }
+    });
+
+    Sse::new(futures::stream::select(status, policy))
+        .keep_alive(axum::response::sse::KeepAlive::default())
+}
 
-    Sse::new(stream).keep_alive(axum::response::sse::KeepAlive::default())
+async fn policy_plan_handler(
+    State(state): State<HttpState>,
+    headers: axum::http::HeaderMap,
+) -> Response {
+    if let Err(status) = authorize_admin(&state, &headers, "policy_plan") {
+        return status.into_response();
+    }
+    Json(state.policy.plans()).into_response()
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct PolicyHistoryQuery {
+    /// Only events with a greater `seq`.
+    since: Option<u64>,
+    /// Only the newest `limit` events.
+    limit: Option<usize>,
+}
+
+async fn policy_history_handler(
+    State(state): State<HttpState>,
+    headers: axum::http::HeaderMap,
+    Query(q): Query<PolicyHistoryQuery>,
+) -> Response {
+    if let Err(status) = authorize_admin(&state, &headers, "policy_history") {
+        return status.into_response();
+    }
+    Json(serde_json::json!({ "events": state.policy.events(q.since, q.limit) })).into_response()
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct AuditQuery {
+    /// Only records with a greater `seq`.
+    since: Option<u64>,
+    /// Only the newest `limit` records (default 100, at most 1000).
+    limit: Option<usize>,
+}
+
+async fn audit_handler(
+    State(state): State<HttpState>,
+    headers: axum::http::HeaderMap,
+    Query(q): Query<AuditQuery>,
+) -> Response {
+    if let Err(status) = authorize_admin(&state, &headers, "audit") {
+        return status.into_response();
+    }
+    let Some(audit) = state.audit else {
+        return (StatusCode::NOT_FOUND, "audit log disabled").into_response();
+    };
+    let limit = q.limit.unwrap_or(100).min(1000);
+    match tokio::task::spawn_blocking(move || audit.records(q.since, limit)).await {
+        Ok(Ok(records)) => Json(serde_json::json!({ "records": records })).into_response(),
+        Ok(Err(err)) => {
+            tracing::warn!("reading audit log failed: {:?}", err);
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+        Err(_) => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+    }
 }
 
 #[derive(Debug, serde::Deserialize)]
@@ -117,46 +222,404 @@ struct ExportQuery {
     from: Option<i64>,
     to: Option<i64>,
     metrics: Option<String>,
+    /// `text` (default), `openmetrics`, `jsonl`, `csv` or `columnar`.
+    format: Option<String>,
+    /// Page size in samples; without it the whole range is streamed.
+    limit: Option<usize>,
+    /// Token from the `X-Next-Token` header of the previous page.
+    next: Option<String>,
 }
 
+/// Response header carrying the token of the next export page.
+const NEXT_TOKEN_HEADER: &str = "x-next-token";
+
 async fn tsdb_export_handler(
     State(state): State<HttpState>,
     Query(q): Query<ExportQuery>,
 ) -> impl IntoResponse {
+    use futures::TryStreamExt;
+
     if state.tsdb.is_none() {
         return (StatusCode::NOT_FOUND, "local TSDB disabled").into_response();
     }
     let tsdb = state.tsdb.clone().unwrap();
+    let format = match q.format.as_deref().map(str::parse::<ExportFormat>) {
+        None => ExportFormat::default(),
+        Some(Ok(format)) => format,
+        Some(Err(msg)) => return (StatusCode::BAD_REQUEST, msg).into_response(),
+    };
+    let after = match q.next.as_deref().map(str::parse::<ExportCursor>) {
+        None => None,
+        Some(Ok(cursor)) => Some(cursor),
+        Some(Err(msg)) => return (StatusCode::BAD_REQUEST, msg).into_response(),
+    };
+    if q.limit == Some(0) {
+        return (StatusCode::BAD_REQUEST, "limit must be positive").into_response();
+    }
     let metrics_filter = q.metrics.map(|s| {
         s.split(',')
             .map(|m| m.trim().to_string())
             .filter(|m| !m.is_empty())
             .collect::<Vec<_>>()
     });
+    let headers = [
+        (axum::http::header::CONTENT_TYPE, format.content_type()),
+        (
+            axum::http::header::CACHE_CONTROL,
+            "no-store, max-age=0, must-revalidate",
+        ),
+    ];
+    let mut scan = match tsdb.scan(q.from, q.to, metrics_filter, after).await {
+        Ok(scan) => scan,
+        Err(err) => {
+            tracing::warn!("tsdb export failed: {:?}", err);
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+    };
+    let Some(limit) = q.limit else {
+        let stream = format
+            .stream(scan)
+            .inspect_err(|err| tracing::warn!("tsdb export stream failed: {:?}", err));
+        return (headers, axum::body::Body::from_stream(stream)).into_response();
+    };
+    match format.page(&mut scan, limit).await {
+        Ok((body, next)) => {
+            let mut response = (headers, body).into_response();
+            if let Some(next) = next {
+                if let Ok(value) = axum::http::HeaderValue::from_str(&next.to_string()) {
+                    response.headers_mut().insert(NEXT_TOKEN_HEADER, value);
+                }
+            }
+            response
+        }
+        Err(err) => {
+            tracing::warn!("tsdb export failed: {:?}", err);
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+/// Whether the request carries the admin token, or needs none because the
+/// agent only listens on loopback.
+fn is_admin(state: &HttpState, headers: &axum::http::HeaderMap) -> bool {
+    match &state.admin_token {
+        Some(token) => {
+            let expected = format!("Bearer {token}");
+            headers
+                .get(axum::http::header::AUTHORIZATION)
+                .is_some_and(|h| constant_time_eq(h.as_bytes(), expected.as_bytes()))
+        }
+        None => state.listen_is_loopback,
+    }
+}
+
+/// Gate for the `/tsdb/*` admin, `/v1/policy/*` and `/v1/audit` endpoints:
+/// the configured bearer token, or a loopback listener when none is set.
+fn authorize_admin(
+    state: &HttpState,
+    headers: &axum::http::HeaderMap,
+    action: &str,
+) -> Result<(), StatusCode> {
+    let token_present = headers.contains_key(axum::http::header::AUTHORIZATION);
+    if is_admin(state, headers) {
+        tracing::info!(target: "audit", action, token_present, "admin request");
+        return Ok(());
+    }
+    tracing::warn!(target: "audit", action, token_present, "admin request denied");
+    Err(if state.admin_token.is_some() {
+        StatusCode::UNAUTHORIZED
+    } else {
+        StatusCode::FORBIDDEN
+    })
+}
+
+/// Compares secrets in time independent of where they differ. Both sides
+/// are hashed first so the comparison also hides the expected length.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    use ring::digest::{digest, SHA256};
+
+    let (a, b) = (digest(&SHA256, a), digest(&SHA256, b));
+    a.as_ref()
+        .iter()
+        .zip(b.as_ref())
+        .fold(0u8, |diff, (x, y)| diff | (x ^ y))
+        == 0
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct StatsQuery {
+    /// Number of metrics listed by sample count.
+    top: Option<usize>,
+}
+
+async fn tsdb_stats_handler(
+    State(state): State<HttpState>,
+    headers: axum::http::HeaderMap,
+    Query(q): Query<StatsQuery>,
+) -> Response {
+    let Some(tsdb) = state.tsdb.clone() else {
+        return (StatusCode::NOT_FOUND, "local TSDB disabled").into_response();
+    };
+    if let Err(status) = authorize_admin(&state, &headers, "tsdb_stats") {
+        return status.into_response();
+    }
+    match tsdb.stats(q.top.unwrap_or(10)).await {
+        Ok(stats) => Json(stats).into_response(),
+        Err(err) => {
+            tracing::warn!("tsdb stats failed: {:?}", err);
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+async fn tsdb_blocks_handler(
+    State(state): State<HttpState>,
+    headers: axum::http::HeaderMap,
+) -> Response {
+    let Some(tsdb) = state.tsdb.clone() else {
+        return (StatusCode::NOT_FOUND, "local TSDB disabled").into_response();
+    };
+    if let Err(status) = authorize_admin(&state, &headers, "tsdb_blocks") {
+        return status.into_response();
+    }
+    match tsdb.blocks().await {
+        Ok(blocks) => Json(blocks).into_response(),
+        Err(err) => {
+            tracing::warn!("tsdb block listing failed: {:?}", err);
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct DeleteSeriesQuery {
+    #[serde(rename = "match")]
+    selector: Option<String>,
+    start: Option<String>,
+    end: Option<String>,
+}
+
+async fn tsdb_delete_series_handler(
+    State(state): State<HttpState>,
+    headers: axum::http::HeaderMap,
+    Query(q): Query<DeleteSeriesQuery>,
+) -> Response {
+    use crate::tsdb::query::{parse_time_ms, QueryError};
+
+    let Some(tsdb) = state.tsdb.clone() else {
+        return prom_error(StatusCode::NOT_FOUND, "unavailable", "local TSDB disabled");
+    };
+    if let Err(status) = authorize_admin(&state, &headers, "tsdb_delete_series") {
+        return status.into_response();
+    }
+    let Some(selector) = q.selector else {
+        return prom_error(
+            StatusCode::BAD_REQUEST,
+            "bad_data",
+            "missing match parameter",
+        );
+    };
+    let mut range = [None, None];
+    for (slot, raw) in range.iter_mut().zip([q.start, q.end]) {
+        if let Some(raw) = raw {
+            match parse_time_ms(&raw) {
+                Some(ts) => *slot = Some(ts),
+                None => {
+                    return prom_error(StatusCode::BAD_REQUEST, "bad_data", "invalid start/end")
+                }
+            }
+        }
+    }
+    let now_ms = chrono::Utc::now().timestamp_millis();
     match tsdb
-        .export_lines(q.from, q.to, metrics_filter.as_ref())
+        .delete_series(&selector, range[0], range[1], now_ms)
         .await
     {
-        Ok(lines) => {
-            let body = lines.join("\n");
-            (
-                [
-                    (
-                        axum::http::header::CONTENT_TYPE,
-                        "text/plain; charset=utf-8",
-                    ),
-                    (
-                        axum::http::header::CACHE_CONTROL,
-                        "no-store, max-age=0, must-revalidate",
-                    ),
-                ],
-                body,
+        Ok(_) => StatusCode::NO_CONTENT.into_response(),
+        Err(err @ QueryError::Storage(_)) => {
+            tracing::warn!("tsdb delete series failed: {:?}", err);
+            prom_error(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                err.error_type(),
+                &err.to_string(),
             )
-                .into_response()
         }
+        Err(err) => prom_error(StatusCode::BAD_REQUEST, err.error_type(), &err.to_string()),
+    }
+}
+
+/// Blocking `Write` feeding a response body channel.
+struct ChannelWriter(tokio::sync::mpsc::Sender<std::io::Result<Vec<u8>>>);
+
+impl std::io::Write for ChannelWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0
+            .blocking_send(Ok(buf.to_vec()))
+            .map_err(|_| std::io::Error::new(std::io::ErrorKind::BrokenPipe, "client went away"))?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+async fn tsdb_snapshot_handler(
+    State(state): State<HttpState>,
+    headers: axum::http::HeaderMap,
+) -> Response {
+    let Some(tsdb) = state.tsdb.clone() else {
+        return (StatusCode::NOT_FOUND, "local TSDB disabled").into_response();
+    };
+    if let Err(status) = authorize_admin(&state, &headers, "tsdb_snapshot") {
+        return status.into_response();
+    }
+    let now_ms = chrono::Utc::now().timestamp_millis();
+    let snapshot = match tsdb.snapshot(now_ms).await {
+        Ok(snapshot) => snapshot,
         Err(err) => {
-            tracing::warn!("tsdb export failed: {:?}", err);
-            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+            tracing::warn!("tsdb snapshot failed: {:?}", err);
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+    };
+    let (tx, rx) = tokio::sync::mpsc::channel(16);
+    tokio::task::spawn_blocking(move || {
+        // Batches the small header writes of the tar builder.
+        let out = std::io::BufWriter::with_capacity(64 * 1024, ChannelWriter(tx.clone()));
+        if let Err(err) = snapshot.write_tar(out) {
+            tracing::warn!("tsdb snapshot stream failed: {:?}", err);
+            let _ = tx.blocking_send(Err(std::io::Error::other(err.to_string())));
+        }
+    });
+    let disposition = format!("attachment; filename=\"esnode-tsdb-{now_ms}.tar\"");
+    (
+        [
+            (axum::http::header::CONTENT_TYPE, "application/x-tar".to_string()),
+            (axum::http::header::CONTENT_DISPOSITION, disposition),
+        ],
+        axum::body::Body::from_stream(tokio_stream::wrappers::ReceiverStream::new(rx)),
+    )
+        .into_response()
+}
+
+/// Parameters of `/api/v1/query`; `Form` reads them from the query string
+/// for GET and from the urlencoded body for POST, as Grafana may use either.
+#[derive(Debug, serde::Deserialize)]
+struct PromQueryParams {
+    query: Option<String>,
+    time: Option<String>,
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct PromRangeQueryParams {
+    query: Option<String>,
+    start: Option<String>,
+    end: Option<String>,
+    step: Option<String>,
+}
+
+async fn prom_query_handler(
+    State(state): State<HttpState>,
+    Form(q): Form<PromQueryParams>,
+) -> Response {
+    let Some(tsdb) = state.tsdb.clone() else {
+        return prom_error(StatusCode::NOT_FOUND, "unavailable", "local TSDB disabled");
+    };
+    let Some(query) = q.query else {
+        return prom_error(
+            StatusCode::BAD_REQUEST,
+            "bad_data",
+            "missing query parameter",
+        );
+    };
+    let ts_ms = match q.time.as_deref() {
+        None => chrono::Utc::now().timestamp_millis(),
+        Some(raw) => match crate::tsdb::query::parse_time_ms(raw) {
+            Some(ts) => ts,
+            None => return prom_error(StatusCode::BAD_REQUEST, "bad_data", "invalid time"),
+        },
+    };
+    prom_result(tsdb.query_instant(&query, ts_ms).await)
+}
+
+async fn prom_query_range_handler(
+    State(state): State<HttpState>,
+    Form(q): Form<PromRangeQueryParams>,
+) -> Response {
+    use crate::tsdb::query::{parse_duration_ms, parse_time_ms};
+
+    let Some(tsdb) = state.tsdb.clone() else {
+        return prom_error(StatusCode::NOT_FOUND, "unavailable", "local TSDB disabled");
+    };
+    let Some(query) = q.query else {
+        return prom_error(
+            StatusCode::BAD_REQUEST,
+            "bad_data",
+            "missing query parameter",
+        );
+    };
+    let Some(start_ms) = q.start.as_deref().and_then(parse_time_ms) else {
+        return prom_error(
+            StatusCode::BAD_REQUEST,
+            "bad_data",
+            "invalid or missing start",
+        );
+    };
+    let Some(end_ms) = q.end.as_deref().and_then(parse_time_ms) else {
+        return prom_error(
+            StatusCode::BAD_REQUEST,
+            "bad_data",
+            "invalid or missing end",
+        );
+    };
+    // Like Prometheus, accept either float seconds or a duration string.
+    let step_ms = q.step.as_deref().and_then(|raw| {
+        raw.parse::<f64>()
+            .ok()
+            .map(|secs| (secs * 1000.0).round() as i64)
+            .or_else(|| parse_duration_ms(raw))
+    });
+    let Some(step_ms) = step_ms else {
+        return prom_error(
+            StatusCode::BAD_REQUEST,
+            "bad_data",
+            "invalid or missing step",
+        );
+    };
+    prom_result(tsdb.query_range(&query, start_ms, end_ms, step_ms).await)
+}
+
+fn prom_result(
+    result: Result<crate::tsdb::query::QueryResult, crate::tsdb::query::QueryError>,
+) -> Response {
+    use crate::tsdb::query::QueryError;
+
+    match result {
+        Ok(result) => Json(serde_json::json!({
+            "status": "success",
+            "data": result.to_json(),
+        }))
+        .into_response(),
+        Err(err @ QueryError::Storage(_)) => {
+            tracing::warn!("tsdb query failed: {:?}", err);
+            prom_error(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                err.error_type(),
+                &err.to_string(),
+            )
         }
+        Err(err) => prom_error(StatusCode::BAD_REQUEST, err.error_type(), &err.to_string()),
     }
 }
+
+fn prom_error(status: StatusCode, error_type: &str, message: &str) -> Response {
+    (
+        status,
+        Json(serde_json::json!({
+            "status": "error",
+            "errorType": error_type,
+            "error": message,
+        })),
+    )
+        .into_response()
+}
@@ -1,5 +1,6 @@
 // ESNODE | Source Available BUSL-1.1 | Copyright (c) 2024 Estimatedstocks AB
 pub mod telemetry;
+pub mod audit;
 pub mod config;
 mod collectors;
 mod event_worker;
@@ -11,6 +12,7 @@ pub mod nvml_ext;
 pub mod policy;
 pub mod predictive;
 pub mod rca;
+pub mod remote_write;
 pub mod state;
 pub mod tsdb;
 
@@ -31,11 +33,12 @@ use crate::collectors::ebpf::{EbpfCollector, EbpfConfig};
 pub use config::{AgentConfig, ConfigOverrides, LogLevel};
 use http::{build_router, serve, HttpState};
 use metrics::MetricsRegistry;
+use remote_write::RemoteWriter;
 use std::net::SocketAddr;
 use tokio::signal;
 use tokio::sync::Mutex;
 use tracing::{info, warn};
-use tsdb::{samples_from_registry, LocalTsdb, LocalTsdbConfig};
+use tsdb::{samples_from_registry, LocalTsdb, LocalTsdbConfig, WriteSchedule};
 
 pub struct Agent {
     config: AgentConfig,
@@ -51,6 +54,7 @@ impl Agent {
         let metrics = MetricsRegistry::new()?;
         let healthy = Arc::new(AtomicBool::new(true));
         let status = state::StatusState::new(healthy.clone());
+        status.set_node_power_envelope(config.node_power_envelope_watts);
         let pue_calc = Arc::new(collectors::pue::PueCalculator::new(status.clone()));
         let power_aggregator = collectors::pue::PowerAggregator::new(pue_calc.clone());
         let mut collectors: Vec<Box<dyn Collector>> = Vec::new();
@@ -284,10 +288,39 @@ impl Agent {
         let scrape_interval = config.scrape_interval;
         let status_state = status.clone();
         let tsdb_for_collection = local_tsdb.clone();
+        let mut tsdb_schedule = WriteSchedule::from(&config);
         let tsdb_for_shutdown = local_tsdb.clone();
         let tsdb_pruner_handle = local_tsdb
             .clone()
             .map(|tsdb| tsdb.spawn_pruner(std::time::Duration::from_secs(60)));
+        let tsdb_compactor_handle = local_tsdb
+            .clone()
+            .filter(|tsdb| tsdb.has_rollups())
+            .map(|tsdb| tsdb.spawn_compactor(std::time::Duration::from_secs(60)));
+        let remote_write_handles: Vec<_> = match &local_tsdb {
+            Some(tsdb) => {
+                let now_ms = chrono::Utc::now().timestamp_millis();
+                config
+                    .remote_write
+                    .iter()
+                    .filter_map(|endpoint| {
+                        match RemoteWriter::new(endpoint.clone(), tsdb.clone(), metrics.clone(), now_ms) {
+                            Ok(writer) => Some(writer.spawn()),
+                            Err(err) => {
+                                warn!("remote write to {} disabled: {err:#}", endpoint.url);
+                                None
+                            }
+                        }
+                    })
+                    .collect()
+            }
+            None => {
+                if !config.remote_write.is_empty() {
+                    warn!("remote_write endpoints ignored: they replay from the local TSDB, which is disabled");
+                }
+                Vec::new()
+            }
+        };
         
         let orchestrator_state_clone = if let Some(orch_config) = &config.orchestrator {
              if orch_config.enabled {
@@ -322,7 +355,6 @@ impl Agent {
 
         let collection_task = tokio::spawn(async move {
             let mut ticker = tokio::time::interval(scrape_interval);
-            let mut last_tsdb_write_ms: i64 = 0;
             
             let mut rca_engine = crate::rca::RcaEngine::new(
                 std::time::Duration::from_secs(300), 
@@ -441,12 +473,22 @@ impl Agent {
                 drop(guard);
 
                 if let Some(tsdb) = tsdb_for_collection.clone() {
-                    if ts_ms - last_tsdb_write_ms >= 30_000 {
-                        let samples = samples_from_registry(&metrics_clone, ts_ms);
+                    if tsdb_schedule.any_due(ts_ms) {
+                        let samples = tsdb_schedule
+                            .select(samples_from_registry(&metrics_clone, ts_ms), ts_ms);
                         if let Err(err) = tsdb.write_samples(&samples).await {
                             warn!("local TSDB write failed: {:?}", err);
                         }
-                        last_tsdb_write_ms = ts_ms;
+                        let stats = tsdb.take_cardinality_stats();
+                        metrics_clone.tsdb_active_series.set(stats.active_series as i64);
+                        metrics_clone
+                            .tsdb_samples_limited_total
+                            .with_label_values(&["dropped"])
+                            .inc_by(stats.dropped_samples);
+                        metrics_clone
+                            .tsdb_samples_limited_total
+                            .with_label_values(&["aggregated"])
+                            .inc_by(stats.aggregated_samples);
                     }
                 }
             }
@@ -459,97 +501,96 @@ impl Agent {
         let enforcement_config = config.clone();
         let enforcement_status = status.clone();
         let enforcement_metrics = metrics.clone();
+        let policy_history = crate::policy::PolicyHistory::new();
+        let enforcement_history = policy_history.clone();
+        let audit_log = if config.audit.enabled {
+            match crate::audit::AuditLog::open(&config.audit) {
+                Ok(log) => Some(log),
+                Err(err) => {
+                    warn!("Enforcement audit log disabled: {err:#}");
+                    None
+                }
+            }
+        } else {
+            None
+        };
+        let enforcement_audit = audit_log.clone();
+        let (stop_enforcement, mut enforcement_stopped) = tokio::sync::oneshot::channel::<()>();
         
-        let enforcement_task = tokio::spawn(async move {
-            if enforcement_config.efficiency_profile_path.is_none() {
+        let mut enforcement_task = tokio::spawn(async move {
+            let sources: Vec<std::path::PathBuf> = enforcement_config.efficiency_profile_path.iter()
+                .chain(&enforcement_config.efficiency_profiles_dir)
+                .cloned()
+                .collect();
+            let mut enforcement_config = enforcement_config;
+            let ledger = match crate::control::ActionLedger::open(&enforcement_config.action_ledger_path) {
+                Ok(ledger) => ledger,
+                Err(err) => {
+                    // Changes that cannot be recorded cannot be rolled back.
+                    tracing::error!("Refusing to enforce: {err:#}");
+                    enforcement_config.enforcement_mode = crate::config::EnforcementMode::Monitor;
+                    crate::control::ActionLedger::default()
+                }
+            };
+            if sources.is_empty() && ledger.is_empty() {
                 // Determine if we should exit or sleep. Sleeping is safer for the select! block.
-                std::future::pending::<()>().await;
+                let _ = enforcement_stopped.await;
+                return;
+            }
+            let profiles = crate::policy::ProfileSet::new(sources.clone());
+            let mut control = crate::control::EnforcementLoop::new(
+                profiles,
+                crate::control::Enforcer::new().with_kill_guards(enforcement_config.kill_process.clone()),
+                &enforcement_config,
+                ledger,
+                enforcement_history,
+                enforcement_metrics,
+            );
+            if let Some(audit) = enforcement_audit {
+                control = control.with_audit_log(audit);
+            }
+            // Undo what a crashed predecessor left in place.
+            control.recover(chrono::Utc::now().timestamp_millis());
+            if sources.is_empty() {
+                let _ = enforcement_stopped.await;
                 return;
             }
-            let profile_path = enforcement_config.efficiency_profile_path.as_ref().unwrap();
-            let mode = &enforcement_config.enforcement_mode;
-            // Enforcer needs to be Send. agent_core::control::Enforcer holds Nvml which is Send.
-            let enforcer = crate::control::Enforcer::new();
-            let mut dampener = crate::control::FlapDampener::new(enforcement_config.dampening_interval);
 
             loop {
-                enforcement_ticker.tick().await;
-                
-                let contents = match tokio::fs::read_to_string(profile_path).await {
-                    Ok(c) => c,
-                    Err(e) => {
-                        warn!("Failed to read efficiency profile at {}: {}", profile_path.display(), e);
-                        continue;
+                tokio::select! {
+                    _ = enforcement_ticker.tick() => {}
+                    _ = &mut enforcement_stopped => {
+                        // Roll back every setting policies changed.
+                        control.shutdown(chrono::Utc::now().timestamp_millis());
+                        return;
                     }
-                };
-                
-                let profile: crate::policy::EfficiencyProfile = match serde_yaml::from_str(&contents) {
-                     Ok(p) => p,
-                     Err(e) => {
-                         warn!("Failed to parse efficiency profile: {}", e);
-                         continue;
-                     }
-                };
-                
+                }
+
+                control.reload();
                 // We need a StatusSnapshot. status is typically updated by collection_task.
                 // StatusState is thread-safe (Arc<RwLock>).
                 let snapshot = enforcement_status.snapshot();
-                let plan = profile.plan(&snapshot);
-                
-                let violations: Vec<_> = plan.matched_policies.iter()
-                    .filter(|p| matches!(p.status, crate::policy::PlanStatus::Violated))
-                    .collect();
-
-                if !violations.is_empty() {
-                    info!("Efficiency Audit: Found {} violations", violations.len());
-                    for v in &violations {
-                         info!("Violation: {} on {} (Current: {}, Limit: {})", 
-                            v.policy_name, v.target_resource, v.current_value, v.threshold);
-                         
-                         enforcement_metrics.policy_violations_total
-                            .with_label_values(&[&v.policy_name, &v.target_resource, "violation"])
-                            .inc();
-
-                         if *mode == crate::config::EnforcementMode::Enforce {
-                             if !dampener.can_apply(&v.policy_name, &v.target_resource) {
-                                 info!("Dampened enforcement of {} on {}", v.policy_name, v.target_resource);
-                                 continue;
-                             }
-                             // Re-find policy definition to get the action details
-                             if let Some(policy) = profile.policies.iter().find(|p| p.name == v.policy_name) {
-                                match enforcer.apply_action(&v.target_resource, &policy.action) {
-                                    Ok(msg) => {
-                                        info!("ENFORCED: {}", msg);
-                                        dampener.record_action(&v.policy_name, &v.target_resource);
-                                        enforcement_metrics.policy_enforced_total
-                                            .with_label_values(&[&v.policy_name, &v.target_resource, "success"])
-                                            .inc();
-                                    },
-                                    Err(e) => {
-                                        warn!("ENFORCEMENT FAILED: {}", e);
-                                        enforcement_metrics.policy_enforced_total
-                                            .with_label_values(&[&v.policy_name, &v.target_resource, "failure"])
-                                            .inc();
-                                    },
-                                }
-                             }
-                         }
-                    }
-                }
+                control.tick(&snapshot, chrono::Utc::now().timestamp_millis());
             }
         });
                 
         // Orchestrator already initialized above
         let orchestrator_state = orchestrator_state_clone;
+        if config.local_tsdb_admin_token.is_some() {
+            warn!("local_tsdb_admin_token is deprecated, use admin_token");
+        }
         let http_state = HttpState {
             metrics: metrics.clone(),
             healthy: healthy.clone(),
             status: status.clone(),
+            policy: policy_history,
             tsdb: local_tsdb.clone(),
             orchestrator: orchestrator_state,
             orchestrator_allow_public: config.orchestrator.as_ref().is_some_and(|o| o.allow_public),
             listen_is_loopback: listen_is_loopback(&config.listen_address),
             orchestrator_token: config.orchestrator.as_ref().and_then(|o| o.token.clone()),
+            admin_token: config.admin_token().map(str::to_string),
+            audit: audit_log,
         };
         let router = build_router(http_state);
         let http_task = serve(&config.listen_address, router)
@@ -562,7 +603,7 @@ impl Agent {
                     return Err(anyhow::anyhow!("collection task panicked: {err:?}"));
                 }
             },
-            res = enforcement_task => {
+            res = &mut enforcement_task => {
                 if let Err(err) = res {
                     // If the enforcement task panics (unlikely unless FS error or similar), log it.
                     // We might not want to kill the whole agent, but for now strict mode is fine.
@@ -574,11 +615,15 @@ impl Agent {
                     return Err(anyhow::anyhow!("http server task panicked: {err:?}"));
                 }
             },
-            _ = signal::ctrl_c() => {
+            _ = shutdown_signal() => {
+                let _ = stop_enforcement.send(());
+                let _ = enforcement_task.await;
                 if let Some(tsdb) = tsdb_for_shutdown {
                     let _ = tsdb.flush_current().await;
                 }
                 if let Some(handle) = tsdb_pruner_handle { handle.abort(); }
+                if let Some(handle) = tsdb_compactor_handle { handle.abort(); }
+                for handle in remote_write_handles { handle.abort(); }
                 return Ok(());
             }
         }
@@ -586,6 +631,26 @@ impl Agent {
     }
 }
 
+/// Resolves on Ctrl-C, or on SIGTERM from systemd or Kubernetes, so both
+/// stop the agent through the same rollback path.
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::SignalKind;
+        match signal::unix::signal(SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                tokio::select! {
+                    _ = signal::ctrl_c() => {}
+                    _ = terminate.recv() => info!("Received SIGTERM, shutting down"),
+                }
+                return;
+            }
+            Err(err) => warn!("Cannot listen for SIGTERM: {err}"),
+        }
+    }
+    let _ = signal::ctrl_c().await;
+}
+
 fn listen_is_loopback(listen: &str) -> bool {
     listen
         .parse::<SocketAddr>()
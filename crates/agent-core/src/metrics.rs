@@ -1,7 +1,7 @@
 // ESNODE | Source Available BUSL-1.1 | Copyright (c) 2024 Estimatedstocks AB
 use anyhow::Context;
 use prometheus::{
-    proto::MetricFamily, Counter, CounterVec, Encoder, Gauge, GaugeVec, IntCounter, IntCounterVec, Opts,
+    proto::MetricFamily, Counter, CounterVec, Encoder, Gauge, GaugeVec, IntCounter, IntCounterVec, IntGauge, Opts,
     Registry, TextEncoder,
 };
 
@@ -164,6 +164,15 @@ pub struct MetricsRegistry {
     pub ebpf_power_mw_avg: Gauge,
     pub ebpf_energy_uj_total: Counter,
     pub ebpf_sample_count: Counter,
+
+    // Remote write
+    pub remote_write_samples_sent_total: IntCounterVec,
+    pub remote_write_samples_failed_total: IntCounterVec,
+    pub remote_write_lag_seconds: GaugeVec,
+
+    // Local TSDB
+    pub tsdb_active_series: IntGauge,
+    pub tsdb_samples_limited_total: IntCounterVec,
 }
 
 impl MetricsRegistry {
@@ -1069,6 +1078,42 @@ impl MetricsRegistry {
             "Number of eBPF samples collected",
         )?;
 
+        // Remote write
+        let remote_write_samples_sent_total = IntCounterVec::new(
+            Opts::new(
+                "esnode_remote_write_samples_sent_total",
+                "Samples acknowledged by a remote-write endpoint",
+            ),
+            &["endpoint"],
+        )?;
+        let remote_write_samples_failed_total = IntCounterVec::new(
+            Opts::new(
+                "esnode_remote_write_samples_failed_total",
+                "Samples in remote-write requests that failed (counted per attempt)",
+            ),
+            &["endpoint"],
+        )?;
+        let remote_write_lag_seconds = GaugeVec::new(
+            Opts::new(
+                "esnode_remote_write_lag_seconds",
+                "Age of the newest sample acknowledged by a remote-write endpoint",
+            ),
+            &["endpoint"],
+        )?;
+
+        // Local TSDB
+        let tsdb_active_series = IntGauge::new(
+            "esnode_tsdb_active_series",
+            "Series written to the local TSDB within the last block duration",
+        )?;
+        let tsdb_samples_limited_total = IntCounterVec::new(
+            Opts::new(
+                "esnode_tsdb_samples_limited_total",
+                "Samples of series over the local TSDB cardinality limits",
+            ),
+            &["action"],
+        )?;
+
         let metrics = Self {
             registry,
             cpu_load_avg_1m,
@@ -1218,6 +1263,11 @@ impl MetricsRegistry {
             ebpf_power_mw_avg,
             ebpf_energy_uj_total,
             ebpf_sample_count,
+            remote_write_samples_sent_total,
+            remote_write_samples_failed_total,
+            remote_write_lag_seconds,
+            tsdb_active_series,
+            tsdb_samples_limited_total,
         };
 
         metrics.register_all()?;
@@ -1373,6 +1423,11 @@ impl MetricsRegistry {
             Box::new(self.ebpf_power_mw_avg.clone()),
             Box::new(self.ebpf_energy_uj_total.clone()),
             Box::new(self.ebpf_sample_count.clone()),
+            Box::new(self.remote_write_samples_sent_total.clone()),
+            Box::new(self.remote_write_samples_failed_total.clone()),
+            Box::new(self.remote_write_lag_seconds.clone()),
+            Box::new(self.tsdb_active_series.clone()),
+            Box::new(self.tsdb_samples_limited_total.clone()),
         ];
 
         for collector in regs.drain(..) {
@@ -0,0 +1,708 @@
+// ESNODE | Source Available BUSL-1.1 | Copyright (c) 2025 Estimatedstocks AB
+//! Parsed `PolicyRule.condition` expressions.
+//!
+//! ```text
+//! condition  := and ("or" and)*
+//! and        := unary ("and" unary)*
+//! unary      := "not" unary | "(" condition ")" | comparison
+//! comparison := [operand] ("<" | "<=" | ">" | ">=" | "==" | "=" | "!=") operand
+//!             | [operand] "between" operand "and" operand
+//! operand    := term (("+" | "-") term)*
+//! term       := factor (("*" | "/") factor)*
+//! factor     := number [unit] | target | "(" operand ")" | "-" factor
+//! ```
+//!
+//! A comparison without a left operand applies to the policy's own target,
+//! so `"> 80"` keeps working. Targets are referenced by their `target:` name
+//! (`gpu_power_watts > 0.9 * gpu_power_limit_watts`) and resolved for the
+//! same resource. Literals may carry a unit (`W`, `kW`, `C`, `%`, `MiB`, ...)
+//! that is converted to the unit the agent reports, and operands of a
+//! comparison must have the same dimension. `between` is inclusive.
+
+use std::fmt;
+use std::str::FromStr;
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use thiserror::Error;
+
+use super::PolicyTarget;
+
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+pub enum ConditionError {
+    #[error("parse error at char {pos}: {msg}")]
+    Parse { pos: usize, msg: String },
+    #[error("{0}")]
+    Units(String),
+}
+
+/// What a value measures; literals without a unit are `Scalar` and combine
+/// with anything.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Dimension {
+    Scalar,
+    /// Watts.
+    Power,
+    /// Degrees Celsius.
+    Temperature,
+    Percent,
+    Bytes,
+    /// Megahertz.
+    Frequency,
+    /// Milliseconds.
+    Time,
+}
+
+impl Dimension {
+    /// Renders a value in the agent's unit for this dimension.
+    pub fn format(self, value: f64) -> String {
+        match self {
+            Dimension::Scalar => format!("{value:.2}"),
+            Dimension::Power => format!("{value:.1}W"),
+            Dimension::Temperature => format!("{value:.1}C"),
+            Dimension::Percent => format!("{value:.1}%"),
+            Dimension::Bytes => format!("{:.1}MiB", value / MIB),
+            Dimension::Frequency => format!("{value:.0}MHz"),
+            Dimension::Time => format!("{value:.1}ms"),
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Dimension::Scalar => "a plain number",
+            Dimension::Power => "power",
+            Dimension::Temperature => "temperature",
+            Dimension::Percent => "a percentage",
+            Dimension::Bytes => "bytes",
+            Dimension::Frequency => "frequency",
+            Dimension::Time => "time",
+        }
+    }
+}
+
+const MIB: f64 = 1024.0 * 1024.0;
+
+/// Literal suffixes with their dimension and factor to the agent's unit.
+const UNITS: &[(&str, Dimension, f64)] = &[
+    ("mW", Dimension::Power, 0.001),
+    ("W", Dimension::Power, 1.0),
+    ("kW", Dimension::Power, 1_000.0),
+    ("C", Dimension::Temperature, 1.0),
+    ("°C", Dimension::Temperature, 1.0),
+    ("%", Dimension::Percent, 1.0),
+    ("B", Dimension::Bytes, 1.0),
+    ("KB", Dimension::Bytes, 1e3),
+    ("MB", Dimension::Bytes, 1e6),
+    ("GB", Dimension::Bytes, 1e9),
+    ("TB", Dimension::Bytes, 1e12),
+    ("KiB", Dimension::Bytes, 1024.0),
+    ("MiB", Dimension::Bytes, MIB),
+    ("GiB", Dimension::Bytes, MIB * 1024.0),
+    ("TiB", Dimension::Bytes, MIB * MIB),
+    ("MHz", Dimension::Frequency, 1.0),
+    ("GHz", Dimension::Frequency, 1_000.0),
+    ("us", Dimension::Time, 0.001),
+    ("ms", Dimension::Time, 1.0),
+    ("s", Dimension::Time, 1_000.0),
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum CmpOp {
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    Eq,
+    Ne,
+}
+
+impl CmpOp {
+    fn apply(self, lhs: f64, rhs: f64) -> bool {
+        match self {
+            CmpOp::Lt => lhs < rhs,
+            CmpOp::Le => lhs <= rhs,
+            CmpOp::Gt => lhs > rhs,
+            CmpOp::Ge => lhs >= rhs,
+            CmpOp::Eq => (lhs - rhs).abs() < f64::EPSILON,
+            CmpOp::Ne => (lhs - rhs).abs() >= f64::EPSILON,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ArithOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Operand {
+    /// The policy's own target.
+    Subject,
+    Literal {
+        value: f64,
+        dimension: Dimension,
+    },
+    Target(PolicyTarget),
+    Neg(Box<Operand>),
+    Binary {
+        op: ArithOp,
+        lhs: Box<Operand>,
+        rhs: Box<Operand>,
+    },
+}
+
+impl Operand {
+    fn evaluate(
+        &self,
+        subject: PolicyTarget,
+        resolve: &dyn Fn(PolicyTarget) -> Option<f64>,
+    ) -> Option<f64> {
+        Some(match self {
+            Operand::Subject => resolve(subject)?,
+            Operand::Literal { value, .. } => *value,
+            Operand::Target(target) => resolve(*target)?,
+            Operand::Neg(inner) => -inner.evaluate(subject, resolve)?,
+            Operand::Binary { op, lhs, rhs } => {
+                let (lhs, rhs) = (
+                    lhs.evaluate(subject, resolve)?,
+                    rhs.evaluate(subject, resolve)?,
+                );
+                match op {
+                    ArithOp::Add => lhs + rhs,
+                    ArithOp::Sub => lhs - rhs,
+                    ArithOp::Mul => lhs * rhs,
+                    ArithOp::Div if rhs == 0.0 => return None,
+                    ArithOp::Div => lhs / rhs,
+                }
+            }
+        })
+    }
+
+    fn dimension(&self, subject: Dimension) -> Result<Dimension, ConditionError> {
+        match self {
+            Operand::Subject => Ok(subject),
+            Operand::Literal { dimension, .. } => Ok(*dimension),
+            Operand::Target(target) => Ok(target.dimension()),
+            Operand::Neg(inner) => inner.dimension(subject),
+            Operand::Binary { op, lhs, rhs } => {
+                let (lhs, rhs) = (lhs.dimension(subject)?, rhs.dimension(subject)?);
+                match (op, lhs, rhs) {
+                    (_, d, Dimension::Scalar) => Ok(d),
+                    (ArithOp::Add | ArithOp::Sub | ArithOp::Mul, Dimension::Scalar, d) => Ok(d),
+                    (ArithOp::Add | ArithOp::Sub, a, b) if a == b => Ok(a),
+                    (ArithOp::Div, a, b) if a == b => Ok(Dimension::Scalar),
+                    (_, a, b) => Err(ConditionError::Units(format!(
+                        "cannot combine {} with {}",
+                        a.name(),
+                        b.name()
+                    ))),
+                }
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Expr {
+    Compare {
+        lhs: Operand,
+        op: CmpOp,
+        rhs: Operand,
+    },
+    Between {
+        value: Operand,
+        low: Operand,
+        high: Operand,
+    },
+    And(Box<Expr>, Box<Expr>),
+    Or(Box<Expr>, Box<Expr>),
+    Not(Box<Expr>),
+}
+
+impl Expr {
+    /// Three-valued: `None` when a referenced value is unavailable and the
+    /// rest of the expression does not decide the outcome.
+    fn evaluate(
+        &self,
+        subject: PolicyTarget,
+        resolve: &dyn Fn(PolicyTarget) -> Option<f64>,
+    ) -> Option<bool> {
+        match self {
+            Expr::Compare { lhs, op, rhs } => Some(op.apply(
+                lhs.evaluate(subject, resolve)?,
+                rhs.evaluate(subject, resolve)?,
+            )),
+            Expr::Between { value, low, high } => {
+                let value = value.evaluate(subject, resolve)?;
+                Some(
+                    low.evaluate(subject, resolve)? <= value
+                        && value <= high.evaluate(subject, resolve)?,
+                )
+            }
+            Expr::And(lhs, rhs) => match (
+                lhs.evaluate(subject, resolve),
+                rhs.evaluate(subject, resolve),
+            ) {
+                (Some(false), _) | (_, Some(false)) => Some(false),
+                (Some(true), Some(true)) => Some(true),
+                _ => None,
+            },
+            Expr::Or(lhs, rhs) => match (
+                lhs.evaluate(subject, resolve),
+                rhs.evaluate(subject, resolve),
+            ) {
+                (Some(true), _) | (_, Some(true)) => Some(true),
+                (Some(false), Some(false)) => Some(false),
+                _ => None,
+            },
+            Expr::Not(inner) => inner.evaluate(subject, resolve).map(|v| !v),
+        }
+    }
+
+    fn check(&self, subject: Dimension) -> Result<(), ConditionError> {
+        let same = |operands: &[&Operand]| -> Result<(), ConditionError> {
+            let mut seen = Dimension::Scalar;
+            for operand in operands {
+                match operand.dimension(subject)? {
+                    Dimension::Scalar => {}
+                    d if seen == Dimension::Scalar || seen == d => seen = d,
+                    d => {
+                        return Err(ConditionError::Units(format!(
+                            "cannot compare {} with {}",
+                            seen.name(),
+                            d.name()
+                        )))
+                    }
+                }
+            }
+            Ok(())
+        };
+        match self {
+            Expr::Compare { lhs, rhs, .. } => same(&[lhs, rhs]),
+            Expr::Between { value, low, high } => same(&[value, low, high]),
+            Expr::And(lhs, rhs) | Expr::Or(lhs, rhs) => {
+                lhs.check(subject)?;
+                rhs.check(subject)
+            }
+            Expr::Not(inner) => inner.check(subject),
+        }
+    }
+}
+
+/// A parsed policy condition. It (de)serializes as its source string, so
+/// invalid conditions fail when the profile is loaded.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Condition {
+    source: String,
+    expr: Expr,
+}
+
+impl Condition {
+    pub fn parse(source: &str) -> Result<Self, ConditionError> {
+        let mut parser = Parser {
+            src: source,
+            pos: 0,
+        };
+        let expr = parser.condition()?;
+        parser.skip_ws();
+        if parser.pos < source.len() {
+            return Err(parser.error("unexpected trailing input"));
+        }
+        Ok(Self {
+            source: source.trim().to_string(),
+            expr,
+        })
+    }
+
+    /// Checks that every comparison relates values of one dimension, with
+    /// `subject` standing in for comparisons without a left operand.
+    pub fn check(&self, subject: PolicyTarget) -> Result<(), ConditionError> {
+        self.expr.check(subject.dimension())
+    }
+
+    /// Evaluates the condition for one resource. `resolve` returns the
+    /// current value of a target on that resource; `None` when a value the
+    /// outcome depends on is missing.
+    pub fn evaluate(
+        &self,
+        subject: PolicyTarget,
+        resolve: impl Fn(PolicyTarget) -> Option<f64>,
+    ) -> Option<bool> {
+        self.expr.evaluate(subject, &resolve)
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.source
+    }
+}
+
+impl fmt::Display for Condition {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.source)
+    }
+}
+
+impl FromStr for Condition {
+    type Err = ConditionError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::parse(s)
+    }
+}
+
+impl Serialize for Condition {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.source)
+    }
+}
+
+impl<'de> Deserialize<'de> for Condition {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let source = String::deserialize(deserializer)?;
+        Self::parse(&source)
+            .map_err(|err| serde::de::Error::custom(format!("invalid condition {source:?}: {err}")))
+    }
+}
+
+struct Parser<'a> {
+    src: &'a str,
+    pos: usize,
+}
+
+impl Parser<'_> {
+    fn error(&self, msg: impl Into<String>) -> ConditionError {
+        ConditionError::Parse {
+            pos: self.pos,
+            msg: msg.into(),
+        }
+    }
+
+    fn rest(&self) -> &str {
+        &self.src[self.pos..]
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.rest().chars().next()
+    }
+
+    fn skip_ws(&mut self) {
+        let trimmed = self.rest().trim_start();
+        self.pos = self.src.len() - trimmed.len();
+    }
+
+    fn eat(&mut self, token: &str) -> bool {
+        self.skip_ws();
+        if self.rest().starts_with(token) {
+            self.pos += token.len();
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expect(&mut self, token: &str) -> Result<(), ConditionError> {
+        if self.eat(token) {
+            Ok(())
+        } else {
+            Err(self.error(format!("expected {token:?}")))
+        }
+    }
+
+    fn peek_ident(&mut self) -> Option<&str> {
+        self.skip_ws();
+        let rest = self.rest();
+        let mut end = 0;
+        for (i, c) in rest.char_indices() {
+            let ok = c.is_ascii_alphabetic() || c == '_' || (i > 0 && c.is_ascii_digit());
+            if !ok {
+                break;
+            }
+            end = i + c.len_utf8();
+        }
+        (end > 0).then(|| &rest[..end])
+    }
+
+    fn eat_keyword(&mut self, keyword: &str) -> bool {
+        if self.peek_ident() == Some(keyword) {
+            self.pos += keyword.len();
+            true
+        } else {
+            false
+        }
+    }
+
+    fn condition(&mut self) -> Result<Expr, ConditionError> {
+        let mut lhs = self.and()?;
+        while self.eat_keyword("or") {
+            lhs = Expr::Or(Box::new(lhs), Box::new(self.and()?));
+        }
+        Ok(lhs)
+    }
+
+    fn and(&mut self) -> Result<Expr, ConditionError> {
+        let mut lhs = self.unary()?;
+        while self.eat_keyword("and") {
+            lhs = Expr::And(Box::new(lhs), Box::new(self.unary()?));
+        }
+        Ok(lhs)
+    }
+
+    fn unary(&mut self) -> Result<Expr, ConditionError> {
+        if self.eat_keyword("not") {
+            return Ok(Expr::Not(Box::new(self.unary()?)));
+        }
+        // `(` opens either a nested condition or an arithmetic operand
+        // (`(a + b) > c`); try the former and fall back.
+        let start = self.pos;
+        if self.eat("(") {
+            if let Ok(expr) = self.condition() {
+                if self.eat(")") && !self.at_operator() {
+                    return Ok(expr);
+                }
+            }
+            self.pos = start;
+        }
+        self.comparison()
+    }
+
+    /// Whether an arithmetic or comparison operator follows, i.e. a
+    /// parenthesised group was an operand.
+    fn at_operator(&mut self) -> bool {
+        self.skip_ws();
+        matches!(
+            self.peek(),
+            Some('<' | '>' | '=' | '!' | '+' | '-' | '*' | '/')
+        ) || self.peek_ident() == Some("between")
+    }
+
+    fn comparison(&mut self) -> Result<Expr, ConditionError> {
+        let lhs = if self.at_comparison() {
+            Operand::Subject
+        } else {
+            self.operand()?
+        };
+        if self.eat_keyword("between") {
+            let low = self.operand()?;
+            if !self.eat_keyword("and") {
+                return Err(self.error("expected \"and\" in between"));
+            }
+            let high = self.operand()?;
+            return Ok(Expr::Between {
+                value: lhs,
+                low,
+                high,
+            });
+        }
+        let op = if self.eat("<=") {
+            CmpOp::Le
+        } else if self.eat(">=") {
+            CmpOp::Ge
+        } else if self.eat("==") || self.eat("=") {
+            CmpOp::Eq
+        } else if self.eat("!=") {
+            CmpOp::Ne
+        } else if self.eat("<") {
+            CmpOp::Lt
+        } else if self.eat(">") {
+            CmpOp::Gt
+        } else {
+            return Err(self.error("expected comparison operator"));
+        };
+        let rhs = self.operand()?;
+        Ok(Expr::Compare { lhs, op, rhs })
+    }
+
+    fn at_comparison(&mut self) -> bool {
+        self.skip_ws();
+        matches!(self.peek(), Some('<' | '>' | '=' | '!')) || self.peek_ident() == Some("between")
+    }
+
+    fn operand(&mut self) -> Result<Operand, ConditionError> {
+        let mut lhs = self.term()?;
+        loop {
+            let op = if self.eat("+") {
+                ArithOp::Add
+            } else if self.eat("-") {
+                ArithOp::Sub
+            } else {
+                return Ok(lhs);
+            };
+            let rhs = self.term()?;
+            lhs = Operand::Binary {
+                op,
+                lhs: Box::new(lhs),
+                rhs: Box::new(rhs),
+            };
+        }
+    }
+
+    fn term(&mut self) -> Result<Operand, ConditionError> {
+        let mut lhs = self.factor()?;
+        loop {
+            let op = if self.eat("*") {
+                ArithOp::Mul
+            } else if self.eat("/") {
+                ArithOp::Div
+            } else {
+                return Ok(lhs);
+            };
+            let rhs = self.factor()?;
+            lhs = Operand::Binary {
+                op,
+                lhs: Box::new(lhs),
+                rhs: Box::new(rhs),
+            };
+        }
+    }
+
+    fn factor(&mut self) -> Result<Operand, ConditionError> {
+        if self.eat("-") {
+            return Ok(match self.factor()? {
+                Operand::Literal { value, dimension } => Operand::Literal {
+                    value: -value,
+                    dimension,
+                },
+                operand => Operand::Neg(Box::new(operand)),
+            });
+        }
+        if self.eat("(") {
+            let operand = self.operand()?;
+            self.expect(")")?;
+            return Ok(operand);
+        }
+        match self.peek() {
+            Some(c) if c.is_ascii_digit() || c == '.' => return self.number(),
+            _ => {}
+        }
+        let Some(ident) = self.peek_ident().map(str::to_string) else {
+            return Err(self.error("expected number or target"));
+        };
+        let target = PolicyTarget::from_name(&ident)
+            .ok_or_else(|| self.error(format!("unknown target {ident:?}")))?;
+        self.pos += ident.len();
+        Ok(Operand::Target(target))
+    }
+
+    fn number(&mut self) -> Result<Operand, ConditionError> {
+        let rest = self.rest();
+        let end = rest
+            .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+            .unwrap_or(rest.len());
+        let value = rest[..end]
+            .parse::<f64>()
+            .map_err(|_| self.error(format!("invalid number {:?}", &rest[..end])))?;
+        self.pos += end;
+        let (dimension, factor) = self.unit()?;
+        Ok(Operand::Literal {
+            value: value * factor,
+            dimension,
+        })
+    }
+
+    /// Unit suffix of a number literal, attached or after a space.
+    fn unit(&mut self) -> Result<(Dimension, f64), ConditionError> {
+        let attached = self
+            .peek()
+            .is_some_and(|c| c.is_alphabetic() || c == '%' || c == '°');
+        let start = self.pos;
+        self.skip_ws();
+        let rest = self.rest();
+        let end = if rest.starts_with('%') {
+            1
+        } else {
+            rest.find(|c: char| !(c.is_alphabetic() || c == '°'))
+                .unwrap_or(rest.len())
+        };
+        let word = &rest[..end];
+        if let Some((_, dimension, factor)) = UNITS.iter().find(|(unit, _, _)| *unit == word) {
+            self.pos += end;
+            return Ok((*dimension, *factor));
+        }
+        if attached {
+            return Err(self.error(format!("unknown unit {word:?}")));
+        }
+        self.pos = start;
+        Ok((Dimension::Scalar, 1.0))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn eval(condition: &str, values: &[(PolicyTarget, f64)]) -> Option<bool> {
+        Condition::parse(condition)
+            .unwrap()
+            .evaluate(PolicyTarget::GpuTempCelsius, |target| {
+                values.iter().find(|(t, _)| *t == target).map(|(_, v)| *v)
+            })
+    }
+
+    #[test]
+    fn evaluates_compound_conditions() {
+        let temp = |v| [(PolicyTarget::GpuTempCelsius, v)];
+        assert_eq!(eval("> 80", &temp(85.0)), Some(true));
+        assert_eq!(eval(">= 80C", &temp(79.0)), Some(false));
+        assert_eq!(eval("between 70 and 85", &temp(85.0)), Some(true));
+        assert_eq!(eval("not between 70C and 85C", &temp(60.0)), Some(true));
+        assert_eq!(eval("< 10 or > 90", &temp(50.0)), Some(false));
+        assert_eq!(
+            eval(
+                "(> 80 or < 5) and gpu_utilization < 5%",
+                &[
+                    (PolicyTarget::GpuTempCelsius, 2.0),
+                    (PolicyTarget::GpuUtilization, 1.0),
+                ]
+            ),
+            Some(true)
+        );
+        let power = [
+            (PolicyTarget::GpuPowerWatts, 650.0),
+            (PolicyTarget::GpuPowerLimitWatts, 700.0),
+        ];
+        assert_eq!(
+            eval("gpu_power_watts > 0.9 * gpu_power_limit_watts", &power),
+            Some(true)
+        );
+        assert_eq!(
+            eval(
+                "(gpu_power_watts - 50W) / gpu_power_limit_watts > 0.9",
+                &power
+            ),
+            Some(false)
+        );
+        assert_eq!(eval("gpu_power_watts < 0.5kW", &power), Some(false));
+        // Missing values only matter when they decide the outcome.
+        assert_eq!(
+            eval("> 80 and gpu_utilization < 5", &temp(70.0)),
+            Some(false)
+        );
+        assert_eq!(eval("> 80 and gpu_utilization < 5", &temp(90.0)), None);
+    }
+
+    #[test]
+    fn rejects_invalid_conditions() {
+        for bad in [
+            "",
+            "80",
+            "> 80 C and",
+            "> 80X",
+            "gpu_temp > 80",
+            "between 1 or 2",
+            "> (80",
+        ] {
+            assert!(Condition::parse(bad).is_err(), "{bad:?} should not parse");
+        }
+        let units = |c: &str| {
+            Condition::parse(c)
+                .unwrap()
+                .check(PolicyTarget::GpuTempCelsius)
+        };
+        assert!(units("> 80C").is_ok());
+        assert!(units("> 300W").is_err());
+        assert!(units("gpu_power_watts > gpu_utilization").is_err());
+        assert!(units("gpu_power_watts / gpu_power_limit_watts > 0.9").is_ok());
+    }
+}
@@ -0,0 +1,279 @@
+// ESNODE | Source Available BUSL-1.1 | Copyright (c) 2025 Estimatedstocks AB
+//! Stateful policy evaluation across enforcement ticks.
+//!
+//! Each (profile, policy, resource) moves through three states:
+//!
+//! ```text
+//! Satisfied --condition holds--> Pending --held for `duration`--> Violated
+//!     ^                            |                                 |
+//!     +------condition fails-------+                                 |
+//!     +----condition fails and `clear_condition` holds---------------+
+//! ```
+//!
+//! Rules without a `duration` go straight to `Violated`. A violated rule
+//! stays violated inside the band between `condition` and `clear_condition`,
+//! so actions are not toggled by a value hovering on the threshold. When a
+//! value is unavailable the resource is reported `Skipped` and keeps its
+//! state. Outside its `schedule` or in a maintenance window a policy is
+//! `Inactive` or `Maintenance` and its state is dropped.
+
+use std::collections::{HashMap, HashSet};
+
+use super::{EfficiencyProfile, PlanResult, PlanStatus};
+use crate::state::StatusSnapshot;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Track {
+    Pending { since_ms: i64 },
+    Violated { since_ms: i64 },
+}
+
+/// (profile, policy, resource).
+type TrackKey = (String, String, String);
+
+#[derive(Debug, Default)]
+pub struct PolicyEvaluator {
+    tracks: HashMap<TrackKey, Track>,
+}
+
+impl PolicyEvaluator {
+    /// Evaluates `profile` at `now_ms` (unix ms), advancing the sustained
+    /// violation state of every policy and resource. State of resources or
+    /// policies that no longer appear is dropped.
+    pub fn evaluate(
+        &mut self,
+        profile: &EfficiencyProfile,
+        status: &StatusSnapshot,
+        now_ms: i64,
+    ) -> PlanResult {
+        let profile_name = &profile.metadata.name;
+        let mut seen = HashSet::new();
+        let mut plans = Vec::new();
+        let maintenance = profile.maintenance_window(now_ms);
+
+        for observation in profile.observe(status) {
+            let rule = &profile.policies[observation.policy];
+            let mut plan = observation.plan;
+            let key = (
+                profile_name.clone(),
+                plan.policy_name.clone(),
+                plan.target_resource.clone(),
+            );
+            let off = match maintenance {
+                Some(window) => {
+                    plan.maintenance_window = Some(window.name.clone());
+                    Some(PlanStatus::Maintenance)
+                }
+                None if !rule.is_scheduled(now_ms) => Some(PlanStatus::Inactive),
+                None => None,
+            };
+            if let Some(off) = off {
+                plan.status = off;
+                self.tracks.remove(&key);
+                seen.insert(key);
+                plans.push(plan);
+                continue;
+            }
+            let hold_ms = rule.duration.map_or(0, |d| d.as_millis() as i64);
+            let previous = self.tracks.get(&key).copied();
+            let next = match (previous, &plan.status) {
+                (_, PlanStatus::Skipped) => previous,
+                (Some(Track::Violated { since_ms }), _) => match observation.cleared {
+                    Some(true) => None,
+                    _ => Some(Track::Violated { since_ms }),
+                },
+                (Some(Track::Pending { since_ms }), PlanStatus::Violated)
+                    if now_ms - since_ms >= hold_ms =>
+                {
+                    Some(Track::Violated { since_ms })
+                }
+                (Some(Track::Pending { since_ms }), PlanStatus::Violated) => {
+                    Some(Track::Pending { since_ms })
+                }
+                (None, PlanStatus::Violated) if hold_ms == 0 => {
+                    Some(Track::Violated { since_ms: now_ms })
+                }
+                (None, PlanStatus::Violated) => Some(Track::Pending { since_ms: now_ms }),
+                _ => None,
+            };
+
+            if plan.status != PlanStatus::Skipped {
+                plan.status = match next {
+                    Some(Track::Pending { .. }) => PlanStatus::Pending,
+                    Some(Track::Violated { .. }) => PlanStatus::Violated,
+                    None => PlanStatus::Satisfied,
+                };
+            }
+            plan.since_ms = match next {
+                Some(Track::Pending { since_ms } | Track::Violated { since_ms }) => Some(since_ms),
+                None => None,
+            };
+            if let (PlanStatus::Violated, Some(Track::Violated { since_ms })) = (&plan.status, next)
+            {
+                let step = rule.escalation_step(now_ms - since_ms);
+                if !rule.escalation.is_empty() {
+                    plan.escalation_step = Some(step);
+                }
+                plan.computed_action = Some(rule.action_description(step));
+            }
+            match next {
+                Some(track) => {
+                    self.tracks.insert(key.clone(), track);
+                }
+                None => {
+                    self.tracks.remove(&key);
+                }
+            }
+            seen.insert(key);
+            plans.push(plan);
+        }
+
+        self.tracks
+            .retain(|key, _| key.0 != *profile_name || seen.contains(key));
+
+        PlanResult {
+            profile_name: profile_name.clone(),
+            matched_policies: plans,
+        }
+    }
+
+    /// Drops the state of profiles for which `keep` is false, e.g. profiles
+    /// that were removed or no longer apply.
+    pub fn retain_profiles(&mut self, keep: impl Fn(&str) -> bool) {
+        self.tracks.retain(|key, _| keep(&key.0));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::GpuStatus;
+
+    fn profile(extra: &str) -> EfficiencyProfile {
+        EfficiencyProfile::from_yaml(&format!(
+            r#"
+apiVersion: v1
+kind: EfficiencyProfile
+metadata: {{ name: hot, version: "1" }}
+selectors: {{}}
+policies:
+  - name: thermal
+    target: gpu_temp_celsius
+    condition: "> 85"
+    severity: critical
+    action: {{ type: alert }}
+{extra}
+"#
+        ))
+        .unwrap()
+    }
+
+    fn at(temp: Option<f64>) -> StatusSnapshot {
+        StatusSnapshot {
+            gpus: vec![GpuStatus {
+                uuid: Some("GPU-0".to_string()),
+                temperature_celsius: temp,
+                ..Default::default()
+            }],
+            ..Default::default()
+        }
+    }
+
+    fn statuses(profile: &EfficiencyProfile, ticks: &[(i64, Option<f64>)]) -> Vec<PlanStatus> {
+        let mut evaluator = PolicyEvaluator::default();
+        ticks
+            .iter()
+            .map(|(ts, temp)| {
+                evaluator
+                    .evaluate(profile, &at(*temp), *ts)
+                    .matched_policies[0]
+                    .status
+                    .clone()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn duration_delays_violation_until_sustained() {
+        use PlanStatus::*;
+        let sustained = profile("    duration: 5m");
+        assert_eq!(
+            statuses(
+                &sustained,
+                &[
+                    (0, Some(90.0)),
+                    (60_000, Some(90.0)),
+                    (120_000, Some(80.0)),
+                    (180_000, Some(90.0)),
+                    (479_999, Some(90.0)),
+                    (480_000, Some(90.0)),
+                    (481_000, None),
+                    (482_000, Some(90.0)),
+                ]
+            ),
+            [Pending, Pending, Satisfied, Pending, Pending, Violated, Skipped, Violated]
+        );
+    }
+
+    #[test]
+    fn clear_condition_holds_violation_inside_band() {
+        use PlanStatus::*;
+        let banded = profile("    clear_condition: \"< 80\"");
+        assert_eq!(
+            statuses(
+                &banded,
+                &[
+                    (0, Some(90.0)),
+                    (1_000, Some(84.0)),
+                    (2_000, Some(86.0)),
+                    (3_000, Some(79.0)),
+                    (4_000, Some(84.0)),
+                ]
+            ),
+            [Violated, Violated, Violated, Satisfied, Satisfied]
+        );
+    }
+
+    #[test]
+    fn escalation_steps_follow_how_long_the_condition_held() {
+        use PlanStatus::*;
+        let ladder = profile(
+            r#"    duration: 1m
+    escalation:
+      - after: 5m
+        action: { type: throttle_power, parameters: { limit_percent: 80 } }
+      - after: 10m
+        action: { type: throttle_power, parameters: { limit_percent: 60 } }"#,
+        );
+        let mut evaluator = PolicyEvaluator::default();
+        let steps: Vec<_> = [0, 60_000, 299_999, 300_000, 600_000, 3_600_000]
+            .into_iter()
+            .map(|ts| {
+                let plan = &evaluator
+                    .evaluate(&ladder, &at(Some(90.0)), ts)
+                    .matched_policies[0];
+                (plan.status.clone(), plan.escalation_step)
+            })
+            .collect();
+        assert_eq!(
+            steps,
+            [
+                (Pending, None),
+                (Violated, Some(0)),
+                (Violated, Some(0)),
+                (Violated, Some(1)),
+                (Violated, Some(2)),
+                (Violated, Some(2)),
+            ]
+        );
+        let rule = &ladder.policies[0];
+        assert_eq!(rule.action_at(2).number("limit_percent"), Some(60.0));
+
+        let unordered = EfficiencyProfile::from_yaml(
+            &serde_yaml::to_string(&ladder)
+                .unwrap()
+                .replace("after: 5m", "after: 30s"),
+        );
+        assert!(unordered.unwrap_err().to_string().contains("escalation"));
+    }
+}
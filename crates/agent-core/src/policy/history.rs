@@ -0,0 +1,409 @@
+// ESNODE | Source Available BUSL-1.1 | Copyright (c) 2025 Estimatedstocks AB
+//! What the enforcement loop decided, for the HTTP API.
+//!
+//! [`PolicyHistory`] is shared between the enforcement task and the HTTP
+//! server. It keeps the latest [`PlanResult`] of every profile
+//! (`/v1/policy/plan`), a bounded log of policy events (`/v1/policy/history`)
+//! and broadcasts each event as it happens (`/events`).
+//!
+//! Events are derived by comparing consecutive ticks: a (profile, policy,
+//! resource) is `violated` when it becomes `VIOLATED` or `CONFLICT` and
+//! `cleared` when it stops being either (including when the resource or
+//! profile goes away). A `SKIPPED` tick changes nothing. `enforced` and
+//! `failed` record the outcome of each action the enforcer ran,
+//! `deescalated` the limits restored once an escalation ladder clears and
+//! `restored` the clocks put back once a policy that changed them clears.
+
+use std::collections::{HashMap, VecDeque};
+use std::sync::Arc;
+
+use parking_lot::Mutex;
+use serde::Serialize;
+use tokio::sync::broadcast;
+
+use super::{PlanResult, PlanStatus, PolicyPlan};
+
+/// Events kept for `/v1/policy/history`.
+pub const HISTORY_CAPACITY: usize = 1000;
+/// Events buffered per `/events` subscriber before it starts missing some.
+const FEED_CAPACITY: usize = 256;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PolicyEventKind {
+    Violated,
+    Cleared,
+    Enforced,
+    Failed,
+    Deescalated,
+    Restored,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct PolicyEvent {
+    /// Increases by one per event; `since` for `/v1/policy/history`.
+    pub seq: u64,
+    pub unix_ms: i64,
+    pub kind: PolicyEventKind,
+    pub profile_name: String,
+    pub policy_name: String,
+    pub target_resource: String,
+    pub current_value: String,
+    pub threshold: String,
+    /// The enforcer's message or error for `enforced`, `failed`,
+    /// `deescalated` and `restored`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub message: Option<String>,
+}
+
+/// The latest evaluation, as served by `/v1/policy/plan`.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct PlanSnapshot {
+    /// Unix ms of the enforcement tick; `None` before the first one.
+    pub evaluated_unix_ms: Option<i64>,
+    pub profiles: Vec<PlanResult>,
+}
+
+/// (profile, policy, resource).
+type Key = (String, String, String);
+
+#[derive(Default)]
+struct Inner {
+    latest: PlanSnapshot,
+    /// Currently violated, with the plan that last reported it.
+    violated: HashMap<Key, PolicyPlan>,
+    events: VecDeque<PolicyEvent>,
+    next_seq: u64,
+}
+
+#[derive(Clone)]
+pub struct PolicyHistory {
+    inner: Arc<Mutex<Inner>>,
+    feed: broadcast::Sender<PolicyEvent>,
+}
+
+impl Default for PolicyHistory {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl PolicyHistory {
+    pub fn new() -> Self {
+        Self {
+            inner: Arc::new(Mutex::new(Inner {
+                next_seq: 1,
+                ..Default::default()
+            })),
+            feed: broadcast::channel(FEED_CAPACITY).0,
+        }
+    }
+
+    /// Stores the plans of one enforcement tick and records the policies
+    /// that became violated or cleared since the previous one.
+    pub fn update(&self, plans: &[PlanResult], now_ms: i64) {
+        let mut inner = self.inner.lock();
+        let mut violated = HashMap::new();
+        for result in plans {
+            for plan in &result.matched_policies {
+                let key = (
+                    result.profile_name.clone(),
+                    plan.policy_name.clone(),
+                    plan.target_resource.clone(),
+                );
+                let still = match plan.status {
+                    PlanStatus::Violated | PlanStatus::Conflict => true,
+                    PlanStatus::Skipped => inner.violated.contains_key(&key),
+                    PlanStatus::Satisfied
+                    | PlanStatus::Pending
+                    | PlanStatus::Inactive
+                    | PlanStatus::Maintenance => false,
+                };
+                if still {
+                    violated.insert(key, plan.clone());
+                }
+            }
+        }
+
+        let mut events = Vec::new();
+        for (key, plan) in &violated {
+            if !inner.violated.contains_key(key) {
+                events.push((PolicyEventKind::Violated, key.clone(), plan.clone()));
+            }
+        }
+        for (key, previous) in &inner.violated {
+            if !violated.contains_key(key) {
+                // The latest reading, when the resource is still reported.
+                let plan = plans
+                    .iter()
+                    .filter(|r| r.profile_name == key.0)
+                    .flat_map(|r| &r.matched_policies)
+                    .find(|p| p.policy_name == key.1 && p.target_resource == key.2)
+                    .unwrap_or(previous);
+                events.push((PolicyEventKind::Cleared, key.clone(), plan.clone()));
+            }
+        }
+        events.sort_by(|a, b| a.1.cmp(&b.1));
+
+        inner.violated = violated;
+        inner.latest = PlanSnapshot {
+            evaluated_unix_ms: Some(now_ms),
+            profiles: plans.to_vec(),
+        };
+        for (kind, key, plan) in events {
+            self.push(&mut inner, kind, key, &plan, None, now_ms);
+        }
+    }
+
+    /// Records the outcome of running the action of `plan`.
+    pub fn record_enforcement(
+        &self,
+        profile_name: &str,
+        plan: &PolicyPlan,
+        outcome: Result<&str, &str>,
+        now_ms: i64,
+    ) {
+        let (kind, message) = match outcome {
+            Ok(message) => (PolicyEventKind::Enforced, message),
+            Err(error) => (PolicyEventKind::Failed, error),
+        };
+        let key = (
+            profile_name.to_string(),
+            plan.policy_name.clone(),
+            plan.target_resource.clone(),
+        );
+        let mut inner = self.inner.lock();
+        self.push(
+            &mut inner,
+            kind,
+            key,
+            plan,
+            Some(message.to_string()),
+            now_ms,
+        );
+    }
+
+    /// Records the outcome of restoring what an escalation ladder changed
+    /// on `target_resource` once the policy cleared.
+    pub fn record_deescalation(
+        &self,
+        profile_name: &str,
+        policy_name: &str,
+        target_resource: &str,
+        outcome: Result<&str, &str>,
+        now_ms: i64,
+    ) {
+        let outcome = outcome.map(|message| (PolicyEventKind::Deescalated, message));
+        self.record_undo(profile_name, policy_name, target_resource, outcome, now_ms);
+    }
+
+    /// Records the outcome of putting back a clock a policy locked or reset
+    /// on `target_resource`, once it cleared or the agent stopped.
+    pub fn record_restore(
+        &self,
+        profile_name: &str,
+        policy_name: &str,
+        target_resource: &str,
+        outcome: Result<&str, &str>,
+        now_ms: i64,
+    ) {
+        let outcome = outcome.map(|message| (PolicyEventKind::Restored, message));
+        self.record_undo(profile_name, policy_name, target_resource, outcome, now_ms);
+    }
+
+    fn record_undo(
+        &self,
+        profile_name: &str,
+        policy_name: &str,
+        target_resource: &str,
+        outcome: Result<(PolicyEventKind, &str), &str>,
+        now_ms: i64,
+    ) {
+        let (kind, message) = match outcome {
+            Ok((kind, message)) => (kind, message),
+            Err(error) => (PolicyEventKind::Failed, error),
+        };
+        let key = (
+            profile_name.to_string(),
+            policy_name.to_string(),
+            target_resource.to_string(),
+        );
+        let mut inner = self.inner.lock();
+        // The reading that cleared it, unless the resource went away.
+        let plan = inner
+            .latest
+            .profiles
+            .iter()
+            .filter(|r| r.profile_name == profile_name)
+            .flat_map(|r| &r.matched_policies)
+            .find(|p| p.policy_name == policy_name && p.target_resource == target_resource)
+            .cloned()
+            .unwrap_or_else(|| PolicyPlan {
+                policy_name: policy_name.to_string(),
+                target_resource: target_resource.to_string(),
+                current_value: "N/A".to_string(),
+                threshold: String::new(),
+                status: PlanStatus::Satisfied,
+                computed_action: None,
+                since_ms: None,
+                conflicts_with: Vec::new(),
+                escalation_step: None,
+                maintenance_window: None,
+            });
+        self.push(
+            &mut inner,
+            kind,
+            key,
+            &plan,
+            Some(message.to_string()),
+            now_ms,
+        );
+    }
+
+    fn push(
+        &self,
+        inner: &mut Inner,
+        kind: PolicyEventKind,
+        (profile_name, policy_name, target_resource): Key,
+        plan: &PolicyPlan,
+        message: Option<String>,
+        now_ms: i64,
+    ) {
+        let event = PolicyEvent {
+            seq: inner.next_seq,
+            unix_ms: now_ms,
+            kind,
+            profile_name,
+            policy_name,
+            target_resource,
+            current_value: plan.current_value.clone(),
+            threshold: plan.threshold.clone(),
+            message,
+        };
+        inner.next_seq += 1;
+        if inner.events.len() == HISTORY_CAPACITY {
+            inner.events.pop_front();
+        }
+        inner.events.push_back(event.clone());
+        // No subscribers is not an error.
+        let _ = self.feed.send(event);
+    }
+
+    pub fn plans(&self) -> PlanSnapshot {
+        self.inner.lock().latest.clone()
+    }
+
+    /// Kept events after `since` (a `seq`), oldest first, at most `limit`
+    /// of the newest ones.
+    pub fn events(&self, since: Option<u64>, limit: Option<usize>) -> Vec<PolicyEvent> {
+        let inner = self.inner.lock();
+        let events: Vec<_> = inner
+            .events
+            .iter()
+            .filter(|e| since.is_none_or(|since| e.seq > since))
+            .cloned()
+            .collect();
+        let skip = limit.map_or(0, |limit| events.len().saturating_sub(limit));
+        events.into_iter().skip(skip).collect()
+    }
+
+    /// Events from now on. A subscriber that falls more than a few hundred
+    /// events behind misses the oldest ones.
+    pub fn subscribe(&self) -> broadcast::Receiver<PolicyEvent> {
+        self.feed.subscribe()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn result(statuses: &[(&str, PlanStatus)]) -> Vec<PlanResult> {
+        vec![PlanResult {
+            profile_name: "p".to_string(),
+            matched_policies: statuses
+                .iter()
+                .map(|(resource, status)| PolicyPlan {
+                    policy_name: "hot".to_string(),
+                    target_resource: resource.to_string(),
+                    current_value: "90C".to_string(),
+                    threshold: "> 85".to_string(),
+                    status: status.clone(),
+                    computed_action: None,
+                    since_ms: None,
+                    conflicts_with: Vec::new(),
+                    escalation_step: None,
+                    maintenance_window: None,
+                })
+                .collect(),
+        }]
+    }
+
+    fn kinds(events: &[PolicyEvent]) -> Vec<(PolicyEventKind, &str)> {
+        events
+            .iter()
+            .map(|e| (e.kind, e.target_resource.as_str()))
+            .collect()
+    }
+
+    #[test]
+    fn transitions_are_recorded_and_broadcast() {
+        use PlanStatus::*;
+        use PolicyEventKind as Kind;
+
+        let history = PolicyHistory::new();
+        let mut feed = history.subscribe();
+
+        history.update(&result(&[("GPU-0", Violated), ("GPU-1", Pending)]), 1);
+        history.record_enforcement(
+            "p",
+            &result(&[("GPU-0", Violated)])[0].matched_policies[0],
+            Ok("capped"),
+            1,
+        );
+        // Skipped keeps GPU-0 violated; GPU-1 becomes violated.
+        history.update(&result(&[("GPU-0", Skipped), ("GPU-1", Conflict)]), 2);
+        // GPU-0 disappears, GPU-1 recovers.
+        history.update(&result(&[("GPU-1", Satisfied)]), 3);
+
+        let events = history.events(None, None);
+        assert_eq!(
+            kinds(&events),
+            [
+                (Kind::Violated, "GPU-0"),
+                (Kind::Enforced, "GPU-0"),
+                (Kind::Violated, "GPU-1"),
+                (Kind::Cleared, "GPU-0"),
+                (Kind::Cleared, "GPU-1"),
+            ]
+        );
+        assert_eq!(events[1].message.as_deref(), Some("capped"));
+        assert_eq!(
+            kinds(&history.events(Some(3), Some(1))),
+            [(Kind::Cleared, "GPU-1")]
+        );
+
+        let plans = history.plans();
+        assert_eq!(plans.evaluated_unix_ms, Some(3));
+        assert_eq!(plans.profiles[0].matched_policies.len(), 1);
+
+        let mut seqs = Vec::new();
+        while let Ok(event) = feed.try_recv() {
+            seqs.push(event.seq);
+        }
+        assert_eq!(seqs, [1, 2, 3, 4, 5]);
+    }
+
+    #[test]
+    fn history_is_bounded() {
+        let history = PolicyHistory::new();
+        let plan = &result(&[("GPU-0", PlanStatus::Violated)])[0].matched_policies[0];
+        for i in 0..HISTORY_CAPACITY + 5 {
+            history.record_enforcement("p", plan, Err("no NVML"), i as i64);
+        }
+        let events = history.events(None, None);
+        assert_eq!(events.len(), HISTORY_CAPACITY);
+        assert_eq!(events[0].seq, 6);
+        assert_eq!(events[0].kind, PolicyEventKind::Failed);
+    }
+}
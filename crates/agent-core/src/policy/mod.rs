@@ -0,0 +1,893 @@
+// ESNODE | Source Available BUSL-1.1 | Copyright (c) 2024 Estimatedstocks AB
+
+mod condition;
+mod evaluator;
+mod history;
+mod replay;
+mod schedule;
+mod schema;
+mod set;
+mod validate;
+
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::time::Duration;
+use thiserror::Error;
+
+use crate::state::{GpuStatus, IotSensorReading, MigDeviceStatus, StatusSnapshot};
+
+pub use condition::{Condition, ConditionError, Dimension};
+pub use evaluator::PolicyEvaluator;
+pub use history::{PlanSnapshot, PolicyEvent, PolicyEventKind, PolicyHistory, HISTORY_CAPACITY};
+pub use replay::{
+    read_snapshots, replay, snapshots_from_samples, PolicyReplay, ReplayReport, ResourceReplay,
+    SnapshotError, REPLAY_METRICS,
+};
+pub use schedule::{parse_days, Cron, MaintenanceWindow, Schedule, TimeOfDay, TimeWindow};
+pub use schema::profile_schema;
+pub use set::{profile_files, ProfileSet, Reload};
+pub use validate::{validate_yaml, Diagnostic};
+
+/// `apiVersion`s this agent understands.
+pub const API_VERSIONS: &[&str] = &["v1"];
+pub const KIND: &str = "EfficiencyProfile";
+
+/// The root manifest for an Efficiency Profile.
+/// Corresponds to the `kind: EfficiencyProfile` YAML.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct EfficiencyProfile {
+    pub api_version: String,
+    pub kind: String,
+    pub metadata: ProfileMetadata,
+    pub selectors: ProfileSelectors,
+    pub policies: Vec<PolicyRule>,
+    /// Periods during which none of the policies are enforced.
+    #[serde(default, rename = "maintenance_windows", alias = "maintenanceWindows")]
+    pub maintenance_windows: Vec<MaintenanceWindow>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ProfileMetadata {
+    pub name: String,
+    pub description: Option<String>,
+    pub version: String,
+    /// Higher priority profiles win conflicting actions in a [`ProfileSet`].
+    #[serde(default)]
+    pub priority: i32,
+}
+
+/// Values are matched exactly, or as a glob where `*` matches any run of
+/// characters (`"*H100*"`).
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+pub struct ProfileSelectors {
+    /// Agent `tags` that must all match for the profile to apply on a node.
+    #[serde(default, alias = "matchTags")]
+    pub match_tags: HashMap<String, String>,
+    /// Resource labels (see [`RESOURCE_LABELS`]) a GPU, MIG device or sensor
+    /// must match to be covered. Labels only filter the resources that carry
+    /// them; node-level policies are gated by `match_tags` alone.
+    #[serde(default, alias = "matchLabels")]
+    pub match_labels: HashMap<String, String>,
+}
+
+/// Labels `match_labels` can select resources on.
+pub const RESOURCE_LABELS: &[&str] = &[
+    "gpu_model",
+    "gpu_uuid",
+    "gpu_index",
+    "pci_bus_id",
+    "numa_node",
+    "mig_profile",
+    "driver_id",
+    "sensor_type",
+];
+
+impl ProfileSelectors {
+    /// Whether the agent's `tags` satisfy every `match_tags` entry.
+    pub fn matches_tags(&self, tags: &HashMap<String, String>) -> bool {
+        self.match_tags
+            .iter()
+            .all(|(key, pattern)| tags.get(key).is_some_and(|v| glob_match(pattern, v)))
+    }
+
+    fn selects(&self, resource: &Resource) -> bool {
+        self.match_labels
+            .iter()
+            .all(|(key, pattern)| match resource.label(key) {
+                Label::NotApplicable => true,
+                Label::Missing => false,
+                Label::Value(v) => glob_match(pattern, &v),
+            })
+    }
+}
+
+/// `*` matches any (possibly empty) run of characters; everything else
+/// matches literally.
+pub(crate) fn glob_match(pattern: &str, value: &str) -> bool {
+    let mut parts = pattern.split('*');
+    let first = parts.next().unwrap_or_default();
+    let Some(mut rest) = value.strip_prefix(first) else {
+        return false;
+    };
+    let parts: Vec<&str> = parts.collect();
+    let Some((last, middle)) = parts.split_last() else {
+        return rest.is_empty();
+    };
+    for part in middle {
+        match rest.find(part) {
+            Some(at) => rest = &rest[at + part.len()..],
+            None => return false,
+        }
+    }
+    rest.len() >= last.len() && rest.ends_with(last)
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PolicyRule {
+    pub name: String,
+    pub description: Option<String>,
+    pub target: PolicyTarget,
+    pub condition: Condition, // e.g., "> 80", "between 70C and 85C"
+    /// How long the condition must hold before the rule is violated.
+    #[serde(default, with = "humantime_serde")]
+    pub duration: Option<Duration>, // e.g., "5m"
+    /// Once violated, the rule only clears when this also holds, e.g.
+    /// `"< 80"` for a `"> 85"` condition. Defaults to the condition no
+    /// longer holding.
+    #[serde(default)]
+    pub clear_condition: Option<Condition>,
+    /// When the policy is in effect; always when omitted.
+    #[serde(default)]
+    pub schedule: Option<Schedule>,
+    /// Applied once the rule is violated; step 0 of the escalation ladder.
+    pub action: PolicyAction,
+    /// Further actions once the condition has held longer, in order.
+    #[serde(default)]
+    pub escalation: Vec<EscalationStep>,
+    pub severity: PolicySeverity,
+}
+
+/// A rung of an escalation ladder.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EscalationStep {
+    /// How long the condition must have held, counted like `duration`.
+    #[serde(with = "humantime_serde")]
+    pub after: Duration,
+    pub action: PolicyAction,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PolicyTarget {
+    GpuTempCelsius,
+    GpuUtilization,
+    GpuPowerWatts,
+    GpuPowerLimitWatts,
+    MemoryAllocatedPercent,
+    TokensPerWatt,
+    CpuUtilization,
+    NodePowerWatts,
+    NodePowerEnvelopeWatts,
+    /// Node power as a percentage of `node_power_envelope_watts`.
+    NodePowerEnvelopePercent,
+    DiskLatencyMs,
+    NetworkDropsPerSecond,
+    /// 1 while the swap degradation flag is raised, 0 otherwise.
+    SwapDegraded,
+    PueRatio,
+    MigUtilization,
+    /// One resource per IoT sensor reading, named `driver/sensor/param`.
+    IotSensorValue,
+}
+
+/// A resource's value for a `match_labels` key.
+enum Label {
+    /// The resource has no such attribute (e.g. `mig_profile` on a sensor),
+    /// so the selector does not filter it.
+    NotApplicable,
+    /// The attribute exists but is not reported; never matches.
+    Missing,
+    Value(String),
+}
+
+/// The resources a target is reported for; a policy is evaluated once per
+/// resource of its target's scope.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Scope {
+    Node,
+    Gpu,
+    Mig,
+    Sensor,
+}
+
+/// One resource a policy is evaluated on. Node-level targets resolve on
+/// every resource, so a GPU policy can reference `node_power_watts`.
+#[derive(Clone, Copy)]
+enum Resource<'a> {
+    Node,
+    Gpu(&'a GpuStatus),
+    Mig(&'a GpuStatus, &'a MigDeviceStatus),
+    Sensor(&'a IotSensorReading),
+}
+
+impl<'a> Resource<'a> {
+    fn all(scope: Scope, status: &'a StatusSnapshot) -> Vec<Resource<'a>> {
+        match scope {
+            Scope::Node => vec![Resource::Node],
+            Scope::Gpu => status.gpus.iter().map(Resource::Gpu).collect(),
+            Scope::Mig => status
+                .gpus
+                .iter()
+                .flat_map(|gpu| {
+                    gpu.mig_tree
+                        .iter()
+                        .flat_map(|tree| &tree.devices)
+                        .map(move |mig| Resource::Mig(gpu, mig))
+                })
+                .collect(),
+            Scope::Sensor => status.iot_sensors.iter().map(Resource::Sensor).collect(),
+        }
+    }
+
+    fn name(&self) -> String {
+        match self {
+            Resource::Node => "node".to_string(),
+            Resource::Gpu(gpu) => format!("GPU-{}", gpu.uuid.as_ref().unwrap_or(&gpu.gpu)),
+            Resource::Mig(_, mig) => format!("MIG-{}", mig.uuid.as_ref().unwrap_or(&mig.id)),
+            Resource::Sensor(reading) => format!(
+                "{}/{}/{}",
+                reading.driver_id, reading.sensor_type, reading.param
+            ),
+        }
+    }
+
+    /// `key`'s value on this resource, for `match_labels`.
+    fn label(&self, key: &str) -> Label {
+        let value = match (*self, key) {
+            (Resource::Gpu(gpu) | Resource::Mig(gpu, _), "gpu_model") => {
+                gpu.identity.as_ref().and_then(|i| i.model.clone())
+            }
+            (Resource::Gpu(gpu) | Resource::Mig(gpu, _), "gpu_uuid") => gpu.uuid.clone(),
+            (Resource::Gpu(gpu) | Resource::Mig(gpu, _), "gpu_index") => Some(gpu.gpu.clone()),
+            (Resource::Gpu(gpu) | Resource::Mig(gpu, _), "pci_bus_id") => {
+                gpu.identity.as_ref().and_then(|i| i.pci_bus_id.clone())
+            }
+            (Resource::Gpu(gpu) | Resource::Mig(gpu, _), "numa_node") => gpu
+                .identity
+                .as_ref()
+                .and_then(|i| i.numa_node)
+                .map(|n| n.to_string()),
+            (Resource::Mig(_, mig), "mig_profile") => mig.profile.clone(),
+            (Resource::Sensor(reading), "driver_id") => Some(reading.driver_id.clone()),
+            (Resource::Sensor(reading), "sensor_type") => Some(reading.sensor_type.clone()),
+            _ => return Label::NotApplicable,
+        };
+        value.map_or(Label::Missing, Label::Value)
+    }
+
+    fn gpu(&self) -> Option<&'a GpuStatus> {
+        match *self {
+            Resource::Gpu(gpu) | Resource::Mig(gpu, _) => Some(gpu),
+            Resource::Node | Resource::Sensor(_) => None,
+        }
+    }
+}
+
+impl PolicyTarget {
+    pub const ALL: &'static [PolicyTarget] = &[
+        PolicyTarget::GpuTempCelsius,
+        PolicyTarget::GpuUtilization,
+        PolicyTarget::GpuPowerWatts,
+        PolicyTarget::GpuPowerLimitWatts,
+        PolicyTarget::MemoryAllocatedPercent,
+        PolicyTarget::TokensPerWatt,
+        PolicyTarget::CpuUtilization,
+        PolicyTarget::NodePowerWatts,
+        PolicyTarget::NodePowerEnvelopeWatts,
+        PolicyTarget::NodePowerEnvelopePercent,
+        PolicyTarget::DiskLatencyMs,
+        PolicyTarget::NetworkDropsPerSecond,
+        PolicyTarget::SwapDegraded,
+        PolicyTarget::PueRatio,
+        PolicyTarget::MigUtilization,
+        PolicyTarget::IotSensorValue,
+    ];
+
+    /// The `target:` name, also used to reference it in conditions.
+    pub fn name(self) -> &'static str {
+        match self {
+            PolicyTarget::GpuTempCelsius => "gpu_temp_celsius",
+            PolicyTarget::GpuUtilization => "gpu_utilization",
+            PolicyTarget::GpuPowerWatts => "gpu_power_watts",
+            PolicyTarget::GpuPowerLimitWatts => "gpu_power_limit_watts",
+            PolicyTarget::MemoryAllocatedPercent => "memory_allocated_percent",
+            PolicyTarget::TokensPerWatt => "tokens_per_watt",
+            PolicyTarget::CpuUtilization => "cpu_utilization",
+            PolicyTarget::NodePowerWatts => "node_power_watts",
+            PolicyTarget::NodePowerEnvelopeWatts => "node_power_envelope_watts",
+            PolicyTarget::NodePowerEnvelopePercent => "node_power_envelope_percent",
+            PolicyTarget::DiskLatencyMs => "disk_latency_ms",
+            PolicyTarget::NetworkDropsPerSecond => "network_drops_per_second",
+            PolicyTarget::SwapDegraded => "swap_degraded",
+            PolicyTarget::PueRatio => "pue_ratio",
+            PolicyTarget::MigUtilization => "mig_utilization",
+            PolicyTarget::IotSensorValue => "iot_sensor_value",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.iter().copied().find(|t| t.name() == name)
+    }
+
+    pub fn dimension(self) -> Dimension {
+        match self {
+            PolicyTarget::GpuTempCelsius => Dimension::Temperature,
+            PolicyTarget::GpuUtilization
+            | PolicyTarget::MemoryAllocatedPercent
+            | PolicyTarget::CpuUtilization
+            | PolicyTarget::NodePowerEnvelopePercent
+            | PolicyTarget::MigUtilization => Dimension::Percent,
+            PolicyTarget::GpuPowerWatts
+            | PolicyTarget::GpuPowerLimitWatts
+            | PolicyTarget::NodePowerWatts
+            | PolicyTarget::NodePowerEnvelopeWatts => Dimension::Power,
+            PolicyTarget::DiskLatencyMs => Dimension::Time,
+            PolicyTarget::TokensPerWatt
+            | PolicyTarget::NetworkDropsPerSecond
+            | PolicyTarget::SwapDegraded
+            | PolicyTarget::PueRatio
+            // Sensors report in their own units (`unit` on the reading).
+            | PolicyTarget::IotSensorValue => Dimension::Scalar,
+        }
+    }
+
+    fn scope(self) -> Scope {
+        match self {
+            PolicyTarget::GpuTempCelsius
+            | PolicyTarget::GpuUtilization
+            | PolicyTarget::GpuPowerWatts
+            | PolicyTarget::GpuPowerLimitWatts
+            | PolicyTarget::MemoryAllocatedPercent => Scope::Gpu,
+            PolicyTarget::MigUtilization => Scope::Mig,
+            PolicyTarget::IotSensorValue => Scope::Sensor,
+            PolicyTarget::TokensPerWatt
+            | PolicyTarget::CpuUtilization
+            | PolicyTarget::NodePowerWatts
+            | PolicyTarget::NodePowerEnvelopeWatts
+            | PolicyTarget::NodePowerEnvelopePercent
+            | PolicyTarget::DiskLatencyMs
+            | PolicyTarget::NetworkDropsPerSecond
+            | PolicyTarget::SwapDegraded
+            | PolicyTarget::PueRatio => Scope::Node,
+        }
+    }
+
+    /// Current value on `resource`; `None` when it is not reported there.
+    fn value(self, status: &StatusSnapshot, resource: Resource) -> Option<f64> {
+        match self {
+            PolicyTarget::GpuTempCelsius => resource.gpu()?.temperature_celsius,
+            PolicyTarget::GpuUtilization => resource.gpu()?.util_percent,
+            PolicyTarget::GpuPowerWatts => resource.gpu()?.power_watts,
+            PolicyTarget::GpuPowerLimitWatts => resource.gpu()?.power_limit_watts,
+            PolicyTarget::MemoryAllocatedPercent => {
+                let gpu = resource.gpu()?;
+                let total = gpu.memory_total_bytes.filter(|t| *t > 0.0)?;
+                Some(gpu.memory_used_bytes? / total * 100.0)
+            }
+            PolicyTarget::TokensPerWatt => status.app_tokens_per_watt,
+            PolicyTarget::CpuUtilization => status.cpu_util_percent,
+            PolicyTarget::NodePowerWatts => status.node_power_watts,
+            PolicyTarget::NodePowerEnvelopeWatts => status.node_power_envelope_watts,
+            PolicyTarget::NodePowerEnvelopePercent => {
+                let envelope = status.node_power_envelope_watts.filter(|e| *e > 0.0)?;
+                Some(status.node_power_watts? / envelope * 100.0)
+            }
+            PolicyTarget::DiskLatencyMs => status.disk_io_avg_latency_ms,
+            PolicyTarget::NetworkDropsPerSecond => status.net_drops_per_sec,
+            PolicyTarget::SwapDegraded => Some(if status.swap_degraded { 1.0 } else { 0.0 }),
+            PolicyTarget::PueRatio => status.pue_ratio,
+            PolicyTarget::MigUtilization => match resource {
+                Resource::Mig(_, mig) => mig.util_percent.map(f64::from),
+                _ => None,
+            },
+            PolicyTarget::IotSensorValue => match resource {
+                Resource::Sensor(reading) => Some(reading.value),
+                _ => None,
+            },
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PolicyAction {
+    #[serde(rename = "type")]
+    pub action_type: ActionType,
+    #[serde(default)]
+    pub parameters: HashMap<String, serde_json::Value>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ActionType {
+    ThrottlePower,
+    LockClock,
+    ResetClocks,
+    Alert,
+    KillProcess,
+    MigratePod,
+}
+
+/// A numeric action parameter the enforcer cannot run without.
+#[derive(Debug, Clone, Copy)]
+pub struct RequiredParameter {
+    pub name: &'static str,
+    /// Other names the enforcer accepts for it.
+    pub aliases: &'static [&'static str],
+    /// Parameters that can be given instead, with a meaning of their own.
+    pub alternatives: &'static [&'static str],
+}
+
+impl RequiredParameter {
+    /// Every name that satisfies the requirement.
+    pub fn names(&self) -> impl Iterator<Item = &'static str> {
+        std::iter::once(self.name)
+            .chain(self.aliases.iter().copied())
+            .chain(self.alternatives.iter().copied())
+    }
+}
+
+impl ActionType {
+    pub const ALL: &'static [ActionType] = &[
+        ActionType::ThrottlePower,
+        ActionType::LockClock,
+        ActionType::ResetClocks,
+        ActionType::Alert,
+        ActionType::KillProcess,
+        ActionType::MigratePod,
+    ];
+
+    /// The `action.type` name.
+    pub fn name(&self) -> &'static str {
+        match self {
+            ActionType::ThrottlePower => "throttle_power",
+            ActionType::LockClock => "lock_clock",
+            ActionType::ResetClocks => "reset_clocks",
+            ActionType::Alert => "alert",
+            ActionType::KillProcess => "kill_process",
+            ActionType::MigratePod => "migrate_pod",
+        }
+    }
+
+    pub fn required_parameters(&self) -> &'static [RequiredParameter] {
+        match self {
+            ActionType::ThrottlePower => &[RequiredParameter {
+                name: "limit_watts",
+                aliases: &["limit"],
+                // Of the GPU's default power limit.
+                alternatives: &["limit_percent"],
+            }],
+            ActionType::LockClock => &[RequiredParameter {
+                name: "max_mhz",
+                aliases: &[],
+                // Locks min and max to the same frequency.
+                alternatives: &["frequency_mhz"],
+            }],
+            ActionType::ResetClocks
+            | ActionType::Alert
+            | ActionType::KillProcess
+            | ActionType::MigratePod => &[],
+        }
+    }
+
+    pub fn optional_parameters(&self) -> &'static [OptionalParameter] {
+        const CLOCK: OptionalParameter = OptionalParameter {
+            name: "clock",
+            values: CLOCK_DOMAINS,
+        };
+        match self {
+            ActionType::LockClock => &[
+                OptionalParameter {
+                    name: "min_mhz",
+                    values: &[],
+                },
+                CLOCK,
+            ],
+            // Both clocks without `clock`.
+            ActionType::ResetClocks => &[CLOCK],
+            // Overrides the agent's `kill_process.grace_period`.
+            ActionType::KillProcess => &[OptionalParameter {
+                name: "grace_period_seconds",
+                values: &[],
+            }],
+            ActionType::ThrottlePower | ActionType::Alert | ActionType::MigratePod => &[],
+        }
+    }
+}
+
+/// The `clock` parameter of `lock_clock` and `reset_clocks`; `sm` is the
+/// graphics clock.
+pub const CLOCK_DOMAINS: &[&str] = &["graphics", "sm", "memory"];
+
+/// An action parameter the enforcer can run without.
+#[derive(Debug, Clone, Copy)]
+pub struct OptionalParameter {
+    pub name: &'static str,
+    /// The accepted strings; a number when empty.
+    pub values: &'static [&'static str],
+}
+
+impl PolicyAction {
+    /// A numeric parameter, looked up by its name or any of its aliases.
+    pub fn number(&self, name: &str) -> Option<f64> {
+        let aliases = self
+            .action_type
+            .required_parameters()
+            .iter()
+            .find(|p| p.name == name)
+            .map_or(&[][..], |p| p.aliases);
+        std::iter::once(name)
+            .chain(aliases.iter().copied())
+            .find_map(|n| self.parameters.get(n))
+            .and_then(serde_json::Value::as_f64)
+    }
+
+    /// Checks that the required parameters of the action are numbers.
+    pub fn check(&self) -> Result<(), String> {
+        for required in self.action_type.required_parameters() {
+            let value = required
+                .names()
+                .find_map(|name| self.parameters.get(name).map(|v| (name, v)));
+            match value {
+                None => {
+                    return Err(format!(
+                        "{} requires parameter {:?}",
+                        self.action_type.name(),
+                        required.name
+                    ))
+                }
+                Some((name, v)) if !v.is_number() => {
+                    return Err(format!("parameter {name:?} must be a number"))
+                }
+                Some(_) => {}
+            }
+        }
+        for optional in self.action_type.optional_parameters() {
+            let Some(v) = self.parameters.get(optional.name) else {
+                continue;
+            };
+            match optional.values {
+                [] if v.is_number() => {}
+                [] => return Err(format!("parameter {:?} must be a number", optional.name)),
+                values if v.as_str().is_some_and(|v| values.contains(&v)) => {}
+                values => {
+                    return Err(format!(
+                        "parameter {:?} must be one of {}",
+                        optional.name,
+                        values.join(", ")
+                    ))
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PolicySeverity {
+    Info,
+    Warning,
+    Critical,
+}
+
+impl PolicySeverity {
+    pub const ALL: &'static [PolicySeverity] = &[
+        PolicySeverity::Info,
+        PolicySeverity::Warning,
+        PolicySeverity::Critical,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            PolicySeverity::Info => "info",
+            PolicySeverity::Warning => "warning",
+            PolicySeverity::Critical => "critical",
+        }
+    }
+}
+
+/// The result of a `plan` operation.
+#[derive(Debug, Clone, Serialize)]
+pub struct PlanResult {
+    pub profile_name: String,
+    pub matched_policies: Vec<PolicyPlan>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct PolicyPlan {
+    pub policy_name: String,
+    pub target_resource: String, // e.g., "GPU-0"
+    pub current_value: String,
+    pub threshold: String,
+    pub status: PlanStatus,
+    pub computed_action: Option<String>,
+    /// Unix ms since which the condition has held, while pending or violated.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub since_ms: Option<i64>,
+    /// `profile/policy` of violated policies asking for a different action
+    /// of the same type on this resource.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub conflicts_with: Vec<String>,
+    /// The current step of a policy with an `escalation` ladder, while
+    /// violated: 0 for its `action`, `n` for `escalation[n - 1]`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub escalation_step: Option<usize>,
+    /// The maintenance window suspending the policy, when `MAINTENANCE`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub maintenance_window: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, PartialEq)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum PlanStatus {
+    Satisfied,
+    /// The condition holds but not yet for the rule's `duration`.
+    Pending,
+    Violated,
+    Skipped,
+    /// Violated, but its action conflicts with one of equal or higher
+    /// priority, so it is not applied.
+    Conflict,
+    /// Outside the policy's `schedule`.
+    Inactive,
+    /// The profile is in one of its maintenance windows.
+    Maintenance,
+}
+
+#[derive(Debug, Error)]
+pub enum ProfileError {
+    #[error("failed to read profile: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("invalid profile: {0}")]
+    Yaml(#[from] serde_yaml::Error),
+    #[error("duplicate profile name {name:?}, already defined in {}", other.display())]
+    DuplicateName { name: String, other: PathBuf },
+    #[error("unknown selector label {0:?} (expected one of {labels})", labels = RESOURCE_LABELS.join(", "))]
+    UnknownLabel(String),
+    #[error("unsupported apiVersion {0:?} (supported: {versions})", versions = API_VERSIONS.join(", "))]
+    ApiVersion(String),
+    #[error("kind must be {KIND:?}, got {0:?}")]
+    Kind(String),
+    #[error("policy {policy:?}: {message}")]
+    Action { policy: String, message: String },
+    #[error("policy {policy:?}: {message}")]
+    Schedule { policy: String, message: String },
+    #[error("policy {policy:?}: escalation: {message}")]
+    Escalation { policy: String, message: String },
+    #[error("maintenance window {name:?}: {message}")]
+    MaintenanceWindow { name: String, message: String },
+    #[error("policy {policy:?}: {source}")]
+    Condition {
+        policy: String,
+        #[source]
+        source: ConditionError,
+    },
+}
+
+impl EfficiencyProfile {
+    /// Parses a profile manifest and checks its conditions, so a broken
+    /// profile is rejected when it is loaded rather than evaluating to
+    /// "not violated".
+    pub fn from_yaml(contents: &str) -> Result<Self, ProfileError> {
+        let profile: Self = serde_yaml::from_str(contents)?;
+        profile.validate()?;
+        Ok(profile)
+    }
+
+    /// Whether this profile applies on an agent with `tags`.
+    pub fn applies_to(&self, tags: &HashMap<String, String>) -> bool {
+        self.selectors.matches_tags(tags)
+    }
+
+    /// Checks the apiVersion, the units of every condition against its
+    /// policy target, required action parameters and that `match_labels`
+    /// only uses known labels.
+    pub fn validate(&self) -> Result<(), ProfileError> {
+        if !API_VERSIONS.contains(&self.api_version.as_str()) {
+            return Err(ProfileError::ApiVersion(self.api_version.clone()));
+        }
+        if self.kind != KIND {
+            return Err(ProfileError::Kind(self.kind.clone()));
+        }
+        if let Some(key) = self
+            .selectors
+            .match_labels
+            .keys()
+            .find(|k| !RESOURCE_LABELS.contains(&k.as_str()))
+        {
+            return Err(ProfileError::UnknownLabel(key.clone()));
+        }
+        for policy in &self.policies {
+            let actions =
+                std::iter::once(&policy.action).chain(policy.escalation.iter().map(|s| &s.action));
+            for action in actions {
+                action.check().map_err(|message| ProfileError::Action {
+                    policy: policy.name.clone(),
+                    message,
+                })?;
+            }
+            policy
+                .check_escalation()
+                .map_err(|message| ProfileError::Escalation {
+                    policy: policy.name.clone(),
+                    message,
+                })?;
+            let conditions = std::iter::once(&policy.condition).chain(&policy.clear_condition);
+            for condition in conditions {
+                condition
+                    .check(policy.target)
+                    .map_err(|source| ProfileError::Condition {
+                        policy: policy.name.clone(),
+                        source,
+                    })?;
+            }
+            if let Some(schedule) = &policy.schedule {
+                schedule.check().map_err(|message| ProfileError::Schedule {
+                    policy: policy.name.clone(),
+                    message,
+                })?;
+            }
+        }
+        for window in &self.maintenance_windows {
+            window
+                .check()
+                .map_err(|message| ProfileError::MaintenanceWindow {
+                    name: window.name.clone(),
+                    message,
+                })?;
+        }
+        Ok(())
+    }
+
+    /// The maintenance window in effect at `now_ms` (unix ms), if any.
+    pub fn maintenance_window(&self, now_ms: i64) -> Option<&MaintenanceWindow> {
+        self.maintenance_windows
+            .iter()
+            .find(|window| window.is_active(now_ms))
+    }
+
+    /// Simulates the profile against the current status snapshot (The "Plan" phase).
+    ///
+    /// This is a single evaluation, so rules with a `duration` report
+    /// `Pending` at most; the enforcement loop keeps a [`PolicyEvaluator`]
+    /// across ticks instead.
+    pub fn plan(&self, status: &StatusSnapshot) -> PlanResult {
+        PolicyEvaluator::default().evaluate(self, status, status.last_scrape_unix_ms as i64)
+    }
+
+    /// Instantaneous evaluation of every policy on every resource it covers.
+    fn observe(&self, status: &StatusSnapshot) -> Vec<Observation> {
+        let mut observations = Vec::new();
+
+        for (index, policy) in self.policies.iter().enumerate() {
+            for resource in Resource::all(policy.target.scope(), status)
+                .into_iter()
+                .filter(|r| self.selectors.selects(r))
+            {
+                observations.push(policy.observe(index, resource.name(), |target| {
+                    target.value(status, resource)
+                }));
+            }
+        }
+
+        observations
+    }
+}
+
+/// A policy evaluated on one resource at one instant, before durations and
+/// hysteresis are applied.
+struct Observation {
+    /// Index into `EfficiencyProfile::policies`.
+    policy: usize,
+    /// `Violated` when the condition holds right now.
+    plan: PolicyPlan,
+    /// Whether a violation would clear now: the condition no longer holds
+    /// and the clear condition (if any) does.
+    cleared: Option<bool>,
+}
+
+impl PolicyRule {
+    /// Evaluates the rule on one resource; it is skipped when a value the
+    /// condition needs is unavailable.
+    fn observe(
+        &self,
+        index: usize,
+        target_resource: String,
+        resolve: impl Fn(PolicyTarget) -> Option<f64>,
+    ) -> Observation {
+        let current = resolve(self.target);
+        let holds = self.condition.evaluate(self.target, &resolve);
+        let status = match holds {
+            Some(true) => PlanStatus::Violated,
+            Some(false) => PlanStatus::Satisfied,
+            None => PlanStatus::Skipped,
+        };
+        let cleared = match holds {
+            Some(false) => self
+                .clear_condition
+                .as_ref()
+                .map_or(Some(true), |clear| clear.evaluate(self.target, &resolve)),
+            Some(true) => Some(false),
+            None => None,
+        };
+        Observation {
+            policy: index,
+            plan: PolicyPlan {
+                policy_name: self.name.clone(),
+                target_resource,
+                current_value: current
+                    .map_or_else(|| "N/A".to_string(), |v| self.target.dimension().format(v)),
+                threshold: self.condition.to_string(),
+                status,
+                computed_action: None,
+                since_ms: None,
+                conflicts_with: Vec::new(),
+                escalation_step: None,
+                maintenance_window: None,
+            },
+            cleared,
+        }
+    }
+
+    /// The escalation step due once the condition has held for `held_ms`:
+    /// 0 for `action`, `n` for `escalation[n - 1]`.
+    pub fn escalation_step(&self, held_ms: i64) -> usize {
+        self.escalation
+            .iter()
+            .take_while(|step| held_ms >= step.after.as_millis() as i64)
+            .count()
+    }
+
+    /// The action of an escalation step (see [`Self::escalation_step`]).
+    pub fn action_at(&self, step: usize) -> &PolicyAction {
+        match step.checked_sub(1) {
+            Some(i) => self.escalation.get(i).map_or(&self.action, |s| &s.action),
+            None => &self.action,
+        }
+    }
+
+    /// Steps must come after `duration` and after each other.
+    fn check_escalation(&self) -> Result<(), String> {
+        let mut previous = self.duration.unwrap_or_default();
+        for step in &self.escalation {
+            if step.after <= previous {
+                return Err(format!(
+                    "step after {} must come after {}",
+                    humantime_serde::re::humantime::format_duration(step.after),
+                    humantime_serde::re::humantime::format_duration(previous)
+                ));
+            }
+            previous = step.after;
+        }
+        Ok(())
+    }
+
+    /// Whether the rule is in effect at `now_ms` (unix ms).
+    pub fn is_scheduled(&self, now_ms: i64) -> bool {
+        self.schedule.as_ref().is_none_or(|s| s.is_active(now_ms))
+    }
+
+    fn action_description(&self, step: usize) -> String {
+        let action = self.action_at(step);
+        let description = format!(
+            "Execute {:?} with params {:?}",
+            action.action_type, action.parameters
+        );
+        match self.escalation.len() {
+            0 => description,
+            steps => format!("{description} (escalation step {step}/{steps})"),
+        }
+    }
+}
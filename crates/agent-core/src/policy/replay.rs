@@ -0,0 +1,579 @@
+// ESNODE | Source Available BUSL-1.1 | Copyright (c) 2025 Estimatedstocks AB
+//! Offline evaluation of profiles against recorded history (`plan --replay`).
+//!
+//! History is a sequence of status snapshots, either recorded from `/status`
+//! or `/events`, or rebuilt from local TSDB samples with
+//! [`snapshots_from_samples`]. [`replay`] runs them through one
+//! [`PolicyEvaluator`] in time order, so durations and clear conditions
+//! behave as in the enforcement loop. Each snapshot stands for the time until
+//! the next one; an interval over ten times the median is treated as missing
+//! data and not counted.
+//!
+//! The saving of a `throttle_power` action is estimated as the GPU power
+//! drawn above `limit_watts` while the policy was violated, i.e. assuming the
+//! cap holds power at the limit and the workload is otherwise unchanged.
+
+use std::collections::{BTreeMap, HashMap};
+
+use serde::Serialize;
+use thiserror::Error;
+
+use super::{ActionType, PlanStatus, PolicyAction, PolicyEvaluator, ProfileSet, Resource, Scope};
+use crate::state::{GpuStatus, IotSensorReading, MigDeviceStatus, StatusSnapshot};
+use crate::tsdb::Sample;
+
+/// The metrics [`snapshots_from_samples`] reads.
+pub const REPLAY_METRICS: &[&str] = &[
+    "esnode_gpu_temperature_celsius",
+    "esnode_gpu_utilization_percent",
+    "esnode_gpu_power_watts",
+    "esnode_gpu_power_limit_watts",
+    "esnode_gpu_memory_total_bytes",
+    "esnode_gpu_memory_used_bytes",
+    "esnode_gpu_clock_sm_mhz",
+    "esnode_mig_utilization_percent",
+    "esnode_cpu_usage_percent",
+    "esnode_node_power_watts",
+    "esnode_app_tokens_per_sec",
+    "esnode_disk_io_avg_latency_ms",
+    "esnode_network_rx_dropped_total",
+    "esnode_network_tx_dropped_total",
+    "esnode_swap_degradation_spike",
+    "esnode_pue_ratio",
+    "esnode_iot_sensor_value",
+];
+
+/// How long a sample remains the current value of its series.
+const LOOKBACK_MS: i64 = 5 * 60 * 1000;
+/// Intervals longer than this many median intervals are gaps.
+const GAP_FACTOR: i64 = 10;
+
+#[derive(Debug, Error)]
+#[error("snapshot on line {line}: {source}")]
+pub struct SnapshotError {
+    pub line: usize,
+    #[source]
+    pub source: serde_json::Error,
+}
+
+/// Parses recorded status snapshots: a JSON array, or one JSON object per
+/// line as saved from `/status` or `/events` (whose `policy` events are
+/// skipped).
+pub fn read_snapshots(contents: &str) -> Result<Vec<StatusSnapshot>, SnapshotError> {
+    if contents.trim_start().starts_with('[') {
+        return serde_json::from_str(contents).map_err(|source| SnapshotError {
+            line: source.line(),
+            source,
+        });
+    }
+    let mut snapshots = Vec::new();
+    // Name of the server-sent event being read, if any.
+    let mut event = None;
+    for (i, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() {
+            event = None;
+            continue;
+        }
+        if let Some(name) = line.strip_prefix("event:") {
+            event = Some(name.trim());
+            continue;
+        }
+        let json = line.strip_prefix("data:").map_or(line, str::trim_start);
+        if !json.starts_with('{') || event.is_some_and(|name| name != "message") {
+            continue;
+        }
+        let snapshot = serde_json::from_str(json).map_err(|source| SnapshotError {
+            line: i + 1,
+            source,
+        })?;
+        snapshots.push(snapshot);
+    }
+    Ok(snapshots)
+}
+
+/// Rebuilds a status snapshot every `step_ms` in `[from_ms, to_ms]` from the
+/// [`REPLAY_METRICS`] samples. Each series contributes its latest sample of
+/// the last five minutes; instants without any are left out. GPU identity
+/// (and so `gpu_model` selectors) and the node power envelope are not
+/// recorded in the TSDB.
+pub fn snapshots_from_samples(
+    samples: &[Sample],
+    from_ms: i64,
+    to_ms: i64,
+    step_ms: i64,
+) -> Vec<StatusSnapshot> {
+    let mut series: BTreeMap<SeriesKey, Vec<(i64, f64)>> = BTreeMap::new();
+    for sample in samples.iter().filter(|s| s.value.is_finite()) {
+        let mut labels: Vec<_> = sample
+            .labels
+            .iter()
+            .map(|(k, v)| (k.clone(), v.clone()))
+            .collect();
+        labels.sort();
+        series
+            .entry((sample.metric.clone(), labels))
+            .or_default()
+            .push((sample.ts_ms, sample.value));
+    }
+    for points in series.values_mut() {
+        points.sort_by_key(|(ts, _)| *ts);
+    }
+
+    let mut snapshots = Vec::new();
+    let mut ts_ms = from_ms;
+    while ts_ms <= to_ms {
+        snapshots.extend(snapshot_at(&series, ts_ms));
+        ts_ms += step_ms.max(1);
+    }
+    snapshots
+}
+
+/// (metric, sorted labels).
+type SeriesKey = (String, Vec<(String, String)>);
+
+fn snapshot_at(
+    series: &BTreeMap<SeriesKey, Vec<(i64, f64)>>,
+    ts_ms: i64,
+) -> Option<StatusSnapshot> {
+    let mut status = StatusSnapshot {
+        healthy: true,
+        last_scrape_unix_ms: ts_ms as u64,
+        ..Default::default()
+    };
+    let mut gpus: BTreeMap<(String, String), GpuStatus> = BTreeMap::new();
+    let mut cores = Vec::new();
+    let mut found = false;
+
+    for ((metric, labels), points) in series {
+        let Some(value) = value_at(points, ts_ms) else {
+            continue;
+        };
+        found = true;
+        let label = |name: &str| label(labels, name);
+        match metric.as_str() {
+            "esnode_gpu_temperature_celsius" => {
+                gpu(&mut gpus, labels, "uuid", "index").temperature_celsius = Some(value)
+            }
+            "esnode_gpu_utilization_percent" => {
+                gpu(&mut gpus, labels, "uuid", "index").util_percent = Some(value)
+            }
+            "esnode_gpu_power_watts" => {
+                gpu(&mut gpus, labels, "uuid", "index").power_watts = Some(value)
+            }
+            "esnode_gpu_power_limit_watts" => {
+                gpu(&mut gpus, labels, "uuid", "index").power_limit_watts = Some(value)
+            }
+            "esnode_gpu_memory_total_bytes" => {
+                gpu(&mut gpus, labels, "uuid", "index").memory_total_bytes = Some(value)
+            }
+            "esnode_gpu_memory_used_bytes" => {
+                gpu(&mut gpus, labels, "uuid", "index").memory_used_bytes = Some(value)
+            }
+            "esnode_gpu_clock_sm_mhz" => {
+                gpu(&mut gpus, labels, "uuid", "index").clock_sm_mhz = Some(value)
+            }
+            "esnode_mig_utilization_percent" => gpu(&mut gpus, labels, "gpu_uuid", "gpu_index")
+                .mig_tree
+                .get_or_insert_with(Default::default)
+                .devices
+                .push(MigDeviceStatus {
+                    id: label("mig").to_string(),
+                    util_percent: Some(value.round() as u32),
+                    ..Default::default()
+                }),
+            "esnode_cpu_usage_percent" => cores.push(value),
+            "esnode_node_power_watts" => status.node_power_watts = Some(value),
+            "esnode_app_tokens_per_sec" => status.app_tokens_per_sec = Some(value),
+            // The status reports the worst disk.
+            "esnode_disk_io_avg_latency_ms" => {
+                status.disk_io_avg_latency_ms = Some(
+                    status
+                        .disk_io_avg_latency_ms
+                        .map_or(value, |v| v.max(value)),
+                )
+            }
+            "esnode_network_rx_dropped_total" | "esnode_network_tx_dropped_total" => {
+                if let Some(rate) = rate_at(points, ts_ms) {
+                    status.net_drops_per_sec = Some(status.net_drops_per_sec.unwrap_or(0.0) + rate);
+                }
+            }
+            "esnode_swap_degradation_spike" => status.swap_degraded = value > 0.0,
+            "esnode_pue_ratio" => status.pue_ratio = Some(value),
+            "esnode_iot_sensor_value" => status.iot_sensors.push(IotSensorReading {
+                driver_id: label("driver_id").to_string(),
+                sensor_type: label("sensor_type").to_string(),
+                unit: label("unit").to_string(),
+                param: label("param").to_string(),
+                value,
+            }),
+            _ => {}
+        }
+    }
+
+    if !cores.is_empty() {
+        status.cpu_util_percent = Some(cores.iter().sum::<f64>() / cores.len() as f64);
+    }
+    if let (Some(tokens), Some(watts)) = (status.app_tokens_per_sec, status.node_power_watts) {
+        status.app_tokens_per_watt = (watts > 0.0).then(|| tokens / watts);
+    }
+    status.gpus = gpus.into_values().collect();
+    found.then_some(status)
+}
+
+fn label<'a>(labels: &'a [(String, String)], name: &str) -> &'a str {
+    labels
+        .iter()
+        .find(|(k, _)| k == name)
+        .map_or("", |(_, v)| v.as_str())
+}
+
+/// The GPU a series with `uuid` and `index` labels belongs to.
+fn gpu<'a>(
+    gpus: &'a mut BTreeMap<(String, String), GpuStatus>,
+    labels: &[(String, String)],
+    uuid: &str,
+    index: &str,
+) -> &'a mut GpuStatus {
+    let (uuid, index) = (label(labels, uuid), label(labels, index));
+    gpus.entry((index.to_string(), uuid.to_string()))
+        .or_insert_with(|| GpuStatus {
+            uuid: Some(uuid.to_string()).filter(|u| !u.is_empty()),
+            gpu: index.to_string(),
+            ..Default::default()
+        })
+}
+
+/// The latest value at or before `ts_ms`, unless it is stale.
+fn value_at(points: &[(i64, f64)], ts_ms: i64) -> Option<f64> {
+    let end = points.partition_point(|(t, _)| *t <= ts_ms);
+    let (t, value) = *points[..end].last()?;
+    (ts_ms - t < LOOKBACK_MS).then_some(value)
+}
+
+/// Per-second rate of a counter between its last two samples at or before
+/// `ts_ms`; a decrease is taken as a counter reset.
+fn rate_at(points: &[(i64, f64)], ts_ms: i64) -> Option<f64> {
+    let end = points.partition_point(|(t, _)| *t <= ts_ms);
+    let [(t0, v0), (t1, v1)] = *points[..end].last_chunk::<2>()?;
+    if ts_ms - t1 >= LOOKBACK_MS || t1 <= t0 {
+        return None;
+    }
+    let delta = if v1 >= v0 { v1 - v0 } else { v1 };
+    Some(delta * 1000.0 / (t1 - t0) as f64)
+}
+
+/// What a replay found.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ReplayReport {
+    /// Unix ms of the first and last snapshot.
+    pub from_ms: i64,
+    pub to_ms: i64,
+    pub snapshots: usize,
+    /// Time the snapshots cover, without gaps.
+    pub covered_ms: i64,
+    /// Every evaluated policy, in profile priority order.
+    pub policies: Vec<PolicyReplay>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct PolicyReplay {
+    pub profile_name: String,
+    pub policy_name: String,
+    pub action: ActionType,
+    /// Times the policy became violated, over all resources.
+    pub fired: u64,
+    pub violated_ms: i64,
+    /// Estimated energy `throttle_power` would have saved, in watt-hours.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub energy_saved_wh: Option<f64>,
+    /// Resources the policy fired on.
+    pub resources: Vec<ResourceReplay>,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ResourceReplay {
+    pub resource: String,
+    pub fired: u64,
+    pub violated_ms: i64,
+    /// Part of `violated_ms` in which the action lost a conflict.
+    pub conflict_ms: i64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub energy_saved_wh: Option<f64>,
+    #[serde(skip)]
+    active: bool,
+}
+
+/// Evaluates the profiles of `set` that apply to `tags` over `snapshots`.
+pub fn replay(
+    set: &ProfileSet,
+    snapshots: &[StatusSnapshot],
+    tags: &HashMap<String, String>,
+) -> ReplayReport {
+    let mut snapshots: Vec<_> = snapshots.iter().collect();
+    snapshots.sort_by_key(|s| s.last_scrape_unix_ms);
+    let times: Vec<i64> = snapshots
+        .iter()
+        .map(|s| s.last_scrape_unix_ms as i64)
+        .collect();
+    let intervals = intervals(&times);
+
+    let mut evaluator = PolicyEvaluator::default();
+    let mut policies: Vec<(PolicyReplay, BTreeMap<String, ResourceReplay>)> = Vec::new();
+    let mut index: HashMap<(String, String), usize> = HashMap::new();
+
+    for (snapshot, &interval_ms) in snapshots.iter().zip(&intervals) {
+        let now_ms = snapshot.last_scrape_unix_ms as i64;
+        for result in set.plan(&mut evaluator, snapshot, tags, now_ms) {
+            let Some(profile) = set.get(&result.profile_name) else {
+                continue;
+            };
+            for plan in result.matched_policies {
+                let Some(rule) = profile.policies.iter().find(|r| r.name == plan.policy_name)
+                else {
+                    continue;
+                };
+                let key = (result.profile_name.clone(), plan.policy_name.clone());
+                let i = *index.entry(key).or_insert_with(|| {
+                    policies.push((
+                        PolicyReplay {
+                            profile_name: result.profile_name.clone(),
+                            policy_name: plan.policy_name.clone(),
+                            action: rule.action.action_type.clone(),
+                            fired: 0,
+                            violated_ms: 0,
+                            energy_saved_wh: None,
+                            resources: Vec::new(),
+                        },
+                        BTreeMap::new(),
+                    ));
+                    policies.len() - 1
+                });
+                let resource = policies[i]
+                    .1
+                    .entry(plan.target_resource.clone())
+                    .or_insert_with(|| ResourceReplay {
+                        resource: plan.target_resource.clone(),
+                        ..Default::default()
+                    });
+                match plan.status {
+                    // Unknown: neither a new violation nor the end of one.
+                    PlanStatus::Skipped => {}
+                    PlanStatus::Satisfied
+                    | PlanStatus::Pending
+                    | PlanStatus::Inactive
+                    | PlanStatus::Maintenance => resource.active = false,
+                    PlanStatus::Violated | PlanStatus::Conflict => {
+                        if !resource.active {
+                            resource.fired += 1;
+                            resource.active = true;
+                        }
+                        resource.violated_ms += interval_ms;
+                        if plan.status == PlanStatus::Conflict {
+                            resource.conflict_ms += interval_ms;
+                        } else if let action @ PolicyAction {
+                            action_type: ActionType::ThrottlePower,
+                            ..
+                        } = rule.action_at(plan.escalation_step.unwrap_or(0))
+                        {
+                            let limit = action.number("limit_watts");
+                            let power = Resource::all(Scope::Gpu, snapshot)
+                                .into_iter()
+                                .find(|r| r.name() == plan.target_resource)
+                                .and_then(|r| r.gpu()?.power_watts);
+                            if let (Some(limit), Some(power)) = (limit, power) {
+                                *resource.energy_saved_wh.get_or_insert(0.0) +=
+                                    (power - limit).max(0.0) * interval_ms as f64 / 3_600_000.0;
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    let policies = policies
+        .into_iter()
+        .map(|(mut policy, resources)| {
+            policy.resources = resources.into_values().filter(|r| r.fired > 0).collect();
+            policy.fired = policy.resources.iter().map(|r| r.fired).sum();
+            policy.violated_ms = policy.resources.iter().map(|r| r.violated_ms).sum();
+            if policy.action == ActionType::ThrottlePower {
+                policy.energy_saved_wh = Some(
+                    policy
+                        .resources
+                        .iter()
+                        .filter_map(|r| r.energy_saved_wh)
+                        .sum(),
+                );
+            }
+            policy
+        })
+        .collect();
+
+    ReplayReport {
+        from_ms: times.first().copied().unwrap_or_default(),
+        to_ms: times.last().copied().unwrap_or_default(),
+        snapshots: times.len(),
+        covered_ms: intervals.iter().sum(),
+        policies,
+    }
+}
+
+/// How long each snapshot stands for: until the next one, or nothing across
+/// a gap. The last one counts for the median interval.
+fn intervals(times: &[i64]) -> Vec<i64> {
+    let mut intervals: Vec<i64> = times.windows(2).map(|w| w[1] - w[0]).collect();
+    let mut sorted = intervals.clone();
+    sorted.sort_unstable();
+    let median = sorted.get(sorted.len() / 2).copied().unwrap_or(0);
+    for interval in &mut intervals {
+        if *interval > median * GAP_FACTOR {
+            *interval = 0;
+        }
+    }
+    if !times.is_empty() {
+        intervals.push(median);
+    }
+    intervals
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample(metric: &str, labels: &[(&str, &str)], ts_ms: i64, value: f64) -> Sample {
+        Sample {
+            metric: metric.to_string(),
+            labels: labels
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect(),
+            ts_ms,
+            value,
+        }
+    }
+
+    #[test]
+    fn snapshots_are_rebuilt_from_samples() {
+        let gpu = [("uuid", "abc"), ("index", "0")];
+        let eth = [("iface", "eth0")];
+        let samples = vec![
+            sample("esnode_gpu_power_watts", &gpu, 0, 400.0),
+            sample("esnode_gpu_power_watts", &gpu, 60_000, 450.0),
+            sample("esnode_gpu_temperature_celsius", &gpu, 0, 70.0),
+            sample("esnode_cpu_usage_percent", &[("core", "0")], 60_000, 20.0),
+            sample("esnode_cpu_usage_percent", &[("core", "1")], 60_000, 40.0),
+            sample("esnode_network_rx_dropped_total", &eth, 0, 100.0),
+            sample("esnode_network_rx_dropped_total", &eth, 10_000, 150.0),
+            sample("esnode_node_power_watts", &[], 600_000, 1000.0),
+        ];
+        let snapshots = snapshots_from_samples(&samples, 0, 600_000, 60_000);
+
+        // The samples of minute 1 go stale after minute 5.
+        let times: Vec<_> = snapshots.iter().map(|s| s.last_scrape_unix_ms).collect();
+        assert_eq!(
+            times,
+            [0, 60_000, 120_000, 180_000, 240_000, 300_000, 600_000]
+        );
+
+        let first = &snapshots[0];
+        assert_eq!(first.gpus.len(), 1);
+        assert_eq!(first.gpus[0].uuid.as_deref(), Some("abc"));
+        assert_eq!(first.gpus[0].power_watts, Some(400.0));
+        assert_eq!(first.gpus[0].temperature_celsius, Some(70.0));
+        assert_eq!(first.cpu_util_percent, None);
+        assert_eq!(first.net_drops_per_sec, None);
+
+        let second = &snapshots[1];
+        assert_eq!(second.gpus[0].power_watts, Some(450.0));
+        assert_eq!(second.cpu_util_percent, Some(30.0));
+        assert_eq!(second.net_drops_per_sec, Some(5.0));
+
+        assert!(snapshots[6].gpus.is_empty());
+        assert_eq!(snapshots[6].node_power_watts, Some(1000.0));
+    }
+
+    #[test]
+    fn replay_counts_fires_and_estimates_throttle_savings() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(
+            dir.path().join("p.yaml"),
+            r#"
+apiVersion: v1
+kind: EfficiencyProfile
+metadata: { name: cap, version: "1" }
+selectors: {}
+policies:
+  - name: hot
+    target: gpu_power_watts
+    condition: "> 400W"
+    duration: 2m
+    severity: warning
+    action: { type: throttle_power, parameters: { limit_watts: 350 } }
+"#,
+        )
+        .unwrap();
+        let mut set = ProfileSet::new([dir.path().to_path_buf()]);
+        assert!(set.reload().failed.is_empty());
+
+        // One minute apart, with a gap before the last two.
+        let watts = [
+            (0, 500.0),
+            (1, 500.0),
+            (2, 500.0),
+            (3, 300.0),
+            (4, 500.0),
+            (5, 500.0),
+            (6, 500.0),
+            (60, 500.0),
+            (61, 500.0),
+        ];
+        let snapshots: Vec<_> = watts
+            .iter()
+            .map(|&(minute, power)| StatusSnapshot {
+                last_scrape_unix_ms: minute * 60_000,
+                gpus: vec![GpuStatus {
+                    uuid: Some("abc".to_string()),
+                    power_watts: Some(power),
+                    ..Default::default()
+                }],
+                ..Default::default()
+            })
+            .collect();
+
+        let report = replay(&set, &snapshots, &HashMap::new());
+        assert_eq!(report.snapshots, 9);
+        assert_eq!(report.covered_ms, 8 * 60_000);
+        let policy = &report.policies[0];
+        // Violated from minute 2 and from minute 6 on; the gap after
+        // minute 6 does not clear it but is not counted either.
+        assert_eq!(policy.fired, 2);
+        assert_eq!(policy.resources[0].resource, "GPU-abc");
+        assert_eq!(policy.violated_ms, 3 * 60_000);
+        // 150 W above the cap for 3 minutes.
+        let saved = policy.energy_saved_wh.unwrap();
+        assert!((saved - 7.5).abs() < 1e-9, "{saved}");
+
+        let json = |ts| {
+            serde_json::to_string(&StatusSnapshot {
+                last_scrape_unix_ms: ts,
+                ..Default::default()
+            })
+            .unwrap()
+        };
+        let lines = format!(
+            "data: {}\n\nevent: policy\ndata: {{\"seq\": 1}}\n\n{}\n",
+            json(5),
+            json(6)
+        );
+        let parsed = read_snapshots(&lines).unwrap();
+        assert_eq!(parsed.len(), 2);
+        assert_eq!(
+            read_snapshots(&format!("{}\n{{oops}}", json(5)))
+                .err()
+                .map(|err| err.line),
+            Some(2)
+        );
+    }
+}
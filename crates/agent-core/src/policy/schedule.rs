@@ -0,0 +1,443 @@
+// ESNODE | Source Available BUSL-1.1 | Copyright (c) 2025 Estimatedstocks AB
+//! When policies are in effect.
+//!
+//! A policy's `schedule` limits it to certain times, e.g. peak-tariff hours;
+//! outside them it is `INACTIVE`. A profile's `maintenance_windows` suspend
+//! all of its policies (`MAINTENANCE`), e.g. during a planned training run.
+//! Nothing is enforced while a policy is off, and a pending or violated
+//! policy starts over when it comes back.
+//!
+//! A schedule is active while its `cron` expression matches the current
+//! minute or the current time falls in one of its `windows`, both read in
+//! its `timezone` (an IANA name, UTC by default).
+
+use std::fmt;
+use std::str::FromStr;
+
+use chrono::{DateTime, Datelike, FixedOffset, Timelike, Utc, Weekday};
+use chrono_tz::Tz;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Schedule {
+    #[serde(default = "utc")]
+    pub timezone: Tz,
+    /// Five-field cron expression (`minute hour day-of-month month
+    /// day-of-week`); the schedule is active during every minute it matches.
+    #[serde(default)]
+    pub cron: Option<Cron>,
+    #[serde(default)]
+    pub windows: Vec<TimeWindow>,
+}
+
+fn utc() -> Tz {
+    Tz::UTC
+}
+
+impl Schedule {
+    pub fn is_active(&self, now_ms: i64) -> bool {
+        let Some(now) = DateTime::<Utc>::from_timestamp_millis(now_ms) else {
+            return false;
+        };
+        let local = now.with_timezone(&self.timezone);
+        let (weekday, minute) = (local.weekday(), local.hour() * 60 + local.minute());
+        self.cron.as_ref().is_some_and(|cron| {
+            cron.matches(
+                local.minute(),
+                local.hour(),
+                local.day(),
+                local.month(),
+                weekday,
+            )
+        }) || self.windows.iter().any(|w| w.contains(weekday, minute))
+    }
+
+    /// What deserialization cannot catch.
+    pub fn check(&self) -> Result<(), String> {
+        if self.cron.is_none() && self.windows.is_empty() {
+            return Err("schedule needs a cron expression or windows".to_string());
+        }
+        match self.windows.iter().find(|w| w.start == w.end) {
+            Some(w) => Err(format!("window {}-{} is empty", w.start, w.end)),
+            None => Ok(()),
+        }
+    }
+}
+
+/// A daily time range on some days of the week. When `end` is not after
+/// `start` the window runs past midnight, into the day after each of `days`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TimeWindow {
+    /// Every day when empty.
+    #[serde(default, with = "weekdays")]
+    pub days: Vec<Weekday>,
+    pub start: TimeOfDay,
+    pub end: TimeOfDay,
+}
+
+impl TimeWindow {
+    fn contains(&self, weekday: Weekday, minute: u32) -> bool {
+        let on = |day: Weekday| self.days.is_empty() || self.days.contains(&day);
+        let (start, end) = (self.start.0, self.end.0);
+        if start < end {
+            on(weekday) && start <= minute && minute < end
+        } else {
+            (on(weekday) && minute >= start) || (on(weekday.pred()) && minute < end)
+        }
+    }
+}
+
+/// `HH:MM`, up to `24:00`; minutes since midnight.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimeOfDay(u32);
+
+impl FromStr for TimeOfDay {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("invalid time of day {s:?}, expected HH:MM");
+        let (hours, minutes) = s.split_once(':').ok_or_else(invalid)?;
+        let hours: u32 = hours.parse().map_err(|_| invalid())?;
+        let minutes: u32 = minutes.parse().map_err(|_| invalid())?;
+        if minutes >= 60 || hours * 60 + minutes > 24 * 60 {
+            return Err(invalid());
+        }
+        Ok(Self(hours * 60 + minutes))
+    }
+}
+
+impl fmt::Display for TimeOfDay {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:02}:{:02}", self.0 / 60, self.0 % 60)
+    }
+}
+
+impl Serialize for TimeOfDay {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for TimeOfDay {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+/// `mon`, `monday` or a range such as `mon-fri`.
+pub fn parse_days(s: &str) -> Result<Vec<Weekday>, String> {
+    let day = |name: &str| {
+        Weekday::from_str(name.trim()).map_err(|_| format!("unknown day {:?}", name.trim()))
+    };
+    match s.split_once('-') {
+        Some((first, last)) => {
+            let (mut day, last) = (day(first)?, day(last)?);
+            let mut days = vec![day];
+            while day != last {
+                day = day.succ();
+                days.push(day);
+            }
+            Ok(days)
+        }
+        None => Ok(vec![day(s)?]),
+    }
+}
+
+mod weekdays {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(days: &[Weekday], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(days.iter().map(|d| d.to_string().to_lowercase()))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Vec<Weekday>, D::Error> {
+        let mut days = Vec::new();
+        for entry in Vec::<String>::deserialize(deserializer)? {
+            for day in parse_days(&entry).map_err(serde::de::Error::custom)? {
+                if !days.contains(&day) {
+                    days.push(day);
+                }
+            }
+        }
+        Ok(days)
+    }
+}
+
+/// A standard five-field cron expression. Fields take `*`, numbers, `a-b`
+/// ranges, `/step`s and comma lists; months and days of the week also take
+/// names (`jan`, `mon`), and Sunday is `0` or `7`. When both the day of the
+/// month and the day of the week are restricted, either one matching is
+/// enough, as in cron.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Cron {
+    source: String,
+    /// Bit `n` set when value `n` matches.
+    minutes: u64,
+    hours: u64,
+    days: u64,
+    months: u64,
+    weekdays: u64,
+    days_restricted: bool,
+    weekdays_restricted: bool,
+}
+
+const MONTHS: &[&str] = &[
+    "jan", "feb", "mar", "apr", "may", "jun", "jul", "aug", "sep", "oct", "nov", "dec",
+];
+const WEEKDAYS: &[&str] = &["sun", "mon", "tue", "wed", "thu", "fri", "sat"];
+
+impl Cron {
+    pub fn parse(source: &str) -> Result<Self, String> {
+        let fields: Vec<&str> = source.split_whitespace().collect();
+        let [minutes, hours, days, months, weekdays] = fields[..] else {
+            return Err(format!(
+                "expected 5 fields (minute hour day-of-month month day-of-week), found {}",
+                fields.len()
+            ));
+        };
+        let weekday_bits = cron_field(weekdays, "day-of-week", 0, 7, WEEKDAYS, 0)?;
+        Ok(Self {
+            source: source.to_string(),
+            minutes: cron_field(minutes, "minute", 0, 59, &[], 0)?,
+            hours: cron_field(hours, "hour", 0, 23, &[], 0)?,
+            days: cron_field(days, "day-of-month", 1, 31, &[], 0)?,
+            months: cron_field(months, "month", 1, 12, MONTHS, 1)?,
+            // 7 is Sunday too.
+            weekdays: (weekday_bits | weekday_bits >> 7) & 0x7f,
+            days_restricted: !days.starts_with('*'),
+            weekdays_restricted: !weekdays.starts_with('*'),
+        })
+    }
+
+    fn matches(&self, minute: u32, hour: u32, day: u32, month: u32, weekday: Weekday) -> bool {
+        let bit = |set: u64, n: u32| set & (1 << n) != 0;
+        let day_matches = bit(self.days, day);
+        let weekday_matches = bit(self.weekdays, weekday.num_days_from_sunday());
+        let date_matches = if self.days_restricted && self.weekdays_restricted {
+            day_matches || weekday_matches
+        } else {
+            day_matches && weekday_matches
+        };
+        bit(self.minutes, minute)
+            && bit(self.hours, hour)
+            && bit(self.months, month)
+            && date_matches
+    }
+}
+
+/// Parses one cron field into a bit set. `names[i]` stands for
+/// `first_name + i`.
+fn cron_field(
+    field: &str,
+    what: &str,
+    min: u32,
+    max: u32,
+    names: &[&str],
+    first_name: u32,
+) -> Result<u64, String> {
+    let value = |s: &str| -> Result<u32, String> {
+        let n = match names.iter().position(|n| n.eq_ignore_ascii_case(s)) {
+            Some(i) => i as u32 + first_name,
+            None => s
+                .parse()
+                .map_err(|_| format!("invalid {what} value {s:?}"))?,
+        };
+        if n < min || n > max {
+            return Err(format!("{what} {n} out of range {min}-{max}"));
+        }
+        Ok(n)
+    };
+    let mut bits = 0u64;
+    for part in field.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => match step.parse::<u32>() {
+                Ok(step) if step > 0 => (range, step),
+                _ => return Err(format!("invalid {what} step {step:?}")),
+            },
+            None => (part, 1),
+        };
+        let (first, last) = match range {
+            "*" => (min, max),
+            _ => match range.split_once('-') {
+                Some((first, last)) => (value(first)?, value(last)?),
+                // `5/15` runs from 5 to the end of the range.
+                None if step > 1 => (value(range)?, max),
+                None => (value(range)?, value(range)?),
+            },
+        };
+        if first > last {
+            return Err(format!("invalid {what} range {range:?}"));
+        }
+        for n in (first..=last).step_by(step as usize) {
+            bits |= 1 << n;
+        }
+    }
+    Ok(bits)
+}
+
+impl fmt::Display for Cron {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.source)
+    }
+}
+
+impl Serialize for Cron {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.source)
+    }
+}
+
+impl<'de> Deserialize<'de> for Cron {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let source = String::deserialize(deserializer)?;
+        Cron::parse(&source).map_err(|err| {
+            serde::de::Error::custom(format!("invalid cron expression {source:?}: {err}"))
+        })
+    }
+}
+
+/// A period during which none of a profile's policies are enforced.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MaintenanceWindow {
+    pub name: String,
+    pub description: Option<String>,
+    /// RFC 3339; open-ended when omitted.
+    #[serde(default, with = "rfc3339")]
+    pub start: Option<DateTime<FixedOffset>>,
+    #[serde(default, with = "rfc3339")]
+    pub end: Option<DateTime<FixedOffset>>,
+    /// Recurring window, between `start` and `end` when given.
+    #[serde(default)]
+    pub schedule: Option<Schedule>,
+}
+
+impl MaintenanceWindow {
+    pub fn is_active(&self, now_ms: i64) -> bool {
+        self.start
+            .is_none_or(|start| start.timestamp_millis() <= now_ms)
+            && self.end.is_none_or(|end| now_ms < end.timestamp_millis())
+            && self.schedule.as_ref().is_none_or(|s| s.is_active(now_ms))
+    }
+
+    pub fn check(&self) -> Result<(), String> {
+        if let (Some(start), Some(end)) = (self.start, self.end) {
+            if end <= start {
+                return Err("end must be after start".to_string());
+            }
+        }
+        match &self.schedule {
+            Some(schedule) => schedule.check(),
+            None if self.start.is_none() && self.end.is_none() => {
+                Err("needs start, end or a schedule".to_string())
+            }
+            None => Ok(()),
+        }
+    }
+}
+
+mod rfc3339 {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(
+        time: &Option<DateTime<FixedOffset>>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        match time {
+            Some(time) => serializer.serialize_str(&time.to_rfc3339()),
+            None => serializer.serialize_none(),
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Option<DateTime<FixedOffset>>, D::Error> {
+        let Some(source) = Option::<String>::deserialize(deserializer)? else {
+            return Ok(None);
+        };
+        DateTime::parse_from_rfc3339(&source)
+            .map(Some)
+            .map_err(|err| serde::de::Error::custom(format!("invalid time {source:?}: {err}")))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Unix ms of a UTC date and time.
+    fn at(s: &str) -> i64 {
+        DateTime::parse_from_rfc3339(s).unwrap().timestamp_millis()
+    }
+
+    #[test]
+    fn cron_fields() {
+        let cron = Cron::parse("*/15 17-20 * * mon-fri").unwrap();
+        let active = |m, h, d, wd| cron.matches(m, h, d, 1, wd);
+        assert!(active(0, 17, 5, Weekday::Mon));
+        assert!(active(45, 20, 5, Weekday::Fri));
+        assert!(!active(46, 20, 5, Weekday::Fri));
+        assert!(!active(0, 21, 5, Weekday::Fri));
+        assert!(!active(0, 17, 5, Weekday::Sat));
+
+        // Day of month or Sunday (as 7).
+        let cron = Cron::parse("0 0 1,15 * 7").unwrap();
+        assert!(cron.matches(0, 0, 15, 6, Weekday::Tue));
+        assert!(cron.matches(0, 0, 9, 6, Weekday::Sun));
+        assert!(!cron.matches(0, 0, 9, 6, Weekday::Mon));
+
+        assert_eq!(
+            Cron::parse("0 24 * * *").unwrap_err(),
+            "hour 24 out of range 0-23"
+        );
+        assert!(Cron::parse("0 0 * *").is_err());
+        assert!(Cron::parse("0 0 * foo *").is_err());
+    }
+
+    #[test]
+    fn windows_use_the_timezone_and_wrap_past_midnight() {
+        let schedule: Schedule = serde_yaml::from_str(
+            r#"
+timezone: Europe/Stockholm
+windows:
+  - { days: [mon-fri], start: "17:00", end: "21:00" }
+  - { days: [sat], start: "22:00", end: "02:00" }
+"#,
+        )
+        .unwrap();
+        assert_eq!(schedule.check(), Ok(()));
+        // 2026-01-05 is a Monday; Stockholm is UTC+1 in winter.
+        assert!(schedule.is_active(at("2026-01-05T16:00:00Z")));
+        assert!(!schedule.is_active(at("2026-01-05T20:00:00Z")));
+        assert!(!schedule.is_active(at("2026-01-10T16:00:00Z")));
+        // Saturday night runs into Sunday.
+        assert!(schedule.is_active(at("2026-01-10T23:30:00Z")));
+        assert!(!schedule.is_active(at("2026-01-11T01:00:00Z")));
+        assert!(!schedule.is_active(at("2026-01-11T23:30:00Z")));
+    }
+
+    #[test]
+    fn maintenance_window_bounds() {
+        let window: MaintenanceWindow = serde_yaml::from_str(
+            r#"
+name: training-run
+start: "2026-03-02T08:00:00+01:00"
+end: "2026-03-05T08:00:00+01:00"
+"#,
+        )
+        .unwrap();
+        assert_eq!(window.check(), Ok(()));
+        assert!(!window.is_active(at("2026-03-02T06:59:59Z")));
+        assert!(window.is_active(at("2026-03-02T07:00:00Z")));
+        assert!(!window.is_active(at("2026-03-05T07:00:00Z")));
+
+        let mut reversed = window.clone();
+        reversed.end = window.start;
+        assert!(reversed.check().is_err());
+        let unbounded: MaintenanceWindow = serde_yaml::from_str("name: forever").unwrap();
+        assert!(unbounded.check().is_err());
+    }
+}
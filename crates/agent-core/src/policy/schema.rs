@@ -0,0 +1,211 @@
+// ESNODE | Source Available BUSL-1.1 | Copyright (c) 2025 Estimatedstocks AB
+//! JSON Schema for `EfficiencyProfile` manifests.
+//!
+//! Built from the policy types (targets, action types and their required
+//! parameters, severities, selector labels, apiVersions), so it tracks what
+//! the agent accepts. `docs/efficiency-profile.schema.json` is a rendered
+//! copy that the tests keep in sync, for editors and CI linters.
+
+use serde_json::{json, Value};
+
+use super::{ActionType, PolicySeverity, PolicyTarget, API_VERSIONS, KIND, RESOURCE_LABELS};
+
+pub fn profile_schema() -> Value {
+    json!({
+        "$schema": "https://json-schema.org/draft/2020-12/schema",
+        "title": KIND,
+        "type": "object",
+        "required": ["apiVersion", "kind", "metadata", "selectors", "policies"],
+        "additionalProperties": false,
+        "properties": {
+            "apiVersion": { "enum": API_VERSIONS },
+            "kind": { "const": KIND },
+            "metadata": metadata(),
+            "selectors": selectors(),
+            "policies": { "type": "array", "items": policy() },
+            "maintenance_windows": { "type": "array", "items": maintenance_window() },
+            "maintenanceWindows": { "type": "array", "items": maintenance_window() },
+        },
+    })
+}
+
+fn metadata() -> Value {
+    json!({
+        "type": "object",
+        "required": ["name", "version"],
+        "additionalProperties": false,
+        "properties": {
+            "name": { "type": "string" },
+            "description": { "type": "string" },
+            "version": { "type": "string" },
+            "priority": { "type": "integer" },
+        },
+    })
+}
+
+fn selectors() -> Value {
+    let tags = json!({ "type": "object", "additionalProperties": { "type": "string" } });
+    let labels = json!({
+        "type": "object",
+        "propertyNames": { "enum": RESOURCE_LABELS },
+        "additionalProperties": { "type": "string" },
+    });
+    json!({
+        "type": "object",
+        "additionalProperties": false,
+        "properties": {
+            "match_tags": tags,
+            "matchTags": tags,
+            "match_labels": labels,
+            "matchLabels": labels,
+        },
+    })
+}
+
+fn policy() -> Value {
+    let targets: Vec<_> = PolicyTarget::ALL.iter().map(|t| t.name()).collect();
+    let severities: Vec<_> = PolicySeverity::ALL.iter().map(|s| s.name()).collect();
+    json!({
+        "type": "object",
+        "required": ["name", "target", "condition", "action", "severity"],
+        "additionalProperties": false,
+        "properties": {
+            "name": { "type": "string" },
+            "description": { "type": "string" },
+            "target": { "enum": targets },
+            "condition": { "type": "string", "description": "Condition expression, e.g. \"> 85C\"." },
+            "duration": { "type": "string", "description": "How long the condition must hold, e.g. \"5m\"." },
+            "clear_condition": { "type": "string" },
+            "schedule": schedule(),
+            "action": action(),
+            "escalation": {
+                "type": "array",
+                "items": {
+                    "type": "object",
+                    "required": ["after", "action"],
+                    "additionalProperties": false,
+                    "properties": {
+                        "after": { "type": "string", "description": "How long the condition must have held, e.g. \"10m\"." },
+                        "action": action(),
+                    },
+                },
+            },
+            "severity": { "enum": severities },
+        },
+    })
+}
+
+fn schedule() -> Value {
+    let time = json!({ "type": "string", "description": "HH:MM, up to 24:00." });
+    json!({
+        "type": "object",
+        "additionalProperties": false,
+        "properties": {
+            "timezone": { "type": "string", "description": "IANA timezone, e.g. \"Europe/Stockholm\"; UTC by default." },
+            "cron": { "type": "string", "description": "Active during every minute it matches, e.g. \"* 17-20 * * mon-fri\"." },
+            "windows": {
+                "type": "array",
+                "items": {
+                    "type": "object",
+                    "required": ["start", "end"],
+                    "additionalProperties": false,
+                    "properties": {
+                        "days": { "type": "array", "items": { "type": "string" }, "description": "e.g. [mon-fri, sun]; every day when omitted." },
+                        "start": time,
+                        "end": time,
+                    },
+                },
+            },
+        },
+        "anyOf": [{ "required": ["cron"] }, { "required": ["windows"] }],
+    })
+}
+
+fn maintenance_window() -> Value {
+    let time = json!({ "type": "string", "format": "date-time" });
+    json!({
+        "type": "object",
+        "required": ["name"],
+        "additionalProperties": false,
+        "properties": {
+            "name": { "type": "string" },
+            "description": { "type": "string" },
+            "start": time,
+            "end": time,
+            "schedule": schedule(),
+        },
+        "anyOf": [{ "required": ["start"] }, { "required": ["end"] }, { "required": ["schedule"] }],
+    })
+}
+
+fn action() -> Value {
+    let types: Vec<_> = ActionType::ALL.iter().map(|t| t.name()).collect();
+    // One if/then per action type with parameters; a required parameter
+    // may be given under any of its names.
+    let rules: Vec<_> = ActionType::ALL
+        .iter()
+        .filter(|t| !t.required_parameters().is_empty() || !t.optional_parameters().is_empty())
+        .map(|t| {
+            let required: Vec<_> = t
+                .required_parameters()
+                .iter()
+                .map(|p| {
+                    json!({ "anyOf": p.names().map(|n| json!({ "required": [n] })).collect::<Vec<_>>() })
+                })
+                .collect();
+            let mut properties: serde_json::Map<_, _> = t
+                .required_parameters()
+                .iter()
+                .flat_map(|p| p.names())
+                .map(|n| (n.to_string(), json!({ "type": "number" })))
+                .collect();
+            for p in t.optional_parameters() {
+                let value = match p.values {
+                    [] => json!({ "type": "number" }),
+                    values => json!({ "enum": values }),
+                };
+                properties.insert(p.name.to_string(), value);
+            }
+            let then = if required.is_empty() {
+                json!({ "properties": { "parameters": { "properties": properties } } })
+            } else {
+                json!({
+                    "required": ["parameters"],
+                    "properties": {
+                        "parameters": { "allOf": required, "properties": properties },
+                    },
+                })
+            };
+            json!({
+                "if": { "properties": { "type": { "const": t.name() } } },
+                "then": then,
+            })
+        })
+        .collect();
+    json!({
+        "type": "object",
+        "required": ["type"],
+        "additionalProperties": false,
+        "properties": {
+            "type": { "enum": types },
+            "parameters": { "type": "object" },
+        },
+        "allOf": rules,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn docs_copy_is_current() {
+        let rendered = serde_json::to_string_pretty(&profile_schema()).unwrap();
+        let docs = include_str!("../../../../docs/efficiency-profile.schema.json");
+        assert_eq!(
+            docs.trim_end(),
+            rendered,
+            "regenerate with `esnode-core validate --schema > docs/efficiency-profile.schema.json`"
+        );
+    }
+}
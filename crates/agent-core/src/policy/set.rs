@@ -0,0 +1,391 @@
+// ESNODE | Source Available BUSL-1.1 | Copyright (c) 2025 Estimatedstocks AB
+//! Efficiency profiles loaded from several files and directories.
+//!
+//! A file is only re-read when its modification time changes, so profiles
+//! are parsed and validated once per edit rather than on every enforcement
+//! tick. Profiles are evaluated in `metadata.priority` order (highest
+//! first). When violated policies ask for different actions of the same
+//! type on one resource (e.g. two power caps), the highest priority one is
+//! applied and the others are reported `CONFLICT`; at equal priority none
+//! of them is applied.
+
+use std::collections::hash_map::Entry;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use super::{ActionType, EfficiencyProfile, PlanResult, PlanStatus, PolicyEvaluator, ProfileError};
+use crate::state::StatusSnapshot;
+
+/// File extensions picked up from a profiles directory.
+const PROFILE_EXTENSIONS: &[&str] = &["yaml", "yml", "es"];
+
+struct LoadedFile {
+    modified: Option<SystemTime>,
+    /// `None` when the file failed to load; it is retried once modified.
+    profile: Option<EfficiencyProfile>,
+}
+
+/// What changed in a [`ProfileSet::reload`].
+#[derive(Debug, Default)]
+pub struct Reload {
+    pub loaded: Vec<PathBuf>,
+    pub removed: Vec<PathBuf>,
+    pub failed: Vec<(PathBuf, ProfileError)>,
+}
+
+impl Reload {
+    pub fn is_empty(&self) -> bool {
+        self.loaded.is_empty() && self.removed.is_empty() && self.failed.is_empty()
+    }
+}
+
+pub struct ProfileSet {
+    /// Profile files and directories of profiles.
+    sources: Vec<PathBuf>,
+    files: BTreeMap<PathBuf, LoadedFile>,
+    /// Sources that could not be read, reported once until they recover.
+    unreadable: HashSet<PathBuf>,
+}
+
+impl ProfileSet {
+    pub fn new(sources: impl IntoIterator<Item = PathBuf>) -> Self {
+        Self {
+            sources: sources.into_iter().collect(),
+            files: BTreeMap::new(),
+            unreadable: HashSet::new(),
+        }
+    }
+
+    /// Picks up new, modified and removed profile files.
+    pub fn reload(&mut self) -> Reload {
+        let mut reload = Reload::default();
+        let mut present = Vec::new();
+        for source in &self.sources {
+            match profile_files(source) {
+                Ok(paths) => {
+                    self.unreadable.remove(source);
+                    present.extend(paths);
+                }
+                Err(err) => {
+                    if self.unreadable.insert(source.clone()) {
+                        reload.failed.push((source.clone(), err.into()));
+                    }
+                }
+            }
+        }
+
+        for path in &present {
+            let modified = fs::metadata(path).and_then(|m| m.modified()).ok();
+            if let Some(file) = self.files.get(path) {
+                if modified.is_some() && file.modified == modified {
+                    continue;
+                }
+            }
+            let profile = match fs::read_to_string(path)
+                .map_err(ProfileError::from)
+                .and_then(|contents| EfficiencyProfile::from_yaml(&contents))
+            {
+                Ok(profile) => {
+                    reload.loaded.push(path.clone());
+                    Some(profile)
+                }
+                Err(err) => {
+                    reload.failed.push((path.clone(), err));
+                    None
+                }
+            };
+            self.files
+                .insert(path.clone(), LoadedFile { modified, profile });
+        }
+
+        let present: HashSet<_> = present.into_iter().collect();
+        self.files.retain(|path, _| {
+            let keep = present.contains(path);
+            if !keep {
+                reload.removed.push(path.clone());
+            }
+            keep
+        });
+
+        if !reload.loaded.is_empty() {
+            let mut first: HashMap<&str, &Path> = HashMap::new();
+            for (path, file) in &self.files {
+                let Some(profile) = &file.profile else {
+                    continue;
+                };
+                match first.entry(&profile.metadata.name) {
+                    Entry::Occupied(other) => reload.failed.push((
+                        path.clone(),
+                        ProfileError::DuplicateName {
+                            name: profile.metadata.name.clone(),
+                            other: other.get().to_path_buf(),
+                        },
+                    )),
+                    Entry::Vacant(slot) => {
+                        slot.insert(path);
+                    }
+                }
+            }
+        }
+
+        reload
+    }
+
+    /// Valid profiles, highest priority first. When two files define the
+    /// same profile name, the first path wins.
+    pub fn profiles(&self) -> Vec<&EfficiencyProfile> {
+        let mut seen = HashSet::new();
+        let mut profiles: Vec<_> = self
+            .files
+            .values()
+            .filter_map(|file| file.profile.as_ref())
+            .filter(|profile| seen.insert(profile.metadata.name.as_str()))
+            .collect();
+        profiles.sort_by(|a, b| {
+            b.metadata
+                .priority
+                .cmp(&a.metadata.priority)
+                .then_with(|| a.metadata.name.cmp(&b.metadata.name))
+        });
+        profiles
+    }
+
+    pub fn get(&self, name: &str) -> Option<&EfficiencyProfile> {
+        self.profiles()
+            .into_iter()
+            .find(|profile| profile.metadata.name == name)
+    }
+
+    /// Evaluates every profile that applies to `tags` and resolves
+    /// conflicting actions between them.
+    pub fn plan(
+        &self,
+        evaluator: &mut PolicyEvaluator,
+        status: &StatusSnapshot,
+        tags: &HashMap<String, String>,
+        now_ms: i64,
+    ) -> Vec<PlanResult> {
+        let profiles: Vec<_> = self
+            .profiles()
+            .into_iter()
+            .filter(|profile| profile.applies_to(tags))
+            .collect();
+        evaluator.retain_profiles(|name| profiles.iter().any(|p| p.metadata.name == name));
+        let mut plans: Vec<_> = profiles
+            .iter()
+            .map(|profile| evaluator.evaluate(profile, status, now_ms))
+            .collect();
+        resolve_conflicts(&profiles, &mut plans);
+        plans
+    }
+}
+
+/// Marks violated policies whose action clashes with another one on the
+/// same resource. `plans[i]` must be the plan of `profiles[i]`.
+fn resolve_conflicts(profiles: &[&EfficiencyProfile], plans: &mut [PlanResult]) {
+    struct Claim<'a> {
+        at: (usize, usize),
+        priority: i32,
+        resource: String,
+        action: &'a super::PolicyAction,
+        owner: String,
+    }
+
+    let mut claims = Vec::new();
+    for (p, (profile, result)) in profiles.iter().zip(plans.iter()).enumerate() {
+        for (i, plan) in result.matched_policies.iter().enumerate() {
+            if plan.status != PlanStatus::Violated {
+                continue;
+            }
+            let Some(rule) = profile.policies.iter().find(|r| r.name == plan.policy_name) else {
+                continue;
+            };
+            let action = rule.action_at(plan.escalation_step.unwrap_or(0));
+            // Alerts can all be sent, and clock resets add up.
+            if matches!(
+                action.action_type,
+                ActionType::Alert | ActionType::ResetClocks
+            ) {
+                continue;
+            }
+            claims.push(Claim {
+                at: (p, i),
+                priority: profile.metadata.priority,
+                resource: plan.target_resource.clone(),
+                action,
+                owner: format!("{}/{}", profile.metadata.name, rule.name),
+            });
+        }
+    }
+
+    for claim in &claims {
+        let rivals: Vec<_> = claims
+            .iter()
+            .filter(|other| {
+                other.resource == claim.resource
+                    && other.action.action_type == claim.action.action_type
+                    && locked_clock(other.action) == locked_clock(claim.action)
+                    && other.action.parameters != claim.action.parameters
+            })
+            .collect();
+        if rivals.is_empty() {
+            continue;
+        }
+        let plan = &mut plans[claim.at.0].matched_policies[claim.at.1];
+        plan.conflicts_with = rivals.iter().map(|r| r.owner.clone()).collect();
+        if rivals.iter().any(|r| r.priority >= claim.priority) {
+            plan.status = PlanStatus::Conflict;
+            plan.computed_action = None;
+        }
+    }
+}
+
+/// The clock a `lock_clock` action locks; locks of different clocks do not
+/// clash.
+fn locked_clock(action: &super::PolicyAction) -> Option<&str> {
+    if action.action_type != ActionType::LockClock {
+        return None;
+    }
+    match action.parameters.get("clock").and_then(|v| v.as_str()) {
+        None | Some("sm") => Some("graphics"),
+        clock => clock,
+    }
+}
+
+/// The profile files at `source`: the file itself, or the `*.yaml`, `*.yml`
+/// and `*.es` files of a directory, sorted.
+pub fn profile_files(source: &Path) -> std::io::Result<Vec<PathBuf>> {
+    if !fs::metadata(source)?.is_dir() {
+        return Ok(vec![source.to_path_buf()]);
+    }
+    let mut paths = Vec::new();
+    for entry in fs::read_dir(source)? {
+        let path = entry?.path();
+        let is_profile = path
+            .extension()
+            .and_then(|ext| ext.to_str())
+            .is_some_and(|ext| PROFILE_EXTENSIONS.contains(&ext));
+        if is_profile && path.is_file() {
+            paths.push(path);
+        }
+    }
+    paths.sort();
+    Ok(paths)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::GpuStatus;
+
+    fn write(dir: &Path, file: &str, name: &str, priority: i32, limit: u32) {
+        fs::write(
+            dir.join(file),
+            format!(
+                r#"
+apiVersion: v1
+kind: EfficiencyProfile
+metadata: {{ name: {name}, version: "1", priority: {priority} }}
+selectors: {{}}
+policies:
+  - name: cap
+    target: gpu_temp_celsius
+    condition: "> 80"
+    severity: warning
+    action: {{ type: throttle_power, parameters: {{ limit_watts: {limit} }} }}
+"#
+            ),
+        )
+        .unwrap();
+    }
+
+    /// Moves the mtime forward; rewrites within one tick may keep it.
+    fn bump_mtime(path: &Path, secs: u64) {
+        fs::File::options()
+            .append(true)
+            .open(path)
+            .unwrap()
+            .set_modified(SystemTime::now() + std::time::Duration::from_secs(secs))
+            .unwrap();
+    }
+
+    fn hot() -> StatusSnapshot {
+        StatusSnapshot {
+            gpus: vec![GpuStatus {
+                uuid: Some("0".to_string()),
+                temperature_celsius: Some(90.0),
+                ..Default::default()
+            }],
+            ..Default::default()
+        }
+    }
+
+    fn statuses(set: &ProfileSet) -> Vec<(String, PlanStatus)> {
+        set.plan(&mut PolicyEvaluator::default(), &hot(), &HashMap::new(), 0)
+            .into_iter()
+            .map(|plan| (plan.profile_name, plan.matched_policies[0].status.clone()))
+            .collect()
+    }
+
+    #[test]
+    fn priority_wins_and_equal_priority_conflicts() {
+        let dir = tempfile::tempdir().unwrap();
+        write(dir.path(), "a.yaml", "base", 0, 300);
+        write(dir.path(), "b.yaml", "override", 10, 250);
+        fs::write(dir.path().join("notes.txt"), "ignored").unwrap();
+        let mut set = ProfileSet::new([dir.path().to_path_buf()]);
+
+        let reload = set.reload();
+        assert_eq!(reload.loaded.len(), 2);
+        assert!(set.reload().is_empty(), "unchanged files are not re-read");
+        assert_eq!(
+            statuses(&set),
+            [
+                ("override".to_string(), PlanStatus::Violated),
+                ("base".to_string(), PlanStatus::Conflict),
+            ]
+        );
+
+        write(dir.path(), "b.yaml", "override", 0, 250);
+        bump_mtime(&dir.path().join("b.yaml"), 10);
+        assert_eq!(set.reload().loaded, [dir.path().join("b.yaml")]);
+        assert!(statuses(&set)
+            .iter()
+            .all(|(_, status)| *status == PlanStatus::Conflict));
+
+        // Identical actions do not conflict.
+        write(dir.path(), "b.yaml", "override", 0, 300);
+        bump_mtime(&dir.path().join("b.yaml"), 20);
+        set.reload();
+        assert!(statuses(&set)
+            .iter()
+            .all(|(_, status)| *status == PlanStatus::Violated));
+
+        fs::remove_file(dir.path().join("a.yaml")).unwrap();
+        assert_eq!(set.reload().removed, [dir.path().join("a.yaml")]);
+        assert_eq!(set.profiles().len(), 1);
+    }
+
+    #[test]
+    fn invalid_and_duplicate_profiles_are_reported() {
+        let dir = tempfile::tempdir().unwrap();
+        write(dir.path(), "a.yaml", "same", 0, 300);
+        write(dir.path(), "b.yaml", "same", 0, 250);
+        fs::write(dir.path().join("c.yaml"), "kind: [").unwrap();
+        let mut set = ProfileSet::new([dir.path().to_path_buf()]);
+
+        let reload = set.reload();
+        let failed: Vec<_> = reload.failed.iter().map(|(path, _)| path.clone()).collect();
+        assert_eq!(
+            failed,
+            [dir.path().join("c.yaml"), dir.path().join("b.yaml")]
+        );
+        assert!(matches!(
+            reload.failed[1].1,
+            ProfileError::DuplicateName { .. }
+        ));
+        assert_eq!(set.profiles().len(), 1);
+    }
+}
@@ -0,0 +1,660 @@
+// ESNODE | Source Available BUSL-1.1 | Copyright (c) 2025 Estimatedstocks AB
+//! Profile validation with source positions.
+//!
+//! `serde_yaml` stops at the first error, so manifests are also parsed into
+//! a positioned tree and checked against [`profile_schema`] (structure,
+//! enums, required action parameters) and then semantically (condition
+//! syntax and units, durations). Every problem is reported with its line
+//! and column.
+//!
+//! Only the schema keywords `profile_schema` uses are interpreted: `type`,
+//! `enum`, `const`, `required`, `properties`, `additionalProperties`,
+//! `propertyNames`, `items`, `allOf`, `anyOf` and `if`/`then`.
+
+use std::fmt;
+
+use serde_json::Value;
+use yaml_rust2::parser::{Event, MarkedEventReceiver, Parser};
+use yaml_rust2::scanner::{Marker, TScalarStyle};
+
+use super::schema::profile_schema;
+use super::{
+    parse_days, Condition, ConditionError, Cron, EfficiencyProfile, PolicyTarget, ProfileError,
+    TimeOfDay,
+};
+
+/// One problem in a profile, 1-based line and column.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diagnostic {
+    pub line: usize,
+    pub column: usize,
+    /// Where in the document, e.g. `policies[1].action.type`.
+    pub path: String,
+    pub message: String,
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}: ", self.line, self.column)?;
+        if !self.path.is_empty() {
+            write!(f, "{}: ", self.path)?;
+        }
+        f.write_str(&self.message)
+    }
+}
+
+/// Checks a profile manifest and returns every problem found, in document
+/// order. An empty result means [`EfficiencyProfile::from_yaml`] accepts it.
+pub fn validate_yaml(source: &str) -> Vec<Diagnostic> {
+    let root = match parse(source) {
+        Ok(Some(root)) => root,
+        Ok(None) => {
+            return vec![Diagnostic {
+                line: 1,
+                column: 1,
+                path: String::new(),
+                message: "empty document".to_string(),
+            }]
+        }
+        Err(diagnostic) => return vec![diagnostic],
+    };
+
+    let mut checker = Checker::default();
+    checker.check(&root, &profile_schema(), "");
+    checker.check_semantics(&root);
+    let mut diagnostics = checker.diagnostics;
+
+    if diagnostics.is_empty() {
+        // Anything the checks above do not model, reported by the loader.
+        if let Err(err) = EfficiencyProfile::from_yaml(source) {
+            let (line, column) = match &err {
+                ProfileError::Yaml(e) => e.location().map_or((1, 1), |l| (l.line(), l.column())),
+                _ => (1, 1),
+            };
+            diagnostics.push(Diagnostic {
+                line,
+                column,
+                path: String::new(),
+                message: err.to_string(),
+            });
+        }
+    }
+    diagnostics.sort_by_key(|d| (d.line, d.column));
+    diagnostics
+}
+
+#[derive(Debug)]
+enum NodeValue {
+    Scalar { value: String, quoted: bool },
+    Seq(Vec<Node>),
+    Map(Vec<(Node, Node)>),
+    Alias,
+}
+
+#[derive(Debug)]
+struct Node {
+    value: NodeValue,
+    /// 1-based.
+    line: usize,
+    /// 1-based.
+    column: usize,
+}
+
+impl Node {
+    fn get(&self, key: &str) -> Option<&Node> {
+        match &self.value {
+            NodeValue::Map(entries) => entries
+                .iter()
+                .find(|(k, _)| k.scalar() == Some(key))
+                .map(|(_, v)| v),
+            _ => None,
+        }
+    }
+
+    fn scalar(&self) -> Option<&str> {
+        match &self.value {
+            NodeValue::Scalar { value, .. } => Some(value),
+            _ => None,
+        }
+    }
+
+    /// The JSON type a scalar reads as; plain scalars are typed like YAML
+    /// 1.2 core, but still deserialize into string fields.
+    fn kind(&self) -> &'static str {
+        match &self.value {
+            NodeValue::Map(_) => "object",
+            NodeValue::Seq(_) => "array",
+            NodeValue::Alias => "alias",
+            NodeValue::Scalar { quoted: true, .. } => "string",
+            NodeValue::Scalar { value, .. } => match value.as_str() {
+                "" | "~" | "null" | "Null" | "NULL" => "null",
+                "true" | "True" | "TRUE" | "false" | "False" | "FALSE" => "boolean",
+                v if v.parse::<i64>().is_ok() => "integer",
+                v if v.parse::<f64>().is_ok() => "number",
+                _ => "string",
+            },
+        }
+    }
+
+    fn matches_type(&self, expected: &str) -> bool {
+        let kind = self.kind();
+        match expected {
+            "string" => !matches!(kind, "object" | "array" | "null"),
+            "number" => matches!(kind, "number" | "integer"),
+            other => kind == other,
+        }
+    }
+}
+
+fn parse(source: &str) -> Result<Option<Node>, Diagnostic> {
+    let mut builder = TreeBuilder::default();
+    let mut parser = Parser::new_from_str(source);
+    parser.load(&mut builder, false).map_err(|err| Diagnostic {
+        line: err.marker().line(),
+        column: err.marker().col() + 1,
+        path: String::new(),
+        message: format!("invalid YAML: {}", err.info()),
+    })?;
+    Ok(builder.root)
+}
+
+#[derive(Default)]
+struct TreeBuilder {
+    /// Open collections, each with the key awaiting its value.
+    stack: Vec<(Node, Option<Node>)>,
+    root: Option<Node>,
+}
+
+impl TreeBuilder {
+    fn push(&mut self, node: Node) {
+        let Some((parent, pending_key)) = self.stack.last_mut() else {
+            self.root.get_or_insert(node);
+            return;
+        };
+        match &mut parent.value {
+            NodeValue::Seq(items) => items.push(node),
+            NodeValue::Map(entries) => match pending_key.take() {
+                Some(key) => entries.push((key, node)),
+                None => {
+                    // The parser marks a block mapping after its first key;
+                    // point at that key instead.
+                    if entries.is_empty() {
+                        (parent.line, parent.column) = (node.line, node.column);
+                    }
+                    *pending_key = Some(node);
+                }
+            },
+            _ => {}
+        }
+    }
+}
+
+impl MarkedEventReceiver for TreeBuilder {
+    fn on_event(&mut self, event: Event, mark: Marker) {
+        let node = |value| Node {
+            value,
+            line: mark.line(),
+            column: mark.col() + 1,
+        };
+        match event {
+            Event::Scalar(value, style, ..) => self.push(node(NodeValue::Scalar {
+                value,
+                quoted: style != TScalarStyle::Plain,
+            })),
+            Event::Alias(_) => self.push(node(NodeValue::Alias)),
+            Event::SequenceStart(..) => self.stack.push((node(NodeValue::Seq(Vec::new())), None)),
+            Event::MappingStart(..) => self.stack.push((node(NodeValue::Map(Vec::new())), None)),
+            Event::SequenceEnd | Event::MappingEnd => {
+                if let Some((collection, _)) = self.stack.pop() {
+                    self.push(collection);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+#[derive(Default)]
+struct Checker {
+    diagnostics: Vec<Diagnostic>,
+}
+
+impl Checker {
+    fn report(&mut self, node: &Node, path: &str, message: impl Into<String>) {
+        self.report_at(node.line, node.column, path, message);
+    }
+
+    fn report_at(&mut self, line: usize, column: usize, path: &str, message: impl Into<String>) {
+        self.diagnostics.push(Diagnostic {
+            line,
+            column,
+            path: path.to_string(),
+            message: message.into(),
+        });
+    }
+
+    /// Runs `check` without reporting, returning what it would report.
+    fn trial(node: &Node, schema: &Value, path: &str) -> Vec<Diagnostic> {
+        let mut trial = Checker::default();
+        trial.check(node, schema, path);
+        trial.diagnostics
+    }
+
+    fn check(&mut self, node: &Node, schema: &Value, path: &str) {
+        if let Some(expected) = schema.get("type").and_then(Value::as_str) {
+            if !node.matches_type(expected) {
+                self.report(
+                    node,
+                    path,
+                    format!("expected {expected}, found {}", node.kind()),
+                );
+                return;
+            }
+        }
+        if let Some(expected) = schema.get("const").and_then(Value::as_str) {
+            if node.scalar() != Some(expected) {
+                self.report(node, path, format!("expected {expected:?}"));
+            }
+        }
+        if let Some(allowed) = schema.get("enum").and_then(Value::as_array) {
+            self.check_enum(node, allowed, path, "value");
+        }
+        if let NodeValue::Map(entries) = &node.value {
+            self.check_object(node, entries, schema, path);
+        }
+        if let (NodeValue::Seq(items), Some(item_schema)) = (&node.value, schema.get("items")) {
+            for (i, item) in items.iter().enumerate() {
+                self.check(item, item_schema, &format!("{path}[{i}]"));
+            }
+        }
+        for sub in schema
+            .get("allOf")
+            .and_then(Value::as_array)
+            .into_iter()
+            .flatten()
+        {
+            self.check(node, sub, path);
+        }
+        if let Some(branches) = schema.get("anyOf").and_then(Value::as_array) {
+            let failures: Vec<_> = branches
+                .iter()
+                .map(|b| Self::trial(node, b, path))
+                .collect();
+            if failures.iter().all(|f| !f.is_empty()) {
+                let fields: Vec<_> = branches
+                    .iter()
+                    .filter_map(|b| b.get("required")?.get(0)?.as_str())
+                    .collect();
+                if fields.len() == branches.len() {
+                    let message = match fields.as_slice() {
+                        [only] => format!("missing required field {only:?}"),
+                        [first, rest @ ..] => format!(
+                            "missing required field {first:?} (or {})",
+                            rest.iter()
+                                .map(|f| format!("{f:?}"))
+                                .collect::<Vec<_>>()
+                                .join(", ")
+                        ),
+                        [] => unreachable!(),
+                    };
+                    self.report(node, path, message);
+                } else {
+                    self.diagnostics
+                        .extend(failures.into_iter().next().unwrap_or_default());
+                }
+            }
+        }
+        if let (Some(condition), Some(then)) = (schema.get("if"), schema.get("then")) {
+            if Self::trial(node, condition, path).is_empty() {
+                self.check(node, then, path);
+            }
+        }
+    }
+
+    fn check_enum(&mut self, node: &Node, allowed: &[Value], path: &str, what: &str) {
+        let allowed: Vec<_> = allowed.iter().filter_map(Value::as_str).collect();
+        let Some(value) = node.scalar() else {
+            self.report(
+                node,
+                path,
+                format!("expected one of {}", allowed.join(", ")),
+            );
+            return;
+        };
+        if allowed.contains(&value) {
+            return;
+        }
+        let message = match closest(value, &allowed) {
+            Some(suggestion) => format!("unknown {what} {value:?}, did you mean {suggestion:?}?"),
+            None => format!(
+                "unknown {what} {value:?}, expected one of {}",
+                allowed.join(", ")
+            ),
+        };
+        self.report(node, path, message);
+    }
+
+    fn check_object(&mut self, node: &Node, entries: &[(Node, Node)], schema: &Value, path: &str) {
+        let properties = schema.get("properties").and_then(Value::as_object);
+        let child_path = |key: &str| {
+            if path.is_empty() {
+                key.to_string()
+            } else {
+                format!("{path}.{key}")
+            }
+        };
+
+        for required in schema
+            .get("required")
+            .and_then(Value::as_array)
+            .into_iter()
+            .flatten()
+        {
+            let required = required.as_str().unwrap_or_default();
+            if node.get(required).is_none() {
+                self.report(node, path, format!("missing required field {required:?}"));
+            }
+        }
+
+        for (key, value) in entries {
+            let Some(name) = key.scalar() else {
+                self.report(key, path, "keys must be strings");
+                continue;
+            };
+            let child = child_path(name);
+            if let Some(names) = schema.get("propertyNames").and_then(|n| n.get("enum")) {
+                self.check_enum(key, names.as_array().unwrap_or(&Vec::new()), &child, "key");
+            }
+            match properties.and_then(|p| p.get(name)) {
+                Some(property) => self.check(value, property, &child),
+                None => match schema.get("additionalProperties") {
+                    Some(Value::Bool(false)) => {
+                        let known: Vec<_> = properties
+                            .map(|p| p.keys().map(String::as_str).collect())
+                            .unwrap_or_default();
+                        let message = match closest(name, &known) {
+                            Some(suggestion) => {
+                                format!("unknown field {name:?}, did you mean {suggestion:?}?")
+                            }
+                            None => format!(
+                                "unknown field {name:?}, expected one of {}",
+                                known.join(", ")
+                            ),
+                        };
+                        self.report(key, &child, message);
+                    }
+                    Some(additional @ Value::Object(_)) => self.check(value, additional, &child),
+                    _ => {}
+                },
+            }
+        }
+    }
+
+    /// Checks what the schema cannot express: condition syntax and units
+    /// against the policy target, durations, schedules and times.
+    fn check_semantics(&mut self, root: &Node) {
+        for field in ["maintenance_windows", "maintenanceWindows"] {
+            let Some(Node {
+                value: NodeValue::Seq(windows),
+                ..
+            }) = root.get(field)
+            else {
+                continue;
+            };
+            for (i, window) in windows.iter().enumerate() {
+                let path = format!("{field}[{i}]");
+                for bound in ["start", "end"] {
+                    self.check_scalar(window, bound, &path, |s| {
+                        chrono::DateTime::parse_from_rfc3339(s)
+                            .map(drop)
+                            .map_err(|err| format!("invalid time: {err}"))
+                    });
+                }
+                if let Some(schedule) = window.get("schedule") {
+                    self.check_schedule(schedule, &format!("{path}.schedule"));
+                }
+            }
+        }
+        let Some(Node {
+            value: NodeValue::Seq(policies),
+            ..
+        }) = root.get("policies")
+        else {
+            return;
+        };
+        for (i, policy) in policies.iter().enumerate() {
+            let path = format!("policies[{i}]");
+            let target = policy
+                .get("target")
+                .and_then(Node::scalar)
+                .and_then(PolicyTarget::from_name);
+            for field in ["condition", "clear_condition"] {
+                let Some(node) = policy.get(field) else {
+                    continue;
+                };
+                let Some(source) = node.scalar() else {
+                    continue;
+                };
+                let path = format!("{path}.{field}");
+                let result =
+                    Condition::parse(source).and_then(|c| target.map_or(Ok(()), |t| c.check(t)));
+                match result {
+                    Ok(()) => {}
+                    Err(ConditionError::Parse { pos, msg }) => {
+                        // Point into the expression, past an opening quote.
+                        let quoted = matches!(node.value, NodeValue::Scalar { quoted: true, .. });
+                        let column = node.column + usize::from(quoted) + pos;
+                        self.report_at(node.line, column, &path, msg);
+                    }
+                    Err(err @ ConditionError::Units(_)) => {
+                        self.report(node, &path, err.to_string())
+                    }
+                }
+            }
+            let duration = |s: &str| {
+                humantime_serde::re::humantime::parse_duration(s)
+                    .map(drop)
+                    .map_err(|err| format!("invalid duration: {err}"))
+            };
+            self.check_scalar(policy, "duration", &path, duration);
+            if let Some(Node {
+                value: NodeValue::Seq(steps),
+                ..
+            }) = policy.get("escalation")
+            {
+                for (j, step) in steps.iter().enumerate() {
+                    self.check_scalar(step, "after", &format!("{path}.escalation[{j}]"), duration);
+                }
+            }
+            if let Some(schedule) = policy.get("schedule") {
+                self.check_schedule(schedule, &format!("{path}.schedule"));
+            }
+        }
+    }
+
+    fn check_schedule(&mut self, schedule: &Node, path: &str) {
+        self.check_scalar(schedule, "timezone", path, |s| {
+            s.parse::<chrono_tz::Tz>()
+                .map(drop)
+                .map_err(|_| format!("unknown timezone {s:?}"))
+        });
+        self.check_scalar(schedule, "cron", path, |s| {
+            Cron::parse(s)
+                .map(drop)
+                .map_err(|err| format!("invalid cron expression: {err}"))
+        });
+        let Some(Node {
+            value: NodeValue::Seq(windows),
+            ..
+        }) = schedule.get("windows")
+        else {
+            return;
+        };
+        for (i, window) in windows.iter().enumerate() {
+            let path = format!("{path}.windows[{i}]");
+            for bound in ["start", "end"] {
+                self.check_scalar(window, bound, &path, |s| s.parse::<TimeOfDay>().map(drop));
+            }
+            if let Some(Node {
+                value: NodeValue::Seq(days),
+                ..
+            }) = window.get("days")
+            {
+                for (j, day) in days.iter().enumerate() {
+                    if let Some(Err(message)) = day.scalar().map(parse_days) {
+                        self.report(day, &format!("{path}.days[{j}]"), message);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Reports the error of `parse` on the scalar `field` of `node`, if any.
+    fn check_scalar(
+        &mut self,
+        node: &Node,
+        field: &str,
+        path: &str,
+        parse: impl Fn(&str) -> Result<(), String>,
+    ) {
+        let Some(value) = node.get(field) else {
+            return;
+        };
+        if let Some(Err(message)) = value.scalar().map(parse) {
+            self.report(value, &format!("{path}.{field}"), message);
+        }
+    }
+}
+
+/// The candidate within edit distance 2 of `value`, for typo hints.
+fn closest<'a>(value: &str, candidates: &[&'a str]) -> Option<&'a str> {
+    candidates
+        .iter()
+        .map(|c| (edit_distance(value, c), *c))
+        .filter(|(d, _)| *d <= 2)
+        .min_by_key(|(d, _)| *d)
+        .map(|(_, c)| c)
+}
+
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut prev = row[0];
+        row[0] = i + 1;
+        for (j, cb) in b.iter().enumerate() {
+            let cur = row[j + 1];
+            row[j + 1] = (prev + usize::from(ca != *cb)).min(row[j] + 1).min(cur + 1);
+            prev = cur;
+        }
+    }
+    row[b.len()]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reports_every_problem_with_position() {
+        let source = r#"apiVersion: v2
+kind: EfficiencyProfile
+metadata:
+  name: broken
+selectors: {}
+policies:
+  - name: cap
+    taget: gpu_temp_celsius
+    condition: "> 80 and"
+    severity: critical
+    action:
+      type: throttle_power
+  - name: clocks
+    target: gpu_utilisation
+    condition: "< 5%"
+    duration: 5 minutes later
+    severity: loud
+    action:
+      type: lock_clock
+      parameters: { frequency_mhz: fast, clock: turbo }
+"#;
+        let found: Vec<_> = validate_yaml(source)
+            .into_iter()
+            .map(|d| format!("{}:{} {} | {}", d.line, d.column, d.path, d.message))
+            .collect();
+        assert_eq!(
+            found,
+            [
+                "1:13 apiVersion | unknown value \"v2\", did you mean \"v1\"?",
+                "4:3 metadata | missing required field \"version\"",
+                "7:5 policies[0] | missing required field \"target\"",
+                "8:5 policies[0].taget | unknown field \"taget\", did you mean \"target\"?",
+                "9:25 policies[0].condition | expected number or target",
+                "12:7 policies[0].action | missing required field \"parameters\"",
+                "14:13 policies[1].target | unknown value \"gpu_utilisation\", did you mean \"gpu_utilization\"?",
+                "16:15 policies[1].duration | invalid duration: expected number at 10",
+                "17:15 policies[1].severity | unknown value \"loud\", expected one of info, warning, critical",
+                "20:36 policies[1].action.parameters.frequency_mhz | expected number, found string",
+                "20:49 policies[1].action.parameters.clock | unknown value \"turbo\", expected one of graphics, sm, memory",
+            ]
+        );
+    }
+
+    #[test]
+    fn reports_schedule_problems() {
+        let source = r#"apiVersion: v1
+kind: EfficiencyProfile
+metadata: { name: peak, version: "1" }
+selectors: {}
+maintenance_windows:
+  - name: run
+    end: "next tuesday"
+policies:
+  - name: cap
+    target: gpu_power_watts
+    condition: "> 300W"
+    severity: info
+    action: { type: alert }
+    schedule:
+      timezone: Europe/Stockhlom
+      cron: "0 9-17 * * mon-fry"
+      windows: [{ days: [mon-fri, funday], start: "25:00", end: "18:00" }]
+  - name: idle
+    target: gpu_utilization
+    condition: "< 5"
+    severity: info
+    action: { type: alert }
+    schedule: { timezone: UTC }
+"#;
+        let found: Vec<_> = validate_yaml(source)
+            .into_iter()
+            .map(|d| format!("{}:{} {} | {}", d.line, d.column, d.path, d.message))
+            .collect();
+        assert_eq!(
+            found,
+            [
+                "7:10 maintenance_windows[0].end | invalid time: premature end of input",
+                "15:17 policies[0].schedule.timezone | unknown timezone \"Europe/Stockhlom\"",
+                "16:13 policies[0].schedule.cron | invalid cron expression: invalid day-of-week value \"fry\"",
+                "17:35 policies[0].schedule.windows[0].days[1] | unknown day \"funday\"",
+                "17:51 policies[0].schedule.windows[0].start | invalid time of day \"25:00\", expected HH:MM",
+                "23:17 policies[1].schedule | missing required field \"cron\" (or \"windows\")",
+            ]
+        );
+    }
+
+    #[test]
+    fn valid_profile_has_no_diagnostics() {
+        let source = std::fs::read_to_string(concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/../../sample-profile.yaml"
+        ))
+        .unwrap();
+        assert_eq!(validate_yaml(&source), []);
+        assert!(validate_yaml("kind: [")
+            .first()
+            .unwrap()
+            .message
+            .starts_with("invalid YAML"));
+    }
+}
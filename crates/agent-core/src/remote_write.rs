@@ -0,0 +1,363 @@
+// ESNODE | Source Available BUSL-1.1 | Copyright (c) 2025 Estimatedstocks AB
+//! Prometheus remote-write (1.0) client fed from the local TSDB.
+//!
+//! Each endpoint keeps a watermark, the newest sample timestamp it has
+//! acknowledged, in `<tsdb>/remote_write/<endpoint>.json`. Every pass replays
+//! whatever the TSDB holds past the watermark one block window at a time,
+//! oldest first, so an endpoint that was unreachable catches up in order once
+//! it is back. Failed passes are retried with exponential backoff.
+
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use anyhow::{bail, Context, Result};
+use reqwest::header::{CONTENT_ENCODING, CONTENT_TYPE, USER_AGENT};
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+
+use crate::config::RemoteWriteConfig;
+use crate::metrics::MetricsRegistry;
+use crate::tsdb::{LocalTsdb, Sample};
+
+const STATE_DIR: &str = "remote_write";
+/// Replay reads at most one TSDB block window per step.
+const REPLAY_WINDOW_MS: i64 = 2 * 60 * 60 * 1000;
+/// Samples this recent may still be on their way into the TSDB, so the
+/// watermark never passes `now - SETTLE_MS`.
+const SETTLE_MS: i64 = 10_000;
+
+#[derive(Debug, Serialize, Deserialize)]
+struct WatermarkState {
+    url: String,
+    watermark_ms: i64,
+}
+
+pub struct RemoteWriter {
+    config: RemoteWriteConfig,
+    endpoint: String,
+    tsdb: Arc<LocalTsdb>,
+    metrics: MetricsRegistry,
+    client: reqwest::Client,
+    state_path: PathBuf,
+    watermark_ms: i64,
+}
+
+impl RemoteWriter {
+    /// Loads the endpoint's watermark, or starts it at `now_ms` for an
+    /// endpoint seen for the first time or pointed at a new URL (history is
+    /// not backfilled).
+    pub fn new(
+        config: RemoteWriteConfig,
+        tsdb: Arc<LocalTsdb>,
+        metrics: MetricsRegistry,
+        now_ms: i64,
+    ) -> Result<Self> {
+        if config.max_samples_per_send == 0 {
+            bail!("remote_write max_samples_per_send must be positive");
+        }
+        let endpoint = config.name.clone().unwrap_or_else(|| config.url.clone());
+        let state_dir = tsdb.path().join(STATE_DIR);
+        std::fs::create_dir_all(&state_dir)
+            .with_context(|| format!("creating {}", state_dir.display()))?;
+        let state_path = state_dir.join(format!("{}.json", file_stem(&endpoint)));
+        let client = reqwest::Client::builder()
+            .timeout(config.timeout)
+            .build()
+            .context("building remote-write HTTP client")?;
+        let existing = match std::fs::read(&state_path) {
+            Ok(bytes) => Some(
+                serde_json::from_slice::<WatermarkState>(&bytes)
+                    .with_context(|| format!("parsing {}", state_path.display()))?,
+            ),
+            Err(_) => None,
+        };
+        let watermark_ms = match existing {
+            Some(state) if state.url == config.url => state.watermark_ms,
+            // The watermark says what the old URL received, not the new one.
+            Some(state) => {
+                warn!(
+                    "remote write endpoint {} moved from {} to {}, starting from now without history",
+                    endpoint, state.url, config.url
+                );
+                now_ms
+            }
+            None => now_ms,
+        };
+        let mut writer = Self {
+            watermark_ms,
+            config,
+            endpoint,
+            tsdb,
+            metrics,
+            client,
+            state_path,
+        };
+        writer.persist_watermark(writer.watermark_ms)?;
+        writer.update_lag(now_ms);
+        Ok(writer)
+    }
+
+    /// Newest sample timestamp the endpoint has acknowledged.
+    pub fn watermark_ms(&self) -> i64 {
+        self.watermark_ms
+    }
+
+    /// Sends everything between the watermark and `now_ms` (less a settle
+    /// margin), advancing the watermark after every acknowledged request.
+    /// Returns the number of samples sent; stops at the first retryable
+    /// failure.
+    pub async fn replay_once(&mut self, now_ms: i64) -> Result<usize> {
+        self.update_lag(now_ms);
+        let upper = now_ms - SETTLE_MS;
+        let mut sent = 0;
+        while self.watermark_ms < upper {
+            let from = self.watermark_ms + 1;
+            let to = from.saturating_add(REPLAY_WINDOW_MS - 1).min(upper);
+            let samples = self.tsdb.read_range(from, to).await?;
+            for batch in batches(&samples, self.config.max_samples_per_send) {
+                if self.send(batch).await? {
+                    sent += batch.len();
+                }
+                self.persist_watermark(batch[batch.len() - 1].ts_ms)?;
+                self.update_lag(now_ms);
+            }
+            self.persist_watermark(to)?;
+            self.update_lag(now_ms);
+        }
+        Ok(sent)
+    }
+
+    /// Replays forever: every `interval` while the endpoint accepts writes,
+    /// backing off exponentially (`min_backoff`..`max_backoff`) while it doesn't.
+    pub fn spawn(mut self) -> tokio::task::JoinHandle<()> {
+        tokio::spawn(async move {
+            let mut backoff = self.config.min_backoff;
+            loop {
+                let now_ms = chrono::Utc::now().timestamp_millis();
+                match self.replay_once(now_ms).await {
+                    Ok(sent) => {
+                        if backoff > self.config.min_backoff {
+                            info!(
+                                "remote write to {} recovered, replayed {sent} samples",
+                                self.endpoint
+                            );
+                        }
+                        backoff = self.config.min_backoff;
+                        tokio::time::sleep(self.config.interval).await;
+                    }
+                    Err(err) => {
+                        warn!(
+                            "remote write to {} failed, retrying in {:?}: {err:#}",
+                            self.endpoint, backoff
+                        );
+                        tokio::time::sleep(backoff).await;
+                        backoff = (backoff * 2).min(self.config.max_backoff);
+                    }
+                }
+            }
+        })
+    }
+
+    /// Posts one batch and reports whether the endpoint accepted it. Per the
+    /// remote-write spec, 5xx/429 and transport errors are retryable (`Err`);
+    /// other 4xx responses drop the batch.
+    async fn send(&self, batch: &[Sample]) -> Result<bool> {
+        let body = snap::raw::Encoder::new()
+            .compress_vec(&encode_write_request(batch))
+            .context("snappy-compressing write request")?;
+        let mut request = self
+            .client
+            .post(&self.config.url)
+            .header(CONTENT_ENCODING, "snappy")
+            .header(CONTENT_TYPE, "application/x-protobuf")
+            .header(
+                USER_AGENT,
+                concat!("esnode-core/", env!("CARGO_PKG_VERSION")),
+            )
+            .header("X-Prometheus-Remote-Write-Version", "0.1.0")
+            .body(body);
+        if let Some(token) = &self.config.bearer_token {
+            request = request.bearer_auth(token);
+        }
+        let failed = self
+            .metrics
+            .remote_write_samples_failed_total
+            .with_label_values(&[&self.endpoint]);
+        let status = match request.send().await {
+            Ok(response) => response.status(),
+            Err(err) => {
+                failed.inc_by(batch.len() as u64);
+                return Err(err).context("sending remote-write request");
+            }
+        };
+        if status.is_success() {
+            self.metrics
+                .remote_write_samples_sent_total
+                .with_label_values(&[&self.endpoint])
+                .inc_by(batch.len() as u64);
+            return Ok(true);
+        }
+        failed.inc_by(batch.len() as u64);
+        if status.is_server_error() || status == reqwest::StatusCode::TOO_MANY_REQUESTS {
+            bail!("remote-write endpoint returned {status}");
+        }
+        warn!(
+            "remote write to {} rejected {} samples with {status}; dropping them",
+            self.endpoint,
+            batch.len()
+        );
+        Ok(false)
+    }
+
+    fn persist_watermark(&mut self, watermark_ms: i64) -> Result<()> {
+        let state = WatermarkState {
+            url: self.config.url.clone(),
+            watermark_ms,
+        };
+        let tmp = self.state_path.with_extension("json.tmp");
+        std::fs::write(&tmp, serde_json::to_vec_pretty(&state)?)
+            .with_context(|| format!("writing {}", tmp.display()))?;
+        std::fs::rename(&tmp, &self.state_path)
+            .with_context(|| format!("replacing {}", self.state_path.display()))?;
+        self.watermark_ms = watermark_ms;
+        Ok(())
+    }
+
+    fn update_lag(&self, now_ms: i64) {
+        let lag_secs = (now_ms - self.watermark_ms).max(0) as f64 / 1000.0;
+        self.metrics
+            .remote_write_lag_seconds
+            .with_label_values(&[&self.endpoint])
+            .set(lag_secs);
+    }
+}
+
+/// Splits time-ordered samples into batches of roughly `max` samples,
+/// never splitting a timestamp, so a watermark at a batch's last timestamp
+/// covers everything sent so far.
+fn batches(samples: &[Sample], max: usize) -> Vec<&[Sample]> {
+    let mut out = Vec::new();
+    let mut start = 0;
+    while start < samples.len() {
+        let mut end = (start + max).min(samples.len());
+        while end < samples.len() && samples[end].ts_ms == samples[end - 1].ts_ms {
+            end += 1;
+        }
+        out.push(&samples[start..end]);
+        start = end;
+    }
+    out
+}
+
+fn file_stem(endpoint: &str) -> String {
+    endpoint
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '-' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
+type SeriesLabels<'a> = Vec<(&'a str, &'a str)>;
+
+/// Encodes a `prometheus.WriteRequest` protobuf. Samples are grouped per
+/// series with labels (including `__name__`) sorted by name.
+pub fn encode_write_request(samples: &[Sample]) -> Vec<u8> {
+    let mut series: BTreeMap<SeriesLabels<'_>, Vec<(i64, f64)>> = BTreeMap::new();
+    for sample in samples {
+        let mut labels: Vec<(&str, &str)> = sample
+            .labels
+            .iter()
+            .map(|(k, v)| (k.as_str(), v.as_str()))
+            .collect();
+        labels.push(("__name__", sample.metric.as_str()));
+        labels.sort_unstable();
+        series
+            .entry(labels)
+            .or_default()
+            .push((sample.ts_ms, sample.value));
+    }
+
+    let mut out = Vec::new();
+    let mut ts_buf = Vec::new();
+    let mut field_buf = Vec::new();
+    for (labels, points) in series {
+        ts_buf.clear();
+        for (name, value) in labels {
+            field_buf.clear();
+            put_bytes_field(&mut field_buf, 1, name.as_bytes());
+            put_bytes_field(&mut field_buf, 2, value.as_bytes());
+            put_bytes_field(&mut ts_buf, 1, &field_buf);
+        }
+        for (ts_ms, value) in points {
+            field_buf.clear();
+            field_buf.push(1 << 3 | 1); // value: double
+            field_buf.extend_from_slice(&value.to_le_bytes());
+            field_buf.push(2 << 3); // timestamp: int64
+            put_varint(&mut field_buf, ts_ms as u64);
+            put_bytes_field(&mut ts_buf, 2, &field_buf);
+        }
+        put_bytes_field(&mut out, 1, &ts_buf);
+    }
+    out
+}
+
+fn put_bytes_field(buf: &mut Vec<u8>, field: u64, bytes: &[u8]) {
+    put_varint(buf, field << 3 | 2);
+    put_varint(buf, bytes.len() as u64);
+    buf.extend_from_slice(bytes);
+}
+
+fn put_varint(buf: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        buf.push((value as u8) | 0x80);
+        value >>= 7;
+    }
+    buf.push(value as u8);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn sample(ts_ms: i64) -> Sample {
+        Sample {
+            metric: "m".into(),
+            labels: HashMap::new(),
+            ts_ms,
+            value: 1.0,
+        }
+    }
+
+    #[test]
+    fn batches_do_not_split_a_timestamp() {
+        let samples: Vec<Sample> = [1, 1, 2, 2, 2, 3].into_iter().map(sample).collect();
+        let sizes: Vec<usize> = batches(&samples, 2).iter().map(|b| b.len()).collect();
+        assert_eq!(sizes, vec![2, 3, 1]);
+    }
+
+    #[test]
+    fn encodes_labels_and_samples() {
+        let mut s = sample(1);
+        s.labels.insert("job".into(), "a".into());
+        let bytes = encode_write_request(&[s]);
+        // timeseries { labels{__name__=m} labels{job=a} samples{1.0 @ 1} }
+        let mut expected = vec![0x0a, 0x26];
+        expected.extend_from_slice(&[0x0a, 0x0d, 0x0a, 0x08]);
+        expected.extend_from_slice(b"__name__");
+        expected.extend_from_slice(&[0x12, 0x01, b'm']);
+        expected.extend_from_slice(&[0x0a, 0x08, 0x0a, 0x03]);
+        expected.extend_from_slice(b"job");
+        expected.extend_from_slice(&[0x12, 0x01, b'a']);
+        expected.extend_from_slice(&[0x12, 0x0b, 0x09]);
+        expected.extend_from_slice(&1.0f64.to_le_bytes());
+        expected.extend_from_slice(&[0x10, 0x01]);
+        assert_eq!(bytes, expected);
+    }
+}
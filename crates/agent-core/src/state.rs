@@ -79,6 +79,9 @@ pub struct StatusSnapshot {
     pub disk_root_used_bytes: Option<u64>,
     #[serde(default)]
     pub disk_root_io_time_ms: Option<u64>,
+    /// Worst average I/O latency across block devices over the last interval.
+    #[serde(default)]
+    pub disk_io_avg_latency_ms: Option<f64>,
     #[serde(default)]
     pub primary_nic: Option<String>,
     #[serde(default)]
@@ -92,6 +95,13 @@ pub struct StatusSnapshot {
     #[serde(default)]
     pub app_tokens_per_watt: Option<f64>,
     #[serde(default)]
+    pub node_power_envelope_watts: Option<f64>,
+    #[serde(default)]
+    pub pue_ratio: Option<f64>,
+    /// Latest readings of the IoT/facility protocol drivers.
+    #[serde(default)]
+    pub iot_sensors: Vec<IotSensorReading>,
+    #[serde(default)]
     pub disk_degraded: bool,
     #[serde(default)]
     pub network_degraded: bool,
@@ -121,6 +131,16 @@ pub struct TemperatureReading {
     pub celsius: f64,
 }
 
+/// One `esnode_iot_sensor_value` reading.
+#[derive(Default, Clone, Serialize, Deserialize)]
+pub struct IotSensorReading {
+    pub driver_id: String,
+    pub sensor_type: String,
+    pub unit: String,
+    pub param: String,
+    pub value: f64,
+}
+
 #[derive(Default, Clone, Serialize, Deserialize)]
 pub struct CollectorError {
     pub collector: String,
@@ -151,6 +171,8 @@ pub struct GpuStatus {
     pub mig_tree: Option<MigTree>,
     pub temperature_celsius: Option<f64>,
     pub power_watts: Option<f64>,
+    #[serde(default)]
+    pub power_limit_watts: Option<f64>,
     pub util_percent: Option<f64>,
     pub memory_total_bytes: Option<f64>,
     pub memory_used_bytes: Option<f64>,
@@ -163,6 +185,9 @@ pub struct GpuStatus {
 
 #[derive(Default, Clone, Serialize, Deserialize)]
 pub struct GpuIdentity {
+    /// Product name, e.g. "NVIDIA H100 80GB HBM3".
+    #[serde(default)]
+    pub model: Option<String>,
     #[serde(default)]
     pub pci_bus_id: Option<String>,
     #[serde(default)]
@@ -364,12 +389,16 @@ pub struct HostMetrics {
     pub disk_root_total_bytes: Option<u64>,
     pub disk_root_used_bytes: Option<u64>,
     pub disk_root_io_time_ms: Option<u64>,
+    pub disk_io_avg_latency_ms: Option<f64>,
     pub primary_nic: Option<String>,
     pub net_rx_bytes_per_sec: Option<f64>,
     pub net_tx_bytes_per_sec: Option<f64>,
     pub net_drops_per_sec: Option<f64>,
     pub app_tokens_per_sec: Option<f64>,
     pub k8s_events_detected: Option<bool>,
+    pub node_power_envelope_watts: Option<f64>,
+    pub pue_ratio: Option<f64>,
+    pub iot_sensors: Vec<IotSensorReading>,
 }
 
 impl StatusState {
@@ -445,6 +474,7 @@ impl StatusState {
             disk_root_total_bytes: host.disk_root_total_bytes,
             disk_root_used_bytes: host.disk_root_used_bytes,
             disk_root_io_time_ms: host.disk_root_io_time_ms,
+            disk_io_avg_latency_ms: host.disk_io_avg_latency_ms,
             primary_nic: host.primary_nic,
             net_rx_bytes_per_sec: host.net_rx_bytes_per_sec,
             net_tx_bytes_per_sec: host.net_tx_bytes_per_sec,
@@ -465,6 +495,9 @@ impl StatusState {
                     None
                 }
             },
+            node_power_envelope_watts: host.node_power_envelope_watts,
+            pue_ratio: host.pue_ratio,
+            iot_sensors: host.iot_sensors,
             disk_degraded: self.disk_degraded.load(Ordering::Relaxed),
             network_degraded: self.network_degraded.load(Ordering::Relaxed),
             swap_degraded: self.swap_degraded.load(Ordering::Relaxed),
@@ -582,6 +615,32 @@ impl StatusState {
         }
     }
 
+    pub fn set_disk_latency(&self, avg_latency_ms: Option<f64>) {
+        if let Ok(mut guard) = self.host.write() {
+            guard.disk_io_avg_latency_ms = avg_latency_ms;
+        }
+    }
+
+    pub fn set_node_power_envelope(&self, watts: Option<f64>) {
+        if let Ok(mut guard) = self.host.write() {
+            guard.node_power_envelope_watts = watts;
+        }
+    }
+
+    pub fn set_pue_ratio(&self, pue: Option<f64>) {
+        if let Ok(mut guard) = self.host.write() {
+            guard.pue_ratio = pue;
+        }
+    }
+
+    /// Replaces the readings of one protocol driver.
+    pub fn set_iot_readings(&self, driver_id: &str, readings: Vec<IotSensorReading>) {
+        if let Ok(mut guard) = self.host.write() {
+            guard.iot_sensors.retain(|r| r.driver_id != driver_id);
+            guard.iot_sensors.extend(readings);
+        }
+    }
+
     pub fn set_network_summary(
         &self,
         primary_nic: Option<String>,
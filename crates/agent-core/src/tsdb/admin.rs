@@ -0,0 +1,414 @@
+// ESNODE | Source Available BUSL-1.1 | Copyright (c) 2025 Estimatedstocks AB
+//! Operator views of the local TSDB: stats, block listing, series deletion
+//! and snapshots.
+//!
+//! Deleting series does not rewrite blocks. It records a tombstone (a
+//! selector plus time range) in `tombstones.json`; reads, export, remote
+//! write and compaction skip tombstoned samples, and a tombstone is dropped
+//! once pruning has removed every block it could cover.
+//!
+//! A snapshot hard-links the files of every closed block (raw and rollup)
+//! into `snapshots/<ms>/`, so pruning cannot pull them away while the tarball
+//! is written, and removes the links when the [`Snapshot`] is dropped; links
+//! left by a crashed agent are removed at startup. The tarball has the layout
+//! of the TSDB root and can be opened as one after extraction.
+
+use std::collections::HashMap;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+use super::query::{parse_selector, QueryError, Selector};
+use super::rollup::tier_dir_name;
+use super::{series_ref, BlockMeta, LocalTsdb, Sample};
+
+pub(crate) const TOMBSTONES_FILE: &str = "tombstones.json";
+const SNAPSHOTS_DIR: &str = "snapshots";
+/// Tier name of raw blocks in stats and listings.
+const RAW_TIER: &str = "raw";
+
+/// Samples of series matching `selector` in `[from_ms, to_ms]` are deleted.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct Tombstone {
+    pub selector: String,
+    pub from_ms: Option<i64>,
+    pub to_ms: i64,
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct Tombstones {
+    entries: Vec<(Tombstone, Selector)>,
+}
+
+impl Tombstones {
+    pub(crate) fn load(root: &Path) -> Result<Self> {
+        let path = root.join(TOMBSTONES_FILE);
+        let bytes = match std::fs::read(&path) {
+            Ok(bytes) => bytes,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(Self::default()),
+            Err(err) => return Err(err).with_context(|| format!("reading {}", path.display())),
+        };
+        let tombstones: Vec<Tombstone> = serde_json::from_slice(&bytes)
+            .with_context(|| format!("parsing {}", path.display()))?;
+        Self::from_entries(tombstones)
+    }
+
+    fn from_entries(tombstones: Vec<Tombstone>) -> Result<Self> {
+        let entries = tombstones
+            .into_iter()
+            .map(|t| {
+                let selector = parse_selector(&t.selector)
+                    .with_context(|| format!("invalid tombstone selector {:?}", t.selector))?;
+                Ok((t, selector))
+            })
+            .collect::<Result<_>>()?;
+        Ok(Self { entries })
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    fn list(&self) -> Vec<Tombstone> {
+        self.entries.iter().map(|(t, _)| t.clone()).collect()
+    }
+
+    /// Removes tombstoned samples.
+    pub(crate) fn retain_live(&self, samples: &mut Vec<Sample>) {
+        if self.entries.is_empty() {
+            return;
+        }
+        // Matching is per series; the time range is per sample.
+        let mut matching: HashMap<u64, Vec<usize>> = HashMap::new();
+        samples.retain(|sample| {
+            let hits = matching
+                .entry(series_ref(&sample.metric, &sample.labels))
+                .or_insert_with(|| {
+                    self.entries
+                        .iter()
+                        .enumerate()
+                        .filter(|(_, (_, sel))| sel.matches_series(&sample.metric, &sample.labels))
+                        .map(|(i, _)| i)
+                        .collect()
+                });
+            !hits.iter().any(|&i| {
+                let t = &self.entries[i].0;
+                t.from_ms.is_none_or(|f| sample.ts_ms >= f) && sample.ts_ms <= t.to_ms
+            })
+        });
+    }
+}
+
+/// Samples and size of one tier.
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub struct TierStats {
+    pub tier: String,
+    pub blocks: usize,
+    pub bytes: u64,
+    pub samples: u64,
+}
+
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub struct MetricSamples {
+    pub metric: String,
+    pub samples: u64,
+}
+
+/// Summary served by `/tsdb/stats`.
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub struct TsdbStats {
+    pub blocks: usize,
+    pub bytes: u64,
+    pub samples: u64,
+    pub min_time_ms: Option<i64>,
+    pub max_time_ms: Option<i64>,
+    pub active_series: usize,
+    pub tombstones: usize,
+    pub tiers: Vec<TierStats>,
+    /// Metrics with the most samples across raw blocks.
+    pub top_metrics: Vec<MetricSamples>,
+}
+
+/// One block as listed by `/tsdb/blocks`.
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub struct BlockSummary {
+    pub tier: String,
+    pub start_ms: i64,
+    pub end_ms: i64,
+    pub bytes: u64,
+    pub samples: u64,
+    pub metrics: usize,
+    /// Still being written; its counts are live, not from the index.
+    pub open: bool,
+}
+
+/// Closed blocks hard-linked for a tarball; see [`Snapshot::write_tar`].
+/// The links are removed when it is dropped.
+#[derive(Debug)]
+pub struct Snapshot {
+    dir: PathBuf,
+}
+
+impl Snapshot {
+    /// Writes the snapshot as an uncompressed tarball (chunks are already
+    /// compressed) and removes its links, also on failure. Blocking.
+    pub fn write_tar<W: Write>(self, out: W) -> Result<()> {
+        let mut builder = tar::Builder::new(out);
+        builder
+            .append_dir_all(".", &self.dir)
+            .and_then(|()| builder.finish())
+            .context("writing snapshot tarball")
+    }
+}
+
+impl Drop for Snapshot {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.dir);
+    }
+}
+
+/// Removes the snapshot links a crashed agent left under `root`, which would
+/// otherwise keep pruned blocks on disk.
+pub(crate) fn remove_stale_snapshots(root: &Path) {
+    let dir = root.join(SNAPSHOTS_DIR);
+    match std::fs::remove_dir_all(&dir) {
+        Ok(()) => tracing::info!("removed stale TSDB snapshots in {}", dir.display()),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => {}
+        Err(err) => tracing::warn!("failed to remove {}: {err}", dir.display()),
+    }
+}
+
+impl LocalTsdb {
+    /// Every block, raw tier first, each oldest first.
+    pub async fn blocks(&self) -> Result<Vec<BlockSummary>> {
+        let open = self
+            .current
+            .lock()
+            .await
+            .as_ref()
+            .map(|w| (w.dir.clone(), w.meta.clone()));
+        let mut out = Vec::new();
+        for (tier, root) in self.tier_roots() {
+            let mut blocks = self.list_blocks_in(&root).await?;
+            blocks.sort_by_key(|b| b.start_ms);
+            for blk in blocks {
+                let live = open
+                    .as_ref()
+                    .filter(|(dir, _)| *dir == blk.dir)
+                    .map(|(_, meta)| meta);
+                let meta = live.or(blk.index.as_ref());
+                out.push(BlockSummary {
+                    tier: tier.clone(),
+                    start_ms: blk.start_ms,
+                    end_ms: blk.end_ms,
+                    bytes: blk.size_bytes,
+                    samples: meta.map_or(0, |m| m.samples),
+                    metrics: meta.map_or(0, |m| m.metric_counts.len()),
+                    open: live.is_some(),
+                });
+            }
+        }
+        Ok(out)
+    }
+
+    /// Totals per tier plus the `top` metrics by raw sample count.
+    pub async fn stats(&self, top: usize) -> Result<TsdbStats> {
+        let blocks = self.blocks().await?;
+        let mut tiers: Vec<TierStats> = self
+            .tier_roots()
+            .into_iter()
+            .map(|(tier, _)| TierStats {
+                tier,
+                blocks: 0,
+                bytes: 0,
+                samples: 0,
+            })
+            .collect();
+        for blk in &blocks {
+            if let Some(tier) = tiers.iter_mut().find(|t| t.tier == blk.tier) {
+                tier.blocks += 1;
+                tier.bytes += blk.bytes;
+                tier.samples += blk.samples;
+            }
+        }
+
+        let mut metric_counts: HashMap<String, u64> = HashMap::new();
+        let open = self
+            .current
+            .lock()
+            .await
+            .as_ref()
+            .map(|w| (w.dir.clone(), w.meta.clone()));
+        for blk in self.list_blocks().await? {
+            let meta: Option<BlockMeta> = match &open {
+                Some((dir, meta)) if *dir == blk.dir => Some(meta.clone()),
+                _ => blk.index,
+            };
+            for (metric, count) in meta.map(|m| m.metric_counts).unwrap_or_default() {
+                *metric_counts.entry(metric).or_default() += count;
+            }
+        }
+        let mut top_metrics: Vec<MetricSamples> = metric_counts
+            .into_iter()
+            .map(|(metric, samples)| MetricSamples { metric, samples })
+            .collect();
+        top_metrics.sort_by(|a, b| b.samples.cmp(&a.samples).then(a.metric.cmp(&b.metric)));
+        top_metrics.truncate(top);
+
+        Ok(TsdbStats {
+            blocks: blocks.len(),
+            bytes: blocks.iter().map(|b| b.bytes).sum(),
+            samples: blocks.iter().map(|b| b.samples).sum(),
+            min_time_ms: blocks.iter().map(|b| b.start_ms).min(),
+            max_time_ms: blocks.iter().map(|b| b.end_ms).max(),
+            active_series: self.guard.lock().active_series(),
+            tombstones: self.tombstones().len(),
+            tiers,
+            top_metrics,
+        })
+    }
+
+    /// Tombstones series matching `selector` in `[from_ms, to_ms]` (`to_ms`
+    /// defaults to `now_ms`, so samples written later are kept).
+    pub async fn delete_series(
+        &self,
+        selector: &str,
+        from_ms: Option<i64>,
+        to_ms: Option<i64>,
+        now_ms: i64,
+    ) -> Result<Tombstone, QueryError> {
+        parse_selector(selector)?;
+        let tombstone = Tombstone {
+            selector: selector.to_string(),
+            from_ms,
+            to_ms: to_ms.unwrap_or(now_ms),
+        };
+        if tombstone.from_ms.is_some_and(|f| f > tombstone.to_ms) {
+            return Err(QueryError::BadData("start is after end".to_string()));
+        }
+        let _writer = self.tombstone_writer.lock().await;
+        let mut list = self.tombstones().list();
+        list.push(tombstone.clone());
+        self.store_tombstones(list).await?;
+        tracing::info!(
+            target: "audit",
+            action = "tsdb_delete_series",
+            selector = %tombstone.selector,
+            from_ms = ?tombstone.from_ms,
+            to_ms = tombstone.to_ms
+        );
+        Ok(tombstone)
+    }
+
+    /// Drops tombstones older than every remaining block.
+    pub(crate) async fn expire_tombstones(&self, oldest_block_ms: Option<i64>) -> Result<()> {
+        let _writer = self.tombstone_writer.lock().await;
+        let current = self.tombstones().list();
+        let kept: Vec<Tombstone> = current
+            .iter()
+            .filter(|t| oldest_block_ms.is_some_and(|oldest| t.to_ms >= oldest))
+            .cloned()
+            .collect();
+        if kept.len() != current.len() {
+            self.store_tombstones(kept).await?;
+        }
+        Ok(())
+    }
+
+    async fn store_tombstones(&self, list: Vec<Tombstone>) -> Result<()> {
+        let path = self.config.path.join(TOMBSTONES_FILE);
+        if list.is_empty() {
+            let _ = tokio::fs::remove_file(&path).await;
+        } else {
+            let tmp = path.with_extension("json.tmp");
+            tokio::fs::write(&tmp, serde_json::to_vec_pretty(&list)?)
+                .await
+                .with_context(|| format!("writing {}", tmp.display()))?;
+            tokio::fs::rename(&tmp, &path)
+                .await
+                .with_context(|| format!("publishing {}", path.display()))?;
+        }
+        *self.tombstones.write() = Arc::new(Tombstones::from_entries(list)?);
+        Ok(())
+    }
+
+    pub(crate) fn tombstones(&self) -> Arc<Tombstones> {
+        self.tombstones.read().clone()
+    }
+
+    /// Hard-links every closed block (ended by `now_ms` and not open) and
+    /// the tombstones into a snapshot directory, ready for
+    /// [`Snapshot::write_tar`].
+    pub async fn snapshot(&self, now_ms: i64) -> Result<Snapshot> {
+        let open_dir = self.current.lock().await.as_ref().map(|w| w.dir.clone());
+        let dir = self
+            .config
+            .path
+            .join(SNAPSHOTS_DIR)
+            .join(now_ms.to_string());
+        let mut blocks = Vec::new();
+        for (tier, root) in self.tier_roots() {
+            for blk in self.list_blocks_in(&root).await? {
+                if blk.end_ms > now_ms || Some(&blk.dir) == open_dir.as_ref() || blk.index.is_none()
+                {
+                    continue;
+                }
+                let rel = match blk.dir.file_name() {
+                    Some(name) if tier == RAW_TIER => PathBuf::from(name),
+                    Some(name) => Path::new(&tier).join(name),
+                    None => continue,
+                };
+                blocks.push((blk.dir, rel));
+            }
+        }
+        let root = self.config.path.clone();
+        let target = dir.clone();
+        tokio::task::spawn_blocking(move || -> Result<()> {
+            std::fs::create_dir_all(&target)
+                .with_context(|| format!("creating {}", target.display()))?;
+            for (src, rel) in blocks {
+                // A block pruned meanwhile is simply left out.
+                if let Err(err) = link_dir(&src, &target.join(&rel)) {
+                    tracing::debug!("snapshot skipped {}: {err:?}", src.display());
+                    let _ = std::fs::remove_dir_all(target.join(&rel));
+                }
+            }
+            let tombstones = root.join(TOMBSTONES_FILE);
+            if tombstones.exists() {
+                std::fs::copy(&tombstones, target.join(TOMBSTONES_FILE))?;
+            }
+            Ok(())
+        })
+        .await
+        .context("snapshot task")??;
+        Ok(Snapshot { dir })
+    }
+
+    /// `(tier name, root)` of the raw tier and every rollup tier.
+    fn tier_roots(&self) -> Vec<(String, PathBuf)> {
+        let mut roots = vec![(RAW_TIER.to_string(), self.config.path.clone())];
+        for (i, tier) in self.config.rollups.iter().enumerate() {
+            roots.push((tier_dir_name(tier.resolution), self.tier_path(i)));
+        }
+        roots
+    }
+}
+
+/// Hard-links (or copies, across filesystems) the files of a block dir.
+fn link_dir(src: &Path, dst: &Path) -> Result<()> {
+    std::fs::create_dir_all(dst)?;
+    for entry in std::fs::read_dir(src)? {
+        let entry = entry?;
+        if !entry.file_type()?.is_file() {
+            continue;
+        }
+        let to = dst.join(entry.file_name());
+        if std::fs::hard_link(entry.path(), &to).is_err() {
+            std::fs::copy(entry.path(), &to)?;
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,286 @@
+// ESNODE | Source Available BUSL-1.1 | Copyright (c) 2025 Estimatedstocks AB
+//! Gorilla-style sample chunks: delta-of-delta timestamps and XOR-compressed
+//! float values, packed into a bitstream (same scheme as Prometheus XOR chunks).
+
+#[derive(Debug, Default, Clone)]
+struct BitWriter {
+    bytes: Vec<u8>,
+    /// Number of bits still free in the last byte.
+    free: u8,
+}
+
+impl BitWriter {
+    fn write_bit(&mut self, bit: bool) {
+        if self.free == 0 {
+            self.bytes.push(0);
+            self.free = 8;
+        }
+        if bit {
+            let last = self.bytes.len() - 1;
+            self.bytes[last] |= 1 << (self.free - 1);
+        }
+        self.free -= 1;
+    }
+
+    fn write_bits(&mut self, value: u64, nbits: u8) {
+        for i in (0..nbits).rev() {
+            self.write_bit((value >> i) & 1 == 1);
+        }
+    }
+}
+
+struct BitReader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> BitReader<'a> {
+    fn new(bytes: &'a [u8]) -> Self {
+        Self { bytes, pos: 0 }
+    }
+
+    fn read_bit(&mut self) -> Option<bool> {
+        let byte = *self.bytes.get(self.pos / 8)?;
+        let bit = (byte >> (7 - (self.pos % 8))) & 1 == 1;
+        self.pos += 1;
+        Some(bit)
+    }
+
+    fn read_bits(&mut self, nbits: u8) -> Option<u64> {
+        let mut out = 0u64;
+        for _ in 0..nbits {
+            out = (out << 1) | u64::from(self.read_bit()?);
+        }
+        Some(out)
+    }
+}
+
+/// Delta-of-delta buckets: (control prefix, prefix length, payload bits).
+const DOD_BUCKETS: [(u64, u8, u8); 3] = [(0b10, 2, 7), (0b110, 3, 9), (0b1110, 4, 12)];
+
+/// An append-only chunk of samples for a single series.
+#[derive(Debug, Clone)]
+pub(crate) struct XorChunk {
+    bits: BitWriter,
+    count: u32,
+    min_ts: i64,
+    max_ts: i64,
+    last_ts: i64,
+    last_delta: i64,
+    last_value: u64,
+    leading: u8,
+    trailing: u8,
+}
+
+impl Default for XorChunk {
+    fn default() -> Self {
+        Self {
+            bits: BitWriter::default(),
+            count: 0,
+            min_ts: i64::MAX,
+            max_ts: i64::MIN,
+            last_ts: 0,
+            last_delta: 0,
+            last_value: 0,
+            leading: u8::MAX,
+            trailing: 0,
+        }
+    }
+}
+
+impl XorChunk {
+    pub(crate) fn append(&mut self, ts_ms: i64, value: f64) {
+        let value_bits = value.to_bits();
+        if self.count == 0 {
+            self.bits.write_bits(ts_ms as u64, 64);
+            self.bits.write_bits(value_bits, 64);
+        } else {
+            let delta = ts_ms.wrapping_sub(self.last_ts);
+            self.write_dod(delta.wrapping_sub(self.last_delta));
+            self.write_value(value_bits);
+            self.last_delta = delta;
+        }
+        self.last_ts = ts_ms;
+        self.last_value = value_bits;
+        self.min_ts = self.min_ts.min(ts_ms);
+        self.max_ts = self.max_ts.max(ts_ms);
+        self.count += 1;
+    }
+
+    fn write_dod(&mut self, dod: i64) {
+        if dod == 0 {
+            self.bits.write_bit(false);
+            return;
+        }
+        for (prefix, prefix_len, payload) in DOD_BUCKETS {
+            let lo = -(1i64 << (payload - 1)) + 1;
+            let hi = 1i64 << (payload - 1);
+            if (lo..=hi).contains(&dod) {
+                self.bits.write_bits(prefix, prefix_len);
+                self.bits.write_bits(dod as u64, payload);
+                return;
+            }
+        }
+        self.bits.write_bits(0b1111, 4);
+        self.bits.write_bits(dod as u64, 64);
+    }
+
+    fn write_value(&mut self, value_bits: u64) {
+        let xor = value_bits ^ self.last_value;
+        if xor == 0 {
+            self.bits.write_bit(false);
+            return;
+        }
+        self.bits.write_bit(true);
+        // Leading zeros are stored in 5 bits, so cap at 31.
+        let leading = (xor.leading_zeros() as u8).min(31);
+        let trailing = xor.trailing_zeros() as u8;
+        if self.leading != u8::MAX && leading >= self.leading && trailing >= self.trailing {
+            // Meaningful bits fit in the previous window.
+            self.bits.write_bit(false);
+            let sig = 64 - self.leading - self.trailing;
+            self.bits.write_bits(xor >> self.trailing, sig);
+        } else {
+            self.leading = leading;
+            self.trailing = trailing;
+            self.bits.write_bit(true);
+            self.bits.write_bits(u64::from(leading), 5);
+            // 64 significant bits is encoded as 0 to fit in 6 bits.
+            let sig = 64 - leading - trailing;
+            self.bits.write_bits(u64::from(sig % 64), 6);
+            self.bits.write_bits(xor >> trailing, sig);
+        }
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.count as usize
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.count == 0
+    }
+
+    pub(crate) fn min_ts(&self) -> i64 {
+        self.min_ts
+    }
+
+    pub(crate) fn max_ts(&self) -> i64 {
+        self.max_ts
+    }
+
+    pub(crate) fn bytes(&self) -> &[u8] {
+        &self.bits.bytes
+    }
+}
+
+/// Decodes `count` samples from an encoded chunk payload.
+/// Stops early (returning what was decoded) if the payload is truncated.
+pub(crate) fn decode_chunk(bytes: &[u8], count: usize) -> Vec<(i64, f64)> {
+    let mut out = Vec::with_capacity(count);
+    let mut reader = BitReader::new(bytes);
+    let (Some(first_ts), Some(first_value)) = (reader.read_bits(64), reader.read_bits(64)) else {
+        return out;
+    };
+    if count == 0 {
+        return out;
+    }
+    let mut ts = first_ts as i64;
+    let mut value = first_value;
+    let mut delta = 0i64;
+    let mut leading = 0u8;
+    let mut trailing = 0u8;
+    out.push((ts, f64::from_bits(value)));
+
+    while out.len() < count {
+        let Some(dod) = read_dod(&mut reader) else {
+            break;
+        };
+        delta = delta.wrapping_add(dod);
+        ts = ts.wrapping_add(delta);
+
+        let Some(changed) = reader.read_bit() else {
+            break;
+        };
+        if changed {
+            let Some(new_window) = reader.read_bit() else {
+                break;
+            };
+            if new_window {
+                let (Some(l), Some(s)) = (reader.read_bits(5), reader.read_bits(6)) else {
+                    break;
+                };
+                let sig = if s == 0 { 64 } else { s as u8 };
+                leading = l as u8;
+                trailing = 64 - leading - sig;
+            }
+            let sig = 64 - leading - trailing;
+            let Some(bits) = reader.read_bits(sig) else {
+                break;
+            };
+            value ^= bits << trailing;
+        }
+        out.push((ts, f64::from_bits(value)));
+    }
+    out
+}
+
+fn read_dod(reader: &mut BitReader<'_>) -> Option<i64> {
+    if !reader.read_bit()? {
+        return Some(0);
+    }
+    for (_, _, payload) in DOD_BUCKETS {
+        if !reader.read_bit()? {
+            let raw = reader.read_bits(payload)? as i64;
+            return Some(sign_extend(raw, payload));
+        }
+    }
+    Some(reader.read_bits(64)? as i64)
+}
+
+fn sign_extend(raw: i64, bits: u8) -> i64 {
+    if raw > (1i64 << (bits - 1)) {
+        raw - (1i64 << bits)
+    } else {
+        raw
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn roundtrip_regular_and_irregular_samples() {
+        let mut chunk = XorChunk::default();
+        let mut expected = Vec::new();
+        let mut ts = 1_700_000_000_000i64;
+        for i in 0..500 {
+            // Mix jitter sizes to exercise every delta-of-delta bucket.
+            ts += 30_000 + [0, 3, -40, 200, -1500, 90_000][i % 6];
+            let value = match i % 4 {
+                0 => 250.0,
+                1 => 250.0 + i as f64 * 0.125,
+                2 => -1.0e9 / (i as f64 + 1.0),
+                _ => f64::from(i as u32),
+            };
+            chunk.append(ts, value);
+            expected.push((ts, value));
+        }
+        assert_eq!(chunk.len(), expected.len());
+        let decoded = decode_chunk(chunk.bytes(), chunk.len());
+        assert_eq!(decoded, expected);
+        assert_eq!(chunk.min_ts(), expected[0].0);
+        assert_eq!(chunk.max_ts(), expected.last().unwrap().0);
+    }
+
+    #[test]
+    fn constant_series_compresses_well() {
+        let mut chunk = XorChunk::default();
+        for i in 0..120 {
+            chunk.append(i * 1000, 42.0);
+        }
+        // 16 header bytes, one 16-bit first delta, then two zero bits per sample.
+        assert!(chunk.bytes().len() <= 48, "got {} bytes", chunk.bytes().len());
+        assert_eq!(decode_chunk(chunk.bytes(), 120).len(), 120);
+    }
+}
@@ -0,0 +1,346 @@
+// ESNODE | Source Available BUSL-1.1 | Copyright (c) 2025 Estimatedstocks AB
+//! Encoders for `/tsdb/export` bodies.
+//!
+//! Every format is produced batch by batch (a batch being a time-ordered run
+//! of samples), wrapped in a format-specific header and trailer:
+//!
+//! - `text`: the original `name{labels} <ts_ms> <value>` lines.
+//! - `openmetrics`: `name{labels} <value> <ts_seconds>`, grouped by metric
+//!   family within a batch and terminated by `# EOF`, as accepted by
+//!   `promtool tsdb create-blocks-from openmetrics`.
+//! - `jsonl`: one `{"metric":{"__name__":..},"value":..,"timestamp":..}`
+//!   object per sample.
+//! - `csv`: `metric,labels,timestamp_ms,value` with RFC 4180 quoting.
+//! - `columnar`: one line per series per batch holding parallel `values` and
+//!   `timestamps` columns (the VictoriaMetrics `/api/v1/import` JSON shape).
+//!
+//! Bodies are either streamed batch by batch from a [`SampleScan`] or cut
+//! into pages of `limit` samples, each page a complete body of its own.
+//!
+//! JSON values that are not finite are written as `"NaN"`, `"+Inf"` or
+//! `"-Inf"` strings.
+
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::str::FromStr;
+
+use anyhow::Result;
+use futures::{Stream, TryStreamExt};
+use serde_json::Value;
+
+use super::{format_float, ExportCursor, Sample, SampleScan};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ExportFormat {
+    #[default]
+    Text,
+    OpenMetrics,
+    Jsonl,
+    Csv,
+    Columnar,
+}
+
+impl FromStr for ExportFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(Self::Text),
+            "openmetrics" => Ok(Self::OpenMetrics),
+            "jsonl" | "ndjson" => Ok(Self::Jsonl),
+            "csv" => Ok(Self::Csv),
+            "columnar" | "parquet" => Ok(Self::Columnar),
+            other => Err(format!(
+                "unknown export format {other:?} (expected text, openmetrics, jsonl, csv or columnar)"
+            )),
+        }
+    }
+}
+
+type SeriesKey<'a> = (&'a str, Vec<(&'a str, &'a str)>);
+
+impl ExportFormat {
+    pub fn content_type(self) -> &'static str {
+        match self {
+            Self::Text => "text/plain; charset=utf-8",
+            Self::OpenMetrics => "application/openmetrics-text; version=1.0.0; charset=utf-8",
+            Self::Jsonl | Self::Columnar => "application/x-ndjson",
+            Self::Csv => "text/csv; charset=utf-8",
+        }
+    }
+
+    /// Written once before the first batch.
+    pub fn header(self) -> &'static str {
+        match self {
+            Self::Csv => "metric,labels,timestamp_ms,value\n",
+            _ => "",
+        }
+    }
+
+    /// Written once after the last batch.
+    pub fn trailer(self) -> &'static str {
+        match self {
+            Self::OpenMetrics => "# EOF\n",
+            _ => "",
+        }
+    }
+
+    /// Appends `samples` (oldest first) to `out`, one or more complete lines.
+    pub fn encode_batch(self, samples: &[Sample], out: &mut String) {
+        match self {
+            Self::Text => {
+                for sample in samples {
+                    out.push_str(&text_line(sample));
+                    out.push('\n');
+                }
+            }
+            Self::OpenMetrics => {
+                for ((metric, labels), points) in group_by_series(samples) {
+                    let labels = format_labels(&labels);
+                    for (ts_ms, value) in points {
+                        let _ = writeln!(
+                            out,
+                            "{metric}{labels} {} {}",
+                            format_float(value),
+                            format_float(ts_ms as f64 / 1000.0)
+                        );
+                    }
+                }
+            }
+            Self::Jsonl => {
+                for sample in samples {
+                    let _ = writeln!(
+                        out,
+                        r#"{{"metric":{},"value":{},"timestamp":{}}}"#,
+                        metric_object(&sample.metric, &sorted_labels(sample)),
+                        json_value(sample.value),
+                        sample.ts_ms
+                    );
+                }
+            }
+            Self::Csv => {
+                for sample in samples {
+                    let labels = format_labels(&sorted_labels(sample));
+                    let _ = writeln!(
+                        out,
+                        "{},{},{},{}",
+                        csv_field(&sample.metric),
+                        csv_field(&labels),
+                        sample.ts_ms,
+                        format_float(sample.value)
+                    );
+                }
+            }
+            Self::Columnar => {
+                for ((metric, labels), points) in group_by_series(samples) {
+                    let values: Vec<String> = points.iter().map(|(_, v)| json_value(*v)).collect();
+                    let timestamps: Vec<String> =
+                        points.iter().map(|(ts, _)| ts.to_string()).collect();
+                    let _ = writeln!(
+                        out,
+                        r#"{{"metric":{},"values":[{}],"timestamps":[{}]}}"#,
+                        metric_object(metric, &labels),
+                        values.join(","),
+                        timestamps.join(",")
+                    );
+                }
+            }
+        }
+    }
+
+    /// Streams a complete body from `scan`, one chunk per batch.
+    pub fn stream(self, scan: SampleScan) -> impl Stream<Item = Result<String>> + Send {
+        enum State {
+            Header(SampleScan),
+            Body(SampleScan),
+            Done,
+        }
+        futures::stream::try_unfold(State::Header(scan), move |state| async move {
+            Ok(match state {
+                State::Header(scan) => Some((self.header().to_string(), State::Body(scan))),
+                State::Body(mut scan) => match scan.next_batch().await? {
+                    Some(batch) => {
+                        let mut out = String::new();
+                        self.encode_batch(&batch, &mut out);
+                        Some((out, State::Body(scan)))
+                    }
+                    None => Some((self.trailer().to_string(), State::Done)),
+                },
+                State::Done => None,
+            })
+        })
+        .try_filter(|chunk| futures::future::ready(!chunk.is_empty()))
+    }
+
+    /// Renders the next `limit` samples of `scan` as a complete body, plus
+    /// the cursor to resume from when samples are left.
+    pub async fn page(
+        self,
+        scan: &mut SampleScan,
+        limit: usize,
+    ) -> Result<(String, Option<ExportCursor>)> {
+        let samples = scan.take(limit).await?;
+        let next = match samples.last() {
+            Some(last) if !scan.is_exhausted().await? => Some(ExportCursor::after(last)),
+            _ => None,
+        };
+        let mut body = self.header().to_string();
+        self.encode_batch(&samples, &mut body);
+        body.push_str(self.trailer());
+        Ok((body, next))
+    }
+}
+
+/// The legacy `name{labels} <ts_ms> <value>` export line.
+pub(crate) fn text_line(sample: &Sample) -> String {
+    format!(
+        "{}{} {} {}",
+        sample.metric,
+        format_labels(&sorted_labels(sample)),
+        sample.ts_ms,
+        format_float(sample.value)
+    )
+}
+
+fn sorted_labels(sample: &Sample) -> Vec<(&str, &str)> {
+    let mut labels: Vec<(&str, &str)> = sample
+        .labels
+        .iter()
+        .map(|(k, v)| (k.as_str(), v.as_str()))
+        .collect();
+    labels.sort_unstable();
+    labels
+}
+
+/// `{k="v",...}` with exposition-format escaping, or empty without labels.
+fn format_labels(labels: &[(&str, &str)]) -> String {
+    if labels.is_empty() {
+        return String::new();
+    }
+    let parts: Vec<String> = labels
+        .iter()
+        .map(|(k, v)| format!(r#"{k}="{}""#, escape_label_value(v)))
+        .collect();
+    format!("{{{}}}", parts.join(","))
+}
+
+/// Escapes `\`, `"` and newlines as the Prometheus/OpenMetrics text formats require.
+fn escape_label_value(value: &str) -> String {
+    let mut out = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '\\' => out.push_str(r"\\"),
+            '"' => out.push_str(r#"\""#),
+            '\n' => out.push_str(r"\n"),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+/// `{"__name__":..,"k":"v",...}` in label order.
+fn metric_object(metric: &str, labels: &[(&str, &str)]) -> String {
+    let mut out = format!(r#"{{"__name__":{}"#, json_string(metric));
+    for (k, v) in labels {
+        let _ = write!(out, ",{}:{}", json_string(k), json_string(v));
+    }
+    out.push('}');
+    out
+}
+
+fn json_string(s: &str) -> String {
+    Value::from(s).to_string()
+}
+
+fn json_value(value: f64) -> String {
+    if value.is_finite() {
+        Value::from(value).to_string()
+    } else {
+        json_string(&format_float(value))
+    }
+}
+
+/// Series of a batch in name/label order, each with its points oldest first.
+fn group_by_series(samples: &[Sample]) -> BTreeMap<SeriesKey<'_>, Vec<(i64, f64)>> {
+    let mut series: BTreeMap<SeriesKey<'_>, Vec<(i64, f64)>> = BTreeMap::new();
+    for sample in samples {
+        series
+            .entry((sample.metric.as_str(), sorted_labels(sample)))
+            .or_default()
+            .push((sample.ts_ms, sample.value));
+    }
+    series
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn sample(value: &str, ts_ms: i64, v: f64) -> Sample {
+        Sample {
+            metric: "esnode_app_info".into(),
+            labels: HashMap::from([("path".to_string(), value.to_string())]),
+            ts_ms,
+            value: v,
+        }
+    }
+
+    fn encode(format: ExportFormat, samples: &[Sample]) -> String {
+        let mut out = format.header().to_string();
+        format.encode_batch(samples, &mut out);
+        out.push_str(format.trailer());
+        out
+    }
+
+    #[test]
+    fn label_values_are_escaped() {
+        let s = sample("C:\\tmp\n\"x\"", 1_500, 1.0);
+        assert_eq!(
+            text_line(&s),
+            r#"esnode_app_info{path="C:\\tmp\n\"x\""} 1500 1"#
+        );
+        assert_eq!(
+            encode(ExportFormat::Csv, &[s]),
+            "metric,labels,timestamp_ms,value\n\
+             esnode_app_info,\"{path=\"\"C:\\\\tmp\\n\\\"\"x\\\"\"\"\"}\",1500,1\n"
+        );
+    }
+
+    #[test]
+    fn openmetrics_groups_series_and_ends_with_eof() {
+        let samples = [
+            sample("a", 1_000, 1.0),
+            sample("b", 1_000, 2.0),
+            sample("a", 2_500, f64::INFINITY),
+        ];
+        assert_eq!(
+            encode(ExportFormat::OpenMetrics, &samples),
+            "esnode_app_info{path=\"a\"} 1 1\n\
+             esnode_app_info{path=\"a\"} +Inf 2.5\n\
+             esnode_app_info{path=\"b\"} 2 1\n\
+             # EOF\n"
+        );
+    }
+
+    #[test]
+    fn json_formats_carry_name_and_columns() {
+        let samples = [sample("a", 1_000, 1.5), sample("a", 2_000, f64::NAN)];
+        assert_eq!(
+            encode(ExportFormat::Jsonl, &samples[..1]),
+            "{\"metric\":{\"__name__\":\"esnode_app_info\",\"path\":\"a\"},\"value\":1.5,\"timestamp\":1000}\n"
+        );
+        assert_eq!(
+            encode(ExportFormat::Columnar, &samples),
+            "{\"metric\":{\"__name__\":\"esnode_app_info\",\"path\":\"a\"},\"values\":[1.5,\"NaN\"],\"timestamps\":[1000,2000]}\n"
+        );
+    }
+}
@@ -0,0 +1,266 @@
+// ESNODE | Source Available BUSL-1.1 | Copyright (c) 2025 Estimatedstocks AB
+//! On-disk block layout.
+//!
+//! `chunks.bin` starts with an 8-byte magic followed by a stream of records:
+//! - `0x01` series: `ref u64 | metric str | n uvarint | (name str, value str) * n`
+//! - `0x02` chunk: `ref u64 | count uvarint | min_ts i64 | max_ts i64 | len uvarint | payload`
+//!
+//! Strings are `uvarint len | utf8 bytes`, fixed-width integers are little endian.
+//! Series records are written once per block, the first time a series appears,
+//! so labels are never repeated per sample. Blocks written by older agents only
+//! have `samples.jsonl`; both files are read when present.
+
+use std::collections::HashMap;
+use std::path::Path;
+
+use anyhow::{bail, Context, Result};
+use tokio::fs;
+
+use super::chunk::{decode_chunk, XorChunk};
+use super::Sample;
+
+pub(crate) const CHUNKS_FILE: &str = "chunks.bin";
+pub(crate) const LEGACY_SAMPLES_FILE: &str = "samples.jsonl";
+pub(crate) const MAGIC: &[u8; 8] = b"ESTSDB\x00\x01";
+
+pub(crate) const RECORD_SERIES: u8 = 0x01;
+const RECORD_CHUNK: u8 = 0x02;
+
+#[derive(Debug, Clone)]
+pub(crate) struct SeriesEntry {
+    pub metric: String,
+    pub labels: HashMap<String, String>,
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct ChunkEntry {
+    pub series_ref: u64,
+    pub count: usize,
+    pub min_ts: i64,
+    pub max_ts: i64,
+    pub payload: Vec<u8>,
+}
+
+/// Fully decoded contents of one block directory.
+#[derive(Debug, Default)]
+pub(crate) struct BlockData {
+    pub series: HashMap<u64, SeriesEntry>,
+    pub chunks: Vec<ChunkEntry>,
+    pub legacy: Vec<Sample>,
+}
+
+impl BlockData {
+    /// Materialises samples in `[from, to]` whose metric passes `keep`, sorted by timestamp.
+    pub(crate) fn samples<F>(&self, from: Option<i64>, to: Option<i64>, keep: F) -> Vec<Sample>
+    where
+        F: Fn(&str) -> bool,
+    {
+        let mut out = Vec::new();
+        for chunk in &self.chunks {
+            if !super::overlaps(chunk.min_ts, chunk.max_ts, from, to) {
+                continue;
+            }
+            let Some(series) = self.series.get(&chunk.series_ref) else {
+                continue;
+            };
+            if !keep(&series.metric) {
+                continue;
+            }
+            for (ts_ms, value) in decode_chunk(&chunk.payload, chunk.count) {
+                if super::timestamp_in_range(ts_ms, from, to) {
+                    out.push(Sample {
+                        metric: series.metric.clone(),
+                        labels: series.labels.clone(),
+                        ts_ms,
+                        value,
+                    });
+                }
+            }
+        }
+        out.extend(
+            self.legacy
+                .iter()
+                .filter(|s| super::timestamp_in_range(s.ts_ms, from, to) && keep(&s.metric))
+                .cloned(),
+        );
+        out.sort_by_key(|s| s.ts_ms);
+        out
+    }
+}
+
+pub(crate) fn encode_series_record(
+    buf: &mut Vec<u8>,
+    series_ref: u64,
+    metric: &str,
+    labels: &HashMap<String, String>,
+) {
+    buf.push(RECORD_SERIES);
+    buf.extend_from_slice(&series_ref.to_le_bytes());
+    put_str(buf, metric);
+    let mut pairs: Vec<_> = labels.iter().collect();
+    pairs.sort_by_key(|(k, _)| *k);
+    put_uvarint(buf, pairs.len() as u64);
+    for (k, v) in pairs {
+        put_str(buf, k);
+        put_str(buf, v);
+    }
+}
+
+pub(crate) fn encode_chunk_record(buf: &mut Vec<u8>, series_ref: u64, chunk: &XorChunk) {
+    buf.push(RECORD_CHUNK);
+    buf.extend_from_slice(&series_ref.to_le_bytes());
+    put_uvarint(buf, chunk.len() as u64);
+    buf.extend_from_slice(&chunk.min_ts().to_le_bytes());
+    buf.extend_from_slice(&chunk.max_ts().to_le_bytes());
+    put_uvarint(buf, chunk.bytes().len() as u64);
+    buf.extend_from_slice(chunk.bytes());
+}
+
+/// Decodes a `chunks.bin` image into `out` and returns the length of the
+/// valid prefix. A truncated trailing record (e.g. from a crash mid-write)
+/// is ignored rather than treated as an error.
+pub(crate) fn decode_chunks_file(bytes: &[u8], out: &mut BlockData) -> Result<usize> {
+    if bytes.is_empty() {
+        return Ok(0);
+    }
+    let mut cur = Cursor::after_magic(bytes, MAGIC)?;
+    let mut valid = cur.pos;
+    while cur.pos < bytes.len() {
+        if decode_record(&mut cur, out).is_none() {
+            break;
+        }
+        valid = cur.pos;
+    }
+    Ok(valid)
+}
+
+/// Decodes the body of a series record (after its tag byte).
+pub(crate) fn decode_series_record(cur: &mut Cursor<'_>) -> Option<(u64, SeriesEntry)> {
+    let series_ref = cur.u64()?;
+    let metric = cur.string()?;
+    let n = cur.uvarint()?;
+    let mut labels = HashMap::new();
+    for _ in 0..n {
+        let k = cur.string()?;
+        let v = cur.string()?;
+        labels.insert(k, v);
+    }
+    Some((series_ref, SeriesEntry { metric, labels }))
+}
+
+fn decode_record(cur: &mut Cursor<'_>, out: &mut BlockData) -> Option<()> {
+    match cur.u8()? {
+        RECORD_SERIES => {
+            let (series_ref, entry) = decode_series_record(cur)?;
+            out.series.insert(series_ref, entry);
+        }
+        RECORD_CHUNK => {
+            let series_ref = cur.u64()?;
+            let count = cur.uvarint()? as usize;
+            let min_ts = cur.u64()? as i64;
+            let max_ts = cur.u64()? as i64;
+            let len = cur.uvarint()? as usize;
+            let payload = cur.take(len)?.to_vec();
+            out.chunks.push(ChunkEntry {
+                series_ref,
+                count,
+                min_ts,
+                max_ts,
+                payload,
+            });
+        }
+        _ => return None,
+    }
+    Some(())
+}
+
+/// Reads a block directory, decoding `chunks.bin` and any legacy `samples.jsonl`.
+pub(crate) async fn read_block(dir: &Path) -> Result<BlockData> {
+    let mut data = BlockData::default();
+    let chunks_path = dir.join(CHUNKS_FILE);
+    if let Ok(bytes) = fs::read(&chunks_path).await {
+        decode_chunks_file(&bytes, &mut data)
+            .with_context(|| format!("decoding {}", chunks_path.display()))?;
+    }
+    let legacy_path = dir.join(LEGACY_SAMPLES_FILE);
+    if let Ok(content) = fs::read_to_string(&legacy_path).await {
+        for line in content.lines() {
+            if line.trim().is_empty() {
+                continue;
+            }
+            if let Ok(sample) = serde_json::from_str::<Sample>(line) {
+                data.legacy.push(sample);
+            }
+        }
+    }
+    Ok(data)
+}
+
+pub(crate) struct Cursor<'a> {
+    bytes: &'a [u8],
+    pub pos: usize,
+}
+
+impl<'a> Cursor<'a> {
+    /// Positions a cursor just past `magic`, failing if the header does not match.
+    pub(crate) fn after_magic(bytes: &'a [u8], magic: &[u8]) -> Result<Self> {
+        if bytes.len() < magic.len() || &bytes[..magic.len()] != magic {
+            bail!("unrecognised file header");
+        }
+        Ok(Self {
+            bytes,
+            pos: magic.len(),
+        })
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.pos >= self.bytes.len()
+    }
+
+    fn take(&mut self, n: usize) -> Option<&'a [u8]> {
+        let end = self.pos.checked_add(n)?;
+        let slice = self.bytes.get(self.pos..end)?;
+        self.pos = end;
+        Some(slice)
+    }
+
+    pub(crate) fn u8(&mut self) -> Option<u8> {
+        self.take(1).map(|b| b[0])
+    }
+
+    pub(crate) fn u64(&mut self) -> Option<u64> {
+        let raw: [u8; 8] = self.take(8)?.try_into().ok()?;
+        Some(u64::from_le_bytes(raw))
+    }
+
+    fn uvarint(&mut self) -> Option<u64> {
+        let mut out = 0u64;
+        for shift in (0..64).step_by(7) {
+            let byte = self.u8()?;
+            out |= u64::from(byte & 0x7f) << shift;
+            if byte & 0x80 == 0 {
+                return Some(out);
+            }
+        }
+        None
+    }
+
+    fn string(&mut self) -> Option<String> {
+        let len = self.uvarint()? as usize;
+        let raw = self.take(len)?;
+        String::from_utf8(raw.to_vec()).ok()
+    }
+}
+
+fn put_uvarint(buf: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        buf.push((value as u8) | 0x80);
+        value >>= 7;
+    }
+    buf.push(value as u8);
+}
+
+fn put_str(buf: &mut Vec<u8>, s: &str) {
+    put_uvarint(buf, s.len() as u64);
+    buf.extend_from_slice(s.as_bytes());
+}
@@ -0,0 +1,352 @@
+// ESNODE | Source Available BUSL-1.1 | Copyright (c) 2025 Estimatedstocks AB
+//! Write-side guards for the local TSDB.
+//!
+//! [`CardinalityGuard`] caps the number of active series, overall and per
+//! metric name, before samples reach a block. A series is active while it
+//! has been written within the last block duration; series that stop
+//! reporting (exited PIDs, removed MIG instances) free their slot after that.
+//! New series over a limit are dropped or, with [`SeriesOverflow::Aggregate`],
+//! summed per timestamp into one series per metric labelled
+//! `esnode_series_overflow="true"` (keeping `le`/`quantile` so histogram and
+//! summary series stay coherent).
+//!
+//! [`WriteSchedule`] decides which metrics are due for a write on each
+//! collection tick, from a default interval and per-metric policies.
+
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap};
+use std::time::Duration;
+
+use crate::config::{AgentConfig, LocalTsdbWritePolicy, SeriesOverflow};
+
+use super::{matches_metric, series_ref, Sample, BLOCK_DURATION};
+
+/// Label marking the series that absorbs samples over the cardinality limits.
+pub const OVERFLOW_LABEL: &str = "esnode_series_overflow";
+/// Labels kept on overflow series.
+const STRUCTURAL_LABELS: [&str; 2] = ["le", "quantile"];
+/// Minimum spacing of stale-series sweeps while a limit is being hit.
+const SWEEP_INTERVAL_MS: i64 = 60_000;
+
+/// Overflow series of one metric (with its structural labels) at one timestamp.
+type OverflowKey = (String, Vec<(String, String)>, i64);
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CardinalityLimits {
+    pub max_series: Option<usize>,
+    pub max_series_per_metric: Option<usize>,
+    pub overflow: SeriesOverflow,
+}
+
+impl CardinalityLimits {
+    fn is_unlimited(&self) -> bool {
+        self.max_series.is_none() && self.max_series_per_metric.is_none()
+    }
+}
+
+impl From<&AgentConfig> for CardinalityLimits {
+    fn from(value: &AgentConfig) -> Self {
+        Self {
+            max_series: value.local_tsdb_max_series,
+            max_series_per_metric: value.local_tsdb_max_series_per_metric,
+            overflow: value.local_tsdb_series_overflow,
+        }
+    }
+}
+
+/// Series counts and samples affected by the limits since the last
+/// [`super::LocalTsdb::take_cardinality_stats`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CardinalityStats {
+    pub active_series: usize,
+    pub dropped_samples: u64,
+    pub aggregated_samples: u64,
+}
+
+#[derive(Debug)]
+struct ActiveSeries {
+    metric: String,
+    last_ts_ms: i64,
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct CardinalityGuard {
+    limits: CardinalityLimits,
+    active: HashMap<u64, ActiveSeries>,
+    per_metric: HashMap<String, usize>,
+    newest_ts_ms: i64,
+    last_sweep_ms: Option<i64>,
+    dropped_samples: u64,
+    aggregated_samples: u64,
+}
+
+impl CardinalityGuard {
+    pub(crate) fn new(limits: CardinalityLimits) -> Self {
+        Self {
+            limits,
+            ..Self::default()
+        }
+    }
+
+    /// Returns the samples to write: those of active series, new series
+    /// within the limits and, when aggregating, one overflow sample per
+    /// metric and timestamp.
+    pub(crate) fn admit<'a>(&mut self, samples: &'a [Sample]) -> Cow<'a, [Sample]> {
+        if self.limits.is_unlimited() {
+            return Cow::Borrowed(samples);
+        }
+        let mut kept = Vec::with_capacity(samples.len());
+        let mut overflow: BTreeMap<OverflowKey, f64> = BTreeMap::new();
+        for sample in samples {
+            self.newest_ts_ms = self.newest_ts_ms.max(sample.ts_ms);
+            let series = series_ref(&sample.metric, &sample.labels);
+            if let Some(active) = self.active.get_mut(&series) {
+                active.last_ts_ms = active.last_ts_ms.max(sample.ts_ms);
+                kept.push(sample.clone());
+                continue;
+            }
+            if self.is_full(&sample.metric) {
+                self.evict_stale();
+            }
+            if self.is_full(&sample.metric) {
+                match self.limits.overflow {
+                    SeriesOverflow::Drop => self.dropped_samples += 1,
+                    SeriesOverflow::Aggregate => {
+                        self.aggregated_samples += 1;
+                        let structural = STRUCTURAL_LABELS
+                            .iter()
+                            .filter_map(|name| {
+                                sample
+                                    .labels
+                                    .get(*name)
+                                    .map(|v| (name.to_string(), v.clone()))
+                            })
+                            .collect();
+                        *overflow
+                            .entry((sample.metric.clone(), structural, sample.ts_ms))
+                            .or_default() += sample.value;
+                    }
+                }
+                continue;
+            }
+            self.active.insert(
+                series,
+                ActiveSeries {
+                    metric: sample.metric.clone(),
+                    last_ts_ms: sample.ts_ms,
+                },
+            );
+            *self.per_metric.entry(sample.metric.clone()).or_default() += 1;
+            kept.push(sample.clone());
+        }
+        kept.extend(
+            overflow
+                .into_iter()
+                .map(|((metric, structural, ts_ms), value)| {
+                    let mut labels: HashMap<String, String> = structural.into_iter().collect();
+                    labels.insert(OVERFLOW_LABEL.to_string(), "true".to_string());
+                    Sample {
+                        metric,
+                        labels,
+                        ts_ms,
+                        value,
+                    }
+                }),
+        );
+        Cow::Owned(kept)
+    }
+
+    fn is_full(&self, metric: &str) -> bool {
+        let total_full = self
+            .limits
+            .max_series
+            .is_some_and(|max| self.active.len() >= max);
+        let metric_full = self
+            .limits
+            .max_series_per_metric
+            .is_some_and(|max| self.per_metric.get(metric).copied().unwrap_or(0) >= max);
+        total_full || metric_full
+    }
+
+    /// Forgets series not written within the last block duration, at most
+    /// once per `SWEEP_INTERVAL_MS`.
+    fn evict_stale(&mut self) {
+        if self
+            .last_sweep_ms
+            .is_some_and(|last| self.newest_ts_ms - last < SWEEP_INTERVAL_MS)
+        {
+            return;
+        }
+        self.last_sweep_ms = Some(self.newest_ts_ms);
+        let horizon = self.newest_ts_ms - BLOCK_DURATION.as_millis() as i64;
+        let per_metric = &mut self.per_metric;
+        self.active.retain(|_, series| {
+            let keep = series.last_ts_ms >= horizon;
+            if !keep {
+                if let Some(count) = per_metric.get_mut(&series.metric) {
+                    *count -= 1;
+                    if *count == 0 {
+                        per_metric.remove(&series.metric);
+                    }
+                }
+            }
+            keep
+        });
+    }
+
+    pub(crate) fn active_series(&self) -> usize {
+        self.active.len()
+    }
+
+    pub(crate) fn take_stats(&mut self) -> CardinalityStats {
+        CardinalityStats {
+            active_series: self.active.len(),
+            dropped_samples: std::mem::take(&mut self.dropped_samples),
+            aggregated_samples: std::mem::take(&mut self.aggregated_samples),
+        }
+    }
+}
+
+/// Per-metric write cadence for registry samples. Each metric belongs to the
+/// first policy matching it, or to the default interval.
+#[derive(Debug)]
+pub struct WriteSchedule {
+    /// `metric` patterns of the policies, in priority order.
+    patterns: Vec<String>,
+    /// Interval per policy, followed by the default interval.
+    intervals_ms: Vec<i64>,
+    last_write_ms: Vec<Option<i64>>,
+    classes: HashMap<String, usize>,
+}
+
+impl WriteSchedule {
+    pub fn new(default_interval: Duration, policies: &[LocalTsdbWritePolicy]) -> Self {
+        let mut intervals_ms: Vec<i64> = policies
+            .iter()
+            .map(|p| p.interval.as_millis() as i64)
+            .collect();
+        intervals_ms.push(default_interval.as_millis() as i64);
+        Self {
+            patterns: policies.iter().map(|p| p.metric.clone()).collect(),
+            last_write_ms: vec![None; intervals_ms.len()],
+            intervals_ms,
+            classes: HashMap::new(),
+        }
+    }
+
+    /// Whether any policy (or the default) is due at `ts_ms`, so callers
+    /// can skip gathering the registry otherwise.
+    pub fn any_due(&self, ts_ms: i64) -> bool {
+        (0..self.intervals_ms.len()).any(|class| self.is_due(class, ts_ms))
+    }
+
+    /// Keeps the samples whose policy is due at `ts_ms` and marks those
+    /// policies as written.
+    pub fn select(&mut self, mut samples: Vec<Sample>, ts_ms: i64) -> Vec<Sample> {
+        let due: Vec<bool> = (0..self.intervals_ms.len())
+            .map(|class| self.is_due(class, ts_ms))
+            .collect();
+        samples.retain(|sample| due[self.class_of(&sample.metric)]);
+        for (class, due) in due.into_iter().enumerate() {
+            if due {
+                self.last_write_ms[class] = Some(ts_ms);
+            }
+        }
+        samples
+    }
+
+    fn is_due(&self, class: usize, ts_ms: i64) -> bool {
+        self.last_write_ms[class].is_none_or(|last| ts_ms - last >= self.intervals_ms[class])
+    }
+
+    fn class_of(&mut self, metric: &str) -> usize {
+        if let Some(class) = self.classes.get(metric) {
+            return *class;
+        }
+        let class = self
+            .patterns
+            .iter()
+            .position(|pattern| matches_metric(metric, std::slice::from_ref(pattern)))
+            .unwrap_or(self.patterns.len());
+        self.classes.insert(metric.to_string(), class);
+        class
+    }
+}
+
+impl From<&AgentConfig> for WriteSchedule {
+    fn from(value: &AgentConfig) -> Self {
+        Self::new(
+            value.local_tsdb_write_interval,
+            &value.local_tsdb_write_policies,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample(metric: &str, ts_ms: i64) -> Sample {
+        Sample {
+            metric: metric.to_string(),
+            labels: HashMap::new(),
+            ts_ms,
+            value: 1.0,
+        }
+    }
+
+    fn written(schedule: &mut WriteSchedule, ts_ms: i64) -> Vec<String> {
+        let samples = vec![
+            sample("esnode_gpu_power_watts", ts_ms),
+            sample("esnode_disk_read_bytes_total", ts_ms),
+            sample("esnode_cpu_usage_percent", ts_ms),
+        ];
+        schedule
+            .select(samples, ts_ms)
+            .into_iter()
+            .map(|s| s.metric)
+            .collect()
+    }
+
+    #[test]
+    fn schedule_applies_first_matching_policy() {
+        let policy = |metric: &str, secs| LocalTsdbWritePolicy {
+            metric: metric.to_string(),
+            interval: Duration::from_secs(secs),
+        };
+        let mut schedule = WriteSchedule::new(
+            Duration::from_secs(30),
+            &[
+                policy("esnode_gpu_power_watts", 1),
+                policy("esnode_disk_*", 60),
+            ],
+        );
+        assert_eq!(written(&mut schedule, 0).len(), 3);
+        assert!(!schedule.any_due(500));
+        assert_eq!(written(&mut schedule, 1_000), ["esnode_gpu_power_watts"]);
+        assert_eq!(
+            written(&mut schedule, 30_000),
+            ["esnode_gpu_power_watts", "esnode_cpu_usage_percent"]
+        );
+        assert_eq!(written(&mut schedule, 60_000).len(), 3);
+    }
+
+    #[test]
+    fn stale_series_free_their_slot() {
+        let mut guard = CardinalityGuard::new(CardinalityLimits {
+            max_series: Some(1),
+            ..CardinalityLimits::default()
+        });
+        assert_eq!(guard.admit(&[sample("a", 0), sample("b", 0)]).len(), 1);
+        let later = BLOCK_DURATION.as_millis() as i64 + 1;
+        assert_eq!(guard.admit(&[sample("b", later)])[0].metric, "b");
+        assert_eq!(
+            guard.take_stats(),
+            CardinalityStats {
+                active_series: 1,
+                dropped_samples: 1,
+                aggregated_samples: 0,
+            }
+        );
+    }
+}
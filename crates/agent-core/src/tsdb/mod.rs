@@ -0,0 +1,885 @@
+// ESNODE | Source Available BUSL-1.1 | Copyright (c) 2025 Estimatedstocks AB
+mod admin;
+mod chunk;
+mod export;
+mod format;
+mod limits;
+pub mod query;
+mod recovery;
+mod rollup;
+mod scan;
+mod wal;
+
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use futures::TryStreamExt;
+use prometheus::proto::{MetricFamily, MetricType};
+use serde::{Deserialize, Serialize};
+use tokio::fs::{self, File, OpenOptions};
+use tokio::io::{AsyncWriteExt, BufWriter};
+use tokio::sync::Mutex;
+use tracing::debug;
+
+use crate::config::{AgentConfig, LocalTsdbRollupTier};
+use crate::metrics::MetricsRegistry;
+use chunk::XorChunk;
+use wal::Wal;
+
+pub use admin::{BlockSummary, MetricSamples, Snapshot, TierStats, Tombstone, TsdbStats};
+pub use export::ExportFormat;
+pub use limits::{CardinalityLimits, CardinalityStats, WriteSchedule, OVERFLOW_LABEL};
+pub use scan::{ExportCursor, SampleScan};
+
+const BLOCK_DURATION: Duration = Duration::from_secs(2 * 60 * 60); // 2h blocks
+const FLUSH_INTERVAL: Duration = Duration::from_secs(30);
+const SAMPLES_PER_CHUNK: usize = 120;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Sample {
+    pub metric: String,
+    pub labels: HashMap<String, String>,
+    pub ts_ms: i64,
+    pub value: f64,
+}
+
+#[derive(Debug, Clone)]
+pub struct LocalTsdbConfig {
+    pub path: PathBuf,
+    pub retention_hours: u64,
+    pub max_disk_mb: u64,
+    /// How often the write-ahead log of the open block is fsync'd.
+    /// Zero syncs after every batch of samples.
+    pub wal_fsync_interval: Duration,
+    /// Downsampled tiers, finest first. Raw blocks are kept for
+    /// `retention_hours`; each tier for its own retention.
+    pub rollups: Vec<LocalTsdbRollupTier>,
+    /// Caps on active series applied by `write_samples`.
+    pub cardinality: CardinalityLimits,
+}
+
+impl From<&AgentConfig> for LocalTsdbConfig {
+    fn from(value: &AgentConfig) -> Self {
+        Self {
+            path: PathBuf::from(value.local_tsdb_path.clone()),
+            retention_hours: value.local_tsdb_retention_hours,
+            max_disk_mb: value.local_tsdb_max_disk_mb,
+            wal_fsync_interval: value.local_tsdb_wal_fsync_interval,
+            rollups: value.local_tsdb_rollups.clone(),
+            cardinality: CardinalityLimits::from(value),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Default, Clone)]
+struct BlockMeta {
+    start_ms: i64,
+    end_ms: i64,
+    samples: u64,
+    metric_counts: HashMap<String, u64>,
+    label_hash_counts: HashMap<u64, u64>,
+}
+
+#[derive(Debug)]
+struct BlockWriter {
+    meta: BlockMeta,
+    dir: PathBuf,
+    writer: BufWriter<File>,
+    wal: Wal,
+    last_flush_ms: i64,
+    /// Open (not yet persisted) chunk per series ref.
+    open_chunks: HashMap<u64, XorChunk>,
+    /// Series refs whose dictionary record is already in this block's file.
+    known_series: HashSet<u64>,
+}
+
+impl BlockWriter {
+    async fn create(
+        root: &Path,
+        start_ms: i64,
+        end_ms: i64,
+        wal_fsync_interval: Duration,
+    ) -> Result<Self> {
+        let dir = root.join(format!("{start_ms}-{end_ms}"));
+        fs::create_dir_all(&dir)
+            .await
+            .with_context(|| format!("creating block dir {}", dir.display()))?;
+        let chunks_path = dir.join(format::CHUNKS_FILE);
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&chunks_path)
+            .await
+            .with_context(|| format!("opening chunks file {}", chunks_path.display()))?;
+        let is_new = file.metadata().await.map(|m| m.len() == 0).unwrap_or(true);
+        let mut writer = BufWriter::new(file);
+        if is_new {
+            writer.write_all(format::MAGIC).await?;
+        }
+        let wal = Wal::open(&dir, wal_fsync_interval).await?;
+        // Re-opening a window after a restart continues its existing counts.
+        let meta = match read_block_index(&dir).await {
+            Ok(meta) => meta,
+            Err(_) => BlockMeta {
+                start_ms,
+                end_ms,
+                samples: 0,
+                metric_counts: HashMap::new(),
+                label_hash_counts: HashMap::new(),
+            },
+        };
+        Ok(Self {
+            meta,
+            dir,
+            writer,
+            wal,
+            last_flush_ms: start_ms,
+            open_chunks: HashMap::new(),
+            known_series: HashSet::new(),
+        })
+    }
+
+    async fn write_sample(&mut self, sample: &Sample) -> Result<()> {
+        let series_ref = series_ref(&sample.metric, &sample.labels);
+        self.wal.append(series_ref, sample).await?;
+        if self.known_series.insert(series_ref) {
+            let mut buf = Vec::new();
+            format::encode_series_record(&mut buf, series_ref, &sample.metric, &sample.labels);
+            self.writer
+                .write_all(&buf)
+                .await
+                .context("writing series record")?;
+        }
+        let chunk = self.open_chunks.entry(series_ref).or_default();
+        chunk.append(sample.ts_ms, sample.value);
+        if chunk.len() >= SAMPLES_PER_CHUNK {
+            self.cut_chunk(series_ref).await?;
+        }
+        self.meta.samples += 1;
+        *self
+            .meta
+            .metric_counts
+            .entry(sample.metric.clone())
+            .or_default() += 1;
+        let hash = labels_hash(&sample.labels);
+        *self.meta.label_hash_counts.entry(hash).or_default() += 1;
+        self.flush_if_needed(sample.ts_ms).await?;
+        Ok(())
+    }
+
+    async fn cut_chunk(&mut self, series_ref: u64) -> Result<()> {
+        let Some(chunk) = self.open_chunks.remove(&series_ref) else {
+            return Ok(());
+        };
+        if chunk.is_empty() {
+            return Ok(());
+        }
+        let mut buf = Vec::with_capacity(chunk.bytes().len() + 32);
+        format::encode_chunk_record(&mut buf, series_ref, &chunk);
+        self.writer
+            .write_all(&buf)
+            .await
+            .context("writing chunk record")?;
+        Ok(())
+    }
+
+    /// Persists every open chunk and fsyncs `chunks.bin`.
+    async fn cut_all(&mut self) -> Result<()> {
+        let refs: Vec<u64> = self.open_chunks.keys().copied().collect();
+        for series_ref in refs {
+            self.cut_chunk(series_ref).await?;
+        }
+        self.writer.flush().await?;
+        self.writer
+            .get_ref()
+            .sync_data()
+            .await
+            .context("fsync chunks file")?;
+        Ok(())
+    }
+
+    /// Makes everything written so far durable in `chunks.bin` and the
+    /// index, then drops the WAL records that are now redundant.
+    async fn checkpoint(&mut self) -> Result<()> {
+        self.cut_all().await?;
+        self.persist_index_files().await?;
+        self.wal.checkpoint().await
+    }
+
+    async fn finish(mut self) -> Result<()> {
+        self.cut_all().await?;
+        self.persist_index_files().await?;
+        let wal_path = self.dir.join(wal::WAL_FILE);
+        drop(self.wal);
+        let _ = fs::remove_file(&wal_path).await;
+        Ok(())
+    }
+
+    /// Chunks stay open until full; the WAL covers them in the meantime, so
+    /// this only pushes already-cut chunks to the OS.
+    async fn flush_if_needed(&mut self, ts_ms: i64) -> Result<()> {
+        if ts_ms - self.last_flush_ms >= FLUSH_INTERVAL.as_millis() as i64 {
+            self.writer.flush().await?;
+            self.last_flush_ms = ts_ms;
+        }
+        Ok(())
+    }
+
+    /// Pushes cut chunks to the OS and copies the chunks still being
+    /// filled, so readers see the whole block without cutting them early.
+    async fn open_view(&mut self) -> Result<OpenBlockView> {
+        self.writer.flush().await?;
+        let chunks = self
+            .open_chunks
+            .iter()
+            .filter(|(_, chunk)| !chunk.is_empty())
+            .map(|(series_ref, chunk)| format::ChunkEntry {
+                series_ref: *series_ref,
+                count: chunk.len(),
+                min_ts: chunk.min_ts(),
+                max_ts: chunk.max_ts(),
+                payload: chunk.bytes().to_vec(),
+            })
+            .collect();
+        Ok(OpenBlockView {
+            dir: self.dir.clone(),
+            chunks,
+        })
+    }
+
+    async fn persist_index_files(&self) -> Result<()> {
+        let meta_path = self.dir.join("meta.json");
+        let index_path = self.dir.join("index.json");
+        let meta_bytes = serde_json::to_vec_pretty(&self.meta)?;
+        fs::write(&meta_path, &meta_bytes)
+            .await
+            .with_context(|| format!("writing meta {}", meta_path.display()))?;
+        fs::write(&index_path, &meta_bytes)
+            .await
+            .with_context(|| format!("writing index {}", index_path.display()))?;
+        Ok(())
+    }
+}
+
+/// In-memory chunks of the open block, captured for a single read.
+struct OpenBlockView {
+    dir: PathBuf,
+    chunks: Vec<format::ChunkEntry>,
+}
+
+#[derive(Debug)]
+pub struct LocalTsdb {
+    config: LocalTsdbConfig,
+    block_duration_ms: i64,
+    current: Mutex<Option<BlockWriter>>,
+    guard: parking_lot::Mutex<limits::CardinalityGuard>,
+    tombstones: parking_lot::RwLock<std::sync::Arc<admin::Tombstones>>,
+    /// Serialises read-modify-write cycles of `tombstones.json`.
+    tombstone_writer: Mutex<()>,
+}
+
+impl LocalTsdb {
+    pub fn new(config: LocalTsdbConfig) -> Result<Self> {
+        rollup::validate_tiers(&config.rollups)?;
+        std::fs::create_dir_all(&config.path)
+            .with_context(|| format!("creating TSDB path {}", config.path.display()))?;
+        recovery::recover(&config.path)?;
+        admin::remove_stale_snapshots(&config.path);
+        let tombstones = admin::Tombstones::load(&config.path)?;
+        Ok(Self {
+            guard: parking_lot::Mutex::new(limits::CardinalityGuard::new(
+                config.cardinality.clone(),
+            )),
+            config,
+            block_duration_ms: BLOCK_DURATION.as_millis() as i64,
+            current: Mutex::new(None),
+            tombstones: parking_lot::RwLock::new(std::sync::Arc::new(tombstones)),
+            tombstone_writer: Mutex::new(()),
+        })
+    }
+
+    /// Root directory of the TSDB (raw blocks live directly beneath it).
+    pub fn path(&self) -> &Path {
+        &self.config.path
+    }
+
+    pub async fn write_samples(&self, samples: &[Sample]) -> Result<()> {
+        if samples.is_empty() {
+            return Ok(());
+        }
+        let samples = self.guard.lock().admit(samples);
+
+        let mut guard = self.current.lock().await;
+        for sample in samples.iter() {
+            self.ensure_block_for_ts(sample.ts_ms, &mut guard).await?;
+            if let Some(writer) = guard.as_mut() {
+                writer.write_sample(sample).await?;
+            }
+        }
+        if let Some(writer) = guard.as_mut() {
+            writer.wal.sync_if_due().await?;
+        }
+        Ok(())
+    }
+
+    /// Active series and the samples dropped or aggregated by the
+    /// cardinality limits since the previous call.
+    pub fn take_cardinality_stats(&self) -> CardinalityStats {
+        self.guard.lock().take_stats()
+    }
+
+    async fn ensure_block_for_ts(
+        &self,
+        ts_ms: i64,
+        current: &mut Option<BlockWriter>,
+    ) -> Result<()> {
+        let window_start = (ts_ms / self.block_duration_ms) * self.block_duration_ms;
+        let window_end = window_start + self.block_duration_ms;
+        let needs_new = match current {
+            Some(w) => ts_ms < w.meta.start_ms || ts_ms >= w.meta.end_ms,
+            None => true,
+        };
+        if needs_new {
+            if let Some(writer) = current.take() {
+                writer.finish().await?;
+            }
+            let writer = BlockWriter::create(
+                &self.config.path,
+                window_start,
+                window_end,
+                self.config.wal_fsync_interval,
+            )
+            .await?;
+            *current = Some(writer);
+        }
+        Ok(())
+    }
+
+    /// Drops raw blocks past `retention_hours` and rollup blocks past their
+    /// tier's retention, then the oldest blocks of any tier until the disk
+    /// budget fits. Raw blocks still waiting for their first rollup are kept
+    /// by the retention pass so compaction can catch up. Tombstones older
+    /// than every remaining block are dropped last.
+    pub async fn prune(&self, now_ms: i64) -> Result<()> {
+        let retention_ms = (self.config.retention_hours as i64) * 60 * 60 * 1000;
+        let max_bytes = self.config.max_disk_mb as i64 * 1024 * 1024;
+
+        let first_tier = self.config.rollups.first().map(|tier| {
+            (
+                self.tier_path(0),
+                now_ms - tier.retention.as_millis() as i64,
+            )
+        });
+        for blk in self
+            .list_blocks()
+            .await?
+            .iter()
+            .filter(|b| b.end_ms < now_ms - retention_ms)
+        {
+            if let (Some((tier_path, horizon)), Some(name)) = (&first_tier, blk.dir.file_name()) {
+                if blk.end_ms >= *horizon && !tier_path.join(name).exists() {
+                    continue;
+                }
+            }
+            debug!("pruning expired block {}", blk.dir.display());
+            let _ = fs::remove_dir_all(&blk.dir).await;
+        }
+        for (tier, rollup) in self.config.rollups.iter().enumerate() {
+            let horizon = now_ms - rollup.retention.as_millis() as i64;
+            for blk in self
+                .list_blocks_in(&self.tier_path(tier))
+                .await?
+                .iter()
+                .filter(|b| b.end_ms < horizon)
+            {
+                debug!("pruning expired rollup block {}", blk.dir.display());
+                let _ = fs::remove_dir_all(&blk.dir).await;
+            }
+        }
+
+        // Re-scan after retention deletion; budget pruning drops the oldest
+        // data first and, for the same window, the finest tier first.
+        let mut blocks: Vec<(usize, BlockInfo)> = self
+            .list_blocks()
+            .await?
+            .into_iter()
+            .map(|b| (0, b))
+            .collect();
+        for tier in 0..self.config.rollups.len() {
+            for blk in self.list_blocks_in(&self.tier_path(tier)).await? {
+                blocks.push((tier + 1, blk));
+            }
+        }
+        let mut total_bytes: i64 = blocks.iter().map(|(_, b)| b.size_bytes as i64).sum();
+        blocks.sort_by_key(|(tier, b)| (b.start_ms, *tier));
+        let mut remaining = blocks.into_iter().peekable();
+        while total_bytes > max_bytes {
+            let Some((_, blk)) = remaining.next() else {
+                break;
+            };
+            debug!("pruning block {} to enforce disk budget", blk.dir.display());
+            if fs::remove_dir_all(&blk.dir).await.is_ok() {
+                total_bytes -= blk.size_bytes as i64;
+            }
+        }
+        let oldest_ms = remaining.peek().map(|(_, b)| b.start_ms);
+        self.expire_tombstones(oldest_ms).await
+    }
+
+    async fn list_blocks(&self) -> Result<Vec<BlockInfo>> {
+        self.list_blocks_in(&self.config.path).await
+    }
+
+    async fn list_blocks_in(&self, root: &Path) -> Result<Vec<BlockInfo>> {
+        if !fs::try_exists(root).await.unwrap_or(false) {
+            return Ok(Vec::new());
+        }
+        let mut entries = fs::read_dir(root)
+            .await
+            .with_context(|| format!("reading {}", root.display()))?;
+        let mut out = Vec::new();
+        while let Some(entry) = entries.next_entry().await? {
+            let file_type = entry.file_type().await?;
+            if !file_type.is_dir() {
+                continue;
+            }
+            let name = entry.file_name();
+            let Some((start_ms, end_ms)) = parse_block_name(&name.to_string_lossy()) else {
+                continue;
+            };
+            let dir = entry.path();
+            let size_bytes = tokio::task::spawn_blocking({
+                let dir_cloned = dir.clone();
+                move || dir_size_bytes(&dir_cloned)
+            })
+            .await
+            .ok()
+            .flatten()
+            .unwrap_or(0);
+            let index = read_block_index(&dir).await.ok();
+            out.push(BlockInfo {
+                dir,
+                start_ms,
+                end_ms,
+                size_bytes,
+                index,
+            });
+        }
+        Ok(out)
+    }
+
+    pub async fn flush_current(&self) -> Result<()> {
+        let mut guard = self.current.lock().await;
+        if let Some(writer) = guard.take() {
+            writer.finish().await?;
+        }
+        Ok(())
+    }
+
+    pub async fn snapshot_current(&self) -> Result<()> {
+        let mut guard = self.current.lock().await;
+        if let Some(writer) = guard.as_mut() {
+            writer.checkpoint().await?;
+        }
+        Ok(())
+    }
+
+    pub async fn export_lines(
+        &self,
+        from_ms: Option<i64>,
+        to_ms: Option<i64>,
+        metrics: Option<&Vec<String>>,
+    ) -> Result<Vec<String>> {
+        let (samples, _) = self
+            .read_samples(from_ms, to_ms, metrics.map(Vec::as_slice))
+            .await?;
+        Ok(rollup::averages_only(samples)
+            .iter()
+            .map(export::text_line)
+            .collect())
+    }
+
+    /// Renders the samples `export_lines` would return as a complete body
+    /// in `format`. Prefer [`ExportFormat::stream`] over a [`Self::scan`]
+    /// for large ranges.
+    pub async fn export(
+        &self,
+        from_ms: Option<i64>,
+        to_ms: Option<i64>,
+        metrics: Option<&Vec<String>>,
+        format: ExportFormat,
+    ) -> Result<String> {
+        let scan = self.scan(from_ms, to_ms, metrics.cloned(), None).await?;
+        format.stream(scan).try_collect().await
+    }
+
+    /// Samples in `[from_ms, to_ms]`, oldest first, with rollup tiers
+    /// filling in (as averages) where raw blocks have expired.
+    pub async fn read_range(&self, from_ms: i64, to_ms: i64) -> Result<Vec<Sample>> {
+        let (samples, _) = self.read_samples(Some(from_ms), Some(to_ms), None).await?;
+        Ok(rollup::averages_only(samples))
+    }
+
+    /// Reads samples in `[from, to]`, oldest first, from the finest tiers
+    /// that cover the range (see [`rollup::plan_segments`]). Rollup samples
+    /// carry a `__rollup__` label. Also returns the coarsest resolution used
+    /// (zero when only raw blocks were read). `metrics` uses the same
+    /// exact/`prefix*` filters as export.
+    async fn read_samples(
+        &self,
+        from_ms: Option<i64>,
+        to_ms: Option<i64>,
+        metrics: Option<&[String]>,
+    ) -> Result<(Vec<Sample>, Duration)> {
+        let (reads, resolution) = self.plan_reads(from_ms, to_ms, metrics).await?;
+        let mut out = Vec::new();
+        for read in reads {
+            let Some(data) = read.load().await else {
+                continue;
+            };
+            out.extend(data.samples(read.from_ms, read.to_ms, |metric| {
+                metrics.is_none_or(|filters| matches_metric(metric, filters))
+            }));
+        }
+        self.tombstones().retain_live(&mut out);
+        Ok((out, resolution))
+    }
+
+    /// Blocks a read of `[from, to]` touches, oldest first, each with the
+    /// part of the range it serves. Blocks whose index rules out every
+    /// `metrics` filter are left out.
+    async fn plan_reads(
+        &self,
+        from_ms: Option<i64>,
+        to_ms: Option<i64>,
+        metrics: Option<&[String]>,
+    ) -> Result<(Vec<BlockRead>, Duration)> {
+        // Read the open block as-is rather than checkpointing it: frequent
+        // readers (remote write, queries) would otherwise fragment its chunks.
+        let open = match self.current.lock().await.as_mut() {
+            Some(writer) => Some(writer.open_view().await?),
+            None => None,
+        };
+        let mut roots = vec![self.config.path.clone()];
+        roots.extend((0..self.config.rollups.len()).map(|tier| self.tier_path(tier)));
+        let mut tiers = Vec::with_capacity(roots.len());
+        for root in &roots {
+            let mut blocks = self.list_blocks_in(root).await?;
+            blocks.sort_by_key(|b| b.start_ms);
+            tiers.push(blocks);
+        }
+        let starts: Vec<Option<i64>> = tiers
+            .iter()
+            .map(|blocks| blocks.first().map(|b| b.start_ms))
+            .collect();
+
+        let mut reads = Vec::new();
+        let mut resolution = Duration::ZERO;
+        for segment in rollup::plan_segments(&starts, from_ms, to_ms) {
+            let blocks = &tiers[segment.tier.map_or(0, |t| t + 1)];
+            if let Some(tier) = segment.tier {
+                resolution = resolution.max(self.config.rollups[tier].resolution);
+            }
+            for blk in blocks
+                .iter()
+                .filter(|b| overlaps(b.start_ms, b.end_ms, segment.from_ms, segment.to_ms))
+            {
+                let open_chunks = open
+                    .as_ref()
+                    .filter(|view| segment.tier.is_none() && view.dir == blk.dir)
+                    .map(|view| view.chunks.clone());
+                // The open block's index is only as fresh as its last checkpoint.
+                if let (Some(filters), Some(idx), None) = (metrics, &blk.index, &open_chunks) {
+                    if !metrics_match_index(filters, idx) {
+                        continue;
+                    }
+                }
+                reads.push(BlockRead {
+                    dir: blk.dir.clone(),
+                    start_ms: blk.start_ms,
+                    end_ms: blk.end_ms,
+                    from_ms: segment.from_ms,
+                    to_ms: segment.to_ms,
+                    open_chunks,
+                });
+            }
+        }
+        Ok((reads, resolution))
+    }
+}
+
+impl LocalTsdb {
+    pub fn spawn_pruner(
+        self: std::sync::Arc<Self>,
+        interval: Duration,
+    ) -> tokio::task::JoinHandle<()> {
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            loop {
+                ticker.tick().await;
+                let now_ms = chrono::Utc::now().timestamp_millis();
+                let _ = self.prune(now_ms).await;
+            }
+        })
+    }
+}
+
+/// One block of a planned read and the part of the range it serves.
+struct BlockRead {
+    dir: PathBuf,
+    start_ms: i64,
+    end_ms: i64,
+    from_ms: Option<i64>,
+    to_ms: Option<i64>,
+    /// Chunks still being filled when this is the open block.
+    open_chunks: Option<Vec<format::ChunkEntry>>,
+}
+
+impl BlockRead {
+    /// Decodes the block, or `None` (logged) when it cannot be read.
+    async fn load(&self) -> Option<format::BlockData> {
+        let mut data = match format::read_block(&self.dir).await {
+            Ok(data) => data,
+            Err(err) => {
+                debug!("skipping unreadable block {}: {err:?}", self.dir.display());
+                return None;
+            }
+        };
+        if let Some(chunks) = &self.open_chunks {
+            data.chunks.extend(chunks.iter().cloned());
+        }
+        Some(data)
+    }
+}
+
+struct BlockInfo {
+    dir: PathBuf,
+    start_ms: i64,
+    end_ms: i64,
+    size_bytes: u64,
+    index: Option<BlockMeta>,
+}
+
+async fn read_block_index(dir: &Path) -> Result<BlockMeta> {
+    let index_path = dir.join("index.json");
+    let bytes = fs::read(&index_path)
+        .await
+        .with_context(|| format!("reading index {}", index_path.display()))?;
+    let meta: BlockMeta = serde_json::from_slice(&bytes)?;
+    Ok(meta)
+}
+
+/// Parses a `<start_ms>-<end_ms>` block directory name.
+fn parse_block_name(name: &str) -> Option<(i64, i64)> {
+    let (start, end) = name.split_once('-')?;
+    Some((start.parse().ok()?, end.parse().ok()?))
+}
+
+fn overlaps(start: i64, end: i64, from: Option<i64>, to: Option<i64>) -> bool {
+    let after_from = from.is_none_or(|f| end >= f);
+    let before_to = to.is_none_or(|t| start <= t);
+    after_from && before_to
+}
+
+fn timestamp_in_range(ts: i64, from: Option<i64>, to: Option<i64>) -> bool {
+    let gte_from = from.is_none_or(|f| ts >= f);
+    let lte_to = to.is_none_or(|t| ts <= t);
+    gte_from && lte_to
+}
+
+fn metrics_match_index(filters: &[String], idx: &BlockMeta) -> bool {
+    filters.iter().any(|f| {
+        if f.ends_with('*') {
+            let prefix = f.trim_end_matches('*');
+            idx.metric_counts.keys().any(|m| m.starts_with(prefix))
+        } else {
+            idx.metric_counts.contains_key(f)
+        }
+    })
+}
+
+fn matches_metric(metric: &str, filters: &[String]) -> bool {
+    filters.iter().any(|f| {
+        if f.ends_with('*') {
+            metric.starts_with(f.trim_end_matches('*'))
+        } else {
+            metric == f
+        }
+    })
+}
+
+/// Label-set identity, persisted in block metadata.
+fn labels_hash(labels: &HashMap<String, String>) -> u64 {
+    let mut hasher = Fnv1a::new();
+    hash_label_pairs(&mut hasher, labels);
+    hasher.finish()
+}
+
+/// Series identity within a block: the metric name plus `labels_hash` pairs.
+fn series_ref(metric: &str, labels: &HashMap<String, String>) -> u64 {
+    let mut hasher = Fnv1a::new();
+    hasher.write_str("__name__");
+    hasher.write_str(metric);
+    hash_label_pairs(&mut hasher, labels);
+    hasher.finish()
+}
+
+fn hash_label_pairs(hasher: &mut Fnv1a, labels: &HashMap<String, String>) {
+    let mut pairs: Vec<_> = labels.iter().collect();
+    pairs.sort_by_key(|(k, _)| *k);
+    for (k, v) in pairs {
+        hasher.write_str(k);
+        hasher.write_str(v);
+    }
+}
+
+/// 64-bit FNV-1a. Series refs and label hashes are written to disk, so they
+/// must not depend on the std hasher, which may change between releases.
+struct Fnv1a(u64);
+
+impl Fnv1a {
+    const OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+    const PRIME: u64 = 0x0000_0100_0000_01b3;
+
+    fn new() -> Self {
+        Self(Self::OFFSET_BASIS)
+    }
+
+    /// Hashes `s` followed by a 0xff separator, which never occurs in UTF-8,
+    /// so `("ab", "c")` and `("a", "bc")` differ.
+    fn write_str(&mut self, s: &str) {
+        for byte in s.bytes().chain([0xff]) {
+            self.0 ^= u64::from(byte);
+            self.0 = self.0.wrapping_mul(Self::PRIME);
+        }
+    }
+
+    fn finish(&self) -> u64 {
+        self.0
+    }
+}
+
+fn dir_size_bytes(path: &Path) -> Option<u64> {
+    let mut size = 0u64;
+    let mut stack = vec![path.to_path_buf()];
+    while let Some(p) = stack.pop() {
+        if let Ok(meta) = std::fs::metadata(&p) {
+            if meta.is_file() {
+                size = size.saturating_add(meta.len());
+            } else if meta.is_dir() {
+                if let Ok(read) = std::fs::read_dir(&p) {
+                    for entry in read.flatten() {
+                        stack.push(entry.path());
+                    }
+                }
+            }
+        }
+    }
+    Some(size)
+}
+
+#[must_use]
+pub fn samples_from_registry(registry: &MetricsRegistry, fallback_ts_ms: i64) -> Vec<Sample> {
+    samples_from_families(&registry.gather_families(), fallback_ts_ms)
+}
+
+/// Flattens gathered families into samples the way the text exposition
+/// format does: histograms become cumulative `_bucket` series with an `le`
+/// label (including `+Inf`) plus `_sum` and `_count`, and summaries become
+/// one series per `quantile` plus `_sum` and `_count`.
+#[must_use]
+pub fn samples_from_families(families: &[MetricFamily], fallback_ts_ms: i64) -> Vec<Sample> {
+    let mut out = Vec::new();
+    for fam in families {
+        for metric in fam.get_metric() {
+            let ts_ms = if metric.get_timestamp_ms() > 0 {
+                metric.get_timestamp_ms()
+            } else {
+                fallback_ts_ms
+            };
+            let labels = metric
+                .get_label()
+                .iter()
+                .map(|lp| (lp.get_name().to_string(), lp.get_value().to_string()))
+                .collect::<HashMap<_, _>>();
+            let mut push = |suffix: &str, extra: Option<(&str, f64)>, value: f64| {
+                let mut labels = labels.clone();
+                if let Some((name, bound)) = extra {
+                    labels.insert(name.to_string(), format_float(bound));
+                }
+                out.push(Sample {
+                    metric: format!("{}{suffix}", fam.get_name()),
+                    labels,
+                    ts_ms,
+                    value,
+                });
+            };
+            match fam.get_field_type() {
+                MetricType::GAUGE => push("", None, metric.get_gauge().get_value()),
+                MetricType::COUNTER => push("", None, metric.get_counter().get_value()),
+                MetricType::UNTYPED => push("", None, metric.get_untyped().get_value()),
+                MetricType::HISTOGRAM => {
+                    let h = metric.get_histogram();
+                    let mut has_inf = false;
+                    for bucket in h.get_bucket() {
+                        has_inf |= bucket.get_upper_bound() == f64::INFINITY;
+                        push(
+                            "_bucket",
+                            Some(("le", bucket.get_upper_bound())),
+                            bucket.get_cumulative_count() as f64,
+                        );
+                    }
+                    if !has_inf {
+                        push(
+                            "_bucket",
+                            Some(("le", f64::INFINITY)),
+                            h.get_sample_count() as f64,
+                        );
+                    }
+                    push("_sum", None, h.get_sample_sum());
+                    push("_count", None, h.get_sample_count() as f64);
+                }
+                MetricType::SUMMARY => {
+                    let summary = metric.get_summary();
+                    for q in summary.get_quantile() {
+                        push("", Some(("quantile", q.get_quantile())), q.get_value());
+                    }
+                    push("_sum", None, summary.get_sample_sum());
+                    push("_count", None, summary.get_sample_count() as f64);
+                }
+            }
+        }
+    }
+    out
+}
+
+/// Formats a float like the Prometheus text format: shortest round-trip
+/// digits, `+Inf`/`-Inf` and `NaN`.
+fn format_float(value: f64) -> String {
+    if value.is_nan() {
+        "NaN".to_string()
+    } else if value.is_infinite() {
+        if value > 0.0 { "+Inf" } else { "-Inf" }.to_string()
+    } else {
+        value.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn series_hashes_are_stable() {
+        let labels = HashMap::from([
+            ("uuid".to_string(), "GPU-abc".to_string()),
+            ("gpu".to_string(), "0".to_string()),
+        ]);
+        // Persisted in blocks and the WAL: these values must never change.
+        assert_eq!(series_ref("gpu_power_watts", &labels), 0x3e3e_5373_0e33_fcee);
+        assert_eq!(labels_hash(&labels), 0xf6d7_4683_6462_7351);
+        assert_eq!(labels_hash(&HashMap::new()), Fnv1a::OFFSET_BASIS);
+    }
+}
@@ -0,0 +1,1040 @@
+// ESNODE | Source Available BUSL-1.1 | Copyright (c) 2025 Estimatedstocks AB
+//! A PromQL subset evaluated over local TSDB blocks.
+//!
+//! Supported expressions:
+//! - instant selectors with `=`, `!=`, `=~` and `!~` label matchers
+//! - `rate`, `increase` and `avg_over_time` over a range selector (`metric[5m]`)
+//! - `sum`, `avg`, `max` and `min` with an optional `by (...)` clause
+//! - number literals and `+ - * /` where at least one operand is a scalar
+//!
+//! Semantics follow Prometheus: instant selectors look back five minutes for
+//! the latest sample, `rate`/`increase` are extrapolated and counter-reset
+//! aware, and functions and arithmetic drop the metric name. Results are
+//! shaped like the Prometheus HTTP API so Grafana can query the agent directly.
+//!
+//! Ranges older than raw retention are served from rollup tiers. Selectors
+//! read the `avg` rollup unless they match on `__rollup__` themselves (e.g.
+//! `{__rollup__="max"}`), and the lookback widens to the tier resolution.
+
+use std::collections::{BTreeMap, HashMap};
+
+use regex::Regex;
+use serde_json::{json, Value};
+use thiserror::Error;
+
+use super::rollup::ROLLUP_LABEL;
+use super::{format_float, LocalTsdb, Sample};
+
+/// How far back an instant selector looks for the latest sample.
+pub const LOOKBACK_MS: i64 = 5 * 60 * 1000;
+/// Points per series a range query may return, as in Prometheus.
+const MAX_POINTS: i64 = 11_000;
+const NAME_LABEL: &str = "__name__";
+
+pub type Labels = BTreeMap<String, String>;
+
+#[derive(Debug, Error)]
+pub enum QueryError {
+    #[error("parse error at char {pos}: {msg}")]
+    Parse { pos: usize, msg: String },
+    #[error("{0}")]
+    BadData(String),
+    #[error(transparent)]
+    Storage(#[from] anyhow::Error),
+}
+
+impl QueryError {
+    /// The Prometheus API `errorType` for this error.
+    pub fn error_type(&self) -> &'static str {
+        match self {
+            QueryError::Parse { .. } | QueryError::BadData(_) => "bad_data",
+            QueryError::Storage(_) => "internal",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MatchOp {
+    Equal,
+    NotEqual,
+    RegexMatch,
+    RegexNoMatch,
+}
+
+#[derive(Debug, Clone)]
+pub struct LabelMatcher {
+    pub name: String,
+    pub op: MatchOp,
+    pub value: String,
+    regex: Option<Regex>,
+}
+
+impl LabelMatcher {
+    fn new(name: &str, op: MatchOp, value: String) -> Result<Self, String> {
+        let regex = match op {
+            MatchOp::RegexMatch | MatchOp::RegexNoMatch => Some(
+                // Prometheus regex matchers are fully anchored.
+                Regex::new(&format!("^(?:{value})$"))
+                    .map_err(|err| format!("invalid regex {value:?}: {err}"))?,
+            ),
+            MatchOp::Equal | MatchOp::NotEqual => None,
+        };
+        Ok(Self {
+            name: name.to_string(),
+            op,
+            value,
+            regex,
+        })
+    }
+
+    fn matches(&self, value: &str) -> bool {
+        match (self.op, &self.regex) {
+            (MatchOp::Equal, _) => value == self.value,
+            (MatchOp::NotEqual, _) => value != self.value,
+            (MatchOp::RegexMatch, Some(re)) => re.is_match(value),
+            (MatchOp::RegexNoMatch, Some(re)) => !re.is_match(value),
+            _ => false,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Selector {
+    pub matchers: Vec<LabelMatcher>,
+}
+
+impl Selector {
+    /// Missing labels match as the empty string, as in Prometheus.
+    fn matches(&self, labels: &Labels) -> bool {
+        if !self.mentions_rollup() {
+            if let Some(aggregate) = labels.get(ROLLUP_LABEL) {
+                if aggregate != "avg" {
+                    return false;
+                }
+            }
+        }
+        self.matchers
+            .iter()
+            .all(|m| m.matches(labels.get(&m.name).map(String::as_str).unwrap_or("")))
+    }
+
+    fn mentions_rollup(&self) -> bool {
+        self.matchers.iter().any(|m| m.name == ROLLUP_LABEL)
+    }
+
+    /// Labels of a selected series as they appear in results: the implicit
+    /// rollup label is hidden so series keep their identity across tiers.
+    fn output_labels(&self, labels: &Labels, keep_name: bool) -> Labels {
+        let mut labels = labels.clone();
+        if !keep_name {
+            labels.remove(NAME_LABEL);
+        }
+        if !self.mentions_rollup() {
+            labels.remove(ROLLUP_LABEL);
+        }
+        labels
+    }
+
+    /// Whether a stored series matches, ignoring which rollup aggregate it is.
+    pub fn matches_series(&self, metric: &str, labels: &HashMap<String, String>) -> bool {
+        self.matchers.iter().all(|m| {
+            let value = match m.name.as_str() {
+                NAME_LABEL => metric,
+                ROLLUP_LABEL => "",
+                name => labels.get(name).map(String::as_str).unwrap_or(""),
+            };
+            m.matches(value)
+        })
+    }
+
+    /// The metric name when the selector pins it with `=`.
+    fn metric_name(&self) -> Option<&str> {
+        self.matchers
+            .iter()
+            .find(|m| m.name == NAME_LABEL && m.op == MatchOp::Equal)
+            .map(|m| m.value.as_str())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RangeFunction {
+    Rate,
+    Increase,
+    AvgOverTime,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AggregateOp {
+    Sum,
+    Avg,
+    Max,
+    Min,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BinaryOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+}
+
+impl BinaryOp {
+    fn apply(self, lhs: f64, rhs: f64) -> f64 {
+        match self {
+            BinaryOp::Add => lhs + rhs,
+            BinaryOp::Sub => lhs - rhs,
+            BinaryOp::Mul => lhs * rhs,
+            BinaryOp::Div => lhs / rhs,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum Expr {
+    Number(f64),
+    Selector(Selector),
+    Call {
+        func: RangeFunction,
+        selector: Selector,
+        range_ms: i64,
+    },
+    Aggregate {
+        op: AggregateOp,
+        grouping: Vec<String>,
+        expr: Box<Expr>,
+    },
+    Binary {
+        op: BinaryOp,
+        lhs: Box<Expr>,
+        rhs: Box<Expr>,
+    },
+}
+
+impl Expr {
+    fn is_scalar(&self) -> bool {
+        match self {
+            Expr::Number(_) => true,
+            Expr::Binary { lhs, rhs, .. } => lhs.is_scalar() && rhs.is_scalar(),
+            _ => false,
+        }
+    }
+
+    /// How much history before the first evaluation step the expression reads.
+    fn lookback_ms(&self) -> i64 {
+        match self {
+            Expr::Number(_) => 0,
+            Expr::Selector(_) => LOOKBACK_MS,
+            Expr::Call { range_ms, .. } => *range_ms,
+            Expr::Aggregate { expr, .. } => expr.lookback_ms(),
+            Expr::Binary { lhs, rhs, .. } => lhs.lookback_ms().max(rhs.lookback_ms()),
+        }
+    }
+
+    fn selectors<'a>(&'a self, out: &mut Vec<&'a Selector>) {
+        match self {
+            Expr::Number(_) => {}
+            Expr::Selector(sel) | Expr::Call { selector: sel, .. } => out.push(sel),
+            Expr::Aggregate { expr, .. } => expr.selectors(out),
+            Expr::Binary { lhs, rhs, .. } => {
+                lhs.selectors(out);
+                rhs.selectors(out);
+            }
+        }
+    }
+}
+
+/// One series of a range query result.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RangeSeries {
+    pub labels: Labels,
+    pub points: Vec<(i64, f64)>,
+}
+
+/// One element of an instant query result.
+#[derive(Debug, Clone, PartialEq)]
+pub struct InstantSample {
+    pub labels: Labels,
+    pub ts_ms: i64,
+    pub value: f64,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum QueryResult {
+    Matrix(Vec<RangeSeries>),
+    Vector(Vec<InstantSample>),
+    Scalar { ts_ms: i64, value: f64 },
+}
+
+impl QueryResult {
+    /// Renders the `data` object of a Prometheus API response.
+    pub fn to_json(&self) -> Value {
+        match self {
+            QueryResult::Matrix(series) => json!({
+                "resultType": "matrix",
+                "result": series
+                    .iter()
+                    .map(|s| json!({
+                        "metric": s.labels,
+                        "values": s
+                            .points
+                            .iter()
+                            .map(|(ts, v)| json!([ts_seconds(*ts), format_float(*v)]))
+                            .collect::<Vec<_>>(),
+                    }))
+                    .collect::<Vec<_>>(),
+            }),
+            QueryResult::Vector(samples) => json!({
+                "resultType": "vector",
+                "result": samples
+                    .iter()
+                    .map(|s| json!({
+                        "metric": s.labels,
+                        "value": [ts_seconds(s.ts_ms), format_float(s.value)],
+                    }))
+                    .collect::<Vec<_>>(),
+            }),
+            QueryResult::Scalar { ts_ms, value } => json!({
+                "resultType": "scalar",
+                "result": [ts_seconds(*ts_ms), format_float(*value)],
+            }),
+        }
+    }
+}
+
+fn ts_seconds(ts_ms: i64) -> f64 {
+    ts_ms as f64 / 1000.0
+}
+
+impl LocalTsdb {
+    /// Evaluates `query` at every `step_ms` from `start_ms` to `end_ms` inclusive.
+    pub async fn query_range(
+        &self,
+        query: &str,
+        start_ms: i64,
+        end_ms: i64,
+        step_ms: i64,
+    ) -> Result<QueryResult, QueryError> {
+        if step_ms <= 0 {
+            return Err(QueryError::BadData(
+                "zero or negative query resolution step widths are not accepted".to_string(),
+            ));
+        }
+        if end_ms < start_ms {
+            return Err(QueryError::BadData(
+                "end timestamp must not be before start time".to_string(),
+            ));
+        }
+        let Some(width_ms) = end_ms.checked_sub(start_ms) else {
+            return Err(QueryError::BadData("query range is too wide".to_string()));
+        };
+        if width_ms / step_ms >= MAX_POINTS {
+            return Err(QueryError::BadData(format!(
+                "exceeded maximum resolution of {MAX_POINTS} points per timeseries; \
+                 try decreasing the query resolution (?step=XX)"
+            )));
+        }
+        let expr = parse(query)?;
+        let series = self.load_series(&expr, start_ms, end_ms).await?;
+
+        let mut out: BTreeMap<Labels, Vec<(i64, f64)>> = BTreeMap::new();
+        let mut ts = start_ms;
+        while ts <= end_ms {
+            match eval(&expr, ts, &series) {
+                Evaluated::Scalar(value) => out.entry(Labels::new()).or_default().push((ts, value)),
+                Evaluated::Vector(samples) => {
+                    for (labels, value) in samples {
+                        out.entry(labels).or_default().push((ts, value));
+                    }
+                }
+            }
+            // Overflowing means the next step is past `end_ms`.
+            match ts.checked_add(step_ms) {
+                Some(next) => ts = next,
+                None => break,
+            }
+        }
+        Ok(QueryResult::Matrix(
+            out.into_iter()
+                .map(|(labels, points)| RangeSeries { labels, points })
+                .collect(),
+        ))
+    }
+
+    /// Evaluates `query` at a single timestamp.
+    pub async fn query_instant(&self, query: &str, ts_ms: i64) -> Result<QueryResult, QueryError> {
+        let expr = parse(query)?;
+        let series = self.load_series(&expr, ts_ms, ts_ms).await?;
+        Ok(match eval(&expr, ts_ms, &series) {
+            Evaluated::Scalar(value) => QueryResult::Scalar { ts_ms, value },
+            Evaluated::Vector(samples) => QueryResult::Vector(
+                samples
+                    .into_iter()
+                    .map(|(labels, value)| InstantSample {
+                        labels,
+                        ts_ms,
+                        value,
+                    })
+                    .collect(),
+            ),
+        })
+    }
+
+    /// Loads every series the expression can touch, grouped by label set.
+    async fn load_series(
+        &self,
+        expr: &Expr,
+        start_ms: i64,
+        end_ms: i64,
+    ) -> Result<SeriesSet, QueryError> {
+        let mut selectors = Vec::new();
+        expr.selectors(&mut selectors);
+        if selectors.is_empty() {
+            return Ok(SeriesSet::default());
+        }
+        // Only narrow the block scan when every selector names its metric.
+        let names: Option<Vec<String>> = selectors
+            .iter()
+            .map(|s| s.metric_name().map(str::to_string))
+            .collect();
+        // Rollup points are stamped with their window start, so reach back
+        // one extra window in case the range is served from a tier.
+        let coarsest_ms = self
+            .config
+            .rollups
+            .last()
+            .map_or(0, |tier| tier.resolution.as_millis() as i64);
+        // Evaluation never looks further back than this, so it cannot
+        // overflow either.
+        let Some(from_ms) = start_ms
+            .checked_sub(expr.lookback_ms())
+            .and_then(|ms| ms.checked_sub(coarsest_ms))
+        else {
+            return Err(QueryError::BadData(
+                "query reaches back before the earliest representable time".to_string(),
+            ));
+        };
+        let (samples, resolution) = self
+            .read_samples(Some(from_ms), Some(end_ms), names.as_deref())
+            .await?;
+        Ok(SeriesSet {
+            series: group_series(samples, &selectors),
+            lookback_ms: LOOKBACK_MS.max(resolution.as_millis() as i64),
+        })
+    }
+}
+
+#[derive(Debug, Default)]
+struct SeriesSet {
+    series: Vec<Series>,
+    /// Instant selector lookback: five minutes, or the rollup resolution
+    /// when the range was served from a coarser tier.
+    lookback_ms: i64,
+}
+
+#[derive(Debug)]
+struct Series {
+    /// Labels including `__name__`.
+    labels: Labels,
+    /// Points sorted by timestamp, one per timestamp.
+    points: Vec<(i64, f64)>,
+}
+
+impl Series {
+    /// Points with `from < ts <= to`.
+    fn window(&self, from: i64, to: i64) -> &[(i64, f64)] {
+        let lo = self.points.partition_point(|(ts, _)| *ts <= from);
+        let hi = self.points.partition_point(|(ts, _)| *ts <= to);
+        &self.points[lo..hi.max(lo)]
+    }
+}
+
+fn group_series(samples: Vec<Sample>, selectors: &[&Selector]) -> Vec<Series> {
+    let mut grouped: BTreeMap<Labels, Vec<(i64, f64)>> = BTreeMap::new();
+    for sample in samples {
+        let mut labels: Labels = sample.labels.into_iter().collect();
+        labels.insert(NAME_LABEL.to_string(), sample.metric);
+        if !selectors.iter().any(|s| s.matches(&labels)) {
+            continue;
+        }
+        grouped
+            .entry(labels)
+            .or_default()
+            .push((sample.ts_ms, sample.value));
+    }
+    grouped
+        .into_iter()
+        .map(|(labels, mut points)| {
+            points.sort_by_key(|(ts, _)| *ts);
+            points.dedup_by_key(|(ts, _)| *ts);
+            Series { labels, points }
+        })
+        .collect()
+}
+
+enum Evaluated {
+    Scalar(f64),
+    Vector(Vec<(Labels, f64)>),
+}
+
+fn eval(expr: &Expr, ts: i64, set: &SeriesSet) -> Evaluated {
+    let series = &set.series;
+    match expr {
+        Expr::Number(value) => Evaluated::Scalar(*value),
+        Expr::Selector(selector) => Evaluated::Vector(
+            series
+                .iter()
+                .filter(|s| selector.matches(&s.labels))
+                .filter_map(|s| {
+                    let (_, value) = s.window(ts - set.lookback_ms, ts).last()?;
+                    Some((selector.output_labels(&s.labels, true), *value))
+                })
+                .collect(),
+        ),
+        Expr::Call {
+            func,
+            selector,
+            range_ms,
+        } => Evaluated::Vector(
+            series
+                .iter()
+                .filter(|s| selector.matches(&s.labels))
+                .filter_map(|s| {
+                    let points = s.window(ts - range_ms, ts);
+                    let value = match func {
+                        RangeFunction::Rate => extrapolated_delta(points, ts, *range_ms, true)?,
+                        RangeFunction::Increase => {
+                            extrapolated_delta(points, ts, *range_ms, false)?
+                        }
+                        RangeFunction::AvgOverTime => {
+                            if points.is_empty() {
+                                return None;
+                            }
+                            points.iter().map(|(_, v)| v).sum::<f64>() / points.len() as f64
+                        }
+                    };
+                    Some((selector.output_labels(&s.labels, false), value))
+                })
+                .collect(),
+        ),
+        Expr::Aggregate { op, grouping, expr } => {
+            let Evaluated::Vector(samples) = eval(expr, ts, set) else {
+                return Evaluated::Vector(Vec::new());
+            };
+            Evaluated::Vector(aggregate(*op, grouping, samples))
+        }
+        Expr::Binary { op, lhs, rhs } => match (eval(lhs, ts, set), eval(rhs, ts, set)) {
+            (Evaluated::Scalar(l), Evaluated::Scalar(r)) => Evaluated::Scalar(op.apply(l, r)),
+            (Evaluated::Vector(samples), Evaluated::Scalar(r)) => Evaluated::Vector(
+                samples
+                    .into_iter()
+                    .map(|(labels, l)| (without_name(&labels), op.apply(l, r)))
+                    .collect(),
+            ),
+            (Evaluated::Scalar(l), Evaluated::Vector(samples)) => Evaluated::Vector(
+                samples
+                    .into_iter()
+                    .map(|(labels, r)| (without_name(&labels), op.apply(l, r)))
+                    .collect(),
+            ),
+            // Rejected by the parser.
+            (Evaluated::Vector(_), Evaluated::Vector(_)) => Evaluated::Vector(Vec::new()),
+        },
+    }
+}
+
+fn without_name(labels: &Labels) -> Labels {
+    let mut labels = labels.clone();
+    labels.remove(NAME_LABEL);
+    labels
+}
+
+fn aggregate(
+    op: AggregateOp,
+    grouping: &[String],
+    samples: Vec<(Labels, f64)>,
+) -> Vec<(Labels, f64)> {
+    // (accumulator, count) per output label set.
+    let mut groups: BTreeMap<Labels, (f64, usize)> = BTreeMap::new();
+    for (labels, value) in samples {
+        let key: Labels = labels
+            .into_iter()
+            .filter(|(k, _)| grouping.contains(k))
+            .collect();
+        groups
+            .entry(key)
+            .and_modify(|(acc, count)| {
+                *acc = match op {
+                    AggregateOp::Sum | AggregateOp::Avg => *acc + value,
+                    AggregateOp::Max => acc.max(value),
+                    AggregateOp::Min => acc.min(value),
+                };
+                *count += 1;
+            })
+            .or_insert((value, 1));
+    }
+    groups
+        .into_iter()
+        .map(|(labels, (acc, count))| match op {
+            AggregateOp::Avg => (labels, acc / count as f64),
+            _ => (labels, acc),
+        })
+        .collect()
+}
+
+/// Prometheus' `extrapolatedRate`: the counter increase over the window,
+/// extrapolated towards the window edges and optionally divided by its length.
+fn extrapolated_delta(
+    points: &[(i64, f64)],
+    ts: i64,
+    range_ms: i64,
+    per_second: bool,
+) -> Option<f64> {
+    if points.len() < 2 {
+        return None;
+    }
+    let (first_ts, first_value) = points[0];
+    let (last_ts, last_value) = points[points.len() - 1];
+
+    let mut delta = last_value - first_value;
+    for pair in points.windows(2) {
+        // A drop means the counter was reset; count what it had reached.
+        if pair[1].1 < pair[0].1 {
+            delta += pair[0].1;
+        }
+    }
+
+    let range_start = ts - range_ms;
+    let sampled = (last_ts - first_ts) as f64 / 1000.0;
+    let avg_between = sampled / (points.len() - 1) as f64;
+    let mut to_start = (first_ts - range_start) as f64 / 1000.0;
+    let to_end = (ts - last_ts) as f64 / 1000.0;
+    // Counters cannot go below zero, so never extrapolate past that point.
+    if delta > 0.0 && first_value >= 0.0 {
+        to_start = to_start.min(sampled * (first_value / delta));
+    }
+
+    let threshold = avg_between * 1.1;
+    let mut interval = sampled;
+    interval += if to_start < threshold {
+        to_start
+    } else {
+        avg_between / 2.0
+    };
+    interval += if to_end < threshold {
+        to_end
+    } else {
+        avg_between / 2.0
+    };
+
+    let mut value = delta * (interval / sampled);
+    if per_second {
+        value /= range_ms as f64 / 1000.0;
+    }
+    Some(value)
+}
+
+/// Parses a query into an expression tree.
+pub fn parse(input: &str) -> Result<Expr, QueryError> {
+    let mut parser = Parser { src: input, pos: 0 };
+    let expr = parser.expr()?;
+    parser.skip_ws();
+    if parser.pos < input.len() {
+        return Err(parser.error("unexpected trailing input"));
+    }
+    Ok(expr)
+}
+
+/// Parses a plain series selector such as `esnode_gpu_power_watts{uuid="GPU-1"}`.
+/// At least one matcher must reject the empty string, so a selector can
+/// never match every series (the same rule Prometheus applies to `match[]`).
+pub fn parse_selector(input: &str) -> Result<Selector, QueryError> {
+    let Expr::Selector(selector) = parse(input)? else {
+        return Err(QueryError::BadData(format!(
+            "expected a series selector, got {input:?}"
+        )));
+    };
+    if selector.matchers.iter().all(|m| m.matches("")) {
+        return Err(QueryError::BadData(
+            "selector must contain at least one non-empty matcher".to_string(),
+        ));
+    }
+    Ok(selector)
+}
+
+/// Parses a Prometheus duration such as `30s`, `5m` or `1h30m` into milliseconds.
+pub fn parse_duration_ms(input: &str) -> Option<i64> {
+    let mut rest = input.trim();
+    if rest.is_empty() {
+        return None;
+    }
+    let mut total = 0i64;
+    while !rest.is_empty() {
+        let digits = rest
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(rest.len());
+        if digits == 0 {
+            return None;
+        }
+        let amount: i64 = rest[..digits].parse().ok()?;
+        rest = &rest[digits..];
+        let (unit_ms, unit_len) = [
+            ("ms", 1),
+            ("s", 1_000),
+            ("m", 60_000),
+            ("h", 3_600_000),
+            ("d", 86_400_000),
+            ("w", 604_800_000),
+            ("y", 31_536_000_000),
+        ]
+        .iter()
+        .find(|(unit, _)| rest.starts_with(unit))
+        .map(|(unit, ms)| (*ms, unit.len()))?;
+        total = total.checked_add(amount.checked_mul(unit_ms)?)?;
+        rest = &rest[unit_len..];
+    }
+    Some(total)
+}
+
+/// Parses an API timestamp: Unix seconds (fractional allowed) or RFC 3339.
+pub fn parse_time_ms(input: &str) -> Option<i64> {
+    if let Ok(secs) = input.parse::<f64>() {
+        return secs.is_finite().then(|| (secs * 1000.0).round() as i64);
+    }
+    chrono::DateTime::parse_from_rfc3339(input)
+        .ok()
+        .map(|dt| dt.timestamp_millis())
+}
+
+struct Parser<'a> {
+    src: &'a str,
+    pos: usize,
+}
+
+impl Parser<'_> {
+    fn error(&self, msg: impl Into<String>) -> QueryError {
+        QueryError::Parse {
+            pos: self.pos,
+            msg: msg.into(),
+        }
+    }
+
+    fn rest(&self) -> &str {
+        &self.src[self.pos..]
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.rest().chars().next()
+    }
+
+    fn skip_ws(&mut self) {
+        let trimmed = self.rest().trim_start();
+        self.pos = self.src.len() - trimmed.len();
+    }
+
+    fn eat(&mut self, token: &str) -> bool {
+        self.skip_ws();
+        if self.rest().starts_with(token) {
+            self.pos += token.len();
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expect(&mut self, token: &str) -> Result<(), QueryError> {
+        if self.eat(token) {
+            Ok(())
+        } else {
+            Err(self.error(format!("expected {token:?}")))
+        }
+    }
+
+    fn peek_ident(&mut self) -> Option<&str> {
+        self.skip_ws();
+        let rest = self.rest();
+        let mut end = 0;
+        for (i, c) in rest.char_indices() {
+            let ok =
+                c.is_ascii_alphabetic() || c == '_' || c == ':' || (i > 0 && c.is_ascii_digit());
+            if !ok {
+                break;
+            }
+            end = i + c.len_utf8();
+        }
+        (end > 0).then(|| &rest[..end])
+    }
+
+    fn ident(&mut self) -> Option<String> {
+        let ident = self.peek_ident()?.to_string();
+        self.pos += ident.len();
+        Some(ident)
+    }
+
+    fn eat_keyword(&mut self, keyword: &str) -> bool {
+        if self.peek_ident() == Some(keyword) {
+            self.pos += keyword.len();
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expr(&mut self) -> Result<Expr, QueryError> {
+        let mut lhs = self.term()?;
+        loop {
+            let op = if self.eat("+") {
+                BinaryOp::Add
+            } else if self.eat("-") {
+                BinaryOp::Sub
+            } else {
+                return Ok(lhs);
+            };
+            let rhs = self.term()?;
+            lhs = self.binary(op, lhs, rhs)?;
+        }
+    }
+
+    fn term(&mut self) -> Result<Expr, QueryError> {
+        let mut lhs = self.unary()?;
+        loop {
+            let op = if self.eat("*") {
+                BinaryOp::Mul
+            } else if self.eat("/") {
+                BinaryOp::Div
+            } else {
+                return Ok(lhs);
+            };
+            let rhs = self.unary()?;
+            lhs = self.binary(op, lhs, rhs)?;
+        }
+    }
+
+    fn binary(&self, op: BinaryOp, lhs: Expr, rhs: Expr) -> Result<Expr, QueryError> {
+        if !lhs.is_scalar() && !rhs.is_scalar() {
+            return Err(self.error("binary operations between two vectors are not supported"));
+        }
+        Ok(Expr::Binary {
+            op,
+            lhs: Box::new(lhs),
+            rhs: Box::new(rhs),
+        })
+    }
+
+    fn unary(&mut self) -> Result<Expr, QueryError> {
+        if self.eat("-") {
+            return Ok(match self.unary()? {
+                Expr::Number(value) => Expr::Number(-value),
+                expr => self.binary(BinaryOp::Mul, Expr::Number(-1.0), expr)?,
+            });
+        }
+        self.primary()
+    }
+
+    fn primary(&mut self) -> Result<Expr, QueryError> {
+        if self.eat("(") {
+            let expr = self.expr()?;
+            self.expect(")")?;
+            return Ok(expr);
+        }
+        match self.peek() {
+            Some(c) if c.is_ascii_digit() || c == '.' => return self.number(),
+            Some('{') => return Ok(Expr::Selector(self.selector(None)?)),
+            _ => {}
+        }
+        let start = self.pos;
+        let Some(ident) = self.ident() else {
+            return Err(self.error("expected expression"));
+        };
+        let aggregate = match ident.as_str() {
+            "sum" => Some(AggregateOp::Sum),
+            "avg" => Some(AggregateOp::Avg),
+            "max" => Some(AggregateOp::Max),
+            "min" => Some(AggregateOp::Min),
+            _ => None,
+        };
+        let function = match ident.as_str() {
+            "rate" => Some(RangeFunction::Rate),
+            "increase" => Some(RangeFunction::Increase),
+            "avg_over_time" => Some(RangeFunction::AvgOverTime),
+            _ => None,
+        };
+        let followed_by_call = {
+            self.skip_ws();
+            self.peek() == Some('(')
+        };
+        if let Some(op) = aggregate {
+            if followed_by_call || self.peek_ident() == Some("by") {
+                return self.aggregate(op);
+            }
+        }
+        if let Some(func) = function {
+            if followed_by_call {
+                return self.call(func);
+            }
+        }
+        if followed_by_call {
+            self.pos = start;
+            return Err(self.error(format!("unsupported function {ident:?}")));
+        }
+        Ok(Expr::Selector(self.selector(Some(ident))?))
+    }
+
+    fn number(&mut self) -> Result<Expr, QueryError> {
+        let rest = self.rest();
+        let mut end = 0;
+        let mut prev = ' ';
+        for (i, c) in rest.char_indices() {
+            let ok = c.is_ascii_digit()
+                || c == '.'
+                || c == 'e'
+                || c == 'E'
+                || ((c == '+' || c == '-') && (prev == 'e' || prev == 'E'));
+            if !ok {
+                break;
+            }
+            prev = c;
+            end = i + 1;
+        }
+        let value = rest[..end]
+            .parse::<f64>()
+            .map_err(|_| self.error(format!("invalid number {:?}", &rest[..end])))?;
+        self.pos += end;
+        Ok(Expr::Number(value))
+    }
+
+    fn aggregate(&mut self, op: AggregateOp) -> Result<Expr, QueryError> {
+        let mut grouping = if self.eat_keyword("by") {
+            Some(self.label_list()?)
+        } else {
+            None
+        };
+        self.expect("(")?;
+        let expr = self.expr()?;
+        self.expect(")")?;
+        if grouping.is_none() && self.eat_keyword("by") {
+            grouping = Some(self.label_list()?);
+        }
+        if expr.is_scalar() {
+            return Err(self.error("aggregations expect an instant vector argument"));
+        }
+        Ok(Expr::Aggregate {
+            op,
+            grouping: grouping.unwrap_or_default(),
+            expr: Box::new(expr),
+        })
+    }
+
+    fn label_list(&mut self) -> Result<Vec<String>, QueryError> {
+        self.expect("(")?;
+        let mut labels = Vec::new();
+        if self.eat(")") {
+            return Ok(labels);
+        }
+        loop {
+            let label = self
+                .ident()
+                .ok_or_else(|| self.error("expected label name"))?;
+            labels.push(label);
+            if self.eat(")") {
+                return Ok(labels);
+            }
+            self.expect(",")?;
+        }
+    }
+
+    fn call(&mut self, func: RangeFunction) -> Result<Expr, QueryError> {
+        self.expect("(")?;
+        self.skip_ws();
+        let selector = if self.peek() == Some('{') {
+            self.selector(None)?
+        } else {
+            let name = self
+                .ident()
+                .ok_or_else(|| self.error("expected range selector"))?;
+            self.selector(Some(name))?
+        };
+        self.expect("[")?;
+        let close = self
+            .rest()
+            .find(']')
+            .ok_or_else(|| self.error("unclosed range selector"))?;
+        let range_ms = parse_duration_ms(&self.rest()[..close])
+            .filter(|ms| *ms > 0)
+            .ok_or_else(|| self.error("invalid range duration"))?;
+        self.pos += close + 1;
+        self.expect(")")?;
+        Ok(Expr::Call {
+            func,
+            selector,
+            range_ms,
+        })
+    }
+
+    fn selector(&mut self, name: Option<String>) -> Result<Selector, QueryError> {
+        let mut matchers = Vec::new();
+        if let Some(name) = name {
+            matchers.push(
+                LabelMatcher::new(NAME_LABEL, MatchOp::Equal, name).map_err(|e| self.error(e))?,
+            );
+        }
+        if self.eat("{") {
+            while !self.eat("}") {
+                let label = self
+                    .ident()
+                    .ok_or_else(|| self.error("expected label name"))?;
+                let op = if self.eat("=~") {
+                    MatchOp::RegexMatch
+                } else if self.eat("!~") {
+                    MatchOp::RegexNoMatch
+                } else if self.eat("!=") {
+                    MatchOp::NotEqual
+                } else if self.eat("=") {
+                    MatchOp::Equal
+                } else {
+                    return Err(self.error("expected label matcher operator"));
+                };
+                let value = self.string()?;
+                matchers.push(LabelMatcher::new(&label, op, value).map_err(|e| self.error(e))?);
+                if !self.eat(",") {
+                    self.expect("}")?;
+                    break;
+                }
+            }
+        }
+        if matchers.iter().all(|m| m.matches("")) {
+            return Err(self.error("vector selector must contain at least one non-empty matcher"));
+        }
+        Ok(Selector { matchers })
+    }
+
+    fn string(&mut self) -> Result<String, QueryError> {
+        self.skip_ws();
+        let quote = match self.peek() {
+            Some(q @ ('"' | '\'' | '`')) => q,
+            _ => return Err(self.error("expected quoted string")),
+        };
+        let mut out = String::new();
+        let mut chars = self.rest().char_indices().skip(1);
+        while let Some((i, c)) = chars.next() {
+            if c == quote {
+                self.pos += i + 1;
+                return Ok(out);
+            }
+            if c == '\\' && quote != '`' {
+                let Some((_, escaped)) = chars.next() else {
+                    break;
+                };
+                out.push(match escaped {
+                    'n' => '\n',
+                    't' => '\t',
+                    'r' => '\r',
+                    other => other,
+                });
+            } else {
+                out.push(c);
+            }
+        }
+        Err(self.error("unterminated string"))
+    }
+}
@@ -0,0 +1,216 @@
+// ESNODE | Source Available BUSL-1.1 | Copyright (c) 2025 Estimatedstocks AB
+//! Startup recovery for blocks left behind by a crashed or killed agent.
+//!
+//! For every block directory this truncates torn trailing records in
+//! `chunks.bin` / `samples.jsonl`, replays `wal.log` samples that never made
+//! it into a chunk, and rebuilds `meta.json`/`index.json` when they are
+//! missing or stale.
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use tracing::{info, warn};
+
+use super::chunk::{decode_chunk, XorChunk};
+use super::format::{self, BlockData, ChunkEntry, SeriesEntry};
+use super::wal::{self, WAL_FILE};
+use super::{labels_hash, BlockMeta, Sample, SAMPLES_PER_CHUNK};
+
+/// Recovers every block directory under `root`.
+pub(crate) fn recover(root: &Path) -> Result<()> {
+    let entries = fs::read_dir(root).with_context(|| format!("reading {}", root.display()))?;
+    for entry in entries.flatten() {
+        let dir = entry.path();
+        let Some((start_ms, end_ms)) = dir
+            .file_name()
+            .and_then(|n| n.to_str())
+            .and_then(super::parse_block_name)
+        else {
+            continue;
+        };
+        if !dir.is_dir() {
+            continue;
+        }
+        if let Err(err) = recover_block(&dir, start_ms, end_ms) {
+            warn!("TSDB recovery failed for {}: {err:?}", dir.display());
+        }
+    }
+    Ok(())
+}
+
+fn recover_block(dir: &Path, start_ms: i64, end_ms: i64) -> Result<()> {
+    let mut data = BlockData::default();
+    let mut changed = false;
+
+    let chunks_path = dir.join(format::CHUNKS_FILE);
+    if let Ok(bytes) = fs::read(&chunks_path) {
+        let valid = format::decode_chunks_file(&bytes, &mut data)
+            .with_context(|| format!("decoding {}", chunks_path.display()))?;
+        if valid < bytes.len() {
+            warn!(
+                "truncating {} torn bytes from {}",
+                bytes.len() - valid,
+                chunks_path.display()
+            );
+            truncate(&chunks_path, valid)?;
+            changed = true;
+        }
+    }
+
+    let legacy_path = dir.join(format::LEGACY_SAMPLES_FILE);
+    if let Ok(bytes) = fs::read(&legacy_path) {
+        let valid = decode_legacy(&bytes, &mut data.legacy);
+        if valid < bytes.len() {
+            warn!("truncating torn trailing line in {}", legacy_path.display());
+            truncate(&legacy_path, valid)?;
+            changed = true;
+        }
+    }
+
+    let wal_path = dir.join(WAL_FILE);
+    if let Ok(bytes) = fs::read(&wal_path) {
+        let replayed = replay_wal(&bytes, &chunks_path, &mut data)?;
+        if replayed > 0 {
+            info!("replayed {replayed} samples from {}", wal_path.display());
+            changed = true;
+        }
+        fs::remove_file(&wal_path)
+            .with_context(|| format!("removing {}", wal_path.display()))?;
+    }
+
+    let index_missing = !dir.join("index.json").exists() || !dir.join("meta.json").exists();
+    if changed || index_missing {
+        let meta = rebuild_meta(&data, start_ms, end_ms);
+        let bytes = serde_json::to_vec_pretty(&meta)?;
+        fs::write(dir.join("meta.json"), &bytes)?;
+        fs::write(dir.join("index.json"), &bytes)?;
+    }
+    Ok(())
+}
+
+/// Parses legacy JSON lines into `out` and returns the length of the prefix
+/// that ends in a complete line (or a final line that still parses).
+fn decode_legacy(bytes: &[u8], out: &mut Vec<Sample>) -> usize {
+    let mut valid = 0;
+    let mut pos = 0;
+    while pos < bytes.len() {
+        let (line, next) = match bytes[pos..].iter().position(|b| *b == b'\n') {
+            Some(i) => (&bytes[pos..pos + i], pos + i + 1),
+            None => (&bytes[pos..], bytes.len()),
+        };
+        let parsed = serde_json::from_slice::<Sample>(line);
+        let complete = next < bytes.len() || bytes.last() == Some(&b'\n');
+        match parsed {
+            Ok(sample) => out.push(sample),
+            Err(_) if !complete => break,
+            Err(_) => {}
+        }
+        valid = next;
+        pos = next;
+    }
+    valid
+}
+
+/// Appends WAL samples missing from `chunks.bin` as new chunks and returns
+/// how many were replayed.
+fn replay_wal(bytes: &[u8], chunks_path: &Path, data: &mut BlockData) -> Result<usize> {
+    let mut persisted: HashSet<(u64, i64)> = HashSet::new();
+    for chunk in &data.chunks {
+        for (ts, _) in decode_chunk(&chunk.payload, chunk.count) {
+            persisted.insert((chunk.series_ref, ts));
+        }
+    }
+
+    let mut pending: BTreeMap<u64, (SeriesEntry, Vec<(i64, f64)>)> = BTreeMap::new();
+    let mut replayed = 0;
+    for (series_ref, sample) in wal::decode_wal(bytes) {
+        if !persisted.insert((series_ref, sample.ts_ms)) {
+            continue;
+        }
+        pending
+            .entry(series_ref)
+            .or_insert_with(|| {
+                (
+                    SeriesEntry {
+                        metric: sample.metric.clone(),
+                        labels: sample.labels.clone(),
+                    },
+                    Vec::new(),
+                )
+            })
+            .1
+            .push((sample.ts_ms, sample.value));
+        replayed += 1;
+    }
+    if replayed == 0 {
+        return Ok(0);
+    }
+
+    let mut buf = Vec::new();
+    let is_new = fs::metadata(chunks_path).map(|m| m.len() == 0).unwrap_or(true);
+    if is_new {
+        buf.extend_from_slice(format::MAGIC);
+    }
+    for (series_ref, (series, mut points)) in pending {
+        points.sort_by_key(|(ts, _)| *ts);
+        format::encode_series_record(&mut buf, series_ref, &series.metric, &series.labels);
+        for batch in points.chunks(SAMPLES_PER_CHUNK) {
+            let mut chunk = XorChunk::default();
+            for (ts, value) in batch {
+                chunk.append(*ts, *value);
+            }
+            format::encode_chunk_record(&mut buf, series_ref, &chunk);
+            data.chunks.push(ChunkEntry {
+                series_ref,
+                count: chunk.len(),
+                min_ts: chunk.min_ts(),
+                max_ts: chunk.max_ts(),
+                payload: chunk.bytes().to_vec(),
+            });
+        }
+        data.series.entry(series_ref).or_insert(series);
+    }
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(chunks_path)
+        .with_context(|| format!("opening {}", chunks_path.display()))?;
+    file.write_all(&buf)?;
+    file.sync_all()?;
+    Ok(replayed)
+}
+
+fn rebuild_meta(data: &BlockData, start_ms: i64, end_ms: i64) -> BlockMeta {
+    let mut meta = BlockMeta {
+        start_ms,
+        end_ms,
+        ..Default::default()
+    };
+    let mut record = |metric: &str, labels: &HashMap<String, String>, count: u64| {
+        meta.samples += count;
+        *meta.metric_counts.entry(metric.to_string()).or_default() += count;
+        *meta.label_hash_counts.entry(labels_hash(labels)).or_default() += count;
+    };
+    for chunk in &data.chunks {
+        if let Some(series) = data.series.get(&chunk.series_ref) {
+            record(&series.metric, &series.labels, chunk.count as u64);
+        }
+    }
+    for sample in &data.legacy {
+        record(&sample.metric, &sample.labels, 1);
+    }
+    meta
+}
+
+fn truncate(path: &Path, len: usize) -> Result<()> {
+    let file = OpenOptions::new()
+        .write(true)
+        .open(path)
+        .with_context(|| format!("opening {}", path.display()))?;
+    file.set_len(len as u64)?;
+    file.sync_all()?;
+    Ok(())
+}
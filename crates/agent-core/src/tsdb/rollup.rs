@@ -0,0 +1,456 @@
+// ESNODE | Source Available BUSL-1.1 | Copyright (c) 2025 Estimatedstocks AB
+//! Downsampled rollup tiers.
+//!
+//! Each tier lives in `rollup-<resolution>/` under the TSDB root and holds 2h
+//! blocks in the regular block format, named like the block they were built
+//! from. Every source series becomes four series distinguished by a
+//! `__rollup__` label (`min`, `max`, `avg`, `count`) with one point per
+//! window, stamped with the window start. The first tier is compacted from
+//! closed raw blocks and every further tier from the tier before it, so raw
+//! data is read once. Reads pick the finest tier that reaches back to the
+//! start of the requested range and fill the rest from finer tiers.
+
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use anyhow::{bail, Context, Result};
+use tokio::fs;
+use tracing::{debug, info};
+
+use super::chunk::XorChunk;
+use super::{format, labels_hash, series_ref, BlockMeta, LocalTsdb, Sample, SAMPLES_PER_CHUNK};
+use super::{LocalTsdbRollupTier, BLOCK_DURATION};
+
+/// Label carrying the aggregate of a rollup series.
+pub(crate) const ROLLUP_LABEL: &str = "__rollup__";
+const AVG: &str = "avg";
+
+/// Directory name of a tier, e.g. `rollup-1m` or `rollup-15m`.
+pub(crate) fn tier_dir_name(resolution: Duration) -> String {
+    let secs = resolution.as_secs();
+    if secs.is_multiple_of(3600) {
+        format!("rollup-{}h", secs / 3600)
+    } else if secs.is_multiple_of(60) {
+        format!("rollup-{}m", secs / 60)
+    } else {
+        format!("rollup-{secs}s")
+    }
+}
+
+/// Rejects tiers that cannot be built from whole blocks of the tier before.
+pub(crate) fn validate_tiers(tiers: &[LocalTsdbRollupTier]) -> Result<()> {
+    let block_ms = BLOCK_DURATION.as_millis();
+    let mut previous_ms = 0;
+    for tier in tiers {
+        let resolution_ms = tier.resolution.as_millis();
+        if resolution_ms == 0 || !block_ms.is_multiple_of(resolution_ms) {
+            bail!(
+                "rollup resolution {:?} must evenly divide the {:?} block duration",
+                tier.resolution,
+                BLOCK_DURATION
+            );
+        }
+        if resolution_ms <= previous_ms {
+            bail!("rollup tiers must be listed finest first with increasing resolution");
+        }
+        previous_ms = resolution_ms;
+    }
+    Ok(())
+}
+
+/// Keeps raw samples and the `avg` of rollup samples, without the rollup label.
+/// This is what export and backfill consumers see for downsampled ranges.
+pub(crate) fn averages_only(samples: Vec<Sample>) -> Vec<Sample> {
+    samples
+        .into_iter()
+        .filter_map(|mut sample| match sample.labels.remove(ROLLUP_LABEL) {
+            None => Some(sample),
+            Some(agg) if agg == AVG => Some(sample),
+            Some(_) => None,
+        })
+        .collect()
+}
+
+/// Aggregates of one source point, which is either a raw sample or the four
+/// rollup series of a finer tier at the same timestamp.
+#[derive(Default)]
+struct Partial {
+    min: Option<f64>,
+    max: Option<f64>,
+    avg: Option<f64>,
+    count: Option<f64>,
+}
+
+#[derive(Clone, Copy)]
+struct Accumulator {
+    min: f64,
+    max: f64,
+    sum: f64,
+    count: f64,
+}
+
+impl Accumulator {
+    fn merge(&mut self, other: Accumulator) {
+        self.min = self.min.min(other.min);
+        self.max = self.max.max(other.max);
+        self.sum += other.sum;
+        self.count += other.count;
+    }
+}
+
+type SeriesKey = (String, BTreeMap<String, String>);
+type Points = Vec<(i64, f64)>;
+
+/// Rolls `samples` (raw or from a finer tier) up into `resolution_ms` windows.
+pub(crate) fn downsample(samples: Vec<Sample>, resolution_ms: i64) -> Vec<Sample> {
+    let mut partials: BTreeMap<(SeriesKey, i64), Partial> = BTreeMap::new();
+    for mut sample in samples {
+        let aggregate = sample.labels.remove(ROLLUP_LABEL);
+        let key = (sample.metric, sample.labels.into_iter().collect());
+        let partial = partials.entry((key, sample.ts_ms)).or_default();
+        let v = Some(sample.value);
+        match aggregate.as_deref() {
+            None => {
+                *partial = Partial {
+                    min: v,
+                    max: v,
+                    avg: v,
+                    count: Some(1.0),
+                }
+            }
+            Some("min") => partial.min = v,
+            Some("max") => partial.max = v,
+            Some(AVG) => partial.avg = v,
+            Some("count") => partial.count = v,
+            Some(_) => {}
+        }
+    }
+
+    let mut windows: BTreeMap<(SeriesKey, i64), Accumulator> = BTreeMap::new();
+    for ((key, ts_ms), partial) in partials {
+        let Some(avg) = partial.avg else {
+            continue;
+        };
+        let count = partial.count.unwrap_or(1.0);
+        let acc = Accumulator {
+            min: partial.min.unwrap_or(avg),
+            max: partial.max.unwrap_or(avg),
+            sum: avg * count,
+            count,
+        };
+        let window = ts_ms - ts_ms.rem_euclid(resolution_ms);
+        windows
+            .entry((key, window))
+            .and_modify(|existing| existing.merge(acc))
+            .or_insert(acc);
+    }
+
+    let mut out = Vec::with_capacity(windows.len() * 4);
+    for (((metric, labels), ts_ms), acc) in windows {
+        for (aggregate, value) in [
+            ("min", acc.min),
+            ("max", acc.max),
+            (AVG, acc.sum / acc.count),
+            ("count", acc.count),
+        ] {
+            let mut labels: HashMap<String, String> = labels.clone().into_iter().collect();
+            labels.insert(ROLLUP_LABEL.to_string(), aggregate.to_string());
+            out.push(Sample {
+                metric: metric.clone(),
+                labels,
+                ts_ms,
+                value,
+            });
+        }
+    }
+    out
+}
+
+/// Writes a complete block in one go. The block is assembled in a temporary
+/// directory and renamed into place, so an existing block dir is always whole.
+pub(crate) async fn write_block(
+    dir: &Path,
+    start_ms: i64,
+    end_ms: i64,
+    samples: &[Sample],
+) -> Result<()> {
+    let mut meta = BlockMeta {
+        start_ms,
+        end_ms,
+        ..Default::default()
+    };
+    // Series ref -> (first sample, for its labels; points).
+    let mut series: BTreeMap<u64, (&Sample, Points)> = BTreeMap::new();
+    for sample in samples {
+        series
+            .entry(series_ref(&sample.metric, &sample.labels))
+            .or_insert_with(|| (sample, Vec::new()))
+            .1
+            .push((sample.ts_ms, sample.value));
+        meta.samples += 1;
+        *meta.metric_counts.entry(sample.metric.clone()).or_default() += 1;
+        *meta
+            .label_hash_counts
+            .entry(labels_hash(&sample.labels))
+            .or_default() += 1;
+    }
+
+    let mut buf = format::MAGIC.to_vec();
+    for (series_ref, (first, mut points)) in series {
+        points.sort_by_key(|(ts, _)| *ts);
+        format::encode_series_record(&mut buf, series_ref, &first.metric, &first.labels);
+        for batch in points.chunks(SAMPLES_PER_CHUNK) {
+            let mut chunk = XorChunk::default();
+            for (ts, value) in batch {
+                chunk.append(*ts, *value);
+            }
+            format::encode_chunk_record(&mut buf, series_ref, &chunk);
+        }
+    }
+
+    let tmp = dir.with_extension("tmp");
+    let _ = fs::remove_dir_all(&tmp).await;
+    fs::create_dir_all(&tmp)
+        .await
+        .with_context(|| format!("creating {}", tmp.display()))?;
+    fs::write(tmp.join(format::CHUNKS_FILE), &buf).await?;
+    let meta_bytes = serde_json::to_vec_pretty(&meta)?;
+    fs::write(tmp.join("meta.json"), &meta_bytes).await?;
+    fs::write(tmp.join("index.json"), &meta_bytes).await?;
+    fs::rename(&tmp, dir)
+        .await
+        .with_context(|| format!("publishing rollup block {}", dir.display()))?;
+    Ok(())
+}
+
+/// One stretch of a read served by a single tier (`None` tier = raw).
+#[derive(Debug, PartialEq)]
+pub(crate) struct TierSegment {
+    pub tier: Option<usize>,
+    pub from_ms: Option<i64>,
+    pub to_ms: Option<i64>,
+}
+
+/// Chooses tiers for a read starting at `from_ms`. `starts[0]` is the oldest
+/// raw block start and `starts[i + 1]` that of rollup tier `i` (`None` when
+/// empty). The finest tier reaching back to `from_ms` serves the start of the
+/// range and each finer tier takes over from where its own data begins.
+pub(crate) fn plan_segments(
+    starts: &[Option<i64>],
+    from_ms: Option<i64>,
+    to_ms: Option<i64>,
+) -> Vec<TierSegment> {
+    let covering = starts
+        .iter()
+        .position(|start| matches!((start, from_ms), (Some(s), Some(f)) if *s <= f));
+    // Nothing reaches back far enough (or the range is unbounded): start with
+    // whichever tier has the oldest data, preferring finer tiers on ties.
+    let first = covering.or_else(|| {
+        starts
+            .iter()
+            .enumerate()
+            .filter_map(|(i, s)| s.map(|s| (s, i)))
+            .min()
+            .map(|(_, i)| i)
+    });
+    let Some(first) = first else {
+        return vec![TierSegment {
+            tier: None,
+            from_ms,
+            to_ms,
+        }];
+    };
+
+    let mut segments = Vec::new();
+    let mut cursor = from_ms;
+    for idx in (0..=first).rev() {
+        if idx != first && starts[idx].is_none() {
+            continue;
+        }
+        let next_start = starts[..idx].iter().rev().flatten().next().copied();
+        let seg_to = match next_start {
+            Some(next) => Some(to_ms.map_or(next - 1, |t| t.min(next - 1))),
+            None => to_ms,
+        };
+        let empty = matches!((cursor, seg_to), (Some(f), Some(t)) if t < f);
+        if !empty {
+            segments.push(TierSegment {
+                tier: idx.checked_sub(1),
+                from_ms: cursor,
+                to_ms: seg_to,
+            });
+        }
+        if let Some(next) = next_start {
+            cursor = Some(cursor.map_or(next, |c| c.max(next)));
+        }
+    }
+    segments
+}
+
+impl LocalTsdb {
+    pub fn has_rollups(&self) -> bool {
+        !self.config.rollups.is_empty()
+    }
+
+    pub(crate) fn tier_path(&self, tier: usize) -> PathBuf {
+        self.config
+            .path
+            .join(tier_dir_name(self.config.rollups[tier].resolution))
+    }
+
+    /// Builds rollup blocks for every closed source block that does not have
+    /// one yet and returns how many were written.
+    pub async fn compact(&self, now_ms: i64) -> Result<usize> {
+        let open_start = self.current.lock().await.as_ref().map(|w| w.meta.start_ms);
+        let mut written = 0;
+        for tier in 0..self.config.rollups.len() {
+            let source_root = match tier {
+                0 => self.config.path.clone(),
+                _ => self.tier_path(tier - 1),
+            };
+            let target_root = self.tier_path(tier);
+            let rollup = &self.config.rollups[tier];
+            let resolution_ms = rollup.resolution.as_millis() as i64;
+            let horizon_ms = now_ms - rollup.retention.as_millis() as i64;
+
+            let mut sources = self.list_blocks_in(&source_root).await?;
+            sources.sort_by_key(|b| b.start_ms);
+            for src in sources {
+                if src.end_ms > now_ms
+                    || Some(src.start_ms) == open_start
+                    || src.end_ms < horizon_ms
+                {
+                    continue;
+                }
+                let Some(name) = src.dir.file_name() else {
+                    continue;
+                };
+                let target = target_root.join(name);
+                if fs::try_exists(&target).await.unwrap_or(false) {
+                    continue;
+                }
+                fs::create_dir_all(&target_root)
+                    .await
+                    .with_context(|| format!("creating {}", target_root.display()))?;
+                let data = format::read_block(&src.dir).await?;
+                let mut samples = data.samples(None, None, |_| true);
+                self.tombstones().retain_live(&mut samples);
+                let rolled = downsample(samples, resolution_ms);
+                write_block(&target, src.start_ms, src.end_ms, &rolled).await?;
+                debug!(
+                    "compacted {} into {} ({} points)",
+                    src.dir.display(),
+                    target.display(),
+                    rolled.len()
+                );
+                written += 1;
+            }
+        }
+        if written > 0 {
+            info!("TSDB compaction wrote {written} rollup blocks");
+        }
+        Ok(written)
+    }
+
+    pub fn spawn_compactor(
+        self: std::sync::Arc<Self>,
+        interval: Duration,
+    ) -> tokio::task::JoinHandle<()> {
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            loop {
+                ticker.tick().await;
+                let now_ms = chrono::Utc::now().timestamp_millis();
+                if let Err(err) = self.compact(now_ms).await {
+                    tracing::warn!("TSDB compaction failed: {err:?}");
+                }
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn seg(tier: Option<usize>, from_ms: Option<i64>, to_ms: Option<i64>) -> TierSegment {
+        TierSegment {
+            tier,
+            from_ms,
+            to_ms,
+        }
+    }
+
+    #[test]
+    fn plan_prefers_finest_covering_tier() {
+        // raw from 600, 1m tier from 100, 15m tier from 0.
+        let starts = [Some(600), Some(100), Some(0)];
+        assert_eq!(
+            plan_segments(&starts, Some(700), Some(900)),
+            vec![seg(None, Some(700), Some(900))]
+        );
+        assert_eq!(
+            plan_segments(&starts, Some(200), Some(900)),
+            vec![
+                seg(Some(0), Some(200), Some(599)),
+                seg(None, Some(600), Some(900))
+            ]
+        );
+        assert_eq!(
+            plan_segments(&starts, None, None),
+            vec![
+                seg(Some(1), None, Some(99)),
+                seg(Some(0), Some(100), Some(599)),
+                seg(None, Some(600), None),
+            ]
+        );
+        // A range entirely inside an old tier never touches finer tiers.
+        assert_eq!(
+            plan_segments(&starts, Some(10), Some(50)),
+            vec![seg(Some(1), Some(10), Some(50))]
+        );
+    }
+
+    #[test]
+    fn plan_skips_empty_tiers() {
+        assert_eq!(
+            plan_segments(&[None, None], Some(5), None),
+            vec![seg(None, Some(5), None)]
+        );
+        assert_eq!(
+            plan_segments(&[Some(600), None, Some(0)], Some(10), None),
+            vec![
+                seg(Some(1), Some(10), Some(599)),
+                seg(None, Some(600), None)
+            ]
+        );
+    }
+
+    #[test]
+    fn downsample_merges_finer_rollups_by_count() {
+        let raw: Vec<Sample> = [(0, 1.0), (20_000, 3.0), (40_000, 5.0), (60_000, 10.0)]
+            .into_iter()
+            .map(|(ts_ms, value)| Sample {
+                metric: "m".to_string(),
+                labels: HashMap::new(),
+                ts_ms,
+                value,
+            })
+            .collect();
+        let minute = downsample(raw, 60_000);
+        assert_eq!(minute.len(), 8);
+        let fifteen = downsample(minute, 900_000);
+        let get = |agg: &str| {
+            fifteen
+                .iter()
+                .find(|s| s.labels[ROLLUP_LABEL] == agg)
+                .map(|s| (s.ts_ms, s.value))
+                .unwrap()
+        };
+        assert_eq!(get("min"), (0, 1.0));
+        assert_eq!(get("max"), (0, 10.0));
+        assert_eq!(get("count"), (0, 4.0));
+        assert_eq!(get("avg"), (0, 4.75));
+    }
+}
@@ -0,0 +1,172 @@
+// ESNODE | Source Available BUSL-1.1 | Copyright (c) 2025 Estimatedstocks AB
+//! Incremental reads for export.
+//!
+//! A [`SampleScan`] plans the blocks of a read up front, like
+//! `read_samples`, but decodes one block at a time and materialises it in
+//! [`SCAN_WINDOW`] slices, so memory stays bounded by a slice instead of the
+//! whole range. Samples come out ordered by timestamp and then series ref,
+//! which is stable across requests and is what an [`ExportCursor`] points at.
+
+use std::collections::VecDeque;
+use std::fmt;
+use std::str::FromStr;
+use std::time::Duration;
+
+use anyhow::Result;
+
+use super::admin::Tombstones;
+use super::format::BlockData;
+use super::{matches_metric, rollup, series_ref, BlockRead, LocalTsdb, Sample};
+
+/// Time span materialised per batch.
+const SCAN_WINDOW: Duration = Duration::from_secs(10 * 60);
+
+/// Position just after a sample in scan order, handed to clients as an
+/// opaque `next` token.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ExportCursor {
+    ts_ms: i64,
+    series_ref: u64,
+}
+
+impl ExportCursor {
+    /// Cursor resuming after `sample`.
+    pub fn after(sample: &Sample) -> Self {
+        Self {
+            ts_ms: sample.ts_ms,
+            series_ref: series_ref(&sample.metric, &sample.labels),
+        }
+    }
+}
+
+impl fmt::Display for ExportCursor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:016x}{:016x}", self.ts_ms as u64, self.series_ref)
+    }
+}
+
+impl FromStr for ExportCursor {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || "invalid next token".to_string();
+        if s.len() != 32 || !s.is_ascii() {
+            return Err(invalid());
+        }
+        let (ts, series) = s.split_at(16);
+        Ok(Self {
+            ts_ms: u64::from_str_radix(ts, 16).map_err(|_| invalid())? as i64,
+            series_ref: u64::from_str_radix(series, 16).map_err(|_| invalid())?,
+        })
+    }
+}
+
+/// Lazily reads the samples of a range, batch by batch, oldest first.
+pub struct SampleScan {
+    reads: VecDeque<BlockRead>,
+    /// Decoded block being sliced and the start of its next window.
+    current: Option<(BlockRead, BlockData, i64)>,
+    metrics: Option<Vec<String>>,
+    after: Option<ExportCursor>,
+    tombstones: std::sync::Arc<Tombstones>,
+    /// Samples of the last window not yet handed out by [`Self::take`].
+    pending: VecDeque<Sample>,
+}
+
+impl SampleScan {
+    /// Next non-empty batch of samples, or `None` once the range is exhausted.
+    pub async fn next_batch(&mut self) -> Result<Option<Vec<Sample>>> {
+        if !self.pending.is_empty() {
+            return Ok(Some(self.pending.drain(..).collect()));
+        }
+        loop {
+            let Some((read, data, window_start)) = self.current.take() else {
+                let Some(read) = self.reads.pop_front() else {
+                    return Ok(None);
+                };
+                if let Some(data) = read.load().await {
+                    let start = read.from_ms.map_or(read.start_ms, |f| f.max(read.start_ms));
+                    let start = self.after.map_or(start, |c| start.max(c.ts_ms));
+                    self.current = Some((read, data, start));
+                }
+                continue;
+            };
+            let last = read
+                .to_ms
+                .map_or(read.end_ms - 1, |t| t.min(read.end_ms - 1));
+            if window_start > last {
+                continue;
+            }
+            let window_end = (window_start + SCAN_WINDOW.as_millis() as i64 - 1).min(last);
+            let mut samples = data.samples(Some(window_start), Some(window_end), |metric| {
+                self.metrics
+                    .as_deref()
+                    .is_none_or(|filters| matches_metric(metric, filters))
+            });
+            self.tombstones.retain_live(&mut samples);
+            self.current = Some((read, data, window_end + 1));
+
+            let mut batch: Vec<(ExportCursor, Sample)> = rollup::averages_only(samples)
+                .into_iter()
+                .map(|s| (ExportCursor::after(&s), s))
+                .filter(|(key, _)| self.after.is_none_or(|after| *key > after))
+                .collect();
+            if batch.is_empty() {
+                continue;
+            }
+            batch.sort_by_key(|(key, _)| *key);
+            return Ok(Some(batch.into_iter().map(|(_, s)| s).collect()));
+        }
+    }
+
+    /// Up to `limit` samples in scan order; the rest stay queued.
+    pub async fn take(&mut self, limit: usize) -> Result<Vec<Sample>> {
+        let mut out = Vec::new();
+        while out.len() < limit {
+            let Some(batch) = self.next_batch().await? else {
+                break;
+            };
+            self.pending.extend(batch);
+            let n = (limit - out.len()).min(self.pending.len());
+            out.extend(self.pending.drain(..n));
+        }
+        Ok(out)
+    }
+
+    /// Whether any sample is left, reading ahead if needed.
+    pub async fn is_exhausted(&mut self) -> Result<bool> {
+        if self.pending.is_empty() {
+            if let Some(batch) = self.next_batch().await? {
+                self.pending.extend(batch);
+            }
+        }
+        Ok(self.pending.is_empty())
+    }
+}
+
+impl LocalTsdb {
+    /// Starts a scan over `[from_ms, to_ms]` with the export `metrics`
+    /// filters, resuming after `after` when given. Rollup tiers are read
+    /// as averages, as in `export_lines`.
+    pub async fn scan(
+        &self,
+        from_ms: Option<i64>,
+        to_ms: Option<i64>,
+        metrics: Option<Vec<String>>,
+        after: Option<ExportCursor>,
+    ) -> Result<SampleScan> {
+        let from_ms = match (from_ms, after) {
+            (Some(f), Some(c)) => Some(f.max(c.ts_ms)),
+            (f, c) => f.or(c.map(|c| c.ts_ms)),
+        };
+        let (reads, _) = self.plan_reads(from_ms, to_ms, metrics.as_deref()).await?;
+        Ok(SampleScan {
+            reads: reads.into(),
+            current: None,
+            metrics,
+            after,
+            tombstones: self.tombstones(),
+            pending: VecDeque::new(),
+        })
+    }
+}
@@ -0,0 +1,165 @@
+// ESNODE | Source Available BUSL-1.1 | Copyright (c) 2025 Estimatedstocks AB
+//! Write-ahead log for the open block.
+//!
+//! `wal.log` holds every sample written to the open block that is not yet
+//! guaranteed to be in `chunks.bin`. It uses the same series record as the
+//! block file plus a fixed-size sample record:
+//! - `0x03` sample: `ref u64 | ts_ms i64 | value f64`
+//!
+//! The log is fsync'd on a configurable cadence, truncated at every
+//! checkpoint (open chunks cut and `chunks.bin` synced) and removed when the
+//! block is finished. Whatever survives a crash is replayed by recovery; a
+//! log recovery could not replay is moved aside as `wal.log.corrupt-<ms>`
+//! rather than overwritten.
+
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use anyhow::{Context, Result};
+use tokio::fs::{self, File, OpenOptions};
+use tokio::io::{AsyncWriteExt, BufWriter};
+use tracing::warn;
+
+use super::format::{self, Cursor, SeriesEntry};
+use super::Sample;
+
+pub(crate) const WAL_FILE: &str = "wal.log";
+const WAL_MAGIC: &[u8; 8] = b"ESWAL\x00\x00\x01";
+const RECORD_SAMPLE: u8 = 0x03;
+
+#[derive(Debug)]
+pub(crate) struct Wal {
+    writer: BufWriter<File>,
+    /// Series refs already defined since the last checkpoint.
+    series: HashSet<u64>,
+    sync_interval: Duration,
+    last_sync: Instant,
+    dirty: bool,
+}
+
+impl Wal {
+    pub(crate) async fn open(dir: &Path, sync_interval: Duration) -> Result<Self> {
+        let path = dir.join(WAL_FILE);
+        // Recovery replays and removes any previous log before a writer opens
+        // the block, so a non-empty file here means that failed. Its samples
+        // may be the only copy, so keep it for inspection.
+        if fs::metadata(&path).await.is_ok_and(|m| m.len() > 0) {
+            let now_ms = chrono::Utc::now().timestamp_millis();
+            let aside = dir.join(format!("{WAL_FILE}.corrupt-{now_ms}"));
+            fs::rename(&path, &aside)
+                .await
+                .with_context(|| format!("moving aside WAL {}", path.display()))?;
+            warn!(
+                "moved unrecovered WAL {} aside to {}",
+                path.display(),
+                aside.display()
+            );
+        }
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .await
+            .with_context(|| format!("opening WAL {}", path.display()))?;
+        let mut writer = BufWriter::new(file);
+        writer.write_all(WAL_MAGIC).await?;
+        Ok(Self {
+            writer,
+            series: HashSet::new(),
+            sync_interval,
+            last_sync: Instant::now(),
+            dirty: true,
+        })
+    }
+
+    pub(crate) async fn append(&mut self, series_ref: u64, sample: &Sample) -> Result<()> {
+        let mut buf = Vec::with_capacity(25);
+        if self.series.insert(series_ref) {
+            format::encode_series_record(&mut buf, series_ref, &sample.metric, &sample.labels);
+        }
+        buf.push(RECORD_SAMPLE);
+        buf.extend_from_slice(&series_ref.to_le_bytes());
+        buf.extend_from_slice(&sample.ts_ms.to_le_bytes());
+        buf.extend_from_slice(&sample.value.to_bits().to_le_bytes());
+        self.writer
+            .write_all(&buf)
+            .await
+            .context("appending to WAL")?;
+        self.dirty = true;
+        Ok(())
+    }
+
+    /// Flushes and fsyncs the log if the configured cadence has elapsed.
+    pub(crate) async fn sync_if_due(&mut self) -> Result<()> {
+        if self.dirty && self.last_sync.elapsed() >= self.sync_interval {
+            self.sync().await?;
+        }
+        Ok(())
+    }
+
+    pub(crate) async fn sync(&mut self) -> Result<()> {
+        self.writer.flush().await?;
+        self.writer.get_ref().sync_data().await.context("fsync WAL")?;
+        self.last_sync = Instant::now();
+        self.dirty = false;
+        Ok(())
+    }
+
+    /// Drops every record; callers must have synced `chunks.bin` first.
+    pub(crate) async fn checkpoint(&mut self) -> Result<()> {
+        self.writer.flush().await?;
+        self.writer.get_ref().set_len(0).await?;
+        self.writer.write_all(WAL_MAGIC).await?;
+        self.series.clear();
+        self.sync().await
+    }
+}
+
+/// Decodes the valid prefix of a WAL image into samples.
+pub(crate) fn decode_wal(bytes: &[u8]) -> Vec<(u64, Sample)> {
+    let mut out = Vec::new();
+    let Ok(mut cur) = Cursor::after_magic(bytes, WAL_MAGIC) else {
+        return out;
+    };
+    let mut series: HashMap<u64, SeriesEntry> = HashMap::new();
+    while !cur.is_empty() {
+        let Some(record) = decode_wal_record(&mut cur, &mut series) else {
+            break;
+        };
+        if let Some(sample) = record {
+            out.push(sample);
+        }
+    }
+    out
+}
+
+fn decode_wal_record(
+    cur: &mut Cursor<'_>,
+    series: &mut HashMap<u64, SeriesEntry>,
+) -> Option<Option<(u64, Sample)>> {
+    match cur.u8()? {
+        format::RECORD_SERIES => {
+            let (series_ref, entry) = format::decode_series_record(cur)?;
+            series.insert(series_ref, entry);
+            Some(None)
+        }
+        RECORD_SAMPLE => {
+            let series_ref = cur.u64()?;
+            let ts_ms = cur.u64()? as i64;
+            let value = f64::from_bits(cur.u64()?);
+            // A sample whose series record was lost cannot be attributed.
+            let entry = series.get(&series_ref)?;
+            Some(Some((
+                series_ref,
+                Sample {
+                    metric: entry.metric.clone(),
+                    labels: entry.labels.clone(),
+                    ts_ms,
+                    value,
+                },
+            )))
+        }
+        _ => None,
+    }
+}
@@ -1,6 +1,12 @@
 use std::time::Duration;
 
-use agent_core::{config::ConfigOverrides, AgentConfig};
+use agent_core::{
+    config::{
+        load_config, ConfigOverrides, LocalTsdbRollupTier, LocalTsdbWritePolicy, RemoteWriteConfig,
+        SeriesOverflow,
+    },
+    AgentConfig,
+};
 
 #[test]
 fn overrides_apply_all_booleans_and_scalars() {
@@ -11,6 +17,7 @@ fn overrides_apply_all_booleans_and_scalars() {
         enable_memory: Some(false),
         enable_disk: Some(false),
         enable_network: Some(false),
+        enable_ebpf: Some(false),
         enable_gpu: Some(false),
         enable_gpu_amd: Some(true),
         enable_power: Some(false),
@@ -30,10 +37,29 @@ fn overrides_apply_all_booleans_and_scalars() {
         local_tsdb_path: Some("/tmp/tsdb".to_string()),
         local_tsdb_retention_hours: Some(12),
         local_tsdb_max_disk_mb: Some(321),
+        local_tsdb_wal_fsync_interval: Some(Duration::from_millis(250)),
+        local_tsdb_rollups: Some(vec![LocalTsdbRollupTier {
+            resolution: Duration::from_secs(300),
+            retention: Duration::from_secs(3 * 24 * 3600),
+        }]),
+        local_tsdb_write_interval: Some(Duration::from_secs(60)),
+        local_tsdb_write_policies: Some(vec![LocalTsdbWritePolicy {
+            metric: "esnode_gpu_power_watts".to_string(),
+            interval: Duration::from_secs(1),
+        }]),
+        local_tsdb_max_series: Some(5000),
+        local_tsdb_max_series_per_metric: Some(500),
+        local_tsdb_series_overflow: Some(SeriesOverflow::Aggregate),
+        admin_token: Some("s3cret".to_string().into()),
+        local_tsdb_admin_token: None,
+        remote_write: Some(vec![RemoteWriteConfig::new(
+            "http://collector:9090/api/v1/write",
+        )]),
 
         node_power_envelope_watts: Some(456.0),
         log_level: None,
         efficiency_profile_path: None,
+        efficiency_profiles_dir: None,
         enforcement_mode: None,
         enforcement_interval: None,
         dampening_interval: None,
@@ -62,6 +88,85 @@ fn overrides_apply_all_booleans_and_scalars() {
     assert_eq!(base.local_tsdb_path, "/tmp/tsdb");
     assert_eq!(base.local_tsdb_retention_hours, 12);
     assert_eq!(base.local_tsdb_max_disk_mb, 321);
+    assert_eq!(
+        base.local_tsdb_wal_fsync_interval,
+        Duration::from_millis(250)
+    );
+    assert_eq!(base.local_tsdb_rollups.len(), 1);
+    assert_eq!(
+        base.local_tsdb_rollups[0].resolution,
+        Duration::from_secs(300)
+    );
+    assert_eq!(base.local_tsdb_write_interval, Duration::from_secs(60));
+    assert_eq!(base.local_tsdb_write_policies.len(), 1);
+    assert_eq!(base.local_tsdb_max_series, Some(5000));
+    assert_eq!(base.local_tsdb_max_series_per_metric, Some(500));
+    assert_eq!(base.admin_token(), Some("s3cret"));
+    assert_eq!(base.local_tsdb_series_overflow, SeriesOverflow::Aggregate);
 
+    assert_eq!(base.remote_write.len(), 1);
+    assert_eq!(base.remote_write[0].max_samples_per_send, 2000);
     assert_eq!(base.node_power_envelope_watts, Some(456.0));
 }
+
+#[test]
+fn kill_process_guards_load_from_file() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("esnode.toml");
+    std::fs::write(
+        &path,
+        r#"
+[kill_process]
+dry_run = false
+allow_cgroups = ["/kubepods/*"]
+grace_period = "10s"
+"#,
+    )
+    .unwrap();
+
+    let config = load_config(Some(path)).unwrap();
+    let guards = &config.kill_process;
+    assert!(!guards.dry_run);
+    assert_eq!(guards.allow_cgroups, ["/kubepods/*"]);
+    assert_eq!(guards.grace_period, Duration::from_secs(10));
+    // Unset guards keep their defaults.
+    assert_eq!(guards.deny_users, ["root"]);
+    assert_eq!(guards.min_runtime, Duration::from_secs(5 * 60));
+
+    assert!(AgentConfig::default().kill_process.dry_run);
+}
+
+#[test]
+fn admin_token_falls_back_to_the_deprecated_tsdb_token() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("esnode.toml");
+    std::fs::write(&path, "local_tsdb_admin_token = \"old\"\n").unwrap();
+
+    let mut config = load_config(Some(path)).unwrap();
+    assert_eq!(config.admin_token(), Some("old"));
+    config.admin_token = Some("new".to_string().into());
+    assert_eq!(config.admin_token(), Some("new"));
+}
+
+#[test]
+fn debug_output_redacts_tokens() {
+    let mut config = AgentConfig {
+        admin_token: Some("s3cret".to_string().into()),
+        ..Default::default()
+    };
+    config.remote_write = vec![RemoteWriteConfig::new("https://metrics.example/write")];
+    config.remote_write[0].bearer_token = Some("t0ken".to_string());
+    let printed = format!("{config:?}");
+    assert!(printed.contains("https://metrics.example/write"));
+    assert!(!printed.contains("s3cret"), "{printed}");
+    assert!(!printed.contains("t0ken"), "{printed}");
+}
+
+#[test]
+fn remote_write_debug_redacts_the_bearer_token() {
+    let mut endpoint = RemoteWriteConfig::new("https://metrics.example/write");
+    endpoint.bearer_token = Some("s3cret".to_string());
+    let printed = format!("{endpoint:?}");
+    assert!(printed.contains("https://metrics.example/write"));
+    assert!(!printed.contains("s3cret"), "{printed}");
+}
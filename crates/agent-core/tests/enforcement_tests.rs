@@ -0,0 +1,704 @@
+use std::time::Duration;
+
+use agent_core::audit::{self, AuditLog, AuditOutcome};
+use agent_core::config::{AgentConfig, AuditConfig, EnforcementMode, KillProcessConfig};
+use agent_core::control::{
+    ActionLedger, ControlError, EnforcementLoop, Enforcer, GpuProcess, PriorState, ProcessInfo,
+    Signal, SimulatedGpu, SimulatedGpus, SimulatedProcesses,
+};
+use agent_core::metrics::MetricsRegistry;
+use agent_core::policy::{PlanStatus, PolicyEventKind, PolicyHistory, ProfileSet};
+use agent_core::state::{GpuStatus, StatusSnapshot};
+use tempfile::TempDir;
+
+const LADDER: &str = r#"
+apiVersion: v1
+kind: EfficiencyProfile
+metadata: { name: "thermal", version: "1" }
+selectors: {}
+policies:
+  - name: "ladder"
+    target: gpu_temp_celsius
+    condition: "> 85"
+    clear_condition: "< 80"
+    severity: critical
+    action: { type: alert }
+    escalation:
+      - after: 5m
+        action: { type: throttle_power, parameters: { limit_percent: 80 } }
+      - after: 10m
+        action: { type: throttle_power, parameters: { limit_percent: 60 } }
+"#;
+
+const CLOCKS: &str = r#"
+apiVersion: v1
+kind: EfficiencyProfile
+metadata: { name: "clocks", version: "1" }
+selectors: {}
+policies:
+  - name: "cool-down"
+    target: gpu_temp_celsius
+    condition: "> 85"
+    clear_condition: "< 80"
+    severity: warning
+    action: { type: lock_clock, parameters: { max_mhz: 1410 } }
+  - name: "memory"
+    target: gpu_temp_celsius
+    condition: "> 95"
+    severity: warning
+    action: { type: lock_clock, parameters: { clock: memory, min_mhz: 1000, max_mhz: 2000 } }
+"#;
+
+const KILL: &str = r#"
+apiVersion: v1
+kind: EfficiencyProfile
+metadata: { name: "reclaim", version: "1" }
+selectors: {}
+policies:
+  - name: "reclaim"
+    target: gpu_temp_celsius
+    condition: "> 85"
+    severity: critical
+    action: { type: kill_process, parameters: { grace_period_seconds: 0 } }
+"#;
+
+const THROTTLE: &str = r#"
+apiVersion: v1
+kind: EfficiencyProfile
+metadata: { name: "power", version: "1" }
+selectors: {}
+policies:
+  - name: "cap"
+    target: gpu_temp_celsius
+    condition: "> 85"
+    severity: warning
+    action: { type: throttle_power, parameters: { limit_watts: 400 } }
+"#;
+
+/// Two profiles capping the same GPU; `b` takes priority and only clears
+/// below 80C.
+const OVERLAP_A: &str = r#"
+apiVersion: v1
+kind: EfficiencyProfile
+metadata: { name: "a", version: "1", priority: 1 }
+selectors: {}
+policies:
+  - name: "cap"
+    target: gpu_temp_celsius
+    condition: "> 85"
+    severity: warning
+    action: { type: throttle_power, parameters: { limit_watts: 300 } }
+"#;
+
+const OVERLAP_B: &str = r#"
+apiVersion: v1
+kind: EfficiencyProfile
+metadata: { name: "b", version: "1", priority: 2 }
+selectors: {}
+policies:
+  - name: "cap"
+    target: gpu_temp_celsius
+    condition: "> 90"
+    clear_condition: "< 80"
+    severity: warning
+    action: { type: throttle_power, parameters: { limit_watts: 250 } }
+"#;
+
+const MINUTE: i64 = 60_000;
+
+fn snapshot(temp: f64) -> StatusSnapshot {
+    StatusSnapshot {
+        gpus: vec![GpuStatus {
+            gpu: "0".to_string(),
+            temperature_celsius: Some(temp),
+            ..Default::default()
+        }],
+        ..Default::default()
+    }
+}
+
+fn control(
+    dir: &TempDir,
+    profile: &str,
+    gpus: &SimulatedGpus,
+    history: &PolicyHistory,
+) -> EnforcementLoop {
+    control_with(
+        dir,
+        profile,
+        Enforcer::with_gpu_control(gpus.clone()),
+        &config(),
+        history,
+    )
+}
+
+fn config() -> AgentConfig {
+    AgentConfig {
+        enforcement_mode: EnforcementMode::Enforce,
+        dampening_interval: Duration::from_secs(3600),
+        ..Default::default()
+    }
+}
+
+/// A loop whose profile and ledger live in `dir`.
+fn control_with(
+    dir: &TempDir,
+    profile: &str,
+    enforcer: Enforcer,
+    config: &AgentConfig,
+    history: &PolicyHistory,
+) -> EnforcementLoop {
+    let path = dir.path().join("profile.yaml");
+    std::fs::write(&path, profile).unwrap();
+    let mut control = EnforcementLoop::new(
+        ProfileSet::new([path]),
+        enforcer,
+        config,
+        ActionLedger::open(dir.path().join("ledger.json")).unwrap(),
+        history.clone(),
+        MetricsRegistry::new().unwrap(),
+    );
+    control.reload();
+    control
+}
+
+#[test]
+fn escalation_throttles_in_steps_and_restores_the_limit() {
+    let dir = TempDir::new().unwrap();
+    let gpus = SimulatedGpus::new().with_gpu("GPU-0", SimulatedGpu::new(700.0, 200.0, 700.0));
+    gpus.update("GPU-0", |gpu| gpu.power_limit_watts = 650.0);
+    let history = PolicyHistory::new();
+    let mut control = control(&dir, LADDER, &gpus, &history);
+    let limit = || gpus.gpu("GPU-0").unwrap().power_limit_watts;
+
+    let plans = control.tick(&snapshot(90.0), 0);
+    assert_eq!(plans[0].matched_policies[0].status, PlanStatus::Violated);
+    assert_eq!(limit(), 650.0);
+    control.tick(&snapshot(90.0), 5 * MINUTE);
+    assert_eq!(limit(), 560.0);
+    control.tick(&snapshot(90.0), 10 * MINUTE);
+    assert_eq!(limit(), 420.0);
+    // Inside the clear band the ladder holds.
+    control.tick(&snapshot(82.0), 11 * MINUTE);
+    assert_eq!(limit(), 420.0);
+    control.tick(&snapshot(75.0), 12 * MINUTE);
+    assert_eq!(limit(), 650.0);
+
+    let kinds: Vec<_> = history
+        .events(None, None)
+        .into_iter()
+        .map(|e| e.kind)
+        .collect();
+    use PolicyEventKind::*;
+    assert_eq!(
+        kinds,
+        [
+            Violated,
+            Enforced,
+            Enforced,
+            Enforced,
+            Cleared,
+            Deescalated,
+            Restored
+        ]
+    );
+}
+
+#[test]
+fn backend_failures_are_recorded() {
+    let dir = TempDir::new().unwrap();
+    let gpus = SimulatedGpus::new().with_gpu("GPU-0", SimulatedGpu::new(300.0, 250.0, 300.0));
+    let history = PolicyHistory::new();
+    let mut control = control(&dir, LADDER, &gpus, &history);
+
+    control.tick(&snapshot(90.0), 0);
+    // 80% of 300W is below the GPU's 250W minimum.
+    control.tick(&snapshot(90.0), 5 * MINUTE);
+    gpus.update("GPU-0", |gpu| {
+        gpu.failure = Some(ControlError::Backend(
+            "GPU has fallen off the bus".to_string(),
+        ))
+    });
+    control.tick(&snapshot(90.0), 10 * MINUTE);
+
+    let failures: Vec<_> = history
+        .events(None, None)
+        .into_iter()
+        .filter(|e| e.kind == PolicyEventKind::Failed)
+        .filter_map(|e| e.message)
+        .collect();
+    assert_eq!(
+        failures,
+        [
+            "requested power limit 240.0W is out of range (250.0W - 300.0W)",
+            "GPU has fallen off the bus",
+        ]
+    );
+    assert_eq!(gpus.gpu("GPU-0").unwrap().power_limit_watts, 300.0);
+}
+
+#[test]
+fn changes_that_cannot_be_recorded_are_not_applied() {
+    let dir = TempDir::new().unwrap();
+    let gpus = SimulatedGpus::new().with_gpu("GPU-0", SimulatedGpu::new(700.0, 200.0, 700.0));
+    let history = PolicyHistory::new();
+    let mut control = control(&dir, LADDER, &gpus, &history);
+    // The ledger is saved through `ledger.tmp`, which a directory now blocks.
+    std::fs::create_dir(dir.path().join("ledger.tmp")).unwrap();
+
+    control.tick(&snapshot(90.0), 0);
+    control.tick(&snapshot(90.0), 5 * MINUTE);
+
+    assert_eq!(gpus.gpu("GPU-0").unwrap().power_limit_watts, 700.0);
+    let failures: Vec<_> = history
+        .events(None, None)
+        .into_iter()
+        .filter(|e| e.kind == PolicyEventKind::Failed)
+        .filter_map(|e| e.message)
+        .collect();
+    assert_eq!(failures.len(), 1);
+    assert!(failures[0].starts_with("failed to save action ledger"));
+}
+
+#[test]
+fn clock_locks_are_restored_when_the_policy_clears() {
+    let dir = TempDir::new().unwrap();
+    let gpus = SimulatedGpus::new().with_gpu("GPU-0", SimulatedGpu::new(700.0, 200.0, 700.0));
+    gpus.update("GPU-0", |gpu| {
+        gpu.locked_graphics_clocks_mhz = Some((1200, 1980))
+    });
+    let history = PolicyHistory::new();
+    let mut control = control(&dir, CLOCKS, &gpus, &history);
+    let clocks = || gpus.gpu("GPU-0").unwrap().locked_graphics_clocks_mhz;
+
+    control.tick(&snapshot(90.0), 0);
+    assert_eq!(clocks(), Some((210, 1410)));
+    control.tick(&snapshot(75.0), MINUTE);
+    assert_eq!(clocks(), Some((1200, 1980)));
+
+    let events = history.events(None, None);
+    let restored = events.last().unwrap();
+    assert_eq!(restored.kind, PolicyEventKind::Restored);
+    assert_eq!(
+        restored.message.as_deref(),
+        Some("Restored GPU-0 graphics clock lock to 1200-1980MHz")
+    );
+}
+
+#[test]
+fn clock_locks_of_unknown_origin_are_left_as_is() {
+    let dir = TempDir::new().unwrap();
+    let gpus = SimulatedGpus::new().with_gpu("GPU-0", SimulatedGpu::new(700.0, 200.0, 700.0));
+    gpus.update("GPU-0", |gpu| {
+        gpu.locked_graphics_clocks_mhz = Some((1200, 1980));
+        gpu.unknown_clock_locks = true;
+    });
+    let history = PolicyHistory::new();
+    let mut control = control(&dir, CLOCKS, &gpus, &history);
+    let clocks = || gpus.gpu("GPU-0").unwrap().locked_graphics_clocks_mhz;
+
+    control.tick(&snapshot(90.0), 0);
+    assert_eq!(clocks(), Some((210, 1410)));
+    // Resetting could drop a lock another tool set, so nothing is restored.
+    control.tick(&snapshot(75.0), MINUTE);
+    assert_eq!(clocks(), Some((210, 1410)));
+
+    let events = history.events(None, None);
+    let restored = events.last().unwrap();
+    assert_eq!(restored.kind, PolicyEventKind::Restored);
+    assert_eq!(
+        restored.message.as_deref(),
+        Some("Left GPU-0 graphics clock as is: its lock before the policy is unknown")
+    );
+}
+
+#[test]
+fn clocks_are_restored_on_shutdown_and_checked_against_supported_clocks() {
+    let dir = TempDir::new().unwrap();
+    let gpus = SimulatedGpus::new().with_gpu("GPU-0", SimulatedGpu::new(700.0, 200.0, 700.0));
+    let history = PolicyHistory::new();
+    let mut control = control(&dir, CLOCKS, &gpus, &history);
+
+    control.tick(&snapshot(99.0), 0);
+    let gpu = gpus.gpu("GPU-0").unwrap();
+    assert_eq!(gpu.locked_graphics_clocks_mhz, Some((210, 1410)));
+    assert_eq!(gpu.locked_memory_clocks_mhz, None);
+
+    control.shutdown(MINUTE);
+    let gpu = gpus.gpu("GPU-0").unwrap();
+    assert_eq!(gpu.locked_graphics_clocks_mhz, None);
+
+    let events: Vec<_> = history
+        .events(None, None)
+        .into_iter()
+        .filter(|e| e.message.is_some())
+        .map(|e| (e.kind, e.policy_name, e.message.unwrap()))
+        .collect();
+    assert_eq!(
+        events,
+        [
+            (
+                PolicyEventKind::Enforced,
+                "cool-down".to_string(),
+                "Locked GPU-0 graphics clock to 210-1410MHz".to_string()
+            ),
+            (
+                PolicyEventKind::Failed,
+                "memory".to_string(),
+                "requested memory clock lock 1000-2000MHz is out of range (1593MHz - 2619MHz)"
+                    .to_string()
+            ),
+            // The latest change is rolled back first.
+            (
+                PolicyEventKind::Restored,
+                "memory".to_string(),
+                "Restored GPU-0 memory clock to unlocked".to_string()
+            ),
+            (
+                PolicyEventKind::Restored,
+                "cool-down".to_string(),
+                "Restored GPU-0 graphics clock to unlocked".to_string()
+            ),
+        ]
+    );
+}
+
+#[test]
+fn changes_are_rolled_back_once_the_policy_stays_clear() {
+    let dir = TempDir::new().unwrap();
+    let gpus = SimulatedGpus::new().with_gpu("GPU-0", SimulatedGpu::new(700.0, 200.0, 700.0));
+    let history = PolicyHistory::new();
+    let config = AgentConfig {
+        rollback_after: Duration::from_secs(5 * 60),
+        ..config()
+    };
+    let enforcer = Enforcer::with_gpu_control(gpus.clone());
+    let mut control = control_with(&dir, THROTTLE, enforcer, &config, &history);
+    let limit = || gpus.gpu("GPU-0").unwrap().power_limit_watts;
+
+    control.tick(&snapshot(90.0), 0);
+    assert_eq!(limit(), 400.0);
+    control.tick(&snapshot(75.0), MINUTE);
+    // Violated again before the delay is over: the delay starts over.
+    control.tick(&snapshot(90.0), 3 * MINUTE);
+    control.tick(&snapshot(75.0), 4 * MINUTE);
+    control.tick(&snapshot(75.0), 8 * MINUTE);
+    assert_eq!(limit(), 400.0);
+    control.tick(&snapshot(75.0), 9 * MINUTE);
+    assert_eq!(limit(), 700.0);
+
+    let restored = history.events(None, None).pop().unwrap();
+    assert_eq!(restored.kind, PolicyEventKind::Restored);
+    assert_eq!(restored.unix_ms, 9 * MINUTE);
+    assert_eq!(
+        restored.message.as_deref(),
+        Some("Restored GPU-0 power limit to 700.0W")
+    );
+}
+
+#[test]
+fn overlapping_policies_restore_the_original_state_once_both_clear() {
+    let dir = TempDir::new().unwrap();
+    let profiles = dir.path().join("profiles");
+    std::fs::create_dir(&profiles).unwrap();
+    std::fs::write(profiles.join("a.yaml"), OVERLAP_A).unwrap();
+    std::fs::write(profiles.join("b.yaml"), OVERLAP_B).unwrap();
+    let gpus = SimulatedGpus::new().with_gpu("GPU-0", SimulatedGpu::new(350.0, 200.0, 350.0));
+    let history = PolicyHistory::new();
+    let mut control = EnforcementLoop::new(
+        ProfileSet::new([profiles]),
+        Enforcer::with_gpu_control(gpus.clone()),
+        &config(),
+        ActionLedger::open(dir.path().join("ledger.json")).unwrap(),
+        history.clone(),
+        MetricsRegistry::new().unwrap(),
+    );
+    control.reload();
+    let limit = || gpus.gpu("GPU-0").unwrap().power_limit_watts;
+
+    control.tick(&snapshot(87.0), 0);
+    assert_eq!(limit(), 300.0);
+    // `b` wins on priority and takes the limit from 300W to 250W.
+    control.tick(&snapshot(92.0), MINUTE);
+    assert_eq!(limit(), 250.0);
+    // `a` clears while `b` still holds the limit: nothing is restored.
+    control.tick(&snapshot(83.0), 2 * MINUTE);
+    assert_eq!(limit(), 250.0);
+    // Both clear: back to the limit before `a`, not the 300W `b` found.
+    control.tick(&snapshot(75.0), 3 * MINUTE);
+    assert_eq!(limit(), 350.0);
+
+    let restored: Vec<_> = history
+        .events(None, None)
+        .into_iter()
+        .filter(|e| e.kind == PolicyEventKind::Restored)
+        .map(|e| (e.profile_name, e.unix_ms))
+        .collect();
+    assert_eq!(
+        restored,
+        [("a".to_string(), 3 * MINUTE), ("b".to_string(), 3 * MINUTE)]
+    );
+}
+
+#[test]
+fn failed_restores_are_retried_on_the_next_tick() {
+    let dir = TempDir::new().unwrap();
+    let gpus = SimulatedGpus::new().with_gpu("GPU-0", SimulatedGpu::new(700.0, 200.0, 700.0));
+    let history = PolicyHistory::new();
+    let mut control = control(&dir, THROTTLE, &gpus, &history);
+    let limit = || gpus.gpu("GPU-0").unwrap().power_limit_watts;
+
+    control.tick(&snapshot(90.0), 0);
+    assert_eq!(limit(), 400.0);
+    gpus.update("GPU-0", |gpu| {
+        gpu.failure = Some(ControlError::Backend("GPU is busy".to_string()))
+    });
+    control.tick(&snapshot(75.0), MINUTE);
+    assert_eq!(
+        history.events(None, None).pop().unwrap().kind,
+        PolicyEventKind::Failed
+    );
+    assert!(!ActionLedger::open(dir.path().join("ledger.json"))
+        .unwrap()
+        .is_empty());
+
+    gpus.update("GPU-0", |gpu| gpu.failure = None);
+    control.tick(&snapshot(75.0), 2 * MINUTE);
+    assert_eq!(limit(), 700.0);
+    assert_eq!(
+        history.events(None, None).pop().unwrap().kind,
+        PolicyEventKind::Restored
+    );
+    assert!(ActionLedger::open(dir.path().join("ledger.json"))
+        .unwrap()
+        .is_empty());
+}
+
+#[test]
+fn removed_profiles_are_rolled_back_right_away() {
+    let dir = TempDir::new().unwrap();
+    let gpus = SimulatedGpus::new().with_gpu("GPU-0", SimulatedGpu::new(700.0, 200.0, 700.0));
+    let history = PolicyHistory::new();
+    let config = AgentConfig {
+        rollback_after: Duration::from_secs(3600),
+        ..config()
+    };
+    let enforcer = Enforcer::with_gpu_control(gpus.clone());
+    let mut control = control_with(&dir, THROTTLE, enforcer, &config, &history);
+
+    control.tick(&snapshot(90.0), 0);
+    assert_eq!(gpus.gpu("GPU-0").unwrap().power_limit_watts, 400.0);
+    std::fs::remove_file(dir.path().join("profile.yaml")).unwrap();
+    control.reload();
+    control.tick(&snapshot(90.0), MINUTE);
+    assert_eq!(gpus.gpu("GPU-0").unwrap().power_limit_watts, 700.0);
+}
+
+#[test]
+fn a_restarted_agent_rolls_back_what_a_crashed_one_changed() {
+    let dir = TempDir::new().unwrap();
+    let gpus = SimulatedGpus::new().with_gpu("GPU-0", SimulatedGpu::new(700.0, 200.0, 700.0));
+    gpus.update("GPU-0", |gpu| {
+        gpu.locked_graphics_clocks_mhz = Some((1200, 1980))
+    });
+    let history = PolicyHistory::new();
+    let mut crashed = control(&dir, CLOCKS, &gpus, &history);
+    crashed.tick(&snapshot(90.0), 0);
+    assert_eq!(
+        gpus.gpu("GPU-0").unwrap().locked_graphics_clocks_mhz,
+        Some((210, 1410))
+    );
+    // No shutdown.
+    drop(crashed);
+
+    let history = PolicyHistory::new();
+    let mut restarted = control(&dir, CLOCKS, &gpus, &history);
+    restarted.recover(MINUTE);
+    assert_eq!(
+        gpus.gpu("GPU-0").unwrap().locked_graphics_clocks_mhz,
+        Some((1200, 1980))
+    );
+    let events = history.events(None, None);
+    assert_eq!(events.len(), 1);
+    assert_eq!(events[0].kind, PolicyEventKind::Restored);
+    assert_eq!(events[0].policy_name, "cool-down");
+    assert!(ActionLedger::open(dir.path().join("ledger.json"))
+        .unwrap()
+        .is_empty());
+}
+
+#[test]
+fn actions_and_rollbacks_are_audited() {
+    let dir = TempDir::new().unwrap();
+    let gpus = SimulatedGpus::new().with_gpu("GPU-0", SimulatedGpu::new(700.0, 200.0, 700.0));
+    let history = PolicyHistory::new();
+    let audit_config = AuditConfig {
+        path: dir.path().join("audit.jsonl"),
+        ..Default::default()
+    };
+    let audit_log = AuditLog::open(&audit_config).unwrap();
+    let mut control = control(&dir, THROTTLE, &gpus, &history).with_audit_log(audit_log.clone());
+
+    control.tick(&snapshot(90.0), 0);
+    control.tick(&snapshot(75.0), MINUTE);
+
+    let records = audit_log.records(None, 10).unwrap();
+    assert_eq!(records.len(), 2);
+    let (applied, restored) = (&records[0].entry, &records[1].entry);
+    assert_eq!(
+        (applied.profile_name.as_str(), applied.policy_name.as_str()),
+        ("power", "cap")
+    );
+    assert_eq!(applied.action, "throttle_power");
+    assert_eq!(applied.parameters["limit_watts"], 400);
+    assert_eq!(applied.before, [PriorState::PowerLimit { watts: 700.0 }]);
+    assert_eq!(applied.after, [PriorState::PowerLimit { watts: 400.0 }]);
+    assert_eq!(applied.outcome, AuditOutcome::Success);
+    assert!(!applied.dry_run);
+    assert_eq!(restored.action, "restore");
+    assert_eq!(restored.before, applied.after);
+    assert_eq!(restored.after, applied.before);
+    assert_eq!(records[1].prev_hash, records[0].hash);
+    assert_eq!(records[1].unix_ms, MINUTE);
+
+    assert!(audit::verify(&audit_config.path).unwrap().is_intact());
+}
+
+#[test]
+fn dry_run_kills_are_audited_as_such() {
+    let dir = TempDir::new().unwrap();
+    let (gpus, processes) = busy_gpu();
+    let history = PolicyHistory::new();
+    let audit_log = AuditLog::open(&AuditConfig {
+        path: dir.path().join("audit.jsonl"),
+        ..Default::default()
+    })
+    .unwrap();
+    let enforcer = Enforcer::with_gpu_control(gpus).with_process_control(processes);
+    let mut control =
+        control_with(&dir, KILL, enforcer, &config(), &history).with_audit_log(audit_log.clone());
+
+    control.tick(&snapshot(90.0), 0);
+    let records = audit_log.records(None, 10).unwrap();
+    assert_eq!(records.len(), 1);
+    assert!(records[0].entry.dry_run);
+    assert!(records[0]
+        .entry
+        .message
+        .starts_with("Dry run: would send SIGTERM"));
+}
+
+fn process(pid: u32, ppid: u32, user: &str, executable: &str, runtime_secs: u64) -> ProcessInfo {
+    ProcessInfo {
+        pid,
+        ppid,
+        user: user.to_string(),
+        cgroup: "/slurm/job42".to_string(),
+        executable: executable.to_string(),
+        start_ticks: 1000 + u64::from(pid),
+        runtime: Duration::from_secs(runtime_secs),
+    }
+}
+
+/// A GPU running PID 1, processes of root, a young process, a child of the
+/// agent and two candidates, one of which ignores SIGTERM.
+fn busy_gpu() -> (SimulatedGpus, SimulatedProcesses) {
+    let pids = [1, 4242, 4243, 4244, 4245, 4246];
+    let gpus = SimulatedGpus::new().with_gpu("GPU-0", SimulatedGpu::new(700.0, 200.0, 700.0));
+    gpus.update("GPU-0", |gpu| {
+        gpu.processes = pids
+            .iter()
+            .map(|&pid| GpuProcess {
+                pid,
+                used_memory_bytes: Some(1 << 30),
+                sm_utilization_percent: Some(0),
+            })
+            .collect()
+    });
+    let processes = SimulatedProcesses::new()
+        .with_process(process(1, 0, "root", "/sbin/init", 86_400), false)
+        .with_process(process(4242, 1, "alice", "/usr/bin/python3", 3600), true)
+        .with_process(process(4243, 1, "root", "/usr/bin/dcgmi", 3600), false)
+        .with_process(process(4244, 1, "bob", "/usr/bin/python3", 10), false)
+        .with_process(
+            process(4245, std::process::id(), "esnode", "/usr/bin/helper", 3600),
+            false,
+        )
+        .with_process(process(4246, 1, "carol", "/opt/bench/stress", 3600), false);
+    (gpus, processes)
+}
+
+#[test]
+fn kill_process_is_a_dry_run_by_default() {
+    let dir = TempDir::new().unwrap();
+    let (gpus, processes) = busy_gpu();
+    let history = PolicyHistory::new();
+    let enforcer = Enforcer::with_gpu_control(gpus).with_process_control(processes.clone());
+    let mut control = control_with(&dir, KILL, enforcer, &config(), &history);
+
+    control.tick(&snapshot(90.0), 0);
+    assert!(processes.signals().is_empty());
+    let events = history.events(None, None);
+    assert_eq!(events[1].kind, PolicyEventKind::Enforced);
+    assert_eq!(
+        events[1].message.as_deref(),
+        Some(
+            "Dry run: would send SIGTERM to 4242 (python3, alice), 4246 (stress, carol) on GPU-0; \
+             spared 1 (PID 1 is never killed), 4243 (user denied by \"root\"), \
+             4244 (running for less than 5m), 4245 (belongs to the agent)"
+        )
+    );
+}
+
+#[test]
+fn kill_process_sends_sigterm_then_sigkill() {
+    let dir = TempDir::new().unwrap();
+    let (gpus, processes) = busy_gpu();
+    let history = PolicyHistory::new();
+    let enforcer = Enforcer::with_gpu_control(gpus)
+        .with_process_control(processes.clone())
+        .with_kill_guards(KillProcessConfig {
+            dry_run: false,
+            deny_executables: vec!["stress".to_string()],
+            allow_cgroups: vec!["/slurm/*".to_string()],
+            ..Default::default()
+        });
+    let mut control = control_with(&dir, KILL, enforcer, &config(), &history);
+
+    control.tick(&snapshot(90.0), 0);
+    assert_eq!(processes.signals(), [(4242, Signal::Term)]);
+    assert!(processes.is_running(4242));
+    assert!(processes.is_running(4246));
+
+    // 4242 ignored SIGTERM and its grace period is over.
+    control.tick(&snapshot(90.0), MINUTE);
+    assert_eq!(
+        processes.signals(),
+        [(4242, Signal::Term), (4242, Signal::Kill)]
+    );
+    assert!(!processes.is_running(4242));
+}
+
+#[test]
+fn kill_process_requires_idle_gpu_memory() {
+    let dir = TempDir::new().unwrap();
+    let (gpus, processes) = busy_gpu();
+    let history = PolicyHistory::new();
+    let enforcer = Enforcer::with_gpu_control(gpus)
+        .with_process_control(processes.clone())
+        .with_kill_guards(KillProcessConfig {
+            dry_run: false,
+            min_idle_time: Duration::from_secs(3600),
+            ..Default::default()
+        });
+    let mut control = control_with(&dir, KILL, enforcer, &config(), &history);
+
+    control.tick(&snapshot(90.0), 0);
+    assert!(processes.signals().is_empty());
+    let message = history.events(None, None)[1].message.clone().unwrap();
+    assert!(
+        message.starts_with("No process to kill on GPU-0; spared 1 (PID 1 is never killed)"),
+        "{message}"
+    );
+    assert!(message.contains("4242 (not idle for 1h)"), "{message}");
+}
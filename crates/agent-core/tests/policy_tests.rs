@@ -1,7 +1,10 @@
 #[cfg(test)]
 mod tests {
     use agent_core::policy::{EfficiencyProfile, PlanStatus};
-    use agent_core::state::{GpuStatus, StatusSnapshot};
+    use agent_core::state::{
+        GpuIdentity, GpuStatus, IotSensorReading, MigDeviceStatus, MigTree, StatusSnapshot,
+    };
+    use std::collections::HashMap;
 
     fn mock_snapshot() -> StatusSnapshot {
         let gpu = GpuStatus {
@@ -74,4 +77,291 @@ mod tests {
         // Mock GPU util is 2.0, condition is < 5. This should be a violation (it IS idle).
         assert_eq!(result.matched_policies[0].status, PlanStatus::Violated);
     }
+
+    #[test]
+    fn test_compound_condition_references_other_targets() {
+        let yaml = r#"
+        apiVersion: v1
+        kind: EfficiencyProfile
+        metadata:
+          name: "test-profile-power"
+          version: "1.0.0"
+        selectors: {}
+        policies:
+          - name: "near-power-limit"
+            target: gpu_power_watts
+            condition: "> 0.9 * gpu_power_limit_watts and not gpu_temp_celsius between 0C and 60C"
+            severity: warning
+            action:
+              type: alert
+        "#;
+
+        let profile = EfficiencyProfile::from_yaml(yaml).unwrap();
+        let mut status = mock_snapshot();
+        status.gpus[0].power_watts = Some(680.0);
+        status.gpus[0].power_limit_watts = Some(700.0);
+        let plan = &profile.plan(&status).matched_policies[0];
+        assert_eq!(plan.status, PlanStatus::Violated);
+        assert_eq!(plan.target_resource, "GPU-GPU-123");
+        assert_eq!(plan.current_value, "680.0W");
+
+        let yaml = yaml.replace("target: gpu_power_watts", "target: gpu_temp_celsius")
+            .replace("> 0.9 * gpu_power_limit_watts", "gpu_power_watts > 0.9 * gpu_power_limit_watts");
+        let profile = EfficiencyProfile::from_yaml(&yaml).unwrap();
+        let plan = &profile.plan(&status).matched_policies[0];
+        assert_eq!(plan.status, PlanStatus::Violated);
+        assert_eq!(plan.current_value, "85.0C");
+
+        status.gpus[0].power_limit_watts = None;
+        let plan = &profile.plan(&status).matched_policies[0];
+        assert_eq!(plan.status, PlanStatus::Skipped);
+    }
+
+    #[test]
+    fn test_invalid_conditions_fail_at_load() {
+        let profile = |condition: &str| {
+            format!(
+                r#"
+        apiVersion: v1
+        kind: EfficiencyProfile
+        metadata:
+          name: "broken"
+          version: "1.0.0"
+        selectors: {{}}
+        policies:
+          - name: "thermal-safety"
+            target: gpu_temp_celsius
+            condition: "{condition}"
+            severity: critical
+            action:
+              type: alert
+        "#
+            )
+        };
+        assert!(EfficiencyProfile::from_yaml(&profile("> 80C")).is_ok());
+        let err = EfficiencyProfile::from_yaml(&profile("above 80")).unwrap_err();
+        assert!(err.to_string().contains("unknown target"), "{err}");
+        let err = EfficiencyProfile::from_yaml(&profile("> 300W")).unwrap_err();
+        assert!(err.to_string().contains("thermal-safety"), "{err}");
+    }
+
+    #[test]
+    fn test_host_targets_per_resource() {
+        let yaml = r#"
+        apiVersion: v1
+        kind: EfficiencyProfile
+        metadata:
+          name: "host"
+          version: "1.0.0"
+        selectors: {}
+        policies:
+          - name: "envelope"
+            target: node_power_envelope_percent
+            condition: "> 90% and disk_latency_ms > 20ms"
+            severity: warning
+            action:
+              type: alert
+          - name: "mig-idle"
+            target: mig_utilization
+            condition: "< 10% and gpu_temp_celsius > 80C"
+            severity: info
+            action:
+              type: alert
+          - name: "inlet-hot"
+            target: iot_sensor_value
+            condition: "> 30"
+            severity: warning
+            action:
+              type: alert
+          - name: "swap"
+            target: swap_degraded
+            condition: "== 1"
+            severity: info
+            action:
+              type: alert
+        "#;
+        let profile = EfficiencyProfile::from_yaml(yaml).unwrap();
+        let mut status = mock_snapshot();
+        status.node_power_watts = Some(1900.0);
+        status.node_power_envelope_watts = Some(2000.0);
+        status.disk_io_avg_latency_ms = Some(35.0);
+        status.gpus[0].mig_tree = Some(MigTree {
+            devices: vec![
+                MigDeviceStatus {
+                    id: "0".to_string(),
+                    util_percent: Some(3),
+                    ..Default::default()
+                },
+                MigDeviceStatus {
+                    id: "1".to_string(),
+                    util_percent: Some(60),
+                    ..Default::default()
+                },
+            ],
+            ..Default::default()
+        });
+        status.iot_sensors = vec![IotSensorReading {
+            driver_id: "rack-a".to_string(),
+            sensor_type: "temperature".to_string(),
+            unit: "C".to_string(),
+            param: "inlet".to_string(),
+            value: 32.5,
+        }];
+
+        let plan = profile.plan(&status).matched_policies;
+        let found: Vec<_> = plan
+            .iter()
+            .map(|p| (p.policy_name.as_str(), p.target_resource.as_str(), p.status.clone()))
+            .collect();
+        assert_eq!(
+            found,
+            [
+                ("envelope", "node", PlanStatus::Violated),
+                ("mig-idle", "MIG-0", PlanStatus::Violated),
+                ("mig-idle", "MIG-1", PlanStatus::Satisfied),
+                ("inlet-hot", "rack-a/temperature/inlet", PlanStatus::Violated),
+                ("swap", "node", PlanStatus::Satisfied),
+            ]
+        );
+        assert_eq!(plan[0].current_value, "95.0%");
+
+        // Without an envelope the percentage is unknown.
+        status.node_power_envelope_watts = None;
+        let plan = profile.plan(&status).matched_policies;
+        assert_eq!(plan[0].status, PlanStatus::Skipped);
+    }
+
+    #[test]
+    fn test_selectors_gate_profile_and_filter_resources() {
+        let yaml = r#"
+        apiVersion: v1
+        kind: EfficiencyProfile
+        metadata:
+          name: "rack-a-h100"
+          version: "1.0.0"
+        selectors:
+          matchTags:
+            rack: "a"
+            env: "prod*"
+          matchLabels:
+            gpu_model: "*H100*"
+        policies:
+          - name: "thermal-safety"
+            target: gpu_temp_celsius
+            condition: "> 80"
+            severity: critical
+            action:
+              type: alert
+          - name: "node-power"
+            target: cpu_utilization
+            condition: "> 90%"
+            severity: info
+            action:
+              type: alert
+        "#;
+        let profile = EfficiencyProfile::from_yaml(yaml).unwrap();
+
+        let tags = |pairs: &[(&str, &str)]| -> HashMap<String, String> {
+            pairs.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect()
+        };
+        assert!(profile.applies_to(&tags(&[("rack", "a"), ("env", "prod-eu")])));
+        assert!(!profile.applies_to(&tags(&[("rack", "b"), ("env", "prod")])));
+        assert!(!profile.applies_to(&tags(&[("rack", "a")])));
+
+        let gpu = |uuid: &str, model: Option<&str>| GpuStatus {
+            uuid: Some(uuid.to_string()),
+            temperature_celsius: Some(85.0),
+            identity: Some(GpuIdentity {
+                model: model.map(str::to_string),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        let status = StatusSnapshot {
+            gpus: vec![
+                gpu("h100", Some("NVIDIA H100 80GB HBM3")),
+                gpu("a100", Some("NVIDIA A100-SXM4-40GB")),
+                gpu("unknown", None),
+            ],
+            cpu_util_percent: Some(10.0),
+            ..Default::default()
+        };
+        let plan = profile.plan(&status).matched_policies;
+        let resources: Vec<_> = plan
+            .iter()
+            .map(|p| (p.policy_name.as_str(), p.target_resource.as_str()))
+            .collect();
+        // GPU labels do not filter node-level policies.
+        assert_eq!(
+            resources,
+            [("thermal-safety", "GPU-h100"), ("node-power", "node")]
+        );
+
+        let err = EfficiencyProfile::from_yaml(&yaml.replace("gpu_model:", "workload_type:"))
+            .unwrap_err();
+        assert!(err.to_string().contains("workload_type"), "{err}");
+    }
+
+    #[test]
+    fn test_schedules_and_maintenance_windows() {
+        use agent_core::policy::PolicyEvaluator;
+
+        let yaml = r#"
+        apiVersion: v1
+        kind: EfficiencyProfile
+        metadata: { name: "peak", version: "1" }
+        selectors: {}
+        maintenance_windows:
+          - name: "llama-run"
+            start: "2026-01-07T00:00:00Z"
+            end: "2026-01-08T00:00:00Z"
+        policies:
+          - name: "peak-cap"
+            target: gpu_temp_celsius
+            condition: "> 80"
+            schedule:
+              timezone: "America/New_York"
+              windows: [{ days: [mon-fri], start: "14:00", end: "19:00" }]
+            severity: warning
+            action:
+              type: throttle_power
+              parameters: { limit_watts: 300 }
+          - name: "hot"
+            target: gpu_temp_celsius
+            condition: "> 80"
+            severity: critical
+            action: { type: alert }
+        "#;
+        let profile = EfficiencyProfile::from_yaml(yaml).unwrap();
+        let status = mock_snapshot();
+        let statuses = |at: &str| {
+            let now = chrono::DateTime::parse_from_rfc3339(at).unwrap().timestamp_millis();
+            PolicyEvaluator::default()
+                .evaluate(&profile, &status, now)
+                .matched_policies
+                .into_iter()
+                .map(|p| (p.status, p.maintenance_window))
+                .collect::<Vec<_>>()
+        };
+
+        // Tuesday 15:00 in New York.
+        assert_eq!(
+            statuses("2026-01-06T20:00:00Z"),
+            [(PlanStatus::Violated, None), (PlanStatus::Violated, None)]
+        );
+        // Tuesday 21:00 in New York.
+        assert_eq!(
+            statuses("2026-01-07T02:00:00Z")[..1],
+            [(PlanStatus::Maintenance, Some("llama-run".to_string()))]
+        );
+        // Thursday 08:00 in New York.
+        assert_eq!(
+            statuses("2026-01-08T13:00:00Z"),
+            [(PlanStatus::Inactive, None), (PlanStatus::Violated, None)]
+        );
+
+        let broken = yaml.replace("end: \"2026-01-08", "end: \"2026-01-06");
+        let err = EfficiencyProfile::from_yaml(&broken).unwrap_err();
+        assert!(err.to_string().contains("end must be after start"), "{err}");
+    }
 }
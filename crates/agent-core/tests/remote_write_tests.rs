@@ -0,0 +1,358 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use agent_core::config::RemoteWriteConfig;
+use agent_core::metrics::MetricsRegistry;
+use agent_core::remote_write::RemoteWriter;
+use agent_core::tsdb::{LocalTsdb, LocalTsdbConfig, Sample};
+use axum::body::Bytes;
+use axum::extract::State;
+use axum::http::{HeaderMap, StatusCode};
+use axum::routing::post;
+use axum::Router;
+use tempfile::TempDir;
+
+// 2024-01-01T00:00:00Z, aligned to the 2h block window.
+const BASE_MS: i64 = 1_704_067_200_000;
+const HOUR_MS: i64 = 60 * 60 * 1000;
+
+type Labels = Vec<(String, String)>;
+
+#[derive(Debug, Clone)]
+struct Received {
+    labels: Labels,
+    ts_ms: i64,
+    value: f64,
+}
+
+/// Stand-in remote-write receiver: answers the first `failures` requests
+/// with `failure_status`, then decodes and records every write.
+#[derive(Default)]
+struct Receiver {
+    failures: usize,
+    failure_status: u16,
+    requests: usize,
+    writes: Vec<Vec<Received>>,
+}
+
+type Shared = Arc<Mutex<Receiver>>;
+
+async fn receive(State(state): State<Shared>, headers: HeaderMap, body: Bytes) -> StatusCode {
+    let mut receiver = state.lock().unwrap();
+    receiver.requests += 1;
+    if receiver.failures > 0 {
+        receiver.failures -= 1;
+        return StatusCode::from_u16(receiver.failure_status).unwrap();
+    }
+    assert_eq!(headers["content-encoding"], "snappy");
+    assert_eq!(headers["content-type"], "application/x-protobuf");
+    assert_eq!(headers["x-prometheus-remote-write-version"], "0.1.0");
+    let raw = snap::raw::Decoder::new().decompress_vec(&body).unwrap();
+    receiver.writes.push(decode_write_request(&raw));
+    StatusCode::NO_CONTENT
+}
+
+async fn start_receiver(failures: usize, failure_status: u16) -> (String, Shared) {
+    let state: Shared = Arc::new(Mutex::new(Receiver {
+        failures,
+        failure_status,
+        ..Receiver::default()
+    }));
+    let app = Router::new()
+        .route("/api/v1/write", post(receive))
+        .with_state(state.clone());
+    let listener = tokio::net::TcpListener::bind(SocketAddr::from(([127, 0, 0, 1], 0)))
+        .await
+        .unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+    (format!("http://{addr}/api/v1/write"), state)
+}
+
+// Minimal protobuf reader for prometheus.WriteRequest.
+fn varint(buf: &[u8], pos: &mut usize) -> u64 {
+    let mut out = 0u64;
+    let mut shift = 0;
+    loop {
+        let byte = buf[*pos];
+        *pos += 1;
+        out |= u64::from(byte & 0x7f) << shift;
+        if byte & 0x80 == 0 {
+            return out;
+        }
+        shift += 7;
+    }
+}
+
+/// Returns `(field, payload)` pairs; fixed64 payloads are their 8 raw bytes.
+fn fields(buf: &[u8]) -> Vec<(u64, &[u8])> {
+    let mut out = Vec::new();
+    let mut pos = 0;
+    while pos < buf.len() {
+        let key = varint(buf, &mut pos);
+        let start = pos;
+        match key & 7 {
+            0 => {
+                varint(buf, &mut pos);
+                out.push((key >> 3, &buf[start..pos]));
+            }
+            1 => {
+                pos += 8;
+                out.push((key >> 3, &buf[start..pos]));
+            }
+            2 => {
+                let len = varint(buf, &mut pos) as usize;
+                out.push((key >> 3, &buf[pos..pos + len]));
+                pos += len;
+            }
+            wire => panic!("unexpected wire type {wire}"),
+        }
+    }
+    out
+}
+
+fn decode_write_request(buf: &[u8]) -> Vec<Received> {
+    let mut out = Vec::new();
+    for (field, series) in fields(buf) {
+        assert_eq!(field, 1);
+        let mut labels = Labels::new();
+        let mut points = Vec::new();
+        for (field, payload) in fields(series) {
+            match field {
+                1 => {
+                    let parts = fields(payload);
+                    labels.push((
+                        String::from_utf8(parts[0].1.to_vec()).unwrap(),
+                        String::from_utf8(parts[1].1.to_vec()).unwrap(),
+                    ));
+                }
+                2 => {
+                    let parts = fields(payload);
+                    let value = f64::from_le_bytes(parts[0].1.try_into().unwrap());
+                    let ts_ms = varint(parts[1].1, &mut 0) as i64;
+                    points.push((ts_ms, value));
+                }
+                other => panic!("unexpected TimeSeries field {other}"),
+            }
+        }
+        let mut sorted = labels.clone();
+        sorted.sort();
+        assert_eq!(labels, sorted, "labels must be sorted by name");
+        out.extend(points.into_iter().map(|(ts_ms, value)| Received {
+            labels: labels.clone(),
+            ts_ms,
+            value,
+        }));
+    }
+    out
+}
+
+fn open(dir: &TempDir) -> Arc<LocalTsdb> {
+    Arc::new(
+        LocalTsdb::new(LocalTsdbConfig {
+            path: dir.path().to_path_buf(),
+            retention_hours: 24 * 365 * 10,
+            max_disk_mb: 512,
+            wal_fsync_interval: Duration::ZERO,
+            rollups: Vec::new(),
+            cardinality: Default::default(),
+        })
+        .unwrap(),
+    )
+}
+
+fn power_sample(gpu: &str, ts_ms: i64, value: f64) -> Sample {
+    let mut labels = HashMap::new();
+    labels.insert("gpu".to_string(), gpu.to_string());
+    Sample {
+        metric: "esnode_gpu_power_watts".to_string(),
+        labels,
+        ts_ms,
+        value,
+    }
+}
+
+fn endpoint(url: &str, max_samples_per_send: usize) -> RemoteWriteConfig {
+    RemoteWriteConfig {
+        name: Some("central".to_string()),
+        max_samples_per_send,
+        min_backoff: Duration::from_millis(10),
+        max_backoff: Duration::from_millis(40),
+        ..RemoteWriteConfig::new(url)
+    }
+}
+
+fn counter(metrics: &MetricsRegistry, name: &str) -> f64 {
+    metrics
+        .gather()
+        .iter()
+        .find(|family| family.get_name() == name)
+        .map(|family| {
+            let metric = &family.get_metric()[0];
+            if metric.has_counter() {
+                metric.get_counter().get_value()
+            } else {
+                metric.get_gauge().get_value()
+            }
+        })
+        .unwrap_or(0.0)
+}
+
+#[tokio::test]
+async fn replays_blocks_in_order_once_endpoint_recovers() {
+    let dir = TempDir::new().unwrap();
+    let tsdb = open(&dir);
+    // Two series across two 2h blocks.
+    let timestamps = [
+        BASE_MS + 1_000,
+        BASE_MS + HOUR_MS,
+        BASE_MS + 2 * HOUR_MS + 1_000,
+        BASE_MS + 3 * HOUR_MS,
+    ];
+    let mut samples = Vec::new();
+    for (i, ts) in timestamps.iter().enumerate() {
+        samples.push(power_sample("0", *ts, 300.0 + i as f64));
+        samples.push(power_sample("1", *ts, 0.5));
+    }
+    tsdb.write_samples(&samples).await.unwrap();
+
+    let (url, receiver) = start_receiver(1, 503).await;
+    let metrics = MetricsRegistry::new().unwrap();
+    let mut writer =
+        RemoteWriter::new(endpoint(&url, 1), tsdb.clone(), metrics.clone(), BASE_MS).unwrap();
+
+    let now_ms = BASE_MS + 5 * HOUR_MS;
+    assert!(writer.replay_once(now_ms).await.is_err());
+    assert_eq!(writer.watermark_ms(), BASE_MS);
+    // Batches never split a timestamp, so both series at t0 travel together.
+    assert_eq!(
+        counter(&metrics, "esnode_remote_write_samples_failed_total"),
+        2.0
+    );
+    assert_eq!(
+        counter(&metrics, "esnode_remote_write_lag_seconds"),
+        5.0 * 3600.0
+    );
+
+    assert_eq!(writer.replay_once(now_ms).await.unwrap(), 8);
+    {
+        let receiver = receiver.lock().unwrap();
+        assert_eq!(receiver.requests, 1 + 4);
+        let received: Vec<Received> = receiver.writes.iter().flatten().cloned().collect();
+        assert_eq!(received.len(), 8);
+        assert!(received.windows(2).all(|w| w[0].ts_ms <= w[1].ts_ms));
+        let gpu0: Vec<(i64, f64)> = received
+            .iter()
+            .filter(|r| r.labels.contains(&("gpu".to_string(), "0".to_string())))
+            .map(|r| (r.ts_ms, r.value))
+            .collect();
+        assert_eq!(
+            gpu0,
+            timestamps
+                .iter()
+                .enumerate()
+                .map(|(i, ts)| (*ts, 300.0 + i as f64))
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(
+            received[0].labels,
+            vec![
+                ("__name__".to_string(), "esnode_gpu_power_watts".to_string()),
+                ("gpu".to_string(), "0".to_string()),
+            ]
+        );
+    }
+    assert_eq!(writer.watermark_ms(), now_ms - 10_000);
+    assert_eq!(
+        counter(&metrics, "esnode_remote_write_samples_sent_total"),
+        8.0
+    );
+    assert_eq!(counter(&metrics, "esnode_remote_write_lag_seconds"), 10.0);
+
+    // The watermark survives a restart, so nothing is sent twice.
+    let mut restarted = RemoteWriter::new(endpoint(&url, 1), tsdb, metrics, now_ms).unwrap();
+    assert_eq!(restarted.watermark_ms(), now_ms - 10_000);
+    assert_eq!(restarted.replay_once(now_ms + 1_000).await.unwrap(), 0);
+    assert_eq!(receiver.lock().unwrap().requests, 5);
+}
+
+#[tokio::test]
+async fn a_new_url_starts_a_new_watermark() {
+    let dir = TempDir::new().unwrap();
+    let tsdb = open(&dir);
+    let metrics = MetricsRegistry::new().unwrap();
+    let old = endpoint("http://127.0.0.1:9/old", 1);
+    let writer = RemoteWriter::new(old.clone(), tsdb.clone(), metrics.clone(), BASE_MS).unwrap();
+    assert_eq!(writer.watermark_ms(), BASE_MS);
+
+    let later = BASE_MS + HOUR_MS;
+    let same = RemoteWriter::new(old, tsdb.clone(), metrics.clone(), later).unwrap();
+    assert_eq!(same.watermark_ms(), BASE_MS);
+    // Same name, new URL: the old watermark does not apply.
+    let moved =
+        RemoteWriter::new(endpoint("http://127.0.0.1:9/new", 1), tsdb, metrics, later).unwrap();
+    assert_eq!(moved.watermark_ms(), later);
+}
+
+#[tokio::test]
+async fn rejected_batches_are_dropped_not_retried() {
+    let dir = TempDir::new().unwrap();
+    let tsdb = open(&dir);
+    tsdb.write_samples(&[
+        power_sample("0", BASE_MS + 1_000, 1.0),
+        power_sample("0", BASE_MS + 2_000, 2.0),
+    ])
+    .await
+    .unwrap();
+
+    let (url, receiver) = start_receiver(1, 400).await;
+    let metrics = MetricsRegistry::new().unwrap();
+    let mut writer = RemoteWriter::new(endpoint(&url, 1), tsdb, metrics.clone(), BASE_MS).unwrap();
+
+    assert_eq!(writer.replay_once(BASE_MS + HOUR_MS).await.unwrap(), 1);
+    let receiver = receiver.lock().unwrap();
+    assert_eq!(receiver.requests, 2);
+    let received: Vec<i64> = receiver.writes.iter().flatten().map(|r| r.ts_ms).collect();
+    assert_eq!(received, vec![BASE_MS + 2_000]);
+    assert_eq!(
+        counter(&metrics, "esnode_remote_write_samples_failed_total"),
+        1.0
+    );
+    assert_eq!(
+        counter(&metrics, "esnode_remote_write_samples_sent_total"),
+        1.0
+    );
+}
+
+#[tokio::test]
+async fn background_writer_retries_with_backoff() {
+    let dir = TempDir::new().unwrap();
+    let tsdb = open(&dir);
+    let now_ms = chrono::Utc::now().timestamp_millis();
+    tsdb.write_samples(&[power_sample("0", now_ms - 60_000, 42.0)])
+        .await
+        .unwrap();
+
+    let (url, receiver) = start_receiver(3, 503).await;
+    let metrics = MetricsRegistry::new().unwrap();
+    let writer = RemoteWriter::new(endpoint(&url, 100), tsdb, metrics, now_ms - 120_000).unwrap();
+    let handle = writer.spawn();
+
+    let deadline = tokio::time::Instant::now() + Duration::from_secs(5);
+    loop {
+        if !receiver.lock().unwrap().writes.is_empty() {
+            break;
+        }
+        assert!(
+            tokio::time::Instant::now() < deadline,
+            "endpoint never received the sample"
+        );
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    handle.abort();
+    let receiver = receiver.lock().unwrap();
+    assert_eq!(receiver.requests, 4);
+    assert_eq!(receiver.writes[0][0].value, 42.0);
+}
@@ -0,0 +1,296 @@
+use std::collections::HashMap;
+use std::time::Duration;
+
+use agent_core::tsdb::query::{parse, QueryError, QueryResult};
+use agent_core::tsdb::{LocalTsdb, LocalTsdbConfig, Sample};
+use tempfile::TempDir;
+
+// 2024-01-01T00:00:00Z, aligned to the 2h block window.
+const BASE_MS: i64 = 1_704_067_200_000;
+
+fn open(dir: &TempDir) -> LocalTsdb {
+    LocalTsdb::new(LocalTsdbConfig {
+        path: dir.path().to_path_buf(),
+        retention_hours: 24 * 365 * 10,
+        max_disk_mb: 512,
+        wal_fsync_interval: Duration::ZERO,
+        rollups: Vec::new(),
+        cardinality: Default::default(),
+    })
+    .unwrap()
+}
+
+fn sample(metric: &str, labels: &[(&str, &str)], ts_ms: i64, value: f64) -> Sample {
+    Sample {
+        metric: metric.to_string(),
+        labels: labels
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect::<HashMap<_, _>>(),
+        ts_ms,
+        value,
+    }
+}
+
+/// Two GPUs on one node, scraped every 15s for 10 minutes. Energy counters
+/// grow at 10 J/s and 20 J/s; GPU-b's counter resets half way through.
+async fn seeded(dir: &TempDir) -> LocalTsdb {
+    let tsdb = open(dir);
+    let mut samples = Vec::new();
+    for i in 0..=40 {
+        let ts = BASE_MS + i * 15_000;
+        let b_energy = if i < 20 { i * 300 } else { (i - 20) * 300 };
+        samples.push(sample(
+            "esnode_gpu_energy_joules_total",
+            &[("uuid", "GPU-a"), ("model", "H100")],
+            ts,
+            (i * 150) as f64,
+        ));
+        samples.push(sample(
+            "esnode_gpu_energy_joules_total",
+            &[("uuid", "GPU-b"), ("model", "A100")],
+            ts,
+            b_energy as f64,
+        ));
+        samples.push(sample(
+            "esnode_gpu_power_watts",
+            &[("uuid", "GPU-a"), ("model", "H100")],
+            ts,
+            if i % 2 == 0 { 200.0 } else { 300.0 },
+        ));
+        samples.push(sample(
+            "esnode_gpu_power_watts",
+            &[("uuid", "GPU-b"), ("model", "A100")],
+            ts,
+            100.0,
+        ));
+    }
+    tsdb.write_samples(&samples).await.unwrap();
+    tsdb
+}
+
+type LabelPairs = Vec<(String, String)>;
+
+fn matrix(result: QueryResult) -> Vec<(LabelPairs, Vec<(i64, f64)>)> {
+    let QueryResult::Matrix(series) = result else {
+        panic!("expected matrix, got {result:?}");
+    };
+    series
+        .into_iter()
+        .map(|s| (s.labels.into_iter().collect(), s.points))
+        .collect()
+}
+
+fn labels(pairs: &[(&str, &str)]) -> LabelPairs {
+    pairs
+        .iter()
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .collect()
+}
+
+#[tokio::test]
+async fn rate_and_increase_are_extrapolated_and_reset_aware() {
+    let dir = TempDir::new().unwrap();
+    let tsdb = seeded(&dir).await;
+    let start = BASE_MS + 120_000;
+    let end = BASE_MS + 600_000;
+
+    let rates = matrix(
+        tsdb.query_range(
+            r#"rate(esnode_gpu_energy_joules_total{uuid="GPU-a"}[1m])"#,
+            start,
+            end,
+            60_000,
+        )
+        .await
+        .unwrap(),
+    );
+    assert_eq!(rates.len(), 1);
+    assert_eq!(rates[0].0, labels(&[("model", "H100"), ("uuid", "GPU-a")]));
+    assert_eq!(rates[0].1.len(), 9);
+    for (_, value) in &rates[0].1 {
+        assert!((value - 10.0).abs() < 1e-9, "rate {value}");
+    }
+
+    // GPU-b resets at 300s; increase must not go negative across it.
+    let increases = matrix(
+        tsdb.query_range(
+            r#"increase(esnode_gpu_energy_joules_total{uuid="GPU-b"}[1m])"#,
+            start,
+            end,
+            60_000,
+        )
+        .await
+        .unwrap(),
+    );
+    for (ts, value) in &increases[0].1 {
+        assert!(*value > 0.0, "increase at {ts} = {value}");
+    }
+    let (_, clean) = increases[0].1[0];
+    assert!((clean - 1200.0).abs() < 1e-9, "increase {clean}");
+}
+
+#[tokio::test]
+async fn aggregations_group_by_labels() {
+    let dir = TempDir::new().unwrap();
+    let tsdb = seeded(&dir).await;
+    let at = BASE_MS + 300_000;
+
+    let summed = matrix(
+        tsdb.query_range(
+            "sum by (model) (avg_over_time(esnode_gpu_power_watts[1m]))",
+            at,
+            at,
+            15_000,
+        )
+        .await
+        .unwrap(),
+    );
+    assert_eq!(
+        summed,
+        vec![
+            (labels(&[("model", "A100")]), vec![(at, 100.0)]),
+            (labels(&[("model", "H100")]), vec![(at, 250.0)]),
+        ]
+    );
+
+    for (query, expected) in [
+        ("max(esnode_gpu_power_watts)", 200.0),
+        ("min(esnode_gpu_power_watts)", 100.0),
+        ("avg(esnode_gpu_power_watts)", 150.0),
+        ("sum(esnode_gpu_power_watts) / 1000", 0.3),
+    ] {
+        let series = matrix(tsdb.query_range(query, at, at, 15_000).await.unwrap());
+        assert_eq!(series, vec![(vec![], vec![(at, expected)])], "{query}");
+    }
+}
+
+#[tokio::test]
+async fn label_matchers_filter_series() {
+    let dir = TempDir::new().unwrap();
+    let tsdb = seeded(&dir).await;
+    let at = BASE_MS + 300_000;
+
+    for (query, expected) in [
+        (r#"esnode_gpu_power_watts{model="H100"}"#, vec!["GPU-a"]),
+        (r#"esnode_gpu_power_watts{model!="H100"}"#, vec!["GPU-b"]),
+        (
+            r#"esnode_gpu_power_watts{uuid=~"GPU-.*"}"#,
+            vec!["GPU-a", "GPU-b"],
+        ),
+        // Regex matchers are anchored, so a bare prefix matches nothing.
+        (r#"esnode_gpu_power_watts{uuid=~"GPU"}"#, vec![]),
+        (
+            r#"esnode_gpu_power_watts{uuid!~"GPU-a|GPU-c"}"#,
+            vec!["GPU-b"],
+        ),
+        (
+            r#"{__name__=~"esnode_gpu_power.*", uuid="GPU-a"}"#,
+            vec!["GPU-a"],
+        ),
+    ] {
+        let QueryResult::Vector(samples) = tsdb.query_instant(query, at).await.unwrap() else {
+            panic!("expected vector for {query}");
+        };
+        let mut uuids: Vec<_> = samples.iter().map(|s| s.labels["uuid"].as_str()).collect();
+        uuids.sort();
+        assert_eq!(uuids, expected, "{query}");
+        for s in &samples {
+            assert_eq!(s.labels["__name__"], "esnode_gpu_power_watts");
+        }
+    }
+
+    // Instant selectors go stale after the five minute lookback.
+    let stale = tsdb
+        .query_instant("esnode_gpu_power_watts", BASE_MS + 600_000 + 5 * 60_000 + 1)
+        .await
+        .unwrap();
+    assert_eq!(stale, QueryResult::Vector(vec![]));
+}
+
+#[tokio::test]
+async fn results_render_as_prometheus_api_data() {
+    let dir = TempDir::new().unwrap();
+    let tsdb = seeded(&dir).await;
+    let at = BASE_MS + 300_000;
+
+    let data = tsdb
+        .query_range(
+            r#"esnode_gpu_power_watts{uuid="GPU-b"}"#,
+            at,
+            at + 15_000,
+            15_000,
+        )
+        .await
+        .unwrap()
+        .to_json();
+    assert_eq!(
+        data,
+        serde_json::json!({
+            "resultType": "matrix",
+            "result": [{
+                "metric": {"__name__": "esnode_gpu_power_watts", "model": "A100", "uuid": "GPU-b"},
+                "values": [[1_704_067_500.0, "100"], [1_704_067_515.0, "100"]],
+            }],
+        })
+    );
+
+    let scalar = tsdb.query_instant("1+1", at).await.unwrap().to_json();
+    assert_eq!(
+        scalar,
+        serde_json::json!({"resultType": "scalar", "result": [1_704_067_500.0, "2"]})
+    );
+}
+
+#[tokio::test]
+async fn invalid_queries_are_rejected() {
+    for query in [
+        "",
+        "rate(esnode_gpu_power_watts)",
+        "rate(esnode_gpu_power_watts[5x])",
+        "histogram_quantile(0.9, esnode_gpu_power_watts)",
+        r#"esnode_gpu_power_watts{uuid=~"("}"#,
+        r#"{uuid=~".*"}"#,
+        "esnode_gpu_power_watts / esnode_gpu_power_limit_watts",
+        "sum(1)",
+        "sum by (uuid (esnode_gpu_power_watts)",
+    ] {
+        assert!(
+            matches!(parse(query), Err(QueryError::Parse { .. })),
+            "{query:?} should not parse"
+        );
+    }
+
+    let dir = TempDir::new().unwrap();
+    let tsdb = open(&dir);
+    let err = tsdb
+        .query_range("esnode_gpu_power_watts", BASE_MS, BASE_MS + 3_600_000, 100)
+        .await
+        .unwrap_err();
+    assert_eq!(err.error_type(), "bad_data");
+}
+
+#[tokio::test]
+async fn extreme_timestamps_are_rejected_not_overflowed() {
+    let dir = TempDir::new().unwrap();
+    let tsdb = seeded(&dir).await;
+
+    let range = tsdb
+        .query_range("esnode_gpu_power_watts", i64::MIN, i64::MAX, i64::MAX)
+        .await
+        .unwrap_err();
+    assert_eq!(range.error_type(), "bad_data");
+    let lookback = tsdb
+        .query_instant("rate(esnode_gpu_energy_joules_total[5m])", i64::MIN)
+        .await
+        .unwrap_err();
+    assert_eq!(lookback.error_type(), "bad_data");
+
+    // The last step lands near i64::MAX without stepping past it.
+    let series = matrix(
+        tsdb.query_range("esnode_gpu_power_watts", i64::MAX - 10, i64::MAX, 7)
+            .await
+            .unwrap(),
+    );
+    assert!(series.is_empty());
+}
@@ -0,0 +1,649 @@
+use std::collections::HashMap;
+use std::io::Write;
+use std::time::Duration;
+
+use agent_core::config::SeriesOverflow;
+use agent_core::tsdb::{
+    CardinalityLimits, ExportCursor, ExportFormat, LocalTsdb, LocalTsdbConfig, Sample,
+};
+use tempfile::TempDir;
+
+// 2024-01-01T00:00:00Z, aligned to the 2h block window.
+const BASE_MS: i64 = 1_704_067_200_000;
+
+fn open(dir: &TempDir) -> LocalTsdb {
+    LocalTsdb::new(LocalTsdbConfig {
+        path: dir.path().to_path_buf(),
+        retention_hours: 24 * 365 * 10,
+        max_disk_mb: 512,
+        wal_fsync_interval: Duration::ZERO,
+        rollups: Vec::new(),
+        cardinality: Default::default(),
+    })
+    .unwrap()
+}
+
+fn gpu_sample(uuid: &str, ts_ms: i64, value: f64) -> Sample {
+    let mut labels = HashMap::new();
+    labels.insert("uuid".to_string(), uuid.to_string());
+    labels.insert("index".to_string(), "0".to_string());
+    Sample {
+        metric: "esnode_gpu_power_watts".to_string(),
+        labels,
+        ts_ms,
+        value,
+    }
+}
+
+fn block_dir(dir: &TempDir) -> std::path::PathBuf {
+    dir.path()
+        .join(format!("{}-{}", BASE_MS, BASE_MS + 2 * 60 * 60 * 1000))
+}
+
+#[tokio::test]
+async fn columnar_blocks_roundtrip_through_export() {
+    let dir = TempDir::new().unwrap();
+    let tsdb = open(&dir);
+
+    let mut samples = Vec::new();
+    for i in 0..300 {
+        let ts = BASE_MS + i * 1_000;
+        samples.push(gpu_sample("GPU-a", ts, 250.0 + (i % 7) as f64));
+        samples.push(gpu_sample("GPU-b", ts, 100.5));
+    }
+    tsdb.write_samples(&samples).await.unwrap();
+    tsdb.flush_current().await.unwrap();
+
+    let lines = tsdb.export_lines(None, None, None).await.unwrap();
+    assert_eq!(lines.len(), samples.len());
+    assert!(lines.contains(&format!(
+        r#"esnode_gpu_power_watts{{index="0",uuid="GPU-a"}} {} 256"#,
+        BASE_MS + 6_000
+    )));
+
+    let block_dir = block_dir(&dir);
+    assert!(block_dir.join("chunks.bin").exists());
+    assert!(!block_dir.join("samples.jsonl").exists());
+    let json_bytes: usize = samples
+        .iter()
+        .map(|s| serde_json::to_string(s).unwrap().len() + 1)
+        .sum();
+    let block_bytes = std::fs::metadata(block_dir.join("chunks.bin"))
+        .unwrap()
+        .len() as usize;
+    assert!(
+        block_bytes * 10 < json_bytes,
+        "block {block_bytes}B vs jsonl {json_bytes}B"
+    );
+
+    let ranged = tsdb
+        .export_lines(Some(BASE_MS + 10_000), Some(BASE_MS + 19_000), None)
+        .await
+        .unwrap();
+    assert_eq!(ranged.len(), 20);
+}
+
+#[tokio::test]
+async fn wal_replays_samples_after_unclean_shutdown() {
+    let dir = TempDir::new().unwrap();
+    {
+        let tsdb = open(&dir);
+        let samples: Vec<Sample> = (0..50)
+            .map(|i| gpu_sample("GPU-a", BASE_MS + i * 1_000, i as f64))
+            .collect();
+        tsdb.write_samples(&samples).await.unwrap();
+        // Dropped without flush_current: open chunks and the index never hit disk.
+    }
+    assert!(block_dir(&dir).join("wal.log").exists());
+    assert!(!block_dir(&dir).join("index.json").exists());
+
+    let tsdb = open(&dir);
+    assert!(!block_dir(&dir).join("wal.log").exists());
+    let index = std::fs::read_to_string(block_dir(&dir).join("index.json")).unwrap();
+    assert!(index.contains(r#""samples": 50"#), "{index}");
+
+    // Samples written after restart land in the same block without duplicates.
+    tsdb.write_samples(&[gpu_sample("GPU-a", BASE_MS + 60_000, 7.0)])
+        .await
+        .unwrap();
+    let lines = tsdb.export_lines(None, None, None).await.unwrap();
+    assert_eq!(lines.len(), 51);
+    assert!(lines[49].ends_with(" 49"));
+}
+
+#[tokio::test]
+async fn unrecoverable_wal_is_moved_aside_not_truncated() {
+    let dir = TempDir::new().unwrap();
+    {
+        let tsdb = open(&dir);
+        let samples: Vec<Sample> = (0..50)
+            .map(|i| gpu_sample("GPU-a", BASE_MS + i * 1_000, i as f64))
+            .collect();
+        tsdb.write_samples(&samples).await.unwrap();
+    }
+    let wal = std::fs::read(block_dir(&dir).join("wal.log")).unwrap();
+    // A corrupt block file stops recovery before the WAL is replayed.
+    std::fs::write(block_dir(&dir).join("chunks.bin"), b"garbage!").unwrap();
+
+    let tsdb = open(&dir);
+    tsdb.write_samples(&[gpu_sample("GPU-a", BASE_MS + 60_000, 7.0)])
+        .await
+        .unwrap();
+    let aside: Vec<_> = std::fs::read_dir(block_dir(&dir))
+        .unwrap()
+        .flatten()
+        .filter(|e| {
+            e.file_name()
+                .to_string_lossy()
+                .starts_with("wal.log.corrupt-")
+        })
+        .collect();
+    assert_eq!(aside.len(), 1);
+    assert_eq!(std::fs::read(aside[0].path()).unwrap(), wal);
+}
+
+#[tokio::test]
+async fn recovery_truncates_torn_tails() {
+    let dir = TempDir::new().unwrap();
+    {
+        let tsdb = open(&dir);
+        tsdb.write_samples(&[gpu_sample("GPU-a", BASE_MS, 1.0)])
+            .await
+            .unwrap();
+        tsdb.flush_current().await.unwrap();
+    }
+    let chunks = block_dir(&dir).join("chunks.bin");
+    let clean_len = std::fs::metadata(&chunks).unwrap().len();
+    let mut f = std::fs::OpenOptions::new()
+        .append(true)
+        .open(&chunks)
+        .unwrap();
+    f.write_all(&[0x02, 0xde, 0xad]).unwrap();
+
+    let legacy = block_dir(&dir).join("samples.jsonl");
+    let line = serde_json::to_string(&gpu_sample("GPU-old", BASE_MS + 5, 3.0)).unwrap();
+    std::fs::write(&legacy, format!("{line}\n{}", &line[..line.len() / 2])).unwrap();
+
+    let tsdb = open(&dir);
+    assert_eq!(std::fs::metadata(&chunks).unwrap().len(), clean_len);
+    assert_eq!(
+        std::fs::read_to_string(&legacy).unwrap(),
+        format!("{line}\n")
+    );
+    let lines = tsdb.export_lines(None, None, None).await.unwrap();
+    assert_eq!(lines.len(), 2);
+}
+
+#[tokio::test]
+async fn legacy_jsonl_blocks_remain_readable() {
+    let dir = TempDir::new().unwrap();
+    let block_dir = block_dir(&dir);
+    std::fs::create_dir_all(&block_dir).unwrap();
+    let legacy: Vec<String> = (0..5)
+        .map(|i| serde_json::to_string(&gpu_sample("GPU-old", BASE_MS + i * 30_000, 1.0)).unwrap())
+        .collect();
+    std::fs::write(block_dir.join("samples.jsonl"), legacy.join("\n")).unwrap();
+
+    let tsdb = open(&dir);
+    tsdb.write_samples(&[gpu_sample("GPU-new", BASE_MS + 200_000, 2.0)])
+        .await
+        .unwrap();
+    tsdb.flush_current().await.unwrap();
+
+    let lines = tsdb
+        .export_lines(None, None, Some(&vec!["esnode_gpu_*".to_string()]))
+        .await
+        .unwrap();
+    assert_eq!(lines.len(), 6);
+    assert!(lines[0].contains("GPU-old"));
+    assert!(lines[5].contains("GPU-new"));
+}
+
+#[tokio::test]
+async fn rollup_tiers_serve_ranges_past_raw_retention() {
+    use agent_core::config::LocalTsdbRollupTier;
+    use agent_core::tsdb::query::QueryResult;
+
+    const HOUR_MS: i64 = 60 * 60 * 1000;
+    let dir = TempDir::new().unwrap();
+    let tsdb = LocalTsdb::new(LocalTsdbConfig {
+        path: dir.path().to_path_buf(),
+        retention_hours: 1,
+        max_disk_mb: 512,
+        wal_fsync_interval: Duration::ZERO,
+        rollups: vec![
+            LocalTsdbRollupTier {
+                resolution: Duration::from_secs(60),
+                retention: Duration::from_secs(7 * 24 * 3600),
+            },
+            LocalTsdbRollupTier {
+                resolution: Duration::from_secs(15 * 60),
+                retention: Duration::from_secs(90 * 24 * 3600),
+            },
+        ],
+        cardinality: Default::default(),
+    })
+    .unwrap();
+
+    // Three 2h blocks scraped every 15s; each minute averages 1.5 (0..=3).
+    let samples: Vec<Sample> = (0..(6 * 60 * 4))
+        .map(|i| gpu_sample("GPU-a", BASE_MS + i * 15_000, (i % 4) as f64))
+        .collect();
+    tsdb.write_samples(&samples).await.unwrap();
+    tsdb.flush_current().await.unwrap();
+    let now = BASE_MS + 6 * HOUR_MS;
+
+    // Raw blocks past retention survive until their first rollup exists.
+    tsdb.prune(now).await.unwrap();
+    assert_eq!(
+        tsdb.export_lines(None, None, None).await.unwrap().len(),
+        samples.len()
+    );
+
+    assert_eq!(tsdb.compact(now).await.unwrap(), 6);
+    assert_eq!(tsdb.compact(now).await.unwrap(), 0);
+    assert!(dir
+        .path()
+        .join("rollup-15m")
+        .join(block_dir(&dir).file_name().unwrap())
+        .exists());
+    tsdb.prune(now).await.unwrap();
+    assert!(!block_dir(&dir).exists());
+
+    // Expired raw hours come from the 1m tier, the rest from raw blocks.
+    let lines = tsdb.export_lines(None, None, None).await.unwrap();
+    assert_eq!(lines.len(), 2 * 60 * 2 + 2 * 60 * 4);
+    assert_eq!(
+        lines[0],
+        format!(r#"esnode_gpu_power_watts{{index="0",uuid="GPU-a"}} {BASE_MS} 1.5"#)
+    );
+    assert!(lines.last().unwrap().ends_with(" 3"));
+
+    let ranged = tsdb
+        .export_lines(Some(BASE_MS + HOUR_MS / 2), Some(BASE_MS + HOUR_MS), None)
+        .await
+        .unwrap();
+    assert_eq!(ranged.len(), 31);
+
+    let at = BASE_MS + 10 * 60 * 1000;
+    let QueryResult::Vector(avg) = tsdb
+        .query_instant("esnode_gpu_power_watts", at)
+        .await
+        .unwrap()
+    else {
+        panic!("expected vector");
+    };
+    assert_eq!(avg.len(), 1);
+    assert_eq!(avg[0].value, 1.5);
+    assert!(!avg[0].labels.contains_key("__rollup__"));
+    let QueryResult::Vector(max) = tsdb
+        .query_instant(r#"max(esnode_gpu_power_watts{__rollup__="max"})"#, at)
+        .await
+        .unwrap()
+    else {
+        panic!("expected vector");
+    };
+    assert_eq!(max[0].value, 3.0);
+}
+
+/// Parses `name{k="v",...} ts value` back into a sample (no escaping needed
+/// for the label values used here).
+fn parse_export_line(line: &str) -> Sample {
+    let (series, rest) = match line.find('}') {
+        Some(end) => (&line[..=end], line[end + 1..].trim()),
+        None => line.split_once(' ').unwrap(),
+    };
+    let (metric, labels) = match series.split_once('{') {
+        Some((metric, labels)) => (metric, labels.trim_end_matches('}')),
+        None => (series, ""),
+    };
+    let labels = labels
+        .split(',')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (k, v) = pair.split_once('=').unwrap();
+            (k.to_string(), v.trim_matches('"').to_string())
+        })
+        .collect();
+    let (ts, value) = rest.split_once(' ').unwrap();
+    let value = match value {
+        "+Inf" => f64::INFINITY,
+        "-Inf" => f64::NEG_INFINITY,
+        v => v.parse().unwrap(),
+    };
+    Sample {
+        metric: metric.to_string(),
+        labels,
+        ts_ms: ts.parse().unwrap(),
+        value,
+    }
+}
+
+#[tokio::test]
+async fn histograms_and_summaries_roundtrip_through_export() {
+    use agent_core::tsdb::samples_from_families;
+    use prometheus::proto::{MetricFamily, MetricType, Quantile};
+    use prometheus::{HistogramOpts, HistogramVec, Registry};
+
+    let registry = Registry::new();
+    let latency = HistogramVec::new(
+        HistogramOpts::new("esnode_test_latency_seconds", "test latency")
+            .buckets(vec![0.005, 0.1, 1.0]),
+        &["collector"],
+    )
+    .unwrap();
+    registry.register(Box::new(latency.clone())).unwrap();
+    for v in [0.001, 0.05, 0.05, 0.3, 7.25] {
+        latency.with_label_values(&["gpu"]).observe(v);
+    }
+
+    // The prometheus crate has no summary collector; build the family by hand.
+    let mut summary_family = MetricFamily::default();
+    summary_family.set_name("esnode_test_rtt_seconds".to_string());
+    summary_family.set_field_type(MetricType::SUMMARY);
+    let mut metric = prometheus::proto::Metric::default();
+    let mut summary = prometheus::proto::Summary::default();
+    for (q, v) in [(0.5, 0.012), (0.99, 0.3)] {
+        let mut quantile = Quantile::default();
+        quantile.set_quantile(q);
+        quantile.set_value(v);
+        summary.mut_quantile().push(quantile);
+    }
+    summary.set_sample_sum(1.234_567_891);
+    summary.set_sample_count(42);
+    metric.set_summary(summary);
+    summary_family.mut_metric().push(metric);
+
+    let mut families = registry.gather();
+    families.push(summary_family);
+    let samples = samples_from_families(&families, BASE_MS);
+
+    let bucket = |le: &str| {
+        samples
+            .iter()
+            .find(|s| s.metric == "esnode_test_latency_seconds_bucket" && s.labels["le"] == le)
+            .map(|s| s.value)
+    };
+    assert_eq!(bucket("0.005"), Some(1.0));
+    assert_eq!(bucket("0.1"), Some(3.0));
+    assert_eq!(bucket("1"), Some(4.0));
+    assert_eq!(bucket("+Inf"), Some(5.0));
+    assert_eq!(samples.len(), 4 + 2 + 2 + 2);
+
+    let dir = TempDir::new().unwrap();
+    let tsdb = open(&dir);
+    tsdb.write_samples(&samples).await.unwrap();
+    tsdb.flush_current().await.unwrap();
+
+    let mut exported: Vec<Sample> = tsdb
+        .export_lines(None, None, None)
+        .await
+        .unwrap()
+        .iter()
+        .map(|line| parse_export_line(line))
+        .collect();
+    let key = |s: &Sample| {
+        let mut labels: Vec<_> = s.labels.iter().collect();
+        labels.sort();
+        format!("{}{labels:?}", s.metric)
+    };
+    exported.sort_by_key(key);
+    let mut expected = samples.clone();
+    expected.sort_by_key(key);
+    assert_eq!(exported.len(), expected.len());
+    for (got, want) in exported.iter().zip(&expected) {
+        assert_eq!(got.metric, want.metric);
+        assert_eq!(got.labels, want.labels);
+        assert_eq!(got.ts_ms, want.ts_ms);
+        // Bit-exact, including sums that need more than six decimals.
+        assert_eq!(got.value.to_bits(), want.value.to_bits(), "{}", got.metric);
+    }
+}
+
+#[tokio::test]
+async fn export_formats_escape_labels_and_terminate() {
+    let dir = TempDir::new().unwrap();
+    let tsdb = open(&dir);
+    let mut odd = gpu_sample("GPU-a", BASE_MS + 1_250, 7.0);
+    odd.labels.insert(
+        "model".to_string(),
+        "NVIDIA \"H100\"\nPCIe\\80GB".to_string(),
+    );
+    tsdb.write_samples(&[
+        gpu_sample("GPU-b", BASE_MS, 1.0),
+        odd,
+        gpu_sample("GPU-b", BASE_MS + 2_000, 2.0),
+    ])
+    .await
+    .unwrap();
+
+    let body = tsdb
+        .export(None, None, None, ExportFormat::OpenMetrics)
+        .await
+        .unwrap();
+    assert_eq!(
+        body,
+        "esnode_gpu_power_watts{index=\"0\",model=\"NVIDIA \\\"H100\\\"\\nPCIe\\\\80GB\",uuid=\"GPU-a\"} 7 1704067201.25\n\
+         esnode_gpu_power_watts{index=\"0\",uuid=\"GPU-b\"} 1 1704067200\n\
+         esnode_gpu_power_watts{index=\"0\",uuid=\"GPU-b\"} 2 1704067202\n\
+         # EOF\n"
+    );
+
+    let csv = tsdb
+        .export(None, None, None, ExportFormat::Csv)
+        .await
+        .unwrap();
+    let rows: Vec<&str> = csv.lines().collect();
+    assert_eq!(rows.len(), 4);
+    assert_eq!(rows[0], "metric,labels,timestamp_ms,value");
+    assert_eq!(
+        rows[1],
+        format!("esnode_gpu_power_watts,\"{{index=\"\"0\"\",uuid=\"\"GPU-b\"\"}}\",{BASE_MS},1")
+    );
+
+    let jsonl = tsdb
+        .export(None, None, None, ExportFormat::Jsonl)
+        .await
+        .unwrap();
+    let second: serde_json::Value = serde_json::from_str(jsonl.lines().nth(1).unwrap()).unwrap();
+    assert_eq!(second["metric"]["model"], "NVIDIA \"H100\"\nPCIe\\80GB");
+    assert_eq!(second["timestamp"], BASE_MS + 1_250);
+
+    let columnar = tsdb
+        .export(None, None, None, ExportFormat::Columnar)
+        .await
+        .unwrap();
+    let series: Vec<serde_json::Value> = columnar
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect();
+    assert_eq!(series.len(), 2);
+    assert_eq!(series[1]["metric"]["uuid"], "GPU-b");
+    assert_eq!(series[1]["values"], serde_json::json!([1.0, 2.0]));
+    assert_eq!(
+        series[1]["timestamps"],
+        serde_json::json!([BASE_MS, BASE_MS + 2_000])
+    );
+}
+
+#[tokio::test]
+async fn paged_export_resumes_from_cursor_across_blocks() {
+    const HOUR_MS: i64 = 60 * 60 * 1000;
+    let dir = TempDir::new().unwrap();
+    let tsdb = open(&dir);
+    // Two series sharing timestamps, spanning several scan windows and a
+    // closed block plus the open one.
+    let mut samples = Vec::new();
+    for i in 0..40 {
+        let ts = BASE_MS + 2 * HOUR_MS - 20 * 60_000 + i * 60_000;
+        samples.push(gpu_sample("GPU-a", ts, i as f64));
+        samples.push(gpu_sample("GPU-b", ts, -(i as f64)));
+    }
+    tsdb.write_samples(&samples).await.unwrap();
+
+    let full = tsdb
+        .export(None, None, None, ExportFormat::Text)
+        .await
+        .unwrap();
+    assert_eq!(full.lines().count(), samples.len());
+
+    let mut paged = String::new();
+    let mut after: Option<ExportCursor> = None;
+    let mut pages = 0;
+    loop {
+        let mut scan = tsdb.scan(None, None, None, after).await.unwrap();
+        let (body, next) = ExportFormat::Text.page(&mut scan, 7).await.unwrap();
+        paged.push_str(&body);
+        pages += 1;
+        match next {
+            Some(cursor) => after = Some(cursor.to_string().parse().unwrap()),
+            None => break,
+        }
+    }
+    assert_eq!(pages, samples.len().div_ceil(7));
+    assert_eq!(paged, full);
+
+    let mut ranged = tsdb
+        .scan(Some(BASE_MS + 2 * HOUR_MS), None, None, None)
+        .await
+        .unwrap();
+    let (body, next) = ExportFormat::Text.page(&mut ranged, 1_000).await.unwrap();
+    assert!(next.is_none());
+    assert_eq!(body.lines().count(), 40);
+    assert!("not-a-token".parse::<ExportCursor>().is_err());
+}
+
+#[tokio::test]
+async fn cardinality_limits_drop_or_aggregate_new_series() {
+    for overflow in [SeriesOverflow::Drop, SeriesOverflow::Aggregate] {
+        let dir = TempDir::new().unwrap();
+        let tsdb = LocalTsdb::new(LocalTsdbConfig {
+            path: dir.path().to_path_buf(),
+            retention_hours: 24,
+            max_disk_mb: 512,
+            wal_fsync_interval: Duration::ZERO,
+            rollups: Vec::new(),
+            cardinality: CardinalityLimits {
+                max_series: Some(10),
+                max_series_per_metric: Some(2),
+                overflow,
+            },
+        })
+        .unwrap();
+        for ts in [BASE_MS, BASE_MS + 1_000] {
+            let samples: Vec<Sample> = (0..5)
+                .map(|gpu| gpu_sample(&format!("GPU-{gpu}"), ts, 10.0 * (gpu + 1) as f64))
+                .collect();
+            tsdb.write_samples(&samples).await.unwrap();
+        }
+
+        let lines = tsdb.export_lines(None, None, None).await.unwrap();
+        let stats = tsdb.take_cardinality_stats();
+        assert_eq!(stats.active_series, 2);
+        match overflow {
+            SeriesOverflow::Drop => {
+                assert_eq!(lines.len(), 4);
+                assert_eq!(stats.dropped_samples, 6);
+            }
+            SeriesOverflow::Aggregate => {
+                assert_eq!(lines.len(), 6);
+                assert_eq!(stats.aggregated_samples, 6);
+                assert!(lines.contains(&format!(
+                    r#"esnode_gpu_power_watts{{esnode_series_overflow="true"}} {BASE_MS} 120"#
+                )));
+            }
+        }
+        assert_eq!(tsdb.take_cardinality_stats().dropped_samples, 0);
+    }
+}
+
+#[tokio::test]
+async fn admin_stats_tombstones_and_snapshot() {
+    const HOUR_MS: i64 = 60 * 60 * 1000;
+    let dir = TempDir::new().unwrap();
+    let tsdb = open(&dir);
+    // Closed block with both GPUs, open block with GPU-a only.
+    for (uuid, ts) in [
+        ("GPU-a", BASE_MS),
+        ("GPU-b", BASE_MS),
+        ("GPU-a", BASE_MS + HOUR_MS),
+        ("GPU-b", BASE_MS + HOUR_MS),
+        ("GPU-a", BASE_MS + 2 * HOUR_MS),
+    ] {
+        tsdb.write_samples(&[gpu_sample(uuid, ts, 1.0)])
+            .await
+            .unwrap();
+    }
+
+    let blocks = tsdb.blocks().await.unwrap();
+    assert_eq!(blocks.len(), 2);
+    assert_eq!(
+        (blocks[0].samples, blocks[0].open, blocks[1].samples, blocks[1].open),
+        (4, false, 1, true)
+    );
+    let stats = tsdb.stats(5).await.unwrap();
+    assert_eq!((stats.blocks, stats.samples), (2, 5));
+    assert_eq!(stats.top_metrics[0].metric, "esnode_gpu_power_watts");
+    assert_eq!(stats.top_metrics[0].samples, 5);
+
+    assert!(tsdb
+        .delete_series("{uuid=~\".*\"}", None, None, BASE_MS)
+        .await
+        .is_err());
+    let tombstone = tsdb
+        .delete_series(
+            "esnode_gpu_power_watts{uuid=\"GPU-b\"}",
+            Some(BASE_MS + HOUR_MS),
+            None,
+            BASE_MS + 3 * HOUR_MS,
+        )
+        .await
+        .unwrap();
+    assert_eq!(tombstone.to_ms, BASE_MS + 3 * HOUR_MS);
+    let visible = |lines: Vec<String>| {
+        lines
+            .iter()
+            .filter(|l| l.contains("GPU-b"))
+            .map(|l| parse_export_line(l).ts_ms)
+            .collect::<Vec<_>>()
+    };
+    let lines = tsdb.export_lines(None, None, None).await.unwrap();
+    assert_eq!(lines.len(), 4);
+    assert_eq!(visible(lines), [BASE_MS]);
+
+    // Tombstones persist across restarts.
+    drop(tsdb);
+    let tsdb = open(&dir);
+    assert_eq!(tsdb.stats(5).await.unwrap().tombstones, 1);
+
+    let mut tar = Vec::new();
+    tsdb.snapshot(BASE_MS + 3 * HOUR_MS)
+        .await
+        .unwrap()
+        .write_tar(&mut tar)
+        .unwrap();
+    assert!(!dir.path().join("snapshots").join((BASE_MS + 3 * HOUR_MS).to_string()).exists());
+    // Links left by an unfinished snapshot are dropped with it, and those of
+    // a crashed agent at startup.
+    let dropped = tsdb.snapshot(BASE_MS + 3 * HOUR_MS + 1).await.unwrap();
+    let dropped_dir = dir
+        .path()
+        .join("snapshots")
+        .join((BASE_MS + 3 * HOUR_MS + 1).to_string());
+    assert!(dropped_dir.exists());
+    drop(dropped);
+    assert!(!dropped_dir.exists());
+    std::fs::create_dir_all(dir.path().join("snapshots").join("1")).unwrap();
+    drop(tsdb);
+    open(&dir);
+    assert!(!dir.path().join("snapshots").exists());
+    let restored = TempDir::new().unwrap();
+    tar::Archive::new(tar.as_slice())
+        .unpack(restored.path())
+        .unwrap();
+    let offline = open(&restored);
+    let lines = offline.export_lines(None, None, None).await.unwrap();
+    // Only the closed block, with the tombstone still applied.
+    assert_eq!(lines.len(), 3);
+    assert_eq!(visible(lines), [BASE_MS]);
+}
@@ -70,7 +70,7 @@ impl Decoder for Dnp3Codec {
         // Physical: Header contains Ctrl, Dest, Src. 
         // Remaining User Data (Body) = Length - 5.
         
-        let body_len = length as usize - 5;
+        let body_len = length - 5;
         
         // Calculation of CRC blocks for BODY only.
         let num_full_blocks = body_len / 16;
@@ -125,7 +125,7 @@ impl Encoder<Dnp3Frame> for Dnp3Codec {
         
         // Calculate needed capacity: Header (10) + Payload + Payload CRCs
         let payload_len = item.payload.len();
-        let payload_crcs = (payload_len / 16 + if payload_len % 16 > 0 { 1 } else { 0 }) * 2;
+        let payload_crcs = (payload_len / 16 + if !payload_len.is_multiple_of(16) { 1 } else { 0 }) * 2;
         dst.reserve(10 + payload_len + payload_crcs);
         
         dst.put_u8(0x05);
@@ -254,8 +254,6 @@ impl Driver for Dnp3Driver {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use tokio::net::TcpListener;
-    use tokio::io::AsyncReadExt;
     
     #[tokio::test]
     async fn test_dnp3_codec() {
@@ -147,6 +147,7 @@ impl MqttDriver {
     }
 
     /// Parse JSON payload and extract value using JSON path
+    #[allow(dead_code)]
     fn extract_value(&self, payload: &str, value_path: &str) -> Option<f64> {
         let json: serde_json::Value = serde_json::from_str(payload).ok()?;
         
@@ -167,6 +168,7 @@ impl MqttDriver {
     }
 
     /// Match topic to mapping
+    #[allow(dead_code)]
     fn find_mapping(&self, topic: &str) -> Option<&TopicMapping> {
         self.config.topic_mappings.iter().find(|mapping| {
             // Simple wildcard matching (MQTT style)
@@ -311,7 +313,7 @@ impl Driver for MqttDriver {
             } else {
                 // Use system certificates
                 for cert in load_native_certs()? {
-                    root_cert_store.add(rustls::pki_types::CertificateDer::from(cert))?;
+                    root_cert_store.add(cert)?;
                 }
             };
             